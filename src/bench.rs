@@ -0,0 +1,171 @@
+use libdoh::*;
+
+use rand::Rng;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const QUERY_TIMEOUT_SECS: u64 = 10;
+
+/// How the names of the queries are chosen from the list of names
+#[derive(Debug, Clone, Copy)]
+pub enum NameDistribution {
+    /// Every name has the same probability
+    Uniform,
+    /// The probability of a name is inversely proportional to its rank, as with real traffic
+    Zipf,
+    /// A random label is prepended to a random name, so that responses are never cached
+    Random,
+}
+
+impl FromStr for NameDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(NameDistribution::Uniform),
+            "zipf" => Ok(NameDistribution::Zipf),
+            "random" => Ok(NameDistribution::Random),
+            _ => Err(format!("Unsupported name distribution: [{}]", s)),
+        }
+    }
+}
+
+/// Load to generate with the `bench` subcommand
+#[derive(Debug)]
+pub struct BenchOptions {
+    pub url: String,
+    pub concurrency: usize,
+    pub requests: usize,
+    pub get: bool,
+    pub names: Vec<String>,
+    pub distribution: NameDistribution,
+    pub qtype: u16,
+}
+
+struct NamePicker {
+    names: Vec<String>,
+    distribution: NameDistribution,
+    /// Sums of the Zipf weights of the names up to every rank
+    cumulative_weights: Vec<f64>,
+}
+
+impl NamePicker {
+    fn new(names: Vec<String>, distribution: NameDistribution) -> Self {
+        let cumulative_weights = (1..=names.len())
+            .scan(0.0, |sum, rank| {
+                *sum += 1.0 / rank as f64;
+                Some(*sum)
+            })
+            .collect();
+        NamePicker {
+            names,
+            distribution,
+            cumulative_weights,
+        }
+    }
+
+    fn pick<R: Rng>(&self, rng: &mut R) -> String {
+        match self.distribution {
+            NameDistribution::Uniform => self.names[rng.gen_range(0, self.names.len())].clone(),
+            NameDistribution::Zipf => {
+                let total = self.cumulative_weights[self.cumulative_weights.len() - 1];
+                let target = rng.gen::<f64>() * total;
+                let rank = self
+                    .cumulative_weights
+                    .partition_point(|&weight| weight < target)
+                    .min(self.names.len() - 1);
+                self.names[rank].clone()
+            }
+            NameDistribution::Random => {
+                let name = &self.names[rng.gen_range(0, self.names.len())];
+                format!("{:016x}.{}", rng.gen::<u64>(), name)
+            }
+        }
+    }
+}
+
+/// Sends queries to a DoH server, and prints the throughput and latency percentiles
+pub async fn run(options: BenchOptions) -> Result<(), String> {
+    if options.names.is_empty() {
+        return Err("No names to send queries for".to_string());
+    }
+    let upstream = Arc::new(DoHUpstream::new(
+        DoHUpstream::parse_url(&options.url)?,
+        None,
+        None,
+    ));
+    let picker = Arc::new(NamePicker::new(options.names, options.distribution));
+    let sent = Arc::new(AtomicUsize::new(0));
+    let (requests, get, qtype) = (options.requests, options.get, options.qtype);
+    let start = Instant::now();
+    let workers: Vec<_> = (0..options.concurrency.max(1))
+        .map(|_| {
+            let (upstream, picker, sent) = (upstream.clone(), picker.clone(), sent.clone());
+            tokio::spawn(async move {
+                let mut latencies = vec![];
+                let mut failures = 0;
+                while sent.fetch_add(1, Ordering::Relaxed) < requests {
+                    let query = {
+                        let mut rng = rand::thread_rng();
+                        dns::build_query(rng.gen(), &picker.pick(&mut rng), qtype)
+                    };
+                    let query = match query {
+                        Ok(query) => query,
+                        Err(_) => {
+                            failures += 1;
+                            continue;
+                        }
+                    };
+                    let query_start = Instant::now();
+                    let exchange = async {
+                        if get {
+                            upstream.exchange_get(&query).await
+                        } else {
+                            upstream.exchange(&query).await
+                        }
+                    };
+                    let timeout = Duration::from_secs(QUERY_TIMEOUT_SECS);
+                    match tokio::time::timeout(timeout, exchange).await {
+                        Ok(Ok(_)) => latencies.push(query_start.elapsed()),
+                        _ => failures += 1,
+                    }
+                }
+                (latencies, failures)
+            })
+        })
+        .collect();
+    let mut latencies = vec![];
+    let mut failures = 0;
+    for worker in workers {
+        let (worker_latencies, worker_failures) = worker.await.map_err(|e| e.to_string())?;
+        latencies.extend(worker_latencies);
+        failures += worker_failures;
+    }
+    let elapsed = start.elapsed();
+    latencies.sort();
+
+    println!("Requests:   {} ({} failed)", requests, failures);
+    println!("Duration:   {:.2} s", elapsed.as_secs_f64());
+    println!(
+        "Throughput: {:.0} responses/s",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    if latencies.is_empty() {
+        return Ok(());
+    }
+    let percentile = |p: f64| {
+        let index = ((latencies.len() as f64 * p).ceil() as usize).saturating_sub(1);
+        latencies[index.min(latencies.len() - 1)].as_secs_f64() * 1000.0
+    };
+    println!(
+        "Latency:    p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, p99.9 {:.2} ms, max {:.2} ms",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(0.999),
+        percentile(1.0)
+    );
+    Ok(())
+}
@@ -1,50 +1,183 @@
+use crate::config_file::ConfigFile;
+use libdoh::defaults::*;
 use libdoh::*;
 
-use crate::constants::*;
-
 use clap::Arg;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
 #[cfg(feature = "tls")]
-use std::path::PathBuf;
+use crate::bench::BenchOptions;
+#[cfg(target_os = "linux")]
+use crate::utils::verify_bind_device;
+#[cfg(unix)]
+use crate::utils::verify_reuseport;
+#[cfg(feature = "tls")]
+use crate::utils::{
+    verify_block_list_update_interval, verify_otlp_endpoint, verify_qtype, verify_upstream_doh,
+};
+#[cfg(feature = "tls")]
+use clap::{ArgMatches, SubCommand};
+
+/// Options about how the process runs, that are not settings of the proxy itself
+#[derive(Default)]
+pub struct ProcessOptions {
+    #[cfg(windows)]
+    pub windows_service: bool,
+    #[cfg(feature = "tls")]
+    pub otlp_exporter: Option<OtlpExporter>,
+    /// Set when load has to be generated against a server, instead of running the proxy
+    #[cfg(feature = "tls")]
+    pub bench: Option<BenchOptions>,
+    /// Set when the configuration has to be checked, instead of running the proxy
+    pub check_config: bool,
+    /// Number of threads running the proxy
+    pub runtime_threads: usize,
+    /// Set when every thread has to run its own single-threaded runtime
+    #[cfg(unix)]
+    pub per_core_runtime: bool,
+}
 
-pub fn parse_opts(globals: &mut Globals) {
-    use crate::utils::{verify_remote_server, verify_sock_addr};
+pub fn parse_opts(globals: &mut Globals) -> ProcessOptions {
+    use crate::utils::{
+        parse_bootstrap_dns, parse_ddr_hostname, parse_upstream, verify_address_filter,
+        verify_bootstrap_dns, verify_canary_domain, verify_client_key, verify_cors_origins,
+        verify_ddr_hostname, verify_dns64_prefix, verify_ecs_policy, verify_edns_strip,
+        verify_max_query_size, verify_max_udp_size, verify_qtypes, verify_query_log_size,
+        verify_response_header, verify_runtime_threads, verify_sock_addr, verify_stats_labels,
+        verify_trusted_proxies, verify_upstream, verify_upstream_proxy,
+    };
 
     let max_clients = MAX_CLIENTS.to_string();
+    let max_connection_queries = MAX_CONNECTION_QUERIES.to_string();
+    let max_query_size = MAX_QUERY_SIZE.to_string();
+    let max_query_param_len = MAX_DNS_QUERY_PARAM_LEN.to_string();
+    let udp_pool_size = UDP_POOL_SIZE.to_string();
     let timeout_sec = TIMEOUT_SEC.to_string();
+    let query_timeout_ms = QUERY_TIMEOUT_MS.to_string();
+    let header_read_timeout_sec = HEADER_READ_TIMEOUT_SEC.to_string();
+    let http_idle_timeout_sec = HTTP_IDLE_TIMEOUT_SEC.to_string();
+    let body_read_timeout_sec = BODY_READ_TIMEOUT_SEC.to_string();
+    let max_connection_lifetime_sec = MAX_CONNECTION_LIFETIME_SEC.to_string();
+    let upstream_timeout_ms = UPSTREAM_TIMEOUT_MS.to_string();
+    let upstream_retries = UPSTREAM_RETRIES.to_string();
+    let max_udp_size = MAX_UDP_SIZE.to_string();
     let min_ttl = MIN_TTL.to_string();
     let max_ttl = MAX_TTL.to_string();
     let err_ttl = ERR_TTL.to_string();
+    let negative_cache_size = NEGATIVE_CACHE_SIZE.to_string();
+    let cache_size = CACHE_SIZE.to_string();
+    let cache_serve_stale_ttl = CACHE_SERVE_STALE_TTL.to_string();
+    let cache_persist_interval = CACHE_PERSIST_INTERVAL_SEC.to_string();
+    let block_list_reload_interval = BLOCK_LIST_RELOAD_INTERVAL_SEC.to_string();
+    let rewrite_ttl = REWRITE_TTL.to_string();
+    let upstream_probe_interval = UPSTREAM_PROBE_INTERVAL_SEC.to_string();
+    let upstream_resolve_interval = UPSTREAM_RESOLVE_INTERVAL_SEC.to_string();
+    #[cfg(feature = "tls")]
+    let block_list_update_interval = BLOCK_LIST_UPDATE_INTERVAL_SEC.to_string();
+    #[cfg(feature = "tls")]
+    let tls_ticket_rotation_interval = TLS_TICKET_ROTATION_INTERVAL_SEC.to_string();
 
     let _ = include_str!("../Cargo.toml");
     let options = app_from_crate!()
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .help("Path to a TOML configuration file, for settings such as additional endpoints"),
+        )
+        .arg(
+            Arg::with_name("check_config")
+                .long("check-config")
+                .help("Check the configuration, the TLS certificates and the upstream servers, then exit with a non-zero status if anything is wrong"),
+        )
         .arg(
             Arg::with_name("listen_address")
                 .short("l")
                 .long("listen-address")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .default_value(LISTEN_ADDRESS)
                 .validator(verify_sock_addr)
-                .help("Address to listen to"),
+                .help("Address to listen to (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("dns_listen_address")
+                .long("dns-listen-address")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_sock_addr)
+                .conflicts_with_all(&["auth_token", "auth_token_file"])
+                .help("Address to answer plain DNS queries on, over UDP and TCP (can be repeated)"),
         )
         .arg(
             Arg::with_name("server_address")
                 .short("u")
                 .long("server-address")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .default_value(SERVER_ADDRESS)
-                .validator(verify_remote_server)
-                .help("Address to connect to"),
+                .validator(verify_upstream)
+                .help("Address or host:port name to connect to, optionally followed by @weight (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("upstream_strategy")
+                .long("upstream-strategy")
+                .takes_value(true)
+                .default_value("failover")
+                .possible_values(&["failover", "weighted", "latency"])
+                .help("How to choose between upstream servers"),
+        )
+        .arg(
+            Arg::with_name("upstream_resolve_interval")
+                .long("upstream-resolve-interval")
+                .takes_value(true)
+                .default_value(&upstream_resolve_interval)
+                .help("Interval between resolutions of the upstream server names, in seconds (0 to only resolve them at startup)"),
         )
         .arg(
             Arg::with_name("local_bind_address")
                 .short("b")
                 .long("local-bind-address")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .validator(verify_sock_addr)
-                .help("Address to connect from"),
+                .help("Address to connect from (can be repeated, once per address family)"),
+        )
+        .arg(
+            Arg::with_name("upstream_proxy")
+                .long("upstream-proxy")
+                .takes_value(true)
+                .validator(verify_upstream_proxy)
+                .help("Send queries over TCP through a proxy (\"socks5://[user:password@]host:port\" or \"http://[user:password@]host:port\")"),
+        )
+        .arg(
+            Arg::with_name("bootstrap_dns")
+                .long("bootstrap-dns")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_bootstrap_dns)
+                .help("DNS server used only to resolve the names of the upstream servers, instead of the system resolver (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("udp_pool_size")
+                .long("udp-pool-size")
+                .takes_value(true)
+                .default_value(&udp_pool_size)
+                .help("Number of UDP sockets shared by upstream queries, each replaced after 100 queries or 10 seconds (0 to use a new socket for every query)"),
+        )
+        .arg(
+            Arg::with_name("runtime_threads")
+                .long("runtime-threads")
+                .takes_value(true)
+                .validator(verify_runtime_threads)
+                .help("Number of threads processing connections and queries (default: number of CPU cores)"),
         )
         .arg(
             Arg::with_name("path")
@@ -62,13 +195,136 @@ pub fn parse_opts(globals: &mut Globals) {
                 .default_value(&max_clients)
                 .help("Maximum number of simultaneous clients"),
         )
+        .arg(
+            Arg::with_name("max_connection_queries")
+                .long("max-connection-queries")
+                .takes_value(true)
+                .default_value(&max_connection_queries)
+                .help("Maximum number of simultaneous queries on an HTTP/2 connection"),
+        )
+        .arg(
+            Arg::with_name("max_client_queries")
+                .long("max-client-queries")
+                .takes_value(true)
+                .help("Maximum number of simultaneous queries from a client IP address (default: no limit)"),
+        )
+        .arg(
+            Arg::with_name("client_key")
+                .long("client-key")
+                .takes_value(true)
+                .default_value("ip")
+                .validator(verify_client_key)
+                .help("What clients are told apart by for --max-client-queries: \"ip\", \"ip+user-agent\" or \"prefix[:<IPv4 length>/<IPv6 length>]\""),
+        )
+        .arg(
+            Arg::with_name("daily_quota")
+                .long("daily-quota")
+                .takes_value(true)
+                .help("Maximum number of queries per day from a token, or from a client IP address without a token (default: no limit)"),
+        )
+        .arg(
+            Arg::with_name("monthly_quota")
+                .long("monthly-quota")
+                .takes_value(true)
+                .help("Maximum number of queries per month from a token, or from a client IP address without a token (default: no limit)"),
+        )
+        .arg(
+            Arg::with_name("quota_file")
+                .long("quota-file")
+                .takes_value(true)
+                .help("Path to a file the numbers of queries counted against quotas are saved to, and loaded from at startup"),
+        )
         .arg(
             Arg::with_name("timeout")
                 .short("t")
                 .long("timeout")
                 .takes_value(true)
                 .default_value(&timeout_sec)
-                .help("Timeout, in seconds"),
+                .help("Time allowed for pending requests to be answered when connections are closed, in seconds"),
+        )
+        .arg(
+            Arg::with_name("query_timeout")
+                .long("query-timeout")
+                .takes_value(true)
+                .default_value(&query_timeout_ms)
+                .help("Time to answer a query, after which clients get a 504 response, in milliseconds"),
+        )
+        .arg(
+            Arg::with_name("header_read_timeout")
+                .long("header-read-timeout")
+                .takes_value(true)
+                .default_value(&header_read_timeout_sec)
+                .help("Time to complete the TLS handshake and send the first request, in seconds"),
+        )
+        .arg(
+            Arg::with_name("http_idle_timeout")
+                .long("http-idle-timeout")
+                .takes_value(true)
+                .default_value(&http_idle_timeout_sec)
+                .help("Time after which HTTP connections without any requests are closed, in seconds"),
+        )
+        .arg(
+            Arg::with_name("body_read_timeout")
+                .long("body-read-timeout")
+                .takes_value(true)
+                .default_value(&body_read_timeout_sec)
+                .help("Time to send the body of a POST request, in seconds"),
+        )
+        .arg(
+            Arg::with_name("max_connection_lifetime")
+                .long("max-connection-lifetime")
+                .takes_value(true)
+                .default_value(&max_connection_lifetime_sec)
+                .help("Time after which connections are closed, once their pending requests have been answered, in seconds"),
+        )
+        .arg(
+            Arg::with_name("max_requests_per_connection")
+                .long("max-requests-per-connection")
+                .takes_value(true)
+                .help("Number of requests after which HTTP connections are closed, once they have been answered (default: no limit)"),
+        )
+        .arg(
+            Arg::with_name("tcp_keepalive")
+                .long("tcp-keepalive")
+                .takes_value(true)
+                .help("Idle time after which TCP keepalive probes are sent on client connections, in seconds (default: disabled)"),
+        )
+        .arg(
+            Arg::with_name("upstream_timeout")
+                .long("upstream-timeout")
+                .takes_value(true)
+                .default_value(&upstream_timeout_ms)
+                .help("Timeout for every upstream query attempt, in milliseconds"),
+        )
+        .arg(
+            Arg::with_name("upstream_retries")
+                .long("upstream-retries")
+                .takes_value(true)
+                .default_value(&upstream_retries)
+                .help("Number of times a query is retransmitted to the upstream server after a timeout"),
+        )
+        .arg(
+            Arg::with_name("max_udp_size")
+                .long("max-udp-size")
+                .takes_value(true)
+                .default_value(&max_udp_size)
+                .validator(verify_max_udp_size)
+                .help("UDP payload size advertised to the upstream servers, in bytes, reduced to 1232 for a server after a timeout"),
+        )
+        .arg(
+            Arg::with_name("upstream_cookies")
+                .long("upstream-cookies")
+                .help("Send DNS cookies to the upstream servers (RFC 7873)"),
+        )
+        .arg(
+            Arg::with_name("disable_0x20")
+                .long("disable-0x20")
+                .help("Don't randomize the case of the names sent to the upstream servers, for servers that don't preserve it"),
+        )
+        .arg(
+            Arg::with_name("paranoid_upstream_checks")
+                .long("paranoid-upstream-checks")
+                .help("Ignore upstream responses that are not well-formed, in addition to those that don't match the query"),
         )
         .arg(
             Arg::with_name("min_ttl")
@@ -94,6 +350,50 @@ pub fn parse_opts(globals: &mut Globals) {
                 .default_value(&err_ttl)
                 .help("TTL for errors, in seconds"),
         )
+        .arg(
+            Arg::with_name("servfail_on_upstream_error")
+                .long("servfail-on-upstream-error")
+                .help("Answer DoH queries the upstream servers failed to answer with a SERVFAIL response, cacheable for err-ttl seconds, instead of an HTTP error"),
+        )
+        .arg(
+            Arg::with_name("negative_cache_size")
+                .long("negative-cache-size")
+                .takes_value(true)
+                .default_value(&negative_cache_size)
+                .help("Number of error and NXDOMAIN responses cached for err-ttl seconds (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("cache_size")
+                .long("cache-size")
+                .takes_value(true)
+                .default_value(&cache_size)
+                .help("Number of responses to cache (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("cache_serve_stale_ttl")
+                .long("cache-serve-stale-ttl")
+                .takes_value(true)
+                .default_value(&cache_serve_stale_ttl)
+                .help("How long expired responses can be served when the upstream server doesn't respond, in seconds"),
+        )
+        .arg(
+            Arg::with_name("cache_prefetch")
+                .long("cache-prefetch")
+                .help("Refresh popular cached responses before they expire"),
+        )
+        .arg(
+            Arg::with_name("cache_persist")
+                .long("cache-persist")
+                .takes_value(true)
+                .help("Path to a file the cache is saved to periodically, and loaded from at startup"),
+        )
+        .arg(
+            Arg::with_name("cache_persist_interval")
+                .long("cache-persist-interval")
+                .takes_value(true)
+                .default_value(&cache_persist_interval)
+                .help("How often the cache is saved with --cache-persist, in seconds (0 to only save it when the server stops)"),
+        )
         .arg(
             Arg::with_name("disable_keepalive")
                 .short("K")
@@ -105,10 +405,432 @@ pub fn parse_opts(globals: &mut Globals) {
                 .short("P")
                 .long("disable-post")
                 .help("Disable POST queries"),
+        )
+        .arg(
+            Arg::with_name("max_query_size")
+                .long("max-query-size")
+                .takes_value(true)
+                .default_value(&max_query_size)
+                .validator(verify_max_query_size)
+                .help("Maximum size of queries, in bytes, up to 4096"),
+        )
+        .arg(
+            Arg::with_name("max_query_param_len")
+                .long("max-query-param-length")
+                .takes_value(true)
+                .default_value(&max_query_param_len)
+                .help("Maximum length of the dns parameter of GET queries (default: the length of a query of --max-query-size bytes once encoded)"),
+        )
+        .arg(
+            Arg::with_name("admin_listen_address")
+                .long("admin-listen")
+                .takes_value(true)
+                .validator(verify_sock_addr)
+                .help("Address to serve the administration API on, that must not be publicly reachable"),
+        )
+        .arg(
+            Arg::with_name("stats_labels")
+                .long("stats-labels")
+                .takes_value(true)
+                .requires("admin_listen_address")
+                .validator(verify_stats_labels)
+                .help("Comma-separated labels to break the query statistics down by: \"path\", \"qtype\" and \"upstream\""),
+        )
+        .arg(
+            Arg::with_name("stats_label_values")
+                .long("stats-label-values")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("stats_labels")
+                .help("Values of a label to count separately, as \"<label>=<values>\", others being counted as \"other\" (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("query_log_size")
+                .long("query-log-size")
+                .takes_value(true)
+                .requires("admin_listen_address")
+                .validator(verify_query_log_size)
+                .help("Number of recent queries to keep in memory, to be searched with the administration API (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("log_level")
+                .long("log-level")
+                .takes_value(true)
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .default_value("info")
+                .help("Maximum level of the messages written to the standard error output"),
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .long("log-format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Format of the messages written to the standard error output"),
+        )
+        .arg(
+            Arg::with_name("dnssec_validate")
+                .long("dnssec-validate")
+                .alias("require-upstream-ad")
+                .help("Answer with SERVFAIL when responses don't have the AD bit set by the upstream server"),
+        )
+        .arg(
+            Arg::with_name("dns64")
+                .long("dns64")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .validator(verify_dns64_prefix)
+                .help("Synthesize AAAA records from A records using a NAT64 prefix [default prefix: 64:ff9b::/96]"),
+        )
+        .arg(
+            Arg::with_name("auth_token")
+                .long("auth-token")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only accept requests with this bearer token (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("auth_token_file")
+                .long("auth-token-file")
+                .takes_value(true)
+                .help("Path to a file with accepted bearer tokens, one per line"),
+        )
+        .arg(
+            Arg::with_name("allow_cors")
+                .long("allow-cors")
+                .takes_value(true)
+                .validator(verify_cors_origins)
+                .help("Comma-separated origins of web applications allowed to send queries, or \"*\" for any origin"),
+        )
+        .arg(
+            Arg::with_name("response_header")
+                .long("response-header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_response_header)
+                .help("Header added to every response, written as \"Name: value\" (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("landing")
+                .long("landing")
+                .takes_value(true)
+                .help("What web browsers get when opening the URL of the server: \"info\" for a page describing the service, a URL to redirect to, or the path to an HTML page"),
+        )
+        .arg(
+            Arg::with_name("problem_details")
+                .long("problem-details")
+                .help("Describe why requests were rejected in JSON error bodies (RFC 7807)"),
+        )
+        .arg(
+            Arg::with_name("debug_headers")
+                .long("debug-headers")
+                .help("Add X-DNS-Rcode, X-Cache and X-Upstream-RTT-ms headers to responses"),
+        )
+        .arg(
+            Arg::with_name("no_padding")
+                .long("no-padding")
+                .help("Disable EDNS padding of queries and responses"),
+        )
+        .arg(
+            Arg::with_name("access_log")
+                .long("access-log")
+                .takes_value(true)
+                .help("Log queries as JSON lines to a file, or to the standard output if \"stdout\""),
+        )
+        .arg(
+            Arg::with_name("access_log_client_ip")
+                .long("access-log-client-ip")
+                .takes_value(true)
+                .possible_values(&["full", "hash", "omit"])
+                .default_value("full")
+                .help("How client IP addresses are written to the access log"),
+        )
+        .arg(
+            Arg::with_name("trusted_proxies")
+                .long("trusted-proxies")
+                .takes_value(true)
+                .validator(verify_trusted_proxies)
+                .help("Comma-separated networks of reverse proxies allowed to set the client address with Forwarded or X-Forwarded-For"),
+        )
+        .arg(
+            Arg::with_name("ecs_policy")
+                .long("ecs-policy")
+                .takes_value(true)
+                .default_value("forward")
+                .validator(verify_ecs_policy)
+                .help("EDNS Client Subnet handling: \"strip\", \"forward\" or \"set:<prefix>\""),
+        )
+        .arg(
+            Arg::with_name("strip_edns_options")
+                .long("strip-edns-options")
+                .takes_value(true)
+                .validator(verify_edns_strip)
+                .help("Comma-separated EDNS options to remove from queries before forwarding them, such as \"cookie,padding,unknown\", or \"all\""),
+        )
+        .arg(
+            Arg::with_name("local_zone")
+                .long("local-zone")
+                .takes_value(true)
+                .help("Path to a file with local records (\"<name> [<ttl>] <type> <value>\")"),
+        )
+        .arg(
+            Arg::with_name("canary_domain")
+                .long("canary-domain")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_canary_domain)
+                .help("Domain probed by clients to detect the network policy, answered locally, as \"<name>[=nxdomain|nodata|<addresses>]\" (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("block_private_ptr")
+                .long("block-private-ptr")
+                .help("Answer reverse lookups for private, link-local and unique local addresses with NXDOMAIN instead of forwarding them"),
+        )
+        .arg(
+            Arg::with_name("safe_search")
+                .long("safe-search")
+                .help("Answer queries for Google, Bing, DuckDuckGo and YouTube with their safe search variants, that filter explicit results"),
+        )
+        .arg(
+            Arg::with_name("loop_protection")
+                .long("loop-protection")
+                .help("Answer queries for the names of the upstream servers and of the server itself locally, instead of forwarding them"),
+        )
+        .arg(
+            Arg::with_name("ddr_hostname")
+                .long("ddr-hostname")
+                .takes_value(true)
+                .validator(verify_ddr_hostname)
+                .help("Public name of the server, optionally followed by the HTTPS port, advertised to clients querying _dns.resolver.arpa (RFC 9462)"),
+        )
+        .arg(
+            Arg::with_name("ddr_https_record")
+                .long("ddr-https-record")
+                .requires("ddr_hostname")
+                .help("Answer HTTPS queries for the DDR hostname, advertising HTTP/2 on its HTTPS port"),
+        )
+        .arg(
+            Arg::with_name("rewrite_rules")
+                .long("rewrite-rules")
+                .takes_value(true)
+                .help("Path to a file with rules answering matching names with fixed addresses (\"<name> -> <address>\")"),
+        )
+        .arg(
+            Arg::with_name("rewrite_ttl")
+                .long("rewrite-ttl")
+                .takes_value(true)
+                .default_value(&rewrite_ttl)
+                .help("TTL of rewritten answers, in seconds"),
+        )
+        .arg(
+            Arg::with_name("block_list")
+                .long("block-list")
+                .takes_value(true)
+                .help("Path or URL of a list of names to block, in hosts format or with one rule per line"),
+        )
+        .arg(
+            Arg::with_name("block_response")
+                .long("block-response")
+                .takes_value(true)
+                .possible_values(&["nxdomain", "null"])
+                .default_value("nxdomain")
+                .help("Response to blocked queries: NXDOMAIN, or the unspecified address"),
+        )
+        .arg(
+            Arg::with_name("allow_list")
+                .long("allow-list")
+                .takes_value(true)
+                .help("Path or URL of a list of names that are never blocked, in the same format as block lists"),
+        )
+        .arg(
+            Arg::with_name("deny_qtype")
+                .long("deny-qtype")
+                .takes_value(true)
+                .validator(verify_qtypes)
+                .help("Comma-separated list of record types to refuse, such as ANY,HTTPS"),
+        )
+        .arg(
+            Arg::with_name("deny_qtype_response")
+                .long("deny-qtype-response")
+                .takes_value(true)
+                .possible_values(&["notimp", "empty"])
+                .default_value("notimp")
+                .help("Response to queries for a refused record type: NOTIMP, or an empty NOERROR response"),
+        )
+        .arg(
+            Arg::with_name("filter_aaaa")
+                .long("filter-aaaa")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_address_filter)
+                .help("Hide IPv6 addresses: \"strip\" AAAA records from responses, or answer AAAA queries with an \"empty\" response, optionally only for some clients, as \"<mode>:<networks>\" (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("filter_a")
+                .long("filter-a")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_address_filter)
+                .help("Hide IPv4 addresses, like --filter-aaaa for A records (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("block_list_reload_interval")
+                .long("block-list-reload-interval")
+                .takes_value(true)
+                .default_value(&block_list_reload_interval)
+                .help("Interval between checks for changes of the block lists, local zone, rewrite rules, tokens and client certificate authorities, in seconds"),
+        )
+        .arg(
+            Arg::with_name("health_path")
+                .long("health-path")
+                .takes_value(true)
+                .default_value(HEALTH_PATH)
+                .help("URI path of the liveness endpoint"),
+        )
+        .arg(
+            Arg::with_name("readiness_path")
+                .long("readiness-path")
+                .takes_value(true)
+                .default_value(READINESS_PATH)
+                .help("URI path of the readiness endpoint, reporting whether the upstream server is reachable"),
+        )
+        .arg(
+            Arg::with_name("disable_health_endpoints")
+                .long("disable-health-endpoints")
+                .help("Disable the liveness and readiness endpoints"),
+        )
+        .arg(
+            Arg::with_name("upstream_probe_interval")
+                .long("upstream-probe-interval")
+                .takes_value(true)
+                .default_value(&upstream_probe_interval)
+                .help("Interval after which the upstream server is probed if it didn't answer any queries, in seconds"),
+        );
+
+    #[cfg(target_os = "linux")]
+    let options = options.arg(
+        Arg::with_name("bind_device")
+            .long("bind-device")
+            .takes_value(true)
+            .validator(verify_bind_device)
+            .help("Network interface or VRF to send queries to upstream servers through (SO_BINDTODEVICE)"),
+    );
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    let options = options.arg(
+        Arg::with_name("io_uring")
+            .long("io-uring")
+            .help("Send and receive upstream UDP queries with io_uring instead of epoll (requires the UDP pool)"),
+    );
+
+    #[cfg(unix)]
+    let options = options
+        .arg(
+            Arg::with_name("reuseport")
+                .long("reuseport")
+                .takes_value(true)
+                .validator(verify_reuseport)
+                .help("Number of sockets accepting connections on every listen address, with SO_REUSEPORT (default: 1)"),
+        )
+        .arg(
+            Arg::with_name("per_core_runtime")
+                .long("per-core-runtime")
+                .help("Run a single-threaded runtime on every thread, each with its own sockets bound with SO_REUSEPORT"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .takes_value(true)
+                .help("User to switch to once the listening sockets are bound"),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .takes_value(true)
+                .help("Group to switch to once the listening sockets are bound (default: the group of the user)"),
+        )
+        .arg(
+            Arg::with_name("chroot")
+                .long("chroot")
+                .takes_value(true)
+                .help("Directory to use as the root directory once the listening sockets are bound"),
+        )
+        .arg(
+            Arg::with_name("daemonize")
+                .long("daemonize")
+                .help("Run in the background"),
+        )
+        .arg(
+            Arg::with_name("pid_file")
+                .long("pid-file")
+                .takes_value(true)
+                .help("File to write the process ID to"),
+        )
+        .arg(
+            Arg::with_name("log_file")
+                .long("log-file")
+                .takes_value(true)
+                .requires("daemonize")
+                .help("File to write the output to when running in the background (default: discarded)"),
+        )
+        .arg(
+            Arg::with_name("dnstap_socket")
+                .long("dnstap-socket")
+                .takes_value(true)
+                .help("Unix socket of a dnstap collector to send client queries and responses to"),
+        )
+        .arg(
+            Arg::with_name("dnstap_redact_client_ip")
+                .long("dnstap-redact-client-ip")
+                .requires("dnstap_socket")
+                .help("Don't include client IP addresses in dnstap messages"),
         );
 
+    #[cfg(windows)]
+    let options = options.arg(
+        Arg::with_name("windows_service")
+            .long("windows-service")
+            .help("Run as a Windows service, controlled by the Service Control Manager"),
+    );
+
     #[cfg(feature = "tls")]
     let options = options
+        .arg(
+            Arg::with_name("upstream_doh")
+                .long("upstream-doh")
+                .takes_value(true)
+                .validator(verify_upstream_doh)
+                .help("URL of an upstream DoH server to forward queries to, instead of the upstream DNS servers"),
+        )
+        .arg(
+            Arg::with_name("otlp_endpoint")
+                .long("otlp-endpoint")
+                .takes_value(true)
+                .validator(verify_otlp_endpoint)
+                .help("URL to export traces to using OTLP/HTTP, such as http://127.0.0.1:4318/v1/traces"),
+        )
+        .arg(
+            Arg::with_name("otlp_service_name")
+                .long("otlp-service-name")
+                .takes_value(true)
+                .default_value(OTLP_SERVICE_NAME)
+                .help("Service name of the exported traces"),
+        )
+        .arg(
+            Arg::with_name("block_list_update_interval")
+                .long("block-list-update-interval")
+                .takes_value(true)
+                .default_value(&block_list_update_interval)
+                .validator(verify_block_list_update_interval)
+                .help("Interval between downloads of the block lists given by URL, in seconds"),
+        )
         .arg(
             Arg::with_name("tls_cert_path")
                 .short("i")
@@ -122,45 +844,726 @@ pub fn parse_opts(globals: &mut Globals) {
                 .long("tls-cert-key-path")
                 .takes_value(true)
                 .help("Path to the PEM-encoded secret keys (only required for built-in TLS)"),
+        )
+        .arg(
+            Arg::with_name("tls_client_ca")
+                .long("tls-client-ca")
+                .takes_value(true)
+                .help("Path to PEM-encoded certificate authorities that client certificates have to be signed by"),
+        )
+        .arg(
+            Arg::with_name("tls_ticket_rotation_interval")
+                .long("tls-ticket-rotation-interval")
+                .takes_value(true)
+                .default_value(&tls_ticket_rotation_interval)
+                .help("Interval after which the key encrypting TLS session tickets is replaced, in seconds (0 to disable session tickets)"),
+        )
+        .arg(
+            Arg::with_name("tls_self_signed")
+                .long("tls-self-signed")
+                .takes_value(true)
+                .conflicts_with_all(&["tls_cert_path", "tls_cert_key_path"])
+                .help("Serve HTTPS with a self-signed certificate for this host name, generated at startup (for testing only)"),
+        )
+        .arg(
+            Arg::with_name("tls_ocsp_stapling")
+                .long("tls-ocsp-stapling")
+                .requires("tls_cert_path")
+                .help("Fetch OCSP responses for the certificate, and staple them during TLS handshakes"),
+        )
+        .arg(
+            Arg::with_name("plaintext_listen_address")
+                .long("plaintext-listen-address")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_sock_addr)
+                .help("Additional address to listen to without TLS (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("alt_svc")
+                .long("alt-svc")
+                .help("Advertise HTTP/2 on the HTTPS listen ports with an Alt-Svc header"),
+        )
+        .arg(
+            Arg::with_name("dot_listen_address")
+                .long("dot-listen-address")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_sock_addr)
+                .conflicts_with_all(&["auth_token", "auth_token_file"])
+                .help("Address to accept DNS-over-TLS connections on, usually on port 853 (can be repeated)"),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Sends queries to a DoH server, and reports the throughput and latency percentiles")
+                .arg(
+                    Arg::with_name("url")
+                        .required(true)
+                        .validator(verify_upstream_doh)
+                        .help("URL of the DoH server, such as http://127.0.0.1:3000/dns-query"),
+                )
+                .arg(
+                    Arg::with_name("concurrency")
+                        .short("c")
+                        .long("concurrency")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("Number of queries sent simultaneously"),
+                )
+                .arg(
+                    Arg::with_name("requests")
+                        .short("n")
+                        .long("requests")
+                        .takes_value(true)
+                        .default_value("10000")
+                        .help("Total number of queries to send"),
+                )
+                .arg(
+                    Arg::with_name("method")
+                        .long("method")
+                        .takes_value(true)
+                        .possible_values(&["get", "post"])
+                        .default_value("get")
+                        .help("HTTP method of the requests"),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .takes_value(true)
+                        .help("Path to a file with names to send queries for, one per line, the most popular first (default: example.com)"),
+                )
+                .arg(
+                    Arg::with_name("distribution")
+                        .long("distribution")
+                        .takes_value(true)
+                        .possible_values(&["uniform", "zipf", "random"])
+                        .default_value("uniform")
+                        .help("How names are picked: with the same probability, favoring the first ones, or as random subdomains that are never cached"),
+                )
+                .arg(
+                    Arg::with_name("qtype")
+                        .long("qtype")
+                        .takes_value(true)
+                        .default_value("A")
+                        .validator(verify_qtype)
+                        .help("Record type of the queries"),
+                ),
         );
 
     let matches = options.get_matches();
-    globals.listen_address = matches.value_of("listen_address").unwrap().parse().unwrap();
+    #[cfg(feature = "tls")]
+    {
+        if let Some(bench_matches) = matches.subcommand_matches("bench") {
+            return ProcessOptions {
+                bench: Some(parse_bench_opts(bench_matches)),
+                ..Default::default()
+            };
+        }
+    }
+    globals.listen_addresses = matches
+        .values_of("listen_address")
+        .unwrap()
+        .map(|listen_address| listen_address.parse().unwrap())
+        .collect();
+    if let Some(dns_listen_addresses) = matches.values_of("dns_listen_address") {
+        globals.dns_listen_addresses = dns_listen_addresses
+            .map(|listen_address| listen_address.parse().unwrap())
+            .collect();
+    }
 
-    globals.server_address = matches
-        .value_of("server_address")
+    if let Some(bootstrap_dns) = matches.values_of("bootstrap_dns") {
+        let servers = bootstrap_dns
+            .map(|server| parse_bootstrap_dns(server).unwrap())
+            .collect();
+        globals.bootstrap = Some(Arc::new(BootstrapResolver::new(servers)));
+    }
+    let upstreams = matches
+        .values_of("server_address")
         .unwrap()
-        .to_socket_addrs()
+        .map(|server| {
+            parse_upstream(server, globals.bootstrap.as_deref()).unwrap_or_else(|e| {
+                eprintln!("Unable to resolve the upstream server {}", e);
+                std::process::exit(1)
+            })
+        })
+        .collect();
+    let upstream_strategy = matches
+        .value_of("upstream_strategy")
         .unwrap()
-        .next()
+        .parse()
         .unwrap();
-    globals.local_bind_address = match matches.value_of("local_bind_address") {
-        Some(address) => address.parse().unwrap(),
-        None => match globals.server_address {
-            SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
-            SocketAddr::V6(s) => SocketAddr::V6(SocketAddrV6::new(
-                Ipv6Addr::UNSPECIFIED,
-                0,
-                s.flowinfo(),
-                s.scope_id(),
-            )),
-        },
+    globals.upstreams = Upstreams::new(upstreams, upstream_strategy);
+    if let Some(addresses) = matches.values_of("local_bind_address") {
+        for address in addresses {
+            globals
+                .upstreams
+                .set_local_bind_address(address.parse().unwrap());
+        }
+    }
+    globals.upstream_resolve_interval = Duration::from_secs(
+        matches
+            .value_of("upstream_resolve_interval")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    if let Some(upstream_proxy) = matches.value_of("upstream_proxy") {
+        globals.upstream_proxy = Some(upstream_proxy.parse().unwrap());
+    }
+    let runtime_threads = match matches.value_of("runtime_threads") {
+        Some(runtime_threads) => runtime_threads.parse().unwrap(),
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
     };
+    let udp_pool_size: usize = matches.value_of("udp_pool_size").unwrap().parse().unwrap();
+    globals.udp_pool = if udp_pool_size > 0 {
+        Some(UdpPool::new(udp_pool_size))
+    } else {
+        None
+    };
+    #[cfg(target_os = "linux")]
+    {
+        globals.bind_device = matches.value_of("bind_device").map(ToString::to_string);
+    }
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        if matches.is_present("io_uring") && udp_pool_size > 0 {
+            match UdpPool::with_io_uring(udp_pool_size) {
+                Ok(udp_pool) => globals.udp_pool = Some(udp_pool),
+                Err(e) => {
+                    eprintln!("Unable to set up io_uring: {}", e);
+                    std::process::exit(1)
+                }
+            }
+        }
+    }
     globals.path = matches.value_of("path").unwrap().to_string();
     if !globals.path.starts_with('/') {
         globals.path = format!("/{}", globals.path);
     }
     globals.max_clients = matches.value_of("max_clients").unwrap().parse().unwrap();
+    globals.max_connection_queries = matches
+        .value_of("max_connection_queries")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.max_client_queries = matches
+        .value_of("max_client_queries")
+        .map(|max_client_queries| max_client_queries.parse().unwrap());
+    globals.client_key_policy = matches.value_of("client_key").unwrap().parse().unwrap();
+    let daily_quota = matches
+        .value_of("daily_quota")
+        .map(|daily_quota| daily_quota.parse().unwrap());
+    let monthly_quota = matches
+        .value_of("monthly_quota")
+        .map(|monthly_quota| monthly_quota.parse().unwrap());
+    if daily_quota.is_some() || monthly_quota.is_some() {
+        globals.quotas = Some(Quotas::new(daily_quota, monthly_quota));
+        globals.quota_persist_path = matches.value_of("quota_file").map(PathBuf::from);
+    }
     globals.timeout = Duration::from_secs(matches.value_of("timeout").unwrap().parse().unwrap());
+    globals.query_timeout =
+        Duration::from_millis(matches.value_of("query_timeout").unwrap().parse().unwrap());
+    globals.header_read_timeout = Duration::from_secs(
+        matches
+            .value_of("header_read_timeout")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.http_idle_timeout = Duration::from_secs(
+        matches
+            .value_of("http_idle_timeout")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.body_read_timeout = Duration::from_secs(
+        matches
+            .value_of("body_read_timeout")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.max_connection_lifetime = Duration::from_secs(
+        matches
+            .value_of("max_connection_lifetime")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.max_requests_per_connection = matches
+        .value_of("max_requests_per_connection")
+        .map(|max_requests_per_connection| max_requests_per_connection.parse().unwrap());
+    globals.tcp_keepalive = matches
+        .value_of("tcp_keepalive")
+        .map(|tcp_keepalive| Duration::from_secs(tcp_keepalive.parse().unwrap()));
+    globals.upstream_timeout = Duration::from_millis(
+        matches
+            .value_of("upstream_timeout")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.upstream_retries = matches
+        .value_of("upstream_retries")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.max_udp_size = matches.value_of("max_udp_size").unwrap().parse().unwrap();
+    globals.upstream_cookies = matches.is_present("upstream_cookies");
+    globals.qname_randomization = !matches.is_present("disable_0x20");
+    globals.paranoid_upstream_checks = matches.is_present("paranoid_upstream_checks");
     globals.min_ttl = matches.value_of("min_ttl").unwrap().parse().unwrap();
     globals.max_ttl = matches.value_of("max_ttl").unwrap().parse().unwrap();
     globals.err_ttl = matches.value_of("err_ttl").unwrap().parse().unwrap();
+    globals.servfail_on_upstream_error = matches.is_present("servfail_on_upstream_error");
+    let negative_cache_size: usize = matches
+        .value_of("negative_cache_size")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.negative_cache = if negative_cache_size > 0 {
+        Some(Cache::new(negative_cache_size))
+    } else {
+        None
+    };
+    let cache_size: usize = matches.value_of("cache_size").unwrap().parse().unwrap();
+    let cache_serve_stale_ttl: u64 = matches
+        .value_of("cache_serve_stale_ttl")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.cache = if cache_size > 0 {
+        Some(
+            Cache::with_serve_stale(cache_size, Duration::from_secs(cache_serve_stale_ttl))
+                .with_prefetch(matches.is_present("cache_prefetch")),
+        )
+    } else {
+        None
+    };
+    globals.cache_persist_path = matches.value_of("cache_persist").map(PathBuf::from);
+    globals.cache_persist_interval = Duration::from_secs(
+        matches
+            .value_of("cache_persist_interval")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
     globals.keepalive = !matches.is_present("disable_keepalive");
     globals.disable_post = matches.is_present("disable_post");
+    globals.max_query_size = matches.value_of("max_query_size").unwrap().parse().unwrap();
+    globals.max_query_param_len = if matches.occurrences_of("max_query_param_len") > 0 {
+        matches
+            .value_of("max_query_param_len")
+            .unwrap()
+            .parse()
+            .unwrap()
+    } else {
+        libdoh::max_query_param_len(globals.max_query_size)
+    };
+    globals.padding = !matches.is_present("no_padding");
+    globals.problem_details = matches.is_present("problem_details");
+    globals.debug_headers = matches.is_present("debug_headers");
+    globals.dnssec_validate = matches.is_present("dnssec_validate");
+    #[cfg(feature = "tls")]
+    let (otlp_layer, otlp_exporter) = match matches.value_of("otlp_endpoint") {
+        Some(otlp_endpoint) => {
+            let otlp_endpoint = DoHUpstream::parse_url(otlp_endpoint).unwrap();
+            let service_name = matches.value_of("otlp_service_name").unwrap();
+            let (otlp_layer, otlp_exporter) = OtlpExporter::new(otlp_endpoint, service_name);
+            (Some(otlp_layer), Some(otlp_exporter))
+        }
+        None => (None, None),
+    };
+    #[cfg(not(feature = "tls"))]
+    let otlp_layer = None;
+    crate::logger::init(
+        matches.value_of("log_level").unwrap().parse().unwrap(),
+        matches.value_of("log_format") == Some("json"),
+        otlp_layer,
+    );
+    if let Some(admin_listen_address) = matches.value_of("admin_listen_address") {
+        globals.admin_listen_address = Some(admin_listen_address.parse().unwrap());
+        let mut stats_labels = StatsLabels::default();
+        if let Some(labels) = matches.value_of("stats_labels") {
+            stats_labels = labels.parse().unwrap();
+        }
+        if let Some(stats_label_values) = matches.values_of("stats_label_values") {
+            for allowed in stats_label_values {
+                stats_labels.allow(allowed).unwrap_or_else(|e| {
+                    eprintln!("Invalid label values [{}]: {}", allowed, e);
+                    std::process::exit(1)
+                });
+            }
+        }
+        globals.query_stats = Some(QueryStats::new(stats_labels));
+        let query_log_size: usize = matches
+            .value_of("query_log_size")
+            .map_or(0, |size| size.parse().unwrap());
+        if query_log_size > 0 {
+            globals.query_log = Some(QueryLog::new(query_log_size));
+        }
+    }
+    if let Some(auth_tokens) = matches.values_of("auth_token") {
+        auth_tokens.for_each(|auth_token| globals.auth_tokens.add(auth_token));
+    }
+    if let Some(auth_token_file) = matches.value_of("auth_token_file") {
+        globals
+            .auth_tokens
+            .load(auth_token_file)
+            .unwrap_or_else(|e| {
+                eprintln!("Unable to load the tokens [{}]: {}", auth_token_file, e);
+                std::process::exit(1)
+            });
+    }
+    if matches.is_present("dns64") {
+        globals.dns64_prefix = Some(match matches.value_of("dns64") {
+            Some(dns64_prefix) => dns64_prefix.parse().unwrap(),
+            None => Dns64Prefix::default(),
+        });
+    }
+    if let Some(cors_origins) = matches.value_of("allow_cors") {
+        globals.cors_origins = Some(cors_origins.parse().unwrap());
+    }
+    if let Some(response_headers) = matches.values_of("response_header") {
+        for response_header in response_headers {
+            globals.response_headers.add_line(response_header).unwrap();
+        }
+    }
+    if let Some(landing) = matches.value_of("landing") {
+        globals.landing = Some(if landing == "info" {
+            Landing::info(&globals.path)
+        } else if landing.contains("://") {
+            Landing::redirect(landing).unwrap_or_else(|e| {
+                eprintln!("Invalid landing page: {}", e);
+                std::process::exit(1)
+            })
+        } else {
+            Landing::load(landing).unwrap_or_else(|e| {
+                eprintln!("Unable to load the landing page [{}]: {}", landing, e);
+                std::process::exit(1)
+            })
+        });
+    }
+    if let Some(trusted_proxies) = matches.value_of("trusted_proxies") {
+        globals.trusted_proxies = trusted_proxies.parse().unwrap();
+    }
+    globals.ecs_policy = matches.value_of("ecs_policy").unwrap().parse().unwrap();
+    if let Some(strip_edns_options) = matches.value_of("strip_edns_options") {
+        globals.edns_strip = strip_edns_options.parse().unwrap();
+    }
+    if let Some(local_zone) = matches.value_of("local_zone") {
+        let local_zone_path = local_zone;
+        let local_zone = Reloadable::load(local_zone_path, |path| LocalZone::load(path))
+            .unwrap_or_else(|e| {
+                eprintln!("Unable to load the local zone [{}]: {}", local_zone_path, e);
+                std::process::exit(1)
+            });
+        globals.local_zone = Some(local_zone);
+    }
+    globals.block_private_ptr = matches.is_present("block_private_ptr");
+    globals.safe_search = matches.is_present("safe_search");
+    globals.loop_protection = matches.is_present("loop_protection");
+    if let Some(canary_domains) = matches.values_of("canary_domain") {
+        for canary_domain in canary_domains {
+            globals.canary_domains.add(canary_domain).unwrap();
+        }
+    }
+    if let Some(rewrite_rules) = matches.value_of("rewrite_rules") {
+        let rewrite_ttl = matches.value_of("rewrite_ttl").unwrap().parse().unwrap();
+        let rewrite_rules_path = rewrite_rules;
+        let rewrite_rules = Reloadable::load(rewrite_rules_path, move |path| {
+            RewriteRules::load(path).map(|rewrite_rules| rewrite_rules.with_ttl(rewrite_ttl))
+        })
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Unable to load the rewrite rules [{}]: {}",
+                rewrite_rules_path, e
+            );
+            std::process::exit(1)
+        });
+        globals.rewrite_rules = Some(rewrite_rules);
+    }
+    if let Some(block_list) = matches.value_of("block_list") {
+        globals.block_list = Some(Arc::new(BlockList::open(block_list).unwrap_or_else(|e| {
+            eprintln!("Unable to load the block list [{}]: {}", block_list, e);
+            std::process::exit(1)
+        })));
+    }
+    globals.block_response = match matches.value_of("block_response").unwrap() {
+        "null" => BlockResponse::Null,
+        _ => BlockResponse::NxDomain,
+    };
+    if let Some(allow_list) = matches.value_of("allow_list") {
+        globals.allow_list = Some(Arc::new(BlockList::open(allow_list).unwrap_or_else(|e| {
+            eprintln!("Unable to load the allow list [{}]: {}", allow_list, e);
+            std::process::exit(1)
+        })));
+    }
+    if let Some(deny_qtype) = matches.value_of("deny_qtype") {
+        globals.denied_qtypes = deny_qtype.parse().unwrap();
+    }
+    globals.denied_qtype_response = match matches.value_of("deny_qtype_response").unwrap() {
+        "empty" => DeniedQtypeResponse::Empty,
+        _ => DeniedQtypeResponse::NotImp,
+    };
+    for (name, rr_type) in IntoIterator::into_iter([
+        ("filter_aaaa", dns::DNS_TYPE_AAAA),
+        ("filter_a", dns::DNS_TYPE_A),
+    ]) {
+        if let Some(filters) = matches.values_of(name) {
+            for filter in filters {
+                globals
+                    .address_filters
+                    .add(AddressFilter::parse(rr_type, filter).unwrap());
+            }
+        }
+    }
+    globals.block_list_reload_interval = Duration::from_secs(
+        matches
+            .value_of("block_list_reload_interval")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    if matches.is_present("disable_health_endpoints") {
+        globals.health_path = None;
+        globals.readiness_path = None;
+    } else {
+        let absolute_path = |path: &str| {
+            if path.starts_with('/') {
+                path.to_string()
+            } else {
+                format!("/{}", path)
+            }
+        };
+        globals.health_path = matches.value_of("health_path").map(absolute_path);
+        globals.readiness_path = matches.value_of("readiness_path").map(absolute_path);
+    }
+    globals.upstream_probe_interval = Duration::from_secs(
+        matches
+            .value_of("upstream_probe_interval")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    if let Some(access_log) = matches.value_of("access_log") {
+        let client_ip_privacy = match matches.value_of("access_log_client_ip").unwrap() {
+            "hash" => ClientIpPrivacy::Hash,
+            "omit" => ClientIpPrivacy::Omit,
+            _ => ClientIpPrivacy::Full,
+        };
+        globals.access_log = Some(if access_log == "stdout" {
+            AccessLog::stdout(client_ip_privacy)
+        } else {
+            AccessLog::open(access_log, client_ip_privacy).unwrap_or_else(|e| {
+                eprintln!("Unable to open the access log [{}]: {}", access_log, e);
+                std::process::exit(1)
+            })
+        });
+    }
+
+    if let Some(config_path) = matches.value_of("config") {
+        let config_file = ConfigFile::load(config_path).unwrap_or_else(|e| {
+            eprintln!(
+                "Unable to load the configuration file [{}]: {}",
+                config_path, e
+            );
+            std::process::exit(1)
+        });
+        for endpoint in config_file.endpoints {
+            let endpoint = endpoint.into_endpoint(globals).unwrap_or_else(|e| {
+                eprintln!("Invalid endpoint in [{}]: {}", config_path, e);
+                std::process::exit(1)
+            });
+            globals.endpoints.push(Arc::new(endpoint));
+        }
+        for route in config_file.routes {
+            let domain = route.domain.clone();
+            let upstreams = route.into_upstreams(globals).unwrap_or_else(|e| {
+                eprintln!("Invalid route for [{}] in [{}]: {}", domain, config_path, e);
+                std::process::exit(1)
+            });
+            globals.upstream_routes.add(&domain, upstreams);
+        }
+        for ttl_override in &config_file.ttl_overrides {
+            globals
+                .ttl_overrides
+                .add(&ttl_override.domain, ttl_override.ttl_override())
+                .unwrap_or_else(|e| {
+                    eprintln!("Invalid TTL override in [{}]: {}", config_path, e);
+                    std::process::exit(1)
+                });
+        }
+        if !config_file.profiles.is_empty()
+            && (matches.is_present("dns_listen_address")
+                || matches.is_present("dot_listen_address"))
+        {
+            eprintln!(
+                "Profiles require tokens, that plain DNS and DNS-over-TLS queries can't send"
+            );
+            std::process::exit(1)
+        }
+        for profile in config_file.profiles {
+            let name = profile.name.clone();
+            let tokens = profile.tokens.clone();
+            let profile = profile.into_profile().unwrap_or_else(|e| {
+                eprintln!("Invalid profile [{}] in [{}]: {}", name, config_path, e);
+                std::process::exit(1)
+            });
+            let profile = Arc::new(profile);
+            for token in &tokens {
+                globals.auth_tokens.add(token);
+                globals.profiles.add(token, profile.clone());
+            }
+        }
+        for (name, value) in &config_file.response_headers {
+            globals
+                .response_headers
+                .add(name, value)
+                .unwrap_or_else(|e| {
+                    eprintln!("Invalid response header in [{}]: {}", config_path, e);
+                    std::process::exit(1)
+                });
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        globals.privdrop = PrivDrop {
+            user: matches.value_of("user").map(ToString::to_string),
+            group: matches.value_of("group").map(ToString::to_string),
+            chroot: matches.value_of("chroot").map(Into::into),
+        };
+        globals.daemon = Daemon {
+            daemonize: matches.is_present("daemonize"),
+            pid_file: matches.value_of("pid_file").map(Into::into),
+            log_file: matches.value_of("log_file").map(Into::into),
+        };
+        if let Some(dnstap_socket) = matches.value_of("dnstap_socket") {
+            globals.dnstap = Some(Dnstap::new(
+                dnstap_socket,
+                matches.is_present("dnstap_redact_client_ip"),
+            ));
+        }
+        if let Some(reuseport) = matches.value_of("reuseport") {
+            globals.reuseport = reuseport.parse().unwrap();
+        } else if matches.is_present("per_core_runtime") {
+            globals.reuseport = runtime_threads;
+        }
+    }
 
     #[cfg(feature = "tls")]
     {
-        globals.tls_cert_path = matches.value_of("tls_cert_key_path").map(PathBuf::from);
+        if let Some(upstream_doh) = matches.value_of("upstream_doh") {
+            let url = DoHUpstream::parse_url(upstream_doh).unwrap();
+            globals.upstream_doh = Some(DoHUpstream::new(
+                url,
+                globals.upstream_proxy.clone(),
+                globals.bootstrap.clone(),
+            ));
+        }
+        globals.block_list_update_interval = Duration::from_secs(
+            matches
+                .value_of("block_list_update_interval")
+                .unwrap()
+                .parse()
+                .unwrap(),
+        );
+        globals.tls_cert_path = matches.value_of("tls_cert_path").map(PathBuf::from);
         globals.tls_cert_key_path = matches.value_of("tls_cert_key_path").map(PathBuf::from);
+        globals.tls_client_ca_path = matches.value_of("tls_client_ca").map(PathBuf::from);
+        globals.tls_self_signed = matches.value_of("tls_self_signed").map(ToString::to_string);
+        globals.tls_ocsp_stapling = matches.is_present("tls_ocsp_stapling");
+        globals.tls_ticket_rotation_interval = Duration::from_secs(
+            matches
+                .value_of("tls_ticket_rotation_interval")
+                .unwrap()
+                .parse()
+                .unwrap(),
+        );
+        if let Some(plaintext_listen_addresses) = matches.values_of("plaintext_listen_address") {
+            globals.plaintext_listen_addresses = plaintext_listen_addresses
+                .map(|listen_address| listen_address.parse().unwrap())
+                .collect();
+        }
+        if let Some(dot_listen_addresses) = matches.values_of("dot_listen_address") {
+            globals.dot_listen_addresses = dot_listen_addresses
+                .map(|listen_address| listen_address.parse().unwrap())
+                .collect();
+        }
+        if matches.is_present("alt_svc") {
+            if globals.tls_cert_path.is_none() && globals.tls_self_signed.is_none() {
+                eprintln!("--alt-svc requires a TLS certificate");
+                std::process::exit(1)
+            }
+            let ports: Vec<_> = globals
+                .listen_addresses
+                .iter()
+                .map(|listen_address| listen_address.port())
+                .collect();
+            globals.response_headers.add_alt_svc(ports);
+        }
+    }
+
+    if let Some(ddr_hostname) = matches.value_of("ddr_hostname") {
+        let (hostname, port) = parse_ddr_hostname(ddr_hostname).unwrap();
+        #[cfg(feature = "tls")]
+        let dot_port = globals
+            .dot_listen_addresses
+            .first()
+            .map(|listen_address| listen_address.port());
+        #[cfg(not(feature = "tls"))]
+        let dot_port = None;
+        let ddr = Ddr::new(&hostname, port, &globals.path, dot_port).unwrap_or_else(|e| {
+            eprintln!("Invalid DDR hostname: {}", e);
+            std::process::exit(1)
+        });
+        globals.ddr = Some(if matches.is_present("ddr_https_record") {
+            ddr.with_https_record()
+        } else {
+            ddr
+        });
+    }
+
+    ProcessOptions {
+        #[cfg(windows)]
+        windows_service: matches.is_present("windows_service"),
+        #[cfg(feature = "tls")]
+        otlp_exporter,
+        #[cfg(feature = "tls")]
+        bench: None,
+        check_config: matches.is_present("check_config"),
+        runtime_threads,
+        #[cfg(unix)]
+        per_core_runtime: matches.is_present("per_core_runtime"),
+    }
+}
+
+#[cfg(feature = "tls")]
+fn parse_bench_opts(matches: &ArgMatches<'_>) -> BenchOptions {
+    let names = match matches.value_of("names") {
+        None => vec!["example.com".to_string()],
+        Some(names_path) => std::fs::read_to_string(names_path)
+            .unwrap_or_else(|e| {
+                eprintln!("Unable to read the names [{}]: {}", names_path, e);
+                std::process::exit(1)
+            })
+            .lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && !name.starts_with('#'))
+            .map(ToString::to_string)
+            .collect(),
+    };
+    BenchOptions {
+        url: matches.value_of("url").unwrap().to_string(),
+        concurrency: matches.value_of("concurrency").unwrap().parse().unwrap(),
+        requests: matches.value_of("requests").unwrap().parse().unwrap(),
+        get: matches.value_of("method") == Some("get"),
+        names,
+        distribution: matches.value_of("distribution").unwrap().parse().unwrap(),
+        qtype: dns::parse_qtype(matches.value_of("qtype").unwrap()).unwrap(),
     }
 }
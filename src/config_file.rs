@@ -0,0 +1,208 @@
+use libdoh::defaults::{MAX_QUERY_SIZE_LIMIT, REWRITE_TTL};
+use libdoh::*;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Settings that can only be set in the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub endpoints: Vec<EndpointConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub ttl_overrides: Vec<TtlOverrideConfig>,
+    #[serde(default)]
+    pub profiles: Vec<ProfileConfig>,
+    /// Headers added to every response, in addition to the ones given with `--response-header`
+    #[serde(default)]
+    pub response_headers: BTreeMap<String, String>,
+}
+
+/// An additional path, served using its own upstream server.
+/// TTLs that are not set are the same as the ones of the main path.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointConfig {
+    pub path: String,
+    pub server_address: String,
+    pub local_bind_address: Option<SocketAddr>,
+    /// Path or URL
+    pub block_list: Option<String>,
+    pub block_response: Option<String>,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    pub err_ttl: Option<u32>,
+    pub max_query_size: Option<usize>,
+}
+
+/// A domain whose names are resolved using another upstream server than the main path.
+/// `*` matches the names that no other domain matches.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub domain: String,
+    pub server_address: String,
+    pub local_bind_address: Option<SocketAddr>,
+}
+
+/// A zone whose names are cached for a different range of TTLs than the path they are
+/// queried on
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TtlOverrideConfig {
+    pub domain: String,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+}
+
+/// Filtering and logging settings for the clients sending one of the tokens.
+/// Settings that are not set are the ones of the path the query was sent to.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub name: String,
+    pub tokens: Vec<String>,
+    /// Path or URL
+    pub block_list: Option<String>,
+    pub block_response: Option<String>,
+    pub rewrite_rules: Option<PathBuf>,
+    pub rewrite_ttl: Option<u32>,
+    pub safe_search: Option<bool>,
+    pub log_queries: Option<bool>,
+}
+
+impl ConfigFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        toml::from_str(&content).map_err(|e| e.to_string())
+    }
+}
+
+impl EndpointConfig {
+    pub fn into_endpoint(self, globals: &Globals) -> Result<Endpoint, String> {
+        let path = if self.path.starts_with('/') {
+            self.path
+        } else {
+            format!("/{}", self.path)
+        };
+        if path == globals.path {
+            return Err(format!("Path [{}] is already the main path", path));
+        }
+        let upstream = Upstream::resolve(&self.server_address, 1, globals.bootstrap.as_deref())?;
+        let mut upstreams = Upstreams::new(vec![upstream], UpstreamStrategy::Failover);
+        if let Some(local_bind_address) = self.local_bind_address {
+            upstreams.set_local_bind_address(local_bind_address);
+        }
+        let block_list =
+            match &self.block_list {
+                None => None,
+                Some(block_list) => Some(Arc::new(BlockList::open(block_list).map_err(|e| {
+                    format!("Unable to load the block list [{}]: {}", block_list, e)
+                })?)),
+            };
+        let block_response = match self.block_response.as_deref() {
+            None | Some("nxdomain") => BlockResponse::NxDomain,
+            Some("null") => BlockResponse::Null,
+            Some(block_response) => {
+                return Err(format!("Unsupported block response: [{}]", block_response))
+            }
+        };
+        let (max_query_size, max_query_param_len) = match self.max_query_size {
+            None => (globals.max_query_size, globals.max_query_param_len),
+            Some(max_query_size) if (512..=MAX_QUERY_SIZE_LIMIT).contains(&max_query_size) => {
+                (max_query_size, max_query_param_len(max_query_size))
+            }
+            Some(max_query_size) => {
+                return Err(format!(
+                    "Invalid query size: [{}], it must be between 512 and {}",
+                    max_query_size, MAX_QUERY_SIZE_LIMIT
+                ))
+            }
+        };
+        Ok(Endpoint {
+            path,
+            upstreams,
+            block_list,
+            block_response,
+            min_ttl: self.min_ttl.unwrap_or(globals.min_ttl),
+            max_ttl: self.max_ttl.unwrap_or(globals.max_ttl),
+            err_ttl: self.err_ttl.unwrap_or(globals.err_ttl),
+            max_query_size,
+            max_query_param_len,
+        })
+    }
+}
+
+impl ProfileConfig {
+    pub fn into_profile(self) -> Result<Profile, String> {
+        if self.tokens.iter().any(|token| token.trim().is_empty()) {
+            return Err("Tokens can't be empty".to_string());
+        }
+        let block_list =
+            match &self.block_list {
+                None => None,
+                Some(block_list) => Some(Arc::new(BlockList::open(block_list).map_err(|e| {
+                    format!("Unable to load the block list [{}]: {}", block_list, e)
+                })?)),
+            };
+        let block_response = match self.block_response.as_deref() {
+            None | Some("nxdomain") => BlockResponse::NxDomain,
+            Some("null") => BlockResponse::Null,
+            Some(block_response) => {
+                return Err(format!("Unsupported block response: [{}]", block_response))
+            }
+        };
+        let rewrite_rules = match &self.rewrite_rules {
+            None => None,
+            Some(rewrite_rules) => {
+                let rewrite_ttl = self.rewrite_ttl.unwrap_or(REWRITE_TTL);
+                Some(Arc::new(
+                    RewriteRules::load(rewrite_rules)
+                        .map_err(|e| {
+                            format!(
+                                "Unable to load the rewrite rules [{}]: {}",
+                                rewrite_rules.display(),
+                                e
+                            )
+                        })?
+                        .with_ttl(rewrite_ttl),
+                ))
+            }
+        };
+        Ok(Profile {
+            name: self.name,
+            block_list,
+            block_response,
+            rewrite_rules,
+            safe_search: self.safe_search,
+            log_queries: self.log_queries.unwrap_or(true),
+        })
+    }
+}
+
+impl TtlOverrideConfig {
+    pub fn ttl_override(&self) -> TtlOverride {
+        TtlOverride {
+            min_ttl: self.min_ttl,
+            max_ttl: self.max_ttl,
+        }
+    }
+}
+
+impl RouteConfig {
+    pub fn into_upstreams(self, globals: &Globals) -> Result<Upstreams, String> {
+        let upstream = Upstream::resolve(&self.server_address, 1, globals.bootstrap.as_deref())?;
+        let mut upstreams = Upstreams::new(vec![upstream], UpstreamStrategy::Failover);
+        if let Some(local_bind_address) = self.local_bind_address {
+            upstreams.set_local_bind_address(local_bind_address);
+        }
+        Ok(upstreams)
+    }
+}
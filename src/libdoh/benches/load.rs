@@ -0,0 +1,138 @@
+//! Sends POST queries to a `DoH` service at 50k queries per second for a few seconds, with a
+//! resolver answering them over UDP on the loopback interface, and reports the rate at which
+//! they were answered, the latency percentiles and the number of allocations per query.
+//! Upstream queries are sent over the UDP socket pool, and then over a new socket each.
+//! Queries are generated on the same runtime as the one the service runs on.
+//!
+//! Run with `cargo bench -p libdoh --bench load`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{post_request, query, question_end, send, ANSWER_TTL};
+use libdoh::DoHBuilder;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const QUERIES_PER_SECOND: usize = 50_000;
+const DURATION_SECS: u64 = 5;
+const TICK_MS: u64 = 1;
+const RESOLVER_THREADS: usize = 2;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Starts a resolver answering every query with `192.0.2.1`, without allocating
+fn start_resolver() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    for _ in 0..RESOLVER_THREADS {
+        let socket = socket.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut packet = [0u8; 4096];
+            while let Ok((len, client)) = socket.recv_from(&mut packet) {
+                let end = match question_end(&packet[..len]) {
+                    None => continue,
+                    Some(end) => end,
+                };
+                packet[2] = 0x81 | (packet[2] & 0x01);
+                packet[3] = 0x80;
+                packet[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+                packet[end..end + 6].copy_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+                packet[end + 6..end + 10].copy_from_slice(&ANSWER_TTL.to_be_bytes());
+                packet[end + 10..end + 16].copy_from_slice(&[0, 4, 192, 0, 2, 1]);
+                let _ = socket.send_to(&packet[..end + 16], client);
+            }
+        });
+    }
+    address
+}
+
+fn percentile(latencies: &[Duration], percentile: usize) -> Duration {
+    latencies
+        .get((latencies.len() * percentile / 100).min(latencies.len().saturating_sub(1)))
+        .copied()
+        .unwrap_or_default()
+}
+
+fn run(name: &str, builder: DoHBuilder) {
+    // Every query reaches the upstream server
+    let doh = builder
+        .cache(0, Duration::default(), false)
+        .negative_cache_size(0)
+        .build();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let queries_per_tick = QUERIES_PER_SECOND * TICK_MS as usize / 1000;
+        let mut tasks = Vec::with_capacity(QUERIES_PER_SECOND * DURATION_SECS as usize);
+        let mut interval = tokio::time::interval(Duration::from_millis(TICK_MS));
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(DURATION_SECS) {
+            interval.tick().await;
+            for _ in 0..queries_per_tick {
+                let id = tasks.len();
+                let req = post_request(query(id as u16, &format!("q{}.example.com", id)));
+                let doh = doh.clone();
+                tasks.push(tokio::spawn(async move {
+                    let sent = Instant::now();
+                    let (response, _) = send(doh, req).await;
+                    (response.status().is_success(), sent.elapsed())
+                }));
+            }
+        }
+        let queries = tasks.len();
+        let mut latencies = Vec::with_capacity(queries);
+        for task in tasks {
+            if let Ok((true, latency)) = task.await {
+                latencies.push(latency);
+            }
+        }
+        let elapsed = start.elapsed();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        latencies.sort_unstable();
+        println!(
+            "{:<8} {:>7} queries {:>7} answered {:>8.0} answered/s  p50 {:>8.2?}  p99 {:>8.2?}  max {:>8.2?}  {:>5.1} allocations/query",
+            name,
+            queries,
+            latencies.len(),
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            percentile(&latencies, 50),
+            percentile(&latencies, 99),
+            latencies.last().copied().unwrap_or_default(),
+            allocations as f64 / queries as f64,
+        );
+    });
+}
+
+fn main() {
+    let upstream = start_resolver();
+    run("pooled", DoHBuilder::new().upstream(upstream));
+    run(
+        "unpooled",
+        DoHBuilder::new().upstream(upstream).udp_pool_size(0),
+    );
+}
@@ -0,0 +1,21 @@
+//! Functions reading queries and responses received from clients and upstream servers
+#![no_main]
+
+use libdoh::dns;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|packet: &[u8]| {
+    let _ = dns::qname(packet);
+    let _ = dns::question(packet);
+    let _ = dns::qtype_qclass(packet);
+    let _ = dns::cache_key(packet);
+    let _ = dns::truncate(packet);
+    let _ = dns::max_udp_response_len(packet);
+    let _ = dns::min_ttl(packet, 0, u32::MAX, 0);
+    let _ = dns::answer_records(packet, dns::DNS_TYPE_A);
+    let _ = dns::edns_option(packet, dns::DNS_OPTION_COOKIE);
+    let _ = dns::extended_rcode(packet);
+    let _ = dns::has_unvalidated_signatures(packet, packet);
+    let _ = dns::is_response_to(packet, packet);
+    let _ = dns::synthesize_response(packet, dns::DNS_RCODE_NXDOMAIN, &[]);
+});
@@ -0,0 +1,22 @@
+//! Functions modifying queries before they are forwarded, and responses before they are sent
+#![no_main]
+
+use libdoh::dns;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|packet: &[u8]| {
+    let mut rewritten = packet.to_vec();
+    let _ = dns::set_edns_max_payload_size(&mut rewritten, 1232);
+    let mut rewritten = packet.to_vec();
+    let _ = dns::set_edns_option(
+        &mut rewritten,
+        dns::DNS_OPTION_ECS,
+        &[0, 1, 24, 0, 192, 0, 2],
+    );
+    let _ = dns::remove_edns_option(&mut rewritten, dns::DNS_OPTION_ECS);
+    let _ = dns::add_edns_padding(&mut rewritten, 468);
+    let mut rewritten = packet.to_vec();
+    let _ = dns::set_qtype(&mut rewritten, dns::DNS_TYPE_AAAA);
+    let mut rewritten = packet.to_vec();
+    let _ = dns::adapt_response(&mut rewritten, packet);
+});
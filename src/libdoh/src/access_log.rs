@@ -0,0 +1,134 @@
+use std::collections::hash_map::RandomState;
+use std::fs::OpenOptions;
+use std::hash::BuildHasher;
+use std::io::{self, LineWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::constants::*;
+use crate::dns;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientIpPrivacy {
+    Full,
+    Hash,
+    Omit,
+}
+
+type Writer = Box<dyn Write + Send>;
+
+/// Lines are written by a dedicated thread, so that a slow disk doesn't block the workers.
+/// They are dropped if that thread can't keep up.
+pub struct AccessLog {
+    sender: SyncSender<String>,
+    writer: Mutex<Option<(Writer, Receiver<String>)>>,
+    client_ip_privacy: ClientIpPrivacy,
+    client_ip_hasher: RandomState,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        fmt.debug_struct("AccessLog")
+            .field("client_ip_privacy", &self.client_ip_privacy)
+            .finish()
+    }
+}
+
+pub struct AccessLogEntry<'t> {
+    pub client_ip: Option<IpAddr>,
+    pub client_identity: Option<&'t str>,
+    pub method: &'t str,
+    pub query: Option<&'t [u8]>,
+    pub response: Option<&'t [u8]>,
+    pub status: u16,
+    pub upstream_latency: Option<Duration>,
+}
+
+impl AccessLog {
+    pub fn stdout(client_ip_privacy: ClientIpPrivacy) -> Self {
+        Self::with_writer(Box::new(io::stdout()), client_ip_privacy)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, client_ip_privacy: ClientIpPrivacy) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::with_writer(
+            Box::new(LineWriter::new(file)),
+            client_ip_privacy,
+        ))
+    }
+
+    fn with_writer(writer: Writer, client_ip_privacy: ClientIpPrivacy) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(ACCESS_LOG_QUEUE_SIZE);
+        AccessLog {
+            sender,
+            writer: Mutex::new(Some((writer, receiver))),
+            client_ip_privacy,
+            client_ip_hasher: RandomState::new(),
+        }
+    }
+
+    /// Starts the thread writing the lines. Lines logged before are queued.
+    /// This has to be called after the process forked to the background, since the thread
+    /// wouldn't survive the fork.
+    pub fn start(&self) -> io::Result<()> {
+        let (mut writer, receiver) = match self.writer.lock().unwrap().take() {
+            None => return Ok(()),
+            Some(writer) => writer,
+        };
+        thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in receiver {
+                    let _ = writeln!(writer, "{}", line);
+                }
+            })?;
+        Ok(())
+    }
+
+    fn client_ip(&self, client_ip: Option<IpAddr>) -> Option<String> {
+        let client_ip = client_ip?;
+        match self.client_ip_privacy {
+            ClientIpPrivacy::Full => Some(client_ip.to_string()),
+            ClientIpPrivacy::Hash => Some(format!(
+                "{:016x}",
+                self.client_ip_hasher.hash_one(client_ip)
+            )),
+            ClientIpPrivacy::Omit => None,
+        }
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry<'_>) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let (qname, qtype) = match entry.query {
+            Some(query) => (
+                dns::qname(query).ok(),
+                dns::qtype_qclass(query)
+                    .ok()
+                    .map(|(qtype, _)| dns::qtype_name(qtype)),
+            ),
+            None => (None, None),
+        };
+        let line = serde_json::json!({
+            "ts": ts,
+            "client_ip": self.client_ip(entry.client_ip),
+            "client_identity": entry.client_identity,
+            "method": entry.method,
+            "qname": qname,
+            "qtype": qtype,
+            "rcode": entry.response.map(dns::rcode),
+            "status": entry.status,
+            "upstream_latency_ms": entry
+                .upstream_latency
+                .map(|latency| latency.as_secs_f64() * 1000.0),
+            "response_size": entry.response.map(|response| response.len()),
+        });
+        let _ = self.sender.try_send(line.to_string());
+    }
+}
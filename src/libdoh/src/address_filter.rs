@@ -0,0 +1,104 @@
+use crate::forwarded::IpNetwork;
+
+use std::net::IpAddr;
+
+/// How addresses of a filtered family are hidden from clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFilterMode {
+    /// Remove the records from the answers of upstream servers
+    Strip,
+    /// Answer queries for the records locally, with an empty NOERROR response
+    Empty,
+}
+
+/// Hides the A or AAAA records from the clients of some networks, for example to keep
+/// dual-stack clients on a network with broken IPv6 connectivity from trying IPv6 first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressFilter {
+    rr_type: u16,
+    mode: AddressFilterMode,
+    /// Every client if empty
+    networks: Vec<IpNetwork>,
+}
+
+impl AddressFilter {
+    /// Parses `<mode>` or `<mode>:<comma-separated networks>` for records of type `rr_type`
+    pub fn parse(rr_type: u16, s: &str) -> Result<Self, String> {
+        let mut parts = s.trim().splitn(2, ':');
+        let mode = match parts.next().unwrap() {
+            "strip" => AddressFilterMode::Strip,
+            "empty" => AddressFilterMode::Empty,
+            mode => return Err(format!("Unsupported address filter mode: [{}]", mode)),
+        };
+        let networks = match parts.next() {
+            None => vec![],
+            Some(networks) => {
+                let networks: Vec<IpNetwork> = networks
+                    .split(',')
+                    .filter(|network| !network.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?;
+                if networks.is_empty() {
+                    return Err(format!("No networks in the address filter: [{}]", s));
+                }
+                networks
+            }
+        };
+        Ok(AddressFilter {
+            rr_type,
+            mode,
+            networks,
+        })
+    }
+
+    pub fn rr_type(&self) -> u16 {
+        self.rr_type
+    }
+
+    pub fn mode(&self) -> AddressFilterMode {
+        self.mode
+    }
+
+    pub fn networks(&self) -> &[IpNetwork] {
+        &self.networks
+    }
+
+    pub fn applies_to(&self, client_ip: Option<IpAddr>) -> bool {
+        if self.networks.is_empty() {
+            return true;
+        }
+        match client_ip {
+            Some(client_ip) => self
+                .networks
+                .iter()
+                .any(|network| network.contains(&client_ip)),
+            None => false,
+        }
+    }
+}
+
+/// A and AAAA filters, checked in order
+#[derive(Debug, Clone, Default)]
+pub struct AddressFilters(Vec<AddressFilter>);
+
+impl AddressFilters {
+    pub fn add(&mut self, filter: AddressFilter) {
+        self.0.push(filter);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AddressFilter> {
+        self.0.iter()
+    }
+
+    /// Returns the mode of the first filter for `rr_type` that applies to the client
+    pub fn matching(&self, rr_type: u16, client_ip: Option<IpAddr>) -> Option<AddressFilterMode> {
+        self.0
+            .iter()
+            .find(|filter| filter.rr_type == rr_type && filter.applies_to(client_ip))
+            .map(|filter| filter.mode)
+    }
+}
@@ -0,0 +1,515 @@
+use crate::cache::Cache;
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+use crate::globals::Globals;
+use crate::landing::Landing;
+use crate::log_level::*;
+use crate::query_log::QueryLog;
+use crate::quota::Quotas;
+use crate::stats::{QueryStats, StatsLabel};
+use crate::upstream::Upstreams;
+use crate::LocalExecutor;
+
+use hyper::http;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::net::TcpListener;
+use tokio::runtime;
+use tokio::stream::StreamExt;
+use tracing::level_filters::LevelFilter;
+
+/// Serves the administration API. It is not authenticated, and must only be reachable by operators.
+pub async fn serve_admin(globals: Arc<Globals>, mut listener: TcpListener) -> Result<(), DoHError> {
+    let mut server = Http::new();
+    server.http1_only(true);
+    let server = server.with_executor(LocalExecutor::new(runtime::Handle::current()));
+    while let Some(stream) = listener.incoming().next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let globals = globals.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let globals = globals.clone();
+                async move { admin_response(&globals, req).await }
+            });
+            let _ = server.serve_connection(stream, service).await;
+        });
+    }
+    Ok(())
+}
+
+async fn admin_response(
+    globals: &Globals,
+    req: Request<Body>,
+) -> Result<Response<Body>, http::Error> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/admin/config") => json_response(StatusCode::OK, config(globals)),
+        (&Method::GET, "/admin/health") => json_response(StatusCode::OK, health(globals)),
+        (&Method::GET, "/admin/stats") => {
+            let top = match query_param(&req, "top").map(str::parse::<usize>) {
+                None => STATS_TOP_DOMAINS,
+                Some(Ok(top)) => top.min(STATS_TOP_DOMAINS_CAPACITY),
+                Some(Err(_)) => return json_error(StatusCode::BAD_REQUEST),
+            };
+            match &globals.query_stats {
+                None => json_error(StatusCode::NOT_FOUND),
+                Some(query_stats) => {
+                    json_response(StatusCode::OK, stats(globals, query_stats, top))
+                }
+            }
+        }
+        (&Method::GET, "/admin/querylog") => {
+            let query_log = match &globals.query_log {
+                None => return json_error(StatusCode::NOT_FOUND),
+                Some(query_log) => query_log,
+            };
+            let name = query_param(&req, "name").filter(|name| !name.is_empty());
+            let client_ip = match query_param(&req, "client").map(str::parse::<IpAddr>) {
+                None => None,
+                Some(Ok(client_ip)) => Some(client_ip),
+                Some(Err(_)) => return json_error(StatusCode::BAD_REQUEST),
+            };
+            let limit = match query_param(&req, "limit").map(str::parse::<usize>) {
+                None => QUERY_LOG_DEFAULT_LIMIT,
+                Some(Ok(limit)) => limit,
+                Some(Err(_)) => return json_error(StatusCode::BAD_REQUEST),
+            };
+            json_response(
+                StatusCode::OK,
+                search_query_log(query_log, name, client_ip, limit),
+            )
+        }
+        (&Method::GET, "/admin/drain") => json_response(
+            StatusCode::OK,
+            serde_json::json!({ "draining": globals.drain.is_triggered() }),
+        ),
+        (&Method::POST, "/admin/drain") => {
+            if !globals.drain.is_triggered() {
+                tracing::info!("Draining requested through the administration API");
+                globals.drain.trigger();
+            }
+            json_response(StatusCode::OK, serde_json::json!({ "draining": true }))
+        }
+        (&Method::POST, "/admin/cache/flush") => {
+            let name = query_param(&req, "name");
+            let name = match name.map(|name| dns::encode_name(&name.to_ascii_lowercase())) {
+                None => None,
+                Some(Ok(name)) => Some(name),
+                Some(Err(_)) => return json_error(StatusCode::BAD_REQUEST),
+            };
+            json_response(StatusCode::OK, flush_cache(globals, name.as_deref()))
+        }
+        (&Method::GET, "/admin/log-level") => json_response(
+            StatusCode::OK,
+            serde_json::json!({ "log_level": max_log_level().to_string() }),
+        ),
+        (&Method::PUT, "/admin/log-level") | (&Method::POST, "/admin/log-level") => {
+            set_log_level(req).await
+        }
+        (_, "/admin/config")
+        | (_, "/admin/health")
+        | (_, "/admin/stats")
+        | (_, "/admin/querylog")
+        | (_, "/admin/drain")
+        | (_, "/admin/cache/flush")
+        | (_, "/admin/log-level") => json_error(StatusCode::METHOD_NOT_ALLOWED),
+        _ => json_error(StatusCode::NOT_FOUND),
+    }
+}
+
+fn json_response(
+    status: StatusCode,
+    body: serde_json::Value,
+) -> Result<Response<Body>, http::Error> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(hyper::header::CACHE_CONTROL, "no-store")
+        .body(Body::from(body.to_string()))
+}
+
+fn json_error(status: StatusCode) -> Result<Response<Body>, http::Error> {
+    json_response(
+        status,
+        serde_json::json!({ "error": status.canonical_reason() }),
+    )
+}
+
+/// Returns the current configuration, without secrets
+fn config(globals: &Globals) -> serde_json::Value {
+    let endpoints: Vec<_> = globals
+        .endpoints
+        .iter()
+        .map(|endpoint| {
+            serde_json::json!({
+                "path": endpoint.path,
+                "upstreams": upstreams_config(&endpoint.upstreams),
+                "block_list": endpoint.block_list.as_ref().map(|block_list| block_list.to_string()),
+                "min_ttl": endpoint.min_ttl,
+                "max_ttl": endpoint.max_ttl,
+                "err_ttl": endpoint.err_ttl,
+                "max_query_size": endpoint.max_query_size,
+            })
+        })
+        .collect();
+    let mut routes: Vec<_> = globals.upstream_routes.iter().collect();
+    routes.sort_by_key(|&(domain, _)| domain);
+    let routes: Vec<_> = routes
+        .into_iter()
+        .map(|(domain, upstreams)| {
+            serde_json::json!({
+                "domain": domain,
+                "upstreams": upstreams_config(upstreams),
+            })
+        })
+        .collect();
+    let mut ttl_overrides: Vec<_> = globals.ttl_overrides.iter().collect();
+    ttl_overrides.sort_by_key(|&(domain, _)| domain);
+    let ttl_overrides: Vec<_> = ttl_overrides
+        .into_iter()
+        .map(|(domain, ttl_override)| {
+            serde_json::json!({
+                "domain": domain,
+                "min_ttl": ttl_override.min_ttl,
+                "max_ttl": ttl_override.max_ttl,
+            })
+        })
+        .collect();
+    let denied_qtypes: Vec<_> = globals.denied_qtypes.iter().map(dns::qtype_name).collect();
+    let profiles: Vec<_> = globals
+        .profiles
+        .iter()
+        .map(|(profile, tokens)| {
+            serde_json::json!({
+                "name": profile.name,
+                "tokens": tokens,
+                "block_list": profile.block_list.is_some(),
+                "rewrite_rules": profile.rewrite_rules.is_some(),
+                "safe_search": profile.safe_search,
+                "log_queries": profile.log_queries,
+            })
+        })
+        .collect();
+    let address_filters: Vec<_> = globals
+        .address_filters
+        .iter()
+        .map(|filter| {
+            serde_json::json!({
+                "type": dns::qtype_name(filter.rr_type()),
+                "mode": format!("{:?}", filter.mode()),
+                "networks": filter.networks().iter().map(ToString::to_string).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    #[allow(unused_mut)]
+    let mut config = serde_json::json!({
+        "listen_addresses": globals.listen_addresses.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "dns_listen_addresses": globals.dns_listen_addresses.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "path": globals.path,
+        "upstreams": upstreams_config(&globals.upstreams),
+        "routes": routes,
+        "upstream_proxy": globals.upstream_proxy.as_ref().map(|upstream_proxy| upstream_proxy.address.to_string()),
+        "bootstrap_dns": globals.bootstrap.as_ref().map(|bootstrap| bootstrap.servers().iter().map(ToString::to_string).collect::<Vec<_>>()),
+        "bind_device": globals.bind_device,
+        "endpoints": endpoints,
+        "max_clients": globals.max_clients,
+        "clients_count": globals.clients_count.current(),
+        "max_clients_reached": globals.clients_count.limit_reached(),
+        "max_client_queries": globals.max_client_queries,
+        "client_key_policy": format!("{:?}", globals.client_key_policy),
+        "daily_quota": globals.quotas.as_ref().and_then(Quotas::daily),
+        "monthly_quota": globals.quotas.as_ref().and_then(Quotas::monthly),
+        "quota_file": globals.quota_persist_path.as_ref().map(|path| path.display().to_string()),
+        "timeout_secs": globals.timeout.as_secs_f64(),
+        "query_timeout_secs": globals.query_timeout.as_secs_f64(),
+        "http_idle_timeout_secs": globals.http_idle_timeout.as_secs_f64(),
+        "max_requests_per_connection": globals.max_requests_per_connection,
+        "tcp_keepalive_secs": globals.tcp_keepalive.map(|tcp_keepalive| tcp_keepalive.as_secs_f64()),
+        "upstream_timeout_secs": globals.upstream_timeout.as_secs_f64(),
+        "upstream_retries": globals.upstream_retries,
+        "upstream_cookies": globals.upstream_cookies,
+        "qname_randomization": globals.qname_randomization,
+        "paranoid_upstream_checks": globals.paranoid_upstream_checks,
+        "max_udp_size": globals.max_udp_size,
+        "io_uring": globals.udp_pool.as_ref().is_some_and(|udp_pool| udp_pool.uses_io_uring()),
+        "min_ttl": globals.min_ttl,
+        "max_ttl": globals.max_ttl,
+        "err_ttl": globals.err_ttl,
+        "servfail_on_upstream_error": globals.servfail_on_upstream_error,
+        "ttl_overrides": ttl_overrides,
+        "cache_size": globals.cache.as_ref().map(|cache| cache.capacity()),
+        "negative_cache_size": globals.negative_cache.as_ref().map(|cache| cache.capacity()),
+        "cache_persist": globals.cache_persist_path.as_ref().map(|path| path.display().to_string()),
+        "keepalive": globals.keepalive,
+        "disable_post": globals.disable_post,
+        "max_query_size": globals.max_query_size,
+        "max_query_param_len": globals.max_query_param_len,
+        "auth_tokens": !globals.auth_tokens.is_empty(),
+        "profiles": profiles,
+        "landing": globals.landing.as_ref().map(|landing| match landing {
+            Landing::Redirect(url) => url.to_str().unwrap_or_default(),
+            Landing::Page(_) => "page",
+        }),
+        "response_headers": globals.response_headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(),
+        "padding": globals.padding,
+        "debug_headers": globals.debug_headers,
+        "dnssec_validate": globals.dnssec_validate,
+        "denied_qtypes": denied_qtypes,
+        "address_filters": address_filters,
+        "access_log": globals.access_log.is_some(),
+        "query_log_size": globals.query_log.as_ref().map_or(0, QueryLog::capacity),
+        "ecs_policy": format!("{:?}", globals.ecs_policy),
+        "edns_strip": globals.edns_strip.names(),
+        "local_zone": globals.local_zone.is_some(),
+        "canary_domains": globals.canary_domains.len(),
+        "block_private_ptr": globals.block_private_ptr,
+        "safe_search": globals.safe_search,
+        "loop_protection": globals.loop_protection,
+        "ddr": globals.ddr.is_some(),
+        "rewrite_rules": globals.rewrite_rules.is_some(),
+        "block_list": globals.block_list.as_ref().map(|block_list| block_list.to_string()),
+        "allow_list": globals.allow_list.as_ref().map(|allow_list| allow_list.to_string()),
+        "block_list_update_interval": globals.block_list_update_interval.as_secs(),
+        "health_path": globals.health_path,
+        "readiness_path": globals.readiness_path,
+    });
+    #[cfg(unix)]
+    {
+        config["dnstap_socket"] = serde_json::json!(globals
+            .dnstap
+            .as_ref()
+            .map(|dnstap| dnstap.path().display().to_string()));
+        config["reuseport"] = serde_json::json!(globals.reuseport);
+    }
+    #[cfg(feature = "tls")]
+    {
+        config["dot_listen_addresses"] = serde_json::json!(globals
+            .dot_listen_addresses
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>());
+        config["upstream_doh"] = serde_json::json!(globals
+            .upstream_doh
+            .as_ref()
+            .map(|upstream_doh| upstream_doh.url().to_string()));
+    }
+    config
+}
+
+fn upstreams_config(upstreams: &Upstreams) -> serde_json::Value {
+    let servers: Vec<_> = upstreams
+        .iter()
+        .map(|upstream| {
+            serde_json::json!({
+                "server_name": upstream.server_name,
+                "server_address": upstream.server_address().to_string(),
+                "fallback_address": upstream.fallback_address().map(|address| address.to_string()),
+                "local_bind_address": upstream.local_bind_address(&upstream.server_address()).to_string(),
+                "weight": upstream.weight,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "strategy": format!("{:?}", upstreams.strategy()),
+        "servers": servers,
+    })
+}
+
+/// Returns statistics about the upstream servers of every path,
+/// and the time elapsed since they last answered a query
+fn health(globals: &Globals) -> serde_json::Value {
+    let max_age = globals.upstream_probe_interval * 2 + globals.timeout;
+    let paths = std::iter::once((globals.path.as_str(), &globals.upstreams)).chain(
+        globals
+            .endpoints
+            .iter()
+            .map(|endpoint| (endpoint.path.as_str(), &endpoint.upstreams)),
+    );
+    let mut upstreams = vec![];
+    for (path, path_upstreams) in paths {
+        for upstream in path_upstreams.iter() {
+            let age = globals
+                .upstream_health
+                .last_response_age(&upstream.server_address());
+            let stats = upstream.stats();
+            upstreams.push(serde_json::json!({
+                "path": path,
+                "server_address": upstream.server_address().to_string(),
+                "weight": upstream.weight,
+                "queries": stats.queries,
+                "timeouts": stats.timeouts,
+                "rtt_ms": stats.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                "last_response_age_secs": age.map(|age| age.as_secs_f64()),
+                "reachable": matches!(age, Some(age) if age <= max_age),
+            }));
+        }
+    }
+    serde_json::json!({ "upstreams": upstreams })
+}
+
+/// Returns a summary of the queries received since the server started
+fn stats(globals: &Globals, query_stats: &QueryStats, top: usize) -> serde_json::Value {
+    let summary = query_stats.summary(top);
+    let top_domains: Vec<_> = summary
+        .top_domains
+        .iter()
+        .map(|(name, count)| serde_json::json!({ "name": name, "count": count }))
+        .collect();
+    let mut qtypes: serde_json::Map<_, _> = summary
+        .qtypes
+        .iter()
+        .map(|&(qtype, count)| (dns::qtype_name(qtype), count.into()))
+        .collect();
+    if summary.other_qtypes > 0 {
+        qtypes.insert("other".to_string(), summary.other_qtypes.into());
+    }
+    let rcodes: serde_json::Map<_, _> = summary
+        .rcodes
+        .iter()
+        .map(|&(rcode, count)| (dns::rcode_name(rcode), count.into()))
+        .collect();
+    let lookups = summary.cache_hits + summary.cache_misses;
+    let hit_rate = if lookups > 0 {
+        Some(summary.cache_hits as f64 / lookups as f64)
+    } else {
+        None
+    };
+    serde_json::json!({
+        "queries": summary.queries,
+        "top_domains": top_domains,
+        "qtypes": qtypes,
+        "rcodes": rcodes,
+        "failures": summary.failures,
+        "cache": {
+            "hits": summary.cache_hits,
+            "misses": summary.cache_misses,
+            "hit_rate": hit_rate,
+        },
+        "labeled_queries": labeled_counts(&summary.labeled_queries),
+        "labeled_upstream_queries": labeled_counts(&summary.labeled_upstream_queries),
+        "clients_count": globals.clients_count.current(),
+        "max_clients_reached": globals.clients_count.limit_reached(),
+    })
+}
+
+/// Returns the label values and the count of every series
+fn labeled_counts(series: &[(Vec<(StatsLabel, String)>, u64)]) -> Vec<serde_json::Value> {
+    series
+        .iter()
+        .map(|(labels, count)| {
+            let mut entry: serde_json::Map<_, _> = labels
+                .iter()
+                .map(|(label, value)| (label.name().to_string(), value.as_str().into()))
+                .collect();
+            entry.insert("count".to_string(), (*count).into());
+            serde_json::Value::Object(entry)
+        })
+        .collect()
+}
+
+fn query_param<'t>(req: &'t Request<Body>, name: &str) -> Option<&'t str> {
+    req.uri().query()?.split('&').find_map(|parts| {
+        let mut kv = parts.splitn(2, '=');
+        if kv.next() == Some(name) {
+            kv.next()
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns `true` if a cache key is for `name` or one of its subdomains.
+/// `name` is encoded and in lowercase, like the name in the key, that may be prefixed with
+/// `0xff`, an endpoint path and `0` for queries sent to additional endpoints.
+fn cache_key_matches(key: &[u8], name: &[u8]) -> bool {
+    let mut key = key;
+    if key.first() == Some(&0xff) {
+        key = match key.iter().position(|&c| c == 0) {
+            None => return false,
+            Some(path_end) => &key[path_end + 1..],
+        };
+    }
+    let mut offset = 0;
+    while offset < key.len() {
+        if key[offset..].starts_with(name) {
+            return true;
+        }
+        match key[offset] as usize {
+            0 => break,
+            label_len => offset += label_len + 1,
+        }
+    }
+    false
+}
+
+/// Returns the most recent queries for `name` and its subdomains, or of a client
+fn search_query_log(
+    query_log: &QueryLog,
+    name: Option<&str>,
+    client_ip: Option<IpAddr>,
+    limit: usize,
+) -> serde_json::Value {
+    let queries: Vec<_> = query_log
+        .search(name, client_ip, limit)
+        .into_iter()
+        .map(|entry| {
+            serde_json::json!({
+                "ts": entry.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+                "client_ip": entry.client_ip.map(|client_ip| client_ip.to_string()),
+                "client_identity": entry.client_identity.as_deref(),
+                "method": entry.method,
+                "qname": entry.qname,
+                "qtype": dns::qtype_name(entry.qtype),
+                "rcode": entry.rcode.map(dns::rcode_name),
+                "status": entry.status,
+                "latency_ms": entry.latency.as_secs_f64() * 1000.0,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "capacity": query_log.capacity(),
+        "size": query_log.len(),
+        "queries": queries,
+    })
+}
+
+/// Removes the cached responses for `name` and its subdomains, or all of them
+fn flush_cache(globals: &Globals, name: Option<&[u8]>) -> serde_json::Value {
+    let flush = |cache: &Cache| match name {
+        None => cache.clear(),
+        Some(name) => cache.remove_matching(|key| cache_key_matches(key, name)),
+    };
+    let flushed = globals.cache.as_ref().map(flush).unwrap_or(0)
+        + globals.negative_cache.as_ref().map(flush).unwrap_or(0);
+    tracing::info!(entries = flushed, "Cache flushed");
+    serde_json::json!({ "flushed": flushed })
+}
+
+/// Changes the maximum log level, given as the request body
+async fn set_log_level(req: Request<Body>) -> Result<Response<Body>, http::Error> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return json_error(StatusCode::BAD_REQUEST),
+    };
+    let level = match std::str::from_utf8(&body)
+        .ok()
+        .and_then(|level| level.trim().parse::<LevelFilter>().ok())
+    {
+        Some(level) => level,
+        None => return json_error(StatusCode::BAD_REQUEST),
+    };
+    set_max_log_level(level);
+    tracing::info!("Log level set to {}", level);
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({ "log_level": level.to_string() }),
+    )
+}
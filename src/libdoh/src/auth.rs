@@ -0,0 +1,100 @@
+use crate::watcher::Reloadable;
+
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Tokens that clients have to send in an `Authorization: Bearer` header
+#[derive(Debug, Clone, Default)]
+pub struct AuthTokens {
+    tokens: Vec<Vec<u8>>,
+    /// Tokens from a file, that can be reloaded
+    file_tokens: Option<Arc<Reloadable<Vec<Vec<u8>>>>>,
+}
+
+impl AuthTokens {
+    pub fn add(&mut self, token: &str) {
+        self.tokens.push(token.as_bytes().to_vec());
+    }
+
+    /// Adds the tokens from a file, with one token per line, replacing the ones from a
+    /// previously loaded file
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let file_tokens = Reloadable::load(path, |path| {
+            Ok(fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|token| !token.is_empty() && !token.starts_with('#'))
+                .map(|token| token.as_bytes().to_vec())
+                .collect())
+        })?;
+        self.file_tokens = Some(Arc::new(file_tokens));
+        Ok(())
+    }
+
+    /// Returns the path of the file the tokens were loaded from, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.file_tokens
+            .as_ref()
+            .and_then(|file_tokens| file_tokens.path())
+    }
+
+    /// Loads the tokens from the file again
+    pub fn reload(&self) -> io::Result<()> {
+        match &self.file_tokens {
+            None => Ok(()),
+            Some(file_tokens) => file_tokens.reload(),
+        }
+    }
+
+    /// Returns `true` if requests don't have to be authorized.
+    /// Once a file was loaded, they always have to, even if the file has no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty() && self.file_tokens.is_none()
+    }
+
+    /// Checks that the request includes one of the tokens, comparing them in constant time
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let token = match request_token(headers) {
+            None => return false,
+            Some(token) => token.as_bytes(),
+        };
+        let file_tokens = self
+            .file_tokens
+            .as_ref()
+            .map(|file_tokens| file_tokens.get());
+        self.tokens
+            .iter()
+            .chain(
+                file_tokens
+                    .iter()
+                    .flat_map(|file_tokens| file_tokens.iter()),
+            )
+            .fold(false, |found, expected| {
+                found | constant_time_eq(expected, token)
+            })
+    }
+}
+
+/// Returns the token sent in the `Authorization: Bearer` header of a request
+pub fn request_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(bearer_token)
+}
+
+fn bearer_token(authorization: &str) -> Option<&str> {
+    let mut parts = authorization.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(parts.next()?.trim())
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,313 @@
+use hyper::Uri;
+use regex::{Regex, RegexSet};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+const IGNORED_HOSTS: [&str; 5] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockResponse {
+    NxDomain,
+    Null,
+}
+
+/// What a query matching a rule is answered with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockAction {
+    /// The response set for the whole list
+    Default,
+    NxDomain,
+    /// The unspecified address
+    Null,
+    Refused,
+    /// Fixed addresses, answered instead of the real ones
+    Redirect(Vec<IpAddr>),
+}
+
+impl FromStr for BlockAction {
+    type Err = String;
+
+    /// Parses `nxdomain`, `null`, `refused` or `redirect=<comma-separated addresses>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => return Ok(BlockAction::NxDomain),
+            "null" => return Ok(BlockAction::Null),
+            "refused" => return Ok(BlockAction::Refused),
+            _ => {}
+        }
+        let addresses = s
+            .strip_prefix("redirect=")
+            .ok_or_else(|| format!("Unsupported action: [{}]", s))?;
+        let addresses = addresses
+            .split(',')
+            .map(|address| {
+                address
+                    .parse()
+                    .map_err(|_| format!("Invalid address: [{}]", address))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(BlockAction::Redirect(addresses))
+    }
+}
+
+/// Domain names with their actions, matching the names themselves as well as all their
+/// subdomains. The action of the most specific name applies.
+#[derive(Debug, Default)]
+pub struct SuffixTrie {
+    children: HashMap<Box<str>, SuffixTrie>,
+    action: Option<BlockAction>,
+}
+
+impl SuffixTrie {
+    pub fn insert(&mut self, name: &str, action: BlockAction) {
+        let mut node = self;
+        for label in name.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        node.action = Some(action);
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&BlockAction> {
+        let mut node = self;
+        let mut action = None;
+        for label in name.rsplit('.') {
+            node = match node.children.get(label) {
+                None => break,
+                Some(node) => node,
+            };
+            if node.action.is_some() {
+                action = node.action.as_ref();
+            }
+        }
+        action
+    }
+}
+
+/// Rules of a block list: names, that also match their subdomains, wildcards (`*.example.com`),
+/// that only match subdomains, and regular expressions, checked in that order
+#[derive(Debug, Default)]
+pub struct BlockRules {
+    names: SuffixTrie,
+    wildcards: SuffixTrie,
+    regexes: Option<RegexSet>,
+    regex_actions: Vec<BlockAction>,
+}
+
+impl BlockRules {
+    /// Parses a list of rules, either in hosts format or with one rule per line.
+    /// A rule can be followed by `$<action>`, and regular expressions are written as `/<regex>/`.
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut rules = BlockRules::default();
+        let mut regexes = vec![];
+        for (line_nb, line) in content.lines().enumerate() {
+            let invalid = |e: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: {}", line_nb + 1, e),
+                )
+            };
+            let line = line.trim();
+            if let Some(regex) = line.strip_prefix('/') {
+                let (regex, action) = match regex.rfind('/') {
+                    Some(end) => (&regex[..end], &regex[end + 1..]),
+                    None => return Err(invalid("Unterminated regular expression".to_string())),
+                };
+                let action = match action.trim() {
+                    "" => BlockAction::Default,
+                    action => match action.strip_prefix('$') {
+                        Some(action) => action.to_ascii_lowercase().parse().map_err(invalid)?,
+                        None => return Err(invalid(format!("Unexpected [{}]", action))),
+                    },
+                };
+                Regex::new(regex)
+                    .map_err(|e| invalid(format!("Invalid regular expression: {}", e)))?;
+                regexes.push(regex.to_string());
+                rules.regex_actions.push(action);
+                continue;
+            }
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let mut tokens = line.split_whitespace().peekable();
+            if let Some(first) = tokens.peek() {
+                if first.parse::<IpAddr>().is_ok() {
+                    tokens.next();
+                }
+            }
+            for rule in tokens {
+                let rule = rule.to_ascii_lowercase();
+                let (name, action) = match rule.split_once('$') {
+                    None => (rule.as_str(), BlockAction::Default),
+                    Some((name, action)) => (name, action.parse().map_err(invalid)?),
+                };
+                let name = name.trim_end_matches('.');
+                if name.is_empty() || IGNORED_HOSTS.contains(&name) {
+                    continue;
+                }
+                match name.strip_prefix("*.") {
+                    Some(suffix) => rules.wildcards.insert(suffix, action),
+                    None => rules.names.insert(name, action),
+                }
+            }
+        }
+        if !regexes.is_empty() {
+            rules.regexes = Some(RegexSet::new(&regexes).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid regular expressions: {}", e),
+                )
+            })?);
+        }
+        Ok(rules)
+    }
+
+    /// Returns the action of the first rule matching `qname`, that has to be in lowercase
+    pub fn lookup(&self, qname: &str) -> Option<&BlockAction> {
+        let name = qname.trim_end_matches('.');
+        if let Some(action) = self.names.lookup(name) {
+            return Some(action);
+        }
+        if let Some((_, parent)) = name.split_once('.') {
+            if let Some(action) = self.wildcards.lookup(parent) {
+                return Some(action);
+            }
+        }
+        let regexes = self.regexes.as_ref()?;
+        let index = regexes.matches(name).into_iter().next()?;
+        self.regex_actions.get(index)
+    }
+}
+
+/// Where the rules of a block list come from
+#[derive(Debug)]
+enum BlockListSource {
+    File {
+        path: PathBuf,
+        modified: Mutex<Option<SystemTime>>,
+    },
+    /// A list downloaded periodically, see `BlockListSubscription`
+    Url(Uri),
+}
+
+#[derive(Debug)]
+pub struct BlockList {
+    source: BlockListSource,
+    rules: RwLock<Arc<BlockRules>>,
+}
+
+impl BlockList {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let modified = fs::metadata(&path)?.modified().ok();
+        let rules = BlockRules::parse(&fs::read_to_string(&path)?)?;
+        Ok(BlockList {
+            source: BlockListSource::File {
+                path,
+                modified: Mutex::new(modified),
+            },
+            rules: RwLock::new(Arc::new(rules)),
+        })
+    }
+
+    /// Returns a list downloaded from `url`, that doesn't block anything until it is downloaded
+    pub fn subscribe(url: Uri) -> Self {
+        BlockList {
+            source: BlockListSource::Url(url),
+            rules: RwLock::new(Arc::new(BlockRules::default())),
+        }
+    }
+
+    /// Loads a list from a file, or subscribes to it if `location` is an `http://` or
+    /// `https://` URL
+    pub fn open(location: &str) -> io::Result<Self> {
+        if !location.starts_with("http://") && !location.starts_with("https://") {
+            return Self::load(location);
+        }
+        if !cfg!(feature = "tls") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Downloading block lists requires the tls feature",
+            ));
+        }
+        let url = location
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self::subscribe(url))
+    }
+
+    /// Reloads the list if the file was modified since it was last loaded.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let (path, modified) = match &self.source {
+            BlockListSource::File { path, modified } => (path, modified),
+            BlockListSource::Url(_) => return Ok(false),
+        };
+        let current = fs::metadata(path)?.modified().ok();
+        if current.is_some() && current == *modified.lock().unwrap() {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Loads the list again, keeping the previous rules if the file can't be read or is invalid
+    pub fn reload(&self) -> io::Result<()> {
+        let (path, modified) = match &self.source {
+            BlockListSource::File { path, modified } => (path, modified),
+            BlockListSource::Url(_) => return Ok(()),
+        };
+        let current = fs::metadata(path)?.modified().ok();
+        let rules = BlockRules::parse(&fs::read_to_string(path)?)?;
+        self.replace(rules);
+        *modified.lock().unwrap() = current;
+        Ok(())
+    }
+
+    /// Replaces the rules at once, queries being checked either against the previous rules
+    /// or against the new ones
+    pub fn replace(&self, rules: BlockRules) {
+        *self.rules.write().unwrap() = Arc::new(rules);
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        match &self.source {
+            BlockListSource::File { path, .. } => Some(path),
+            BlockListSource::Url(_) => None,
+        }
+    }
+
+    pub fn url(&self) -> Option<&Uri> {
+        match &self.source {
+            BlockListSource::File { .. } => None,
+            BlockListSource::Url(url) => Some(url),
+        }
+    }
+
+    /// Returns the action of the rule matching `qname`, if it is blocked
+    pub fn lookup(&self, qname: &str) -> Option<BlockAction> {
+        let rules = self.rules.read().unwrap().clone();
+        rules.lookup(qname).cloned()
+    }
+}
+
+impl fmt::Display for BlockList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            BlockListSource::File { path, .. } => write!(f, "{}", path.display()),
+            BlockListSource::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
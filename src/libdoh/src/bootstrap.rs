@@ -0,0 +1,184 @@
+use crate::constants::*;
+use crate::dns;
+use crate::globals::unspecified_bind_address;
+
+use futures::future;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct CachedAddresses {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Resolves the names of the upstream servers by sending queries to fixed DNS servers,
+/// instead of relying on the system resolver, that may itself be using the proxy.
+/// Addresses are cached for the TTL of the records, and resolved again when they expire.
+#[derive(Debug)]
+pub struct BootstrapResolver {
+    servers: Vec<SocketAddr>,
+    cache: Mutex<HashMap<String, CachedAddresses>>,
+}
+
+impl BootstrapResolver {
+    pub fn new(servers: Vec<SocketAddr>) -> Self {
+        assert!(!servers.is_empty(), "No bootstrap servers");
+        BootstrapResolver {
+            servers,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.servers
+    }
+
+    /// Resolves a `host:port` name, like `ToSocketAddrs`
+    pub async fn lookup_host(&self, name: &str) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = name
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid port value"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addresses = self.lookup_ip(host).await?;
+        Ok(addresses
+            .into_iter()
+            .map(|address| SocketAddr::new(address, port))
+            .collect())
+    }
+
+    /// Resolves a `host:port` name from outside of a runtime, when the configuration is loaded
+    pub fn lookup_host_blocking(&self, name: &str) -> io::Result<Vec<SocketAddr>> {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()?;
+        runtime.block_on(self.lookup_host(name))
+    }
+
+    /// Returns the IPv4 and IPv6 addresses of `host`, IPv4 addresses first.
+    /// If the name can't be resolved again after they expired, the previous addresses are kept.
+    pub async fn lookup_ip(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if let Ok(address) = host.parse() {
+            return Ok(vec![address]);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(cached) = self.cache.lock().unwrap().get(&host) {
+            if cached.expires > Instant::now() {
+                return Ok(cached.addresses.clone());
+            }
+        }
+        let (v4, v6) = future::join(
+            self.query(&host, dns::DNS_TYPE_A),
+            self.query(&host, dns::DNS_TYPE_AAAA),
+        )
+        .await;
+        let mut addresses = vec![];
+        let mut ttl = BOOTSTRAP_MAX_TTL_SECS;
+        let mut error = None;
+        for result in IntoIterator::into_iter([v4, v6]) {
+            match result {
+                Ok((family_addresses, family_ttl)) if !family_addresses.is_empty() => {
+                    addresses.extend(family_addresses);
+                    ttl = ttl.min(family_ttl);
+                }
+                Ok(_) => {}
+                Err(e) => error = Some(e),
+            }
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if addresses.is_empty() {
+            let error = error
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No addresses found"));
+            return match cache.get(&host) {
+                Some(cached) => {
+                    tracing::warn!(
+                        host = host.as_str(),
+                        error = tracing::field::display(&error),
+                        "Unable to resolve the name again, keeping the previous addresses"
+                    );
+                    Ok(cached.addresses.clone())
+                }
+                None => Err(error),
+            };
+        }
+        let ttl = ttl.clamp(BOOTSTRAP_MIN_TTL_SECS, BOOTSTRAP_MAX_TTL_SECS);
+        cache.insert(
+            host,
+            CachedAddresses {
+                addresses: addresses.clone(),
+                expires: Instant::now() + Duration::from_secs(ttl as u64),
+            },
+        );
+        Ok(addresses)
+    }
+
+    /// Asks the bootstrap servers in turn for the records of type `qtype`, and returns their
+    /// addresses along with the lowest TTL
+    async fn query(&self, host: &str, qtype: u16) -> io::Result<(Vec<IpAddr>, u32)> {
+        let query = dns::build_query(rand::random(), host, qtype)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No bootstrap servers");
+        for server in &self.servers {
+            let timeout = Duration::from_millis(BOOTSTRAP_QUERY_TIMEOUT_MS);
+            let response = match tokio::time::timeout(timeout, exchange(*server, &query)).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(e)) => {
+                    last_error = e;
+                    continue;
+                }
+                Err(_) => {
+                    last_error = io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("No response from [{}]", server),
+                    );
+                    continue;
+                }
+            };
+            let rcode = dns::rcode(&response);
+            if rcode != dns::DNS_RCODE_NOERROR && rcode != dns::DNS_RCODE_NXDOMAIN {
+                last_error = io::Error::other(format!(
+                    "[{}] answered with {}",
+                    server,
+                    dns::rcode_name(rcode)
+                ));
+                continue;
+            }
+            let records = dns::answer_records(&response, qtype)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let ttl = records
+                .iter()
+                .map(|(ttl, _)| *ttl)
+                .min()
+                .unwrap_or(BOOTSTRAP_MIN_TTL_SECS);
+            let addresses = records
+                .into_iter()
+                .filter_map(|(_, rdata)| match qtype {
+                    dns::DNS_TYPE_A => <[u8; 4]>::try_from(rdata).ok().map(IpAddr::from),
+                    _ => <[u8; 16]>::try_from(rdata).ok().map(IpAddr::from),
+                })
+                .collect();
+            return Ok((addresses, ttl));
+        }
+        Err(last_error)
+    }
+}
+
+async fn exchange(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(unspecified_bind_address(&server))?;
+    let mut socket = tokio::net::UdpSocket::from_std(socket)?;
+    socket.send_to(query, &server).await?;
+    let mut packet = vec![0u8; MAX_DNS_RESPONSE_LEN];
+    loop {
+        let (len, response_server) = socket.recv_from(&mut packet).await?;
+        if response_server == server && dns::is_response_to(&packet[..len], query) {
+            packet.truncate(len);
+            return Ok(packet);
+        }
+    }
+}
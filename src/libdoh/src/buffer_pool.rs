@@ -0,0 +1,113 @@
+use crate::constants::*;
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Fixed-size buffers that are reused across queries, instead of being allocated for every
+/// upstream response or query body. At most `max_buffers` idle buffers are kept.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    buffer_len: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_len: usize, max_buffers: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            buffer_len,
+            max_buffers,
+        }
+    }
+
+    /// Returns an empty buffer that can hold up to `buffer_len` bytes, and goes back to the
+    /// pool when dropped
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buffer = self
+            .buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0; self.buffer_len]);
+        PooledBuffer {
+            pool: self,
+            buffer,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of idle buffers
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Copies a response out of a pooled buffer or a cache entry, with enough spare capacity for
+/// the padding added before it is sent to a client, so that padding it never reallocates it
+pub(crate) fn response_packet(response: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(response.len() + RESPONSE_PADDING_HEADROOM);
+    packet.extend_from_slice(response);
+    packet
+}
+
+/// A buffer from a `BufferPool`. Buffers are not cleared when they are reused, so only the
+/// bytes that were written to them since can be read.
+pub struct PooledBuffer<'t> {
+    pool: &'t BufferPool,
+    buffer: Vec<u8>,
+    len: usize,
+}
+
+impl PooledBuffer<'_> {
+    /// Returns the whole buffer, to receive a packet into. Its length has to be set with
+    /// `set_len()` afterwards, for the received bytes to be readable.
+    pub fn recv_buffer(&mut self) -> &mut [u8] {
+        self.len = 0;
+        &mut self.buffer
+    }
+
+    /// Sets the number of bytes that were received into the buffer
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.buffer.len());
+        self.len = len;
+    }
+
+    /// Appends `data` to the buffer, or returns `false` if it doesn't fit
+    pub fn extend_from_slice(&mut self, data: &[u8]) -> bool {
+        let end = self.len + data.len();
+        if end > self.buffer.len() {
+            return false;
+        }
+        self.buffer[self.len..end].copy_from_slice(data);
+        self.len = end;
+        true
+    }
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer[..self.len]
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        let mut buffers = self.pool.buffers.lock().unwrap();
+        if buffers.len() < self.pool.max_buffers {
+            buffers.push(std::mem::take(&mut self.buffer));
+        }
+    }
+}
@@ -0,0 +1,600 @@
+use crate::access_log::AccessLog;
+use crate::address_filter::AddressFilter;
+use crate::auth::AuthTokens;
+use crate::blocklist::{BlockList, BlockResponse};
+use crate::bootstrap::BootstrapResolver;
+use crate::cache::Cache;
+use crate::canary::CanaryDomains;
+use crate::cors::CorsOrigins;
+use crate::ddr::Ddr;
+use crate::defaults::MAX_QUERY_SIZE_LIMIT;
+use crate::dns64::Dns64Prefix;
+#[cfg(unix)]
+use crate::dnstap::Dnstap;
+#[cfg(feature = "tls")]
+use crate::doh_upstream::DoHUpstream;
+use crate::endpoint::Endpoint;
+use crate::filter::{QueryFilter, ResponseFilter};
+use crate::forwarded::TrustedProxies;
+use crate::globals::*;
+use crate::landing::Landing;
+use crate::limits::ClientKeyPolicy;
+use crate::localzone::LocalZone;
+use crate::profile::Profile;
+use crate::query_log::QueryLog;
+use crate::quota::Quotas;
+use crate::response_headers::ResponseHeaders;
+use crate::rewrite::RewriteRules;
+use crate::stats::{QueryStats, StatsLabels};
+use crate::ttl_overrides::TtlOverrides;
+use crate::udp_pool::UdpPool;
+use crate::upstream::Upstreams;
+use crate::upstream_proxy::UpstreamProxy;
+use crate::watcher::Reloadable;
+use crate::DoH;
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Builds a `DoH` service, for example to embed it into an existing hyper server:
+///
+/// ```no_run
+/// # async fn run() -> Result<(), hyper::Error> {
+/// let doh = libdoh::DoHBuilder::new()
+///     .upstream("9.9.9.9:53".parse().unwrap())
+///     .path("/dns-query")
+///     .build();
+/// # let _ = doh;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct DoHBuilder {
+    globals: Globals,
+}
+
+impl DoHBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the upstream DNS server, and sends queries from an address of the same family
+    pub fn upstream(mut self, server_address: SocketAddr) -> Self {
+        self.globals.upstreams = Upstreams::single(server_address);
+        self
+    }
+
+    /// Sets several upstream DNS servers, and how to choose between them
+    pub fn upstreams(mut self, upstreams: Upstreams) -> Self {
+        self.globals.upstreams = upstreams;
+        self
+    }
+
+    /// Sends the queries for `domain` and its subdomains to other upstream servers.
+    /// `*` matches the names that no other domain matches.
+    pub fn upstream_route(mut self, domain: &str, upstreams: Upstreams) -> Self {
+        self.globals.upstream_routes.add(domain, upstreams);
+        self
+    }
+
+    /// Sends queries to the upstream servers over TCP, through a SOCKS5 or HTTP proxy
+    pub fn upstream_proxy(mut self, upstream_proxy: UpstreamProxy) -> Self {
+        self.globals.upstream_proxy = Some(upstream_proxy);
+        self
+    }
+
+    /// Forwards queries to an upstream DoH server, instead of the upstream DNS servers
+    #[cfg(feature = "tls")]
+    pub fn upstream_doh(mut self, upstream_doh: DoHUpstream) -> Self {
+        self.globals.upstream_doh = Some(upstream_doh);
+        self
+    }
+
+    /// Sets how often upstream servers given by name are resolved again (zero to disable)
+    pub fn upstream_resolve_interval(mut self, upstream_resolve_interval: Duration) -> Self {
+        self.globals.upstream_resolve_interval = upstream_resolve_interval;
+        self
+    }
+
+    /// Resolves the names of the upstream servers with this resolver instead of the system
+    /// resolver, when they are resolved again
+    pub fn bootstrap(mut self, bootstrap: Arc<BootstrapResolver>) -> Self {
+        self.globals.bootstrap = Some(bootstrap);
+        self
+    }
+
+    /// Sends queries to the upstream servers of the same family from this address
+    pub fn local_bind_address(mut self, local_bind_address: SocketAddr) -> Self {
+        self.globals
+            .upstreams
+            .set_local_bind_address(local_bind_address);
+        self
+    }
+
+    /// Sends queries to the upstream servers through a network interface or VRF (Linux only)
+    pub fn bind_device(mut self, bind_device: &str) -> Self {
+        self.globals.bind_device = Some(bind_device.to_string());
+        self
+    }
+
+    pub fn listen_address(mut self, listen_address: SocketAddr) -> Self {
+        self.globals.listen_addresses = vec![listen_address];
+        self
+    }
+
+    /// Sets the addresses to accept connections on, with one accept loop per address
+    pub fn listen_addresses(mut self, listen_addresses: Vec<SocketAddr>) -> Self {
+        self.globals.listen_addresses = listen_addresses;
+        self
+    }
+
+    /// Also answers plain DNS queries, over UDP and TCP, on `dns_listen_address`
+    pub fn dns_listen_address(mut self, dns_listen_address: SocketAddr) -> Self {
+        self.globals.dns_listen_addresses.push(dns_listen_address);
+        self
+    }
+
+    /// Also accepts DNS-over-TLS connections on `dot_listen_address`, with the same certificate
+    #[cfg(feature = "tls")]
+    pub fn dot_listen_address(mut self, dot_listen_address: SocketAddr) -> Self {
+        self.globals.dot_listen_addresses.push(dot_listen_address);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.globals.path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+        self
+    }
+
+    /// Adds a path served using its own upstream server and policies
+    pub fn endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.globals.endpoints.push(Arc::new(endpoint));
+        self
+    }
+
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.globals.max_clients = max_clients;
+        self
+    }
+
+    /// Closes connections that didn't send a complete request within `header_read_timeout`
+    /// after they were accepted, answers with `408` if the body of a request cannot be read within `body_read_timeout`,
+    /// and closes connections after `max_connection_lifetime`
+    pub fn connection_timeouts(
+        mut self,
+        header_read_timeout: Duration,
+        body_read_timeout: Duration,
+        max_connection_lifetime: Duration,
+    ) -> Self {
+        self.globals.header_read_timeout = header_read_timeout;
+        self.globals.body_read_timeout = body_read_timeout;
+        self.globals.max_connection_lifetime = max_connection_lifetime;
+        self
+    }
+
+    /// Closes HTTP connections on which no requests were received for `http_idle_timeout`
+    pub fn http_idle_timeout(mut self, http_idle_timeout: Duration) -> Self {
+        self.globals.http_idle_timeout = http_idle_timeout;
+        self
+    }
+
+    /// Closes HTTP connections after `max_requests_per_connection` requests, once they are answered
+    pub fn max_requests_per_connection(mut self, max_requests_per_connection: usize) -> Self {
+        self.globals.max_requests_per_connection = Some(max_requests_per_connection);
+        self
+    }
+
+    /// Enables TCP keepalive on client connections, with probes sent after `tcp_keepalive`
+    pub fn tcp_keepalive(mut self, tcp_keepalive: Duration) -> Self {
+        self.globals.tcp_keepalive = Some(tcp_keepalive);
+        self
+    }
+
+    /// Maximum number of queries processed at the same time on an HTTP/2 connection
+    pub fn max_connection_queries(mut self, max_connection_queries: u32) -> Self {
+        self.globals.max_connection_queries = max_connection_queries;
+        self
+    }
+
+    /// Maximum number of queries processed at the same time for a client address
+    pub fn max_client_queries(mut self, max_client_queries: usize) -> Self {
+        self.globals.max_client_queries = Some(max_client_queries);
+        self
+    }
+
+    /// Sets how the queries of a client are counted by `max_client_queries()`
+    pub fn client_key_policy(mut self, client_key_policy: ClientKeyPolicy) -> Self {
+        self.globals.client_key_policy = client_key_policy;
+        self
+    }
+
+    /// Limits the number of queries per bearer token, or per client address for requests
+    /// without a token
+    pub fn quotas(mut self, quotas: Quotas) -> Self {
+        self.globals.quotas = Some(quotas);
+        self
+    }
+
+    /// Saves the numbers of queries counted against quotas to `path` periodically and when
+    /// the server stops, and loads them when the server starts
+    pub fn quota_persist<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.globals.quota_persist_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Time allowed for the requests being processed to complete when connections are closed
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.globals.timeout = timeout;
+        self
+    }
+
+    /// Answers with `504` if a query couldn't be answered within `query_timeout`
+    pub fn query_timeout(mut self, query_timeout: Duration) -> Self {
+        self.globals.query_timeout = query_timeout;
+        self
+    }
+
+    pub fn upstream_timeout(mut self, upstream_timeout: Duration, upstream_retries: usize) -> Self {
+        self.globals.upstream_timeout = upstream_timeout;
+        self.globals.upstream_retries = upstream_retries;
+        self
+    }
+
+    /// Sets the UDP payload size advertised to the upstream servers, from 512 to 4096 bytes
+    pub fn max_udp_size(mut self, max_udp_size: u16) -> Self {
+        self.globals.max_udp_size = max_udp_size;
+        self
+    }
+
+    /// Sends DNS cookies (RFC 7873) to the upstream servers, and remembers their server cookies
+    pub fn upstream_cookies(mut self, upstream_cookies: bool) -> Self {
+        self.globals.upstream_cookies = upstream_cookies;
+        self
+    }
+
+    /// Randomizes the case of the names sent to the upstream servers over UDP, and rejects
+    /// responses that don't use the same case (enabled by default)
+    pub fn qname_randomization(mut self, qname_randomization: bool) -> Self {
+        self.globals.qname_randomization = qname_randomization;
+        self
+    }
+
+    /// Only accepts upstream responses that are well-formed, besides matching the query
+    pub fn paranoid_upstream_checks(mut self, paranoid_upstream_checks: bool) -> Self {
+        self.globals.paranoid_upstream_checks = paranoid_upstream_checks;
+        self
+    }
+
+    /// Sets the number of UDP sockets shared by upstream queries, `0` meaning one socket per query
+    pub fn udp_pool_size(mut self, udp_pool_size: usize) -> Self {
+        self.globals.udp_pool = if udp_pool_size > 0 {
+            Some(UdpPool::new(udp_pool_size))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Shares a UDP pool between upstream queries, such as one created with `UdpPool::with_io_uring()`
+    pub fn udp_pool(mut self, udp_pool: UdpPool) -> Self {
+        self.globals.udp_pool = Some(udp_pool);
+        self
+    }
+
+    pub fn ttl(mut self, min_ttl: u32, max_ttl: u32, err_ttl: u32) -> Self {
+        self.globals.min_ttl = min_ttl;
+        self.globals.max_ttl = max_ttl;
+        self.globals.err_ttl = err_ttl;
+        self
+    }
+
+    /// Answers queries that the upstream servers failed to answer with a `SERVFAIL` response,
+    /// cacheable for `err_ttl` seconds, instead of an HTTP error
+    pub fn servfail_on_upstream_error(mut self, servfail_on_upstream_error: bool) -> Self {
+        self.globals.servfail_on_upstream_error = servfail_on_upstream_error;
+        self
+    }
+
+    /// Overrides the minimum and maximum TTLs for specific zones
+    pub fn ttl_overrides(mut self, ttl_overrides: TtlOverrides) -> Self {
+        self.globals.ttl_overrides = ttl_overrides;
+        self
+    }
+
+    /// Sets the number of error responses cached for `err_ttl` seconds, `0` disabling the cache
+    pub fn negative_cache_size(mut self, negative_cache_size: usize) -> Self {
+        self.globals.negative_cache = if negative_cache_size > 0 {
+            Some(Cache::new(negative_cache_size))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Sets the number of cached responses, `0` disabling the cache, for how long
+    /// expired responses can be served if the upstream server doesn't respond,
+    /// and whether popular responses are refreshed before they expire
+    pub fn cache(mut self, cache_size: usize, serve_stale: Duration, prefetch: bool) -> Self {
+        self.globals.cache = if cache_size > 0 {
+            Some(Cache::with_serve_stale(cache_size, serve_stale).with_prefetch(prefetch))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Saves the cache to `path` every `interval` and when the server stops,
+    /// and loads it when the server starts
+    pub fn cache_persist<P: AsRef<Path>>(mut self, path: P, interval: Duration) -> Self {
+        self.globals.cache_persist_path = Some(path.as_ref().to_path_buf());
+        self.globals.cache_persist_interval = interval;
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: bool) -> Self {
+        self.globals.keepalive = keepalive;
+        self
+    }
+
+    pub fn disable_post(mut self, disable_post: bool) -> Self {
+        self.globals.disable_post = disable_post;
+        self
+    }
+
+    /// Sets the maximum size of queries, in bytes, and the maximum length of the `dns` parameter
+    /// of GET queries accordingly. Queries can't be larger than `MAX_QUERY_SIZE_LIMIT` bytes.
+    pub fn max_query_size(mut self, max_query_size: usize) -> Self {
+        let max_query_size = max_query_size.min(MAX_QUERY_SIZE_LIMIT);
+        self.globals.max_query_size = max_query_size;
+        self.globals.max_query_param_len = max_query_param_len(max_query_size);
+        self
+    }
+
+    /// Sets the maximum length of the `dns` parameter of GET queries, before it is decoded
+    pub fn max_query_param_len(mut self, max_query_param_len: usize) -> Self {
+        self.globals.max_query_param_len = max_query_param_len;
+        self
+    }
+
+    /// Serves the administration API on a separate address, and keeps query statistics for it
+    pub fn admin_listen_address(mut self, admin_listen_address: SocketAddr) -> Self {
+        self.globals.admin_listen_address = Some(admin_listen_address);
+        self.globals.query_stats = Some(QueryStats::default());
+        self
+    }
+
+    /// Keeps the last `capacity` queries, that can be searched with the administration API
+    pub fn query_log(mut self, capacity: usize) -> Self {
+        self.globals.query_log = Some(QueryLog::new(capacity));
+        self
+    }
+
+    /// Breaks the query statistics down by path, record type or upstream server
+    pub fn stats_labels(mut self, stats_labels: StatsLabels) -> Self {
+        self.globals.query_stats = Some(QueryStats::new(stats_labels));
+        self
+    }
+
+    /// Adds a filter that can inspect, modify or answer queries
+    pub fn query_filter<F: QueryFilter + 'static>(mut self, filter: F) -> Self {
+        self.globals.filters.query_filters.push(Arc::new(filter));
+        self
+    }
+
+    /// Adds a filter that can inspect or modify responses
+    pub fn response_filter<F: ResponseFilter + 'static>(mut self, filter: F) -> Self {
+        self.globals.filters.response_filters.push(Arc::new(filter));
+        self
+    }
+
+    /// Answers queries for the given record types locally
+    pub fn denied_qtypes(
+        mut self,
+        denied_qtypes: DeniedQtypes,
+        denied_qtype_response: DeniedQtypeResponse,
+    ) -> Self {
+        self.globals.denied_qtypes = denied_qtypes;
+        self.globals.denied_qtype_response = denied_qtype_response;
+        self
+    }
+
+    /// Hides A or AAAA records from the clients the filter applies to
+    pub fn address_filter(mut self, filter: AddressFilter) -> Self {
+        self.globals.address_filters.add(filter);
+        self
+    }
+
+    /// Requires the upstream server to set the AD bit in responses, to report that it validated
+    /// them
+    pub fn dnssec_validate(mut self, dnssec_validate: bool) -> Self {
+        self.globals.dnssec_validate = dnssec_validate;
+        self
+    }
+
+    /// Synthesizes `AAAA` records for names that only have `A` records, using a NAT64 prefix
+    pub fn dns64_prefix(mut self, dns64_prefix: Dns64Prefix) -> Self {
+        self.globals.dns64_prefix = Some(dns64_prefix);
+        self
+    }
+
+    /// Only accepts requests with one of the given bearer tokens
+    pub fn auth_tokens(mut self, auth_tokens: AuthTokens) -> Self {
+        self.globals.auth_tokens = auth_tokens;
+        self
+    }
+
+    /// Applies a profile to the requests sent with `token`, that is also accepted.
+    /// Tokens set with `auth_tokens()` afterwards replace it.
+    pub fn profile(mut self, token: &str, profile: Arc<Profile>) -> Self {
+        self.globals.auth_tokens.add(token);
+        self.globals.profiles.add(token, profile);
+        self
+    }
+
+    /// Allows web applications from the given origins to send queries
+    pub fn cors_origins(mut self, cors_origins: CorsOrigins) -> Self {
+        self.globals.cors_origins = Some(cors_origins);
+        self
+    }
+
+    /// Adds static headers to every response
+    pub fn response_headers(mut self, response_headers: ResponseHeaders) -> Self {
+        self.globals.response_headers = response_headers;
+        self
+    }
+
+    /// Redirects web browsers to a page, or serves them one, instead of returning a `404` status code
+    pub fn landing(mut self, landing: Landing) -> Self {
+        self.globals.landing = Some(landing);
+        self
+    }
+
+    /// Pads queries and responses with EDNS padding (RFC 8467)
+    pub fn padding(mut self, padding: bool) -> Self {
+        self.globals.padding = padding;
+        self
+    }
+
+    /// Describes why requests were rejected in problem details bodies (RFC 7807)
+    pub fn problem_details(mut self, problem_details: bool) -> Self {
+        self.globals.problem_details = problem_details;
+        self
+    }
+
+    /// Adds headers with the response code, and whether the response came from the cache or
+    /// from an upstream server, to DoH responses
+    pub fn debug_headers(mut self, debug_headers: bool) -> Self {
+        self.globals.debug_headers = debug_headers;
+        self
+    }
+
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.globals.access_log = Some(access_log);
+        self
+    }
+
+    /// Accepts connections on every listen address with `reuseport` sockets, bound with
+    /// `SO_REUSEPORT`, each with its own accept loop
+    #[cfg(unix)]
+    pub fn reuseport(mut self, reuseport: usize) -> Self {
+        self.globals.reuseport = reuseport;
+        self
+    }
+
+    /// Sends client queries and responses to a dnstap collector
+    #[cfg(unix)]
+    pub fn dnstap(mut self, dnstap: Dnstap) -> Self {
+        self.globals.dnstap = Some(dnstap);
+        self
+    }
+
+    /// Sets the reverse proxies allowed to report the client address in forwarding headers
+    pub fn trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+        self.globals.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub fn ecs_policy(mut self, ecs_policy: EcsPolicy) -> Self {
+        self.globals.ecs_policy = ecs_policy;
+        self
+    }
+
+    /// Removes EDNS options from client queries before they are sent to the upstream servers
+    pub fn edns_strip(mut self, edns_strip: EdnsStrip) -> Self {
+        self.globals.edns_strip = edns_strip;
+        self
+    }
+
+    pub fn local_zone(mut self, local_zone: LocalZone) -> Self {
+        self.globals.local_zone = Some(Reloadable::new(local_zone));
+        self
+    }
+
+    /// Answers queries for probe domains locally, such as `use-application-dns.net`
+    pub fn canary_domains(mut self, canary_domains: CanaryDomains) -> Self {
+        self.globals.canary_domains = canary_domains;
+        self
+    }
+
+    /// Answers reverse lookups for private, link-local and unique local addresses with
+    /// NXDOMAIN, instead of forwarding them
+    pub fn block_private_ptr(mut self, block_private_ptr: bool) -> Self {
+        self.globals.block_private_ptr = block_private_ptr;
+        self
+    }
+
+    /// Answers queries for search engines with their safe search variants
+    pub fn safe_search(mut self, safe_search: bool) -> Self {
+        self.globals.safe_search = safe_search;
+        self
+    }
+
+    /// Answers queries for the names of the upstream servers and of the server itself locally,
+    /// so that they can't loop back to the proxy
+    pub fn loop_protection(mut self, loop_protection: bool) -> Self {
+        self.globals.loop_protection = loop_protection;
+        self
+    }
+
+    /// Answers `_dns.resolver.arpa` SVCB queries, so that clients can discover the encrypted
+    /// protocols of the server (RFC 9462)
+    pub fn ddr(mut self, ddr: Ddr) -> Self {
+        self.globals.ddr = Some(ddr);
+        self
+    }
+
+    /// Answers queries for matching names with fixed addresses
+    pub fn rewrite_rules(mut self, rewrite_rules: RewriteRules) -> Self {
+        self.globals.rewrite_rules = Some(Reloadable::new(rewrite_rules));
+        self
+    }
+
+    pub fn block_list(mut self, block_list: BlockList, block_response: BlockResponse) -> Self {
+        self.globals.block_list = Some(Arc::new(block_list));
+        self.globals.block_response = block_response;
+        self
+    }
+
+    /// Never blocks the names matching `allow_list`, even if a block list matches them
+    pub fn allow_list(mut self, allow_list: BlockList) -> Self {
+        self.globals.allow_list = Some(Arc::new(allow_list));
+        self
+    }
+
+    /// Sets the paths of the liveness and readiness endpoints, `None` disabling them
+    pub fn health_paths(
+        mut self,
+        health_path: Option<String>,
+        readiness_path: Option<String>,
+    ) -> Self {
+        self.globals.health_path = health_path;
+        self.globals.readiness_path = readiness_path;
+        self
+    }
+
+    /// Gives access to all the settings, including the ones without a dedicated method
+    pub fn globals_mut(&mut self) -> &mut Globals {
+        &mut self.globals
+    }
+
+    pub fn build(self) -> DoH {
+        self.globals
+            .clients_count
+            .set_max_clients(self.globals.max_clients);
+        DoH {
+            globals: Arc::new(self.globals),
+            remote_addr: None,
+            endpoint: None,
+            client_identity: None,
+            user_agent_hash: None,
+            token_hash: None,
+            profile: None,
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::buffer_pool::response_packet;
+use crate::constants::*;
+
+/// Identifies the files written by `Cache::save()`, and the version of their format
+const SNAPSHOT_MAGIC: &[u8] = b"DoHcache\x01";
+
+#[derive(Debug)]
+struct CacheEntry {
+    packet: Vec<u8>,
+    ttl: u32,
+    expires: Instant,
+    refresh_after: Instant,
+    hits: u32,
+}
+
+/// Result of a cache lookup
+#[derive(Debug)]
+pub enum CacheLookup {
+    Miss,
+    /// A response that didn't expire yet, along with its remaining TTL, the number of seconds
+    /// it spent in the cache, and `true` if it is popular and about to expire, so it should be
+    /// prefetched
+    Fresh(Vec<u8>, u32, u32, bool),
+    /// An expired response that can still be served while it is being refreshed, along with the
+    /// number of seconds it spent in the cache.
+    /// `true` is returned when the entry should be refreshed, at most once per
+    /// `STALE_REFRESH_INTERVAL_SECS` so that an unreachable server doesn't get more queries.
+    Stale(Vec<u8>, u32, bool),
+}
+
+/// A bounded cache of DNS responses, keyed by `dns::cache_key()`.
+/// Expired responses are kept for `serve_stale` so they can be served if the upstream server
+/// cannot be reached (RFC 8767).
+#[derive(Debug)]
+pub struct Cache {
+    entries: Mutex<HashMap<Vec<u8>, CacheEntry>>,
+    capacity: usize,
+    serve_stale: Duration,
+    prefetch: bool,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_serve_stale(capacity, Duration::from_secs(0))
+    }
+
+    pub fn with_serve_stale(capacity: usize, serve_stale: Duration) -> Self {
+        Cache {
+            entries: Mutex::new(HashMap::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            serve_stale,
+            prefetch: false,
+        }
+    }
+
+    /// Requests popular entries to be refreshed before they expire
+    pub fn with_prefetch(mut self, prefetch: bool) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    pub fn lookup(&self, key: &[u8]) -> CacheLookup {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let entry = match entries.get_mut(key) {
+            None => return CacheLookup::Miss,
+            Some(entry) => entry,
+        };
+        entry.hits = entry.hits.saturating_add(1);
+        if entry.expires > now {
+            let ttl = (entry.expires - now).as_secs_f64().ceil() as u32;
+            let prefetch = self.prefetch
+                && entry.hits >= CACHE_PREFETCH_MIN_HITS
+                && ttl as u64 * 100 <= entry.ttl as u64 * CACHE_PREFETCH_TTL_PERCENT
+                && entry.refresh_after <= now;
+            if prefetch {
+                entry.refresh_after = now + Duration::from_secs(STALE_REFRESH_INTERVAL_SECS);
+            }
+            let age = entry.ttl.saturating_sub(ttl);
+            return CacheLookup::Fresh(response_packet(&entry.packet), ttl, age, prefetch);
+        }
+        if entry.expires + self.serve_stale <= now {
+            entries.remove(key);
+            return CacheLookup::Miss;
+        }
+        let refresh = entry.refresh_after <= now;
+        if refresh {
+            entry.refresh_after = now + Duration::from_secs(STALE_REFRESH_INTERVAL_SECS);
+        }
+        let age = entry
+            .ttl
+            .saturating_add((now - entry.expires).as_secs() as u32);
+        CacheLookup::Stale(response_packet(&entry.packet), age, refresh)
+    }
+
+    /// Returns a copy of a response that didn't expire yet, along with its remaining TTL
+    /// and its age
+    pub fn get(&self, key: &[u8]) -> Option<(Vec<u8>, u32, u32)> {
+        match self.lookup(key) {
+            CacheLookup::Fresh(packet, ttl, age, _) => Some((packet, ttl, age)),
+            _ => None,
+        }
+    }
+
+    /// Stores a response for `ttl` seconds.
+    /// If the cache is full, expired entries are removed, or an arbitrary entry if there are none.
+    pub fn insert(&self, key: Vec<u8>, packet: Vec<u8>, ttl: u32) {
+        if ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let serve_stale = self.serve_stale;
+            entries.retain(|_, entry| entry.expires + serve_stale > now);
+            if entries.len() >= self.capacity {
+                if let Some(evicted) = entries.keys().next().cloned() {
+                    entries.remove(&evicted);
+                }
+            }
+        }
+        let expires = now + Duration::from_secs(ttl as u64);
+        entries.insert(
+            key,
+            CacheEntry {
+                packet,
+                ttl,
+                expires,
+                refresh_after: now,
+                hits: 0,
+            },
+        );
+    }
+
+    /// Removes all the entries, returning how many there were
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    /// Removes the entries whose key matches `predicate`, returning how many were removed
+    pub fn remove_matching<F: Fn(&[u8]) -> bool>(&self, predicate: F) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.retain(|key, _| !predicate(key));
+        count - entries.len()
+    }
+
+    /// Writes the entries that can still be served to a file, so that they can be loaded after
+    /// a restart, and returns how many were written.
+    /// The file is replaced atomically, so that it is never left incomplete.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        let mut count = 0;
+        {
+            let entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            let system_now = SystemTime::now();
+            for (key, entry) in entries.iter() {
+                if entry.expires + self.serve_stale <= now
+                    || key.len() > 0xffff
+                    || entry.packet.len() > 0xffff
+                {
+                    continue;
+                }
+                let expires = if entry.expires > now {
+                    system_now + (entry.expires - now)
+                } else {
+                    system_now - (now - entry.expires)
+                };
+                let expires = expires.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let mut header = [0u8; 16];
+                BigEndian::write_u16(&mut header[0..], key.len() as u16);
+                BigEndian::write_u16(&mut header[2..], entry.packet.len() as u16);
+                BigEndian::write_u32(&mut header[4..], entry.ttl);
+                BigEndian::write_u64(&mut header[8..], expires.as_secs());
+                snapshot.extend_from_slice(&header);
+                snapshot.extend_from_slice(key);
+                snapshot.extend_from_slice(&entry.packet);
+                count += 1;
+            }
+        }
+        let path = path.as_ref();
+        let mut tmp_path = OsString::from(path);
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::write(&tmp_path, &snapshot)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(count)
+    }
+
+    /// Loads the entries written by `save()`, skipping the ones that cannot be served any more,
+    /// and returns how many were loaded
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let snapshot = fs::read(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid cache file");
+        let mut snapshot = snapshot.strip_prefix(SNAPSHOT_MAGIC).ok_or_else(invalid)?;
+        let now = Instant::now();
+        let system_now = SystemTime::now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut count = 0;
+        while !snapshot.is_empty() && entries.len() < self.capacity {
+            if snapshot.len() < 16 {
+                return Err(invalid());
+            }
+            let key_len = BigEndian::read_u16(&snapshot[0..]) as usize;
+            let packet_len = BigEndian::read_u16(&snapshot[2..]) as usize;
+            let ttl = BigEndian::read_u32(&snapshot[4..]);
+            let expires = UNIX_EPOCH + Duration::from_secs(BigEndian::read_u64(&snapshot[8..]));
+            let record = snapshot
+                .get(16..16 + key_len + packet_len)
+                .ok_or_else(invalid)?;
+            snapshot = &snapshot[16 + key_len + packet_len..];
+            let (key, packet) = record.split_at(key_len);
+            if packet.len() < MIN_DNS_PACKET_LEN {
+                return Err(invalid());
+            }
+            let expires = match expires.duration_since(system_now) {
+                Ok(remaining) => now + remaining,
+                Err(e) if e.duration() < self.serve_stale => match now.checked_sub(e.duration()) {
+                    None => continue,
+                    Some(expires) => expires,
+                },
+                Err(_) => continue,
+            };
+            entries.insert(
+                key.to_vec(),
+                CacheEntry {
+                    packet: packet.to_vec(),
+                    ttl,
+                    expires,
+                    refresh_after: now,
+                    hits: 0,
+                },
+            );
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
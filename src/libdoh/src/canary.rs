@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Response to queries for a canary domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanaryResponse {
+    NxDomain,
+    /// An empty NOERROR response
+    NoData,
+    /// The addresses of the query family, or an empty response for other record types
+    Addresses(Vec<IpAddr>),
+}
+
+impl FromStr for CanaryResponse {
+    type Err = String;
+
+    /// Parses `nxdomain`, `nodata`, or a comma-separated list of IP addresses
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "nxdomain" => Ok(CanaryResponse::NxDomain),
+            "nodata" => Ok(CanaryResponse::NoData),
+            addresses => addresses
+                .split(',')
+                .map(|address| {
+                    address
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid canary response: [{}]", address))
+                })
+                .collect::<Result<_, _>>()
+                .map(CanaryResponse::Addresses),
+        }
+    }
+}
+
+/// Domains probed by clients to detect the policy of a network, such as
+/// `use-application-dns.net`, answered locally.
+/// Unlike blocked names, only the names themselves match, not their subdomains.
+#[derive(Debug, Clone, Default)]
+pub struct CanaryDomains {
+    domains: HashMap<String, CanaryResponse>,
+}
+
+impl CanaryDomains {
+    /// Adds a domain given as `<name>` or `<name>=<response>`, answered with NXDOMAIN by default
+    pub fn add(&mut self, canary: &str) -> Result<(), String> {
+        let (name, response) = match canary.split_once('=') {
+            None => (canary, CanaryResponse::NxDomain),
+            Some((name, response)) => (name, response.parse()?),
+        };
+        let name = name.trim().trim_end_matches('.').to_ascii_lowercase();
+        if name.is_empty() {
+            return Err(format!("Missing name in [{}]", canary));
+        }
+        self.domains.insert(name, response);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn get(&self, qname: &str) -> Option<&CanaryResponse> {
+        self.domains.get(qname)
+    }
+}
@@ -0,0 +1,169 @@
+use crate::dns;
+use crate::errors::*;
+#[cfg(feature = "tls")]
+use crate::tls::*;
+use crate::DoH;
+
+use std::fmt;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Outcome of one of the checks run by `DoH::check()`
+#[derive(Debug, Clone)]
+pub struct Check {
+    /// What was checked, such as a certificate or an upstream server
+    pub subject: String,
+    /// A description of what was found, or why the check failed
+    pub result: Result<String, String>,
+}
+
+impl Check {
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(details) => write!(fmt, "[OK]     {}: {}", self.subject, details),
+            Err(e) => write!(fmt, "[FAILED] {}: {}", self.subject, e),
+        }
+    }
+}
+
+impl DoH {
+    /// Checks that the server could start and answer queries, without binding any sockets:
+    /// the TLS certificates are loaded, and a test query is sent to every upstream server
+    pub async fn check(&self) -> Vec<Check> {
+        let mut checks = vec![];
+        #[cfg(feature = "tls")]
+        checks.extend(self.check_tls());
+        #[cfg(feature = "tls")]
+        {
+            if let Some(upstream_doh) = &self.globals.upstream_doh {
+                let subject = format!("Upstream DoH server [{}]", upstream_doh.url());
+                let start = Instant::now();
+                let result = match dns::build_query(rand::random(), ".", dns::DNS_TYPE_NS) {
+                    Ok(query) => self.upstream_doh_exchange(upstream_doh, &query).await,
+                    Err(_) => Err(DoHError::InvalidData),
+                };
+                checks.push(Check {
+                    subject,
+                    result: test_query_result(result, start),
+                });
+                return checks;
+            }
+        }
+        let mut checked = vec![];
+        for upstream in self.globals.all_upstreams() {
+            let server_address = upstream.server_address();
+            if checked.contains(&server_address) {
+                continue;
+            }
+            checked.push(server_address);
+            let subject = match &upstream.server_name {
+                Some(server_name) => {
+                    format!("Upstream server [{}] ({})", server_name, server_address)
+                }
+                None => format!("Upstream server [{}]", server_address),
+            };
+            let start = Instant::now();
+            let result = match dns::build_query(rand::random(), ".", dns::DNS_TYPE_NS) {
+                Ok(query) => self.upstream_exchange_with(upstream, &query).await,
+                Err(_) => Err(DoHError::InvalidData),
+            };
+            checks.push(Check {
+                subject,
+                result: test_query_result(result, start),
+            });
+        }
+        checks
+    }
+
+    #[cfg(feature = "tls")]
+    fn check_tls(&self) -> Vec<Check> {
+        let globals = &self.globals;
+        let mut checks = vec![];
+        let client_ca = match &globals.tls_client_ca_path {
+            None => None,
+            Some(tls_client_ca_path) => {
+                let client_ca = ClientCa::load(tls_client_ca_path);
+                checks.push(Check {
+                    subject: format!(
+                        "Client certificate authorities [{}]",
+                        tls_client_ca_path.display()
+                    ),
+                    result: client_ca
+                        .as_ref()
+                        .map(|_| "loaded".to_string())
+                        .map_err(|e| e.to_string()),
+                });
+                client_ca.ok().map(Arc::new)
+            }
+        };
+        let has_certificate = match (&globals.tls_cert_path, &globals.tls_cert_key_path) {
+            (Some(tls_cert_path), Some(tls_cert_key_path)) => {
+                let result = create_tls_acceptor(
+                    tls_cert_path,
+                    tls_cert_key_path,
+                    client_ca.clone(),
+                    globals.tls_ticket_rotation_interval,
+                    false,
+                );
+                checks.push(Check {
+                    subject: format!(
+                        "TLS certificate [{}] and key [{}]",
+                        tls_cert_path.display(),
+                        tls_cert_key_path.display()
+                    ),
+                    result: result
+                        .map(|_| "loaded".to_string())
+                        .map_err(|e| e.to_string()),
+                });
+                true
+            }
+            _ => match &globals.tls_self_signed {
+                Some(hostname) => {
+                    let result = create_self_signed_tls_acceptor(
+                        hostname,
+                        client_ca.clone(),
+                        globals.tls_ticket_rotation_interval,
+                    );
+                    checks.push(Check {
+                        subject: format!("Self-signed TLS certificate for [{}]", hostname),
+                        result: result
+                            .map(|_| "generated".to_string())
+                            .map_err(|e| e.to_string()),
+                    });
+                    true
+                }
+                None => false,
+            },
+        };
+        if !globals.dot_listen_addresses.is_empty() && !has_certificate {
+            checks.push(Check {
+                subject: "DNS-over-TLS listeners".to_string(),
+                result: Err("DNS-over-TLS requires a TLS certificate".to_string()),
+            });
+        }
+        checks
+    }
+}
+
+/// Test queries ask for the root name servers, that every recursive resolver can answer
+fn test_query_result(result: Result<Vec<u8>, DoHError>, start: Instant) -> Result<String, String> {
+    let response = result.map_err(|e| e.to_string())?;
+    let rcode = dns::rcode(&response);
+    if rcode != dns::DNS_RCODE_NOERROR {
+        return Err(format!(
+            "the test query was answered with {}",
+            dns::rcode_name(rcode)
+        ));
+    }
+    Ok(format!(
+        "the test query was answered in {} ms",
+        start.elapsed().as_millis()
+    ))
+}
@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+#[derive(Debug)]
+struct ActivityState {
+    requests: usize,
+    served: usize,
+    idle_since: Instant,
+}
+
+/// Keeps track of the requests being processed on a connection, to close idle connections
+#[derive(Debug)]
+pub struct ConnectionActivity {
+    state: Mutex<ActivityState>,
+    request_started: Notify,
+}
+
+/// A request being processed, that marks the connection as idle when dropped if it was the last one
+#[derive(Debug)]
+pub struct RequestGuard {
+    activity: Arc<ConnectionActivity>,
+    /// Number of requests received on the connection, including this one
+    pub served: usize,
+}
+
+impl Default for ConnectionActivity {
+    fn default() -> Self {
+        ConnectionActivity {
+            state: Mutex::new(ActivityState {
+                requests: 0,
+                served: 0,
+                idle_since: Instant::now(),
+            }),
+            request_started: Notify::new(),
+        }
+    }
+}
+
+impl ConnectionActivity {
+    pub fn start_request(self: &Arc<Self>) -> RequestGuard {
+        let served = {
+            let mut state = self.state.lock().unwrap();
+            state.requests += 1;
+            state.served += 1;
+            state.served
+        };
+        self.request_started.notify();
+        RequestGuard {
+            activity: self.clone(),
+            served,
+        }
+    }
+
+    /// Completes once no complete requests were received for `timeout`, or for
+    /// `first_request_timeout` after the connection was accepted,
+    /// while no other requests were being processed
+    pub async fn idle_timeout(self: Arc<Self>, first_request_timeout: Duration, timeout: Duration) {
+        loop {
+            let deadline = {
+                let state = self.state.lock().unwrap();
+                let timeout = if state.served == 0 {
+                    first_request_timeout
+                } else {
+                    timeout
+                };
+                if state.requests > 0 {
+                    Instant::now() + timeout
+                } else if state.idle_since.elapsed() >= timeout {
+                    return;
+                } else {
+                    state.idle_since + timeout
+                }
+            };
+            tokio::time::delay_until(deadline.into()).await;
+        }
+    }
+
+    /// Completes once `count` requests were received on the connection
+    pub async fn served(self: Arc<Self>, count: usize) {
+        while self.state.lock().unwrap().served < count {
+            self.request_started.notified().await;
+        }
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        let mut state = self.activity.state.lock().unwrap();
+        state.requests -= 1;
+        if state.requests == 0 {
+            state.idle_since = Instant::now();
+        }
+    }
+}
@@ -1,6 +1,73 @@
+pub const ACCESS_LOG_QUEUE_SIZE: usize = 4096;
+pub const ALT_SVC_MAX_AGE_SECS: u32 = 86400;
+#[cfg(feature = "tls")]
+pub const BLOCK_LIST_FETCH_TIMEOUT_SECS: u64 = 60;
+#[cfg(feature = "tls")]
+pub const BLOCK_LIST_MAX_SIZE: usize = 64 * 1024 * 1024;
+#[cfg(feature = "tls")]
+pub const BLOCK_LIST_RETRY_INTERVAL_SECS: u64 = 300;
+pub const BOOTSTRAP_MAX_TTL_SECS: u32 = 3600;
+pub const BOOTSTRAP_MIN_TTL_SECS: u32 = 60;
+pub const BOOTSTRAP_QUERY_TIMEOUT_MS: u64 = 2000;
+pub const BUFFER_POOL_SIZE: usize = 64;
+pub const CACHE_PREFETCH_MIN_HITS: u32 = 3;
+pub const CACHE_PREFETCH_TTL_PERCENT: u64 = 10;
+pub const CONTENT_TYPE_QUERY_PARAM: &str = "ct";
+#[cfg(unix)]
+pub const DNSTAP_QUEUE_SIZE: usize = 4096;
+#[cfg(unix)]
+pub const DNSTAP_RECONNECT_INTERVAL_SECS: u64 = 5;
 pub const DNS_QUERY_PARAM: &str = "dns";
-pub const MAX_DNS_QUESTION_LEN: usize = 512;
+pub const DO53_UDP_QUEUE_SIZE: usize = 1024;
+pub const FILE_WATCHER_DEBOUNCE_MS: u64 = 200;
+pub const HAPPY_EYEBALLS_DELAY_MS: u64 = 250;
+pub const LANDING_PAGE_MAX_AGE_SECS: u32 = 3600;
+pub const LOCAL_RESPONSE_TTL: u32 = 60;
 pub const MAX_DNS_RESPONSE_LEN: usize = 4096;
 pub const MIN_DNS_PACKET_LEN: usize = 17;
+#[cfg(feature = "tls")]
+pub const OCSP_FETCH_TIMEOUT_SECS: u64 = 10;
+#[cfg(feature = "tls")]
+pub const OCSP_REFRESH_INTERVAL_SECS: u64 = 3600;
+#[cfg(feature = "tls")]
+pub const OCSP_RETRY_INTERVAL_SECS: u64 = 300;
+#[cfg(feature = "tls")]
+pub const OTLP_EXPORT_INTERVAL_MS: u64 = 5000;
+#[cfg(feature = "tls")]
+pub const OTLP_MAX_BATCH_SIZE: usize = 512;
+#[cfg(feature = "tls")]
+pub const OTLP_QUEUE_SIZE: usize = 4096;
+pub const QUERY_PADDING_BLOCK_SIZE: usize = 128;
+pub const QUERY_LOG_DEFAULT_LIMIT: usize = 100;
+pub const QUOTA_PERSIST_INTERVAL_SECS: u64 = 60;
+pub const RESPONSE_PADDING_BLOCK_SIZE: usize = 468;
+pub const RESPONSE_PADDING_HEADROOM: usize = 11 + 4 + RESPONSE_PADDING_BLOCK_SIZE;
+#[cfg(feature = "tls")]
+pub const SELF_SIGNED_CERT_VALIDITY_DAYS: u64 = 30;
+pub const SHUTDOWN_POLL_INTERVAL_MS: u64 = 100;
+pub const STALE_ANSWER_TTL: u32 = 30;
 pub const STALE_IF_ERROR_SECS: u32 = 86400;
+pub const STALE_REFRESH_INTERVAL_SECS: u64 = 30;
+pub const STALE_REFRESH_TIMEOUT_MS: u64 = 1800;
 pub const STALE_WHILE_REVALIDATE_SECS: u32 = 60;
+pub const STATS_MAX_LABEL_SERIES: usize = 1024;
+pub const STATS_MAX_LABEL_VALUES: usize = 32;
+pub const STATS_MAX_QTYPES: usize = 64;
+pub const STATS_SKETCH_DEPTH: usize = 4;
+pub const STATS_SKETCH_WIDTH: usize = 4096;
+pub const STATS_TOP_DOMAINS: usize = 10;
+pub const STATS_TOP_DOMAINS_CAPACITY: usize = 100;
+#[cfg(feature = "tls")]
+pub const TLS_SESSION_CACHE_SIZE: usize = 4096;
+pub const UDP_POOL_RECV_RETRY_DELAY_MS: u64 = 100;
+pub const UDP_POOL_SOCKET_CHECK_INTERVAL_SECS: u64 = 1;
+pub const UDP_POOL_SOCKET_MAX_AGE_SECS: u64 = 10;
+pub const UDP_POOL_SOCKET_MAX_QUERIES: usize = 100;
+pub const UPSTREAM_FAILURE_RETRY_SECS: u64 = 10;
+pub const UPSTREAM_REDUCED_UDP_SIZE: u16 = 1232;
+pub const UPSTREAM_RTT_HALF_LIFE_SECS: u64 = 60;
+pub const UPSTREAM_RTT_SMOOTHING: f64 = 0.3;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub const URING_ENTRIES: u32 = 1024;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub const URING_RECV_RETRY_DELAY_MS: u32 = 100;
@@ -0,0 +1,89 @@
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use std::str::FromStr;
+
+const CORS_MAX_AGE_SECS: u32 = 86400;
+
+/// Origins of the web applications allowed to send queries with `fetch()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorsOrigins {
+    any: bool,
+    origins: Vec<String>,
+}
+
+impl CorsOrigins {
+    /// Returns the value of `Access-Control-Allow-Origin` for a request,
+    /// or `None` if it wasn't sent by an allowed origin
+    pub fn allowed_origin(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+        let origin = headers.get(header::ORIGIN)?;
+        if self.any {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin_str = origin.to_str().ok()?;
+        if self
+            .origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin_str))
+        {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for CorsOrigins {
+    type Err = String;
+
+    /// Parses a comma-separated list of origins, or `*` for any origin
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cors_origins = CorsOrigins::default();
+        for origin in s
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+        {
+            if origin == "*" {
+                cors_origins.any = true;
+            } else if origin.starts_with("http://") || origin.starts_with("https://") {
+                cors_origins
+                    .origins
+                    .push(origin.trim_end_matches('/').to_string());
+            } else {
+                return Err(format!("Invalid origin: [{}]", origin));
+            }
+        }
+        if !cors_origins.any && cors_origins.origins.is_empty() {
+            return Err("No origins".to_string());
+        }
+        Ok(cors_origins)
+    }
+}
+
+/// Adds the CORS headers to a response sent to an allowed origin
+pub fn add_cors_headers(response: &mut Response<Body>, allowed_origin: HeaderValue) {
+    let headers = response.headers_mut();
+    if allowed_origin != "*" {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+}
+
+/// Answers a preflight request
+pub fn preflight_response(disable_post: bool) -> Response<Body> {
+    let methods = if disable_post {
+        "GET, OPTIONS"
+    } else {
+        "GET, POST, OPTIONS"
+    };
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ACCESS_CONTROL_ALLOW_METHODS, methods)
+        .header(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            "Accept, Authorization, Content-Type",
+        )
+        .header(header::ACCESS_CONTROL_MAX_AGE, CORS_MAX_AGE_SECS)
+        .body(Body::empty())
+        .unwrap()
+}
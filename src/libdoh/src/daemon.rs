@@ -0,0 +1,79 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+/// Running in the background, for init systems that don't supervise the process
+#[derive(Debug, Clone, Default)]
+pub struct Daemon {
+    pub daemonize: bool,
+    pub pid_file: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Forks, and exits the parent process
+fn fork() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+impl Daemon {
+    /// Forks to the background if requested, and writes the PID file.
+    /// This must be called before other threads are started, as they would not be forked.
+    pub fn start(&self) -> io::Result<()> {
+        if self.daemonize {
+            // Files are opened first, so that errors are still reported to the terminal
+            let stdin = File::open("/dev/null")?;
+            let log_file = match &self.log_file {
+                Some(log_file) => OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(log_file)?,
+                None => OpenOptions::new().write(true).open("/dev/null")?,
+            };
+            // The second fork ensures that the process can never reacquire a terminal.
+            // The working directory is kept, as paths given on the command line can be
+            // relative to it.
+            fork()?;
+            check(unsafe { libc::setsid() })?;
+            fork()?;
+            self.write_pid_file()?;
+            check(unsafe { libc::dup2(stdin.as_raw_fd(), libc::STDIN_FILENO) })?;
+            check(unsafe { libc::dup2(log_file.as_raw_fd(), libc::STDOUT_FILENO) })?;
+            check(unsafe { libc::dup2(log_file.as_raw_fd(), libc::STDERR_FILENO) })?;
+        } else {
+            self.write_pid_file()?;
+        }
+        Ok(())
+    }
+
+    fn write_pid_file(&self) -> io::Result<()> {
+        match &self.pid_file {
+            Some(pid_file) => fs::write(pid_file, format!("{}\n", std::process::id())),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the PID file, on a clean shutdown
+    pub fn remove_pid_file(&self) {
+        if let Some(pid_file) = &self.pid_file {
+            if let Err(e) = fs::remove_file(pid_file) {
+                tracing::warn!(
+                    path = %pid_file.display(),
+                    error = %e,
+                    "Unable to remove the PID file"
+                );
+            }
+        }
+    }
+}
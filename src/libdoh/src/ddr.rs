@@ -0,0 +1,107 @@
+use crate::dns;
+
+const SVC_PARAM_KEY_ALPN: u16 = 1;
+const SVC_PARAM_KEY_PORT: u16 = 3;
+const SVC_PARAM_KEY_DOHPATH: u16 = 7;
+
+/// The name clients query to discover the encrypted resolvers of their unencrypted resolver
+const RESOLVER_ARPA_NAME: &str = "_dns.resolver.arpa";
+
+/// SVCB records advertising the encrypted protocols of the server, so that clients can
+/// upgrade to them (Discovery of Designated Resolvers, RFC 9462)
+#[derive(Clone, Debug)]
+pub struct Ddr {
+    hostname: String,
+    port: u16,
+    /// `_dns.<hostname>`, that clients knowing the name of the resolver query instead
+    resolver_name: String,
+    records: Vec<Vec<u8>>,
+    /// HTTPS record of the hostname itself, if it is answered locally
+    https_records: Option<Vec<Vec<u8>>>,
+}
+
+impl Ddr {
+    /// Advertises DoH on `hostname:port` with `path`, and DoT if `dot_port` is set.
+    /// `hostname` must be a name the certificate of the server is valid for.
+    pub fn new(
+        hostname: &str,
+        port: u16,
+        path: &str,
+        dot_port: Option<u16>,
+    ) -> Result<Self, String> {
+        let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+        let target = dns::encode_name(&hostname)
+            .map_err(|e| format!("Invalid hostname [{}]: {}", hostname, e))?;
+        let mut records = vec![svcb_rdata(
+            1,
+            &target,
+            b"h2",
+            port,
+            Some(format!("{}{{?dns}}", path).as_bytes()),
+        )];
+        if let Some(dot_port) = dot_port {
+            records.push(svcb_rdata(2, &target, b"dot", dot_port, None));
+        }
+        Ok(Ddr {
+            resolver_name: format!("_dns.{}", hostname),
+            hostname,
+            port,
+            records,
+            https_records: None,
+        })
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Also answers HTTPS queries for the hostname, advertising HTTP/2 on the DoH port, so that
+    /// clients connect with it directly. Other queries for the hostname are still forwarded.
+    pub fn with_https_record(mut self) -> Self {
+        self.https_records = Some(vec![svcb_rdata(1, &[0], b"h2", self.port, None)]);
+        self
+    }
+
+    /// Returns the records answering a question, of the type of the question, or `None` if it
+    /// has to be forwarded. Questions for DDR names and other types than SVCB get an empty answer.
+    pub fn lookup(&self, qname: &str, qtype: u16) -> Option<&[Vec<u8>]> {
+        if qname == self.hostname && qtype == dns::DNS_TYPE_HTTPS {
+            return self.https_records.as_deref();
+        }
+        if qname != RESOLVER_ARPA_NAME && qname != self.resolver_name {
+            return None;
+        }
+        if qtype == dns::DNS_TYPE_SVCB {
+            Some(&self.records)
+        } else {
+            Some(&[])
+        }
+    }
+}
+
+/// Encodes the data of a SVCB record in service mode (RFC 9460), with parameters in
+/// increasing key order
+fn svcb_rdata(
+    priority: u16,
+    target: &[u8],
+    alpn: &[u8],
+    port: u16,
+    dohpath: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut rdata = priority.to_be_bytes().to_vec();
+    rdata.extend_from_slice(target);
+    let mut alpn_value = vec![alpn.len() as u8];
+    alpn_value.extend_from_slice(alpn);
+    push_svc_param(&mut rdata, SVC_PARAM_KEY_ALPN, &alpn_value);
+    push_svc_param(&mut rdata, SVC_PARAM_KEY_PORT, &port.to_be_bytes());
+    if let Some(dohpath) = dohpath {
+        push_svc_param(&mut rdata, SVC_PARAM_KEY_DOHPATH, dohpath);
+    }
+    rdata
+}
+
+fn push_svc_param(rdata: &mut Vec<u8>, key: u16, value: &[u8]) {
+    rdata.extend_from_slice(&key.to_be_bytes());
+    rdata.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(value);
+}
@@ -0,0 +1,37 @@
+//! Default values of the settings
+pub const LISTEN_ADDRESS: &str = "127.0.0.1:3000";
+pub const MAX_CLIENTS: usize = 512;
+pub const MAX_CONNECTION_QUERIES: u32 = 100;
+pub const MAX_QUERY_SIZE: usize = 512;
+/// Largest maximum query size, the size of the largest packets that can be parsed
+pub const MAX_QUERY_SIZE_LIMIT: usize = 4096;
+/// Length of a 512 byte query encoded as base64url
+pub const MAX_DNS_QUERY_PARAM_LEN: usize = 683;
+pub const PATH: &str = "/dns-query";
+pub const SERVER_ADDRESS: &str = "9.9.9.9:53";
+pub const TIMEOUT_SEC: u64 = 10;
+pub const HEADER_READ_TIMEOUT_SEC: u64 = 10;
+pub const BODY_READ_TIMEOUT_SEC: u64 = 5;
+pub const MAX_CONNECTION_LIFETIME_SEC: u64 = 300;
+pub const UPSTREAM_TIMEOUT_MS: u64 = 2000;
+pub const UPSTREAM_RETRIES: usize = 2;
+pub const UDP_POOL_SIZE: usize = 16;
+pub const MAX_TTL: u32 = 86400 * 7;
+pub const MIN_TTL: u32 = 10;
+pub const ERR_TTL: u32 = 2;
+pub const NEGATIVE_CACHE_SIZE: usize = 1024;
+pub const CACHE_SIZE: usize = 4096;
+pub const CACHE_SERVE_STALE_TTL: u32 = 86400;
+pub const BLOCK_LIST_RELOAD_INTERVAL_SEC: u64 = 60;
+pub const BLOCK_LIST_UPDATE_INTERVAL_SEC: u64 = 3600;
+pub const REWRITE_TTL: u32 = 60;
+pub const HEALTH_PATH: &str = "/healthz";
+pub const READINESS_PATH: &str = "/readyz";
+pub const UPSTREAM_PROBE_INTERVAL_SEC: u64 = 10;
+pub const UPSTREAM_RESOLVE_INTERVAL_SEC: u64 = 60;
+pub const OTLP_SERVICE_NAME: &str = "doh-proxy";
+pub const TLS_TICKET_ROTATION_INTERVAL_SEC: u64 = 3600;
+pub const CACHE_PERSIST_INTERVAL_SEC: u64 = 300;
+pub const MAX_UDP_SIZE: u16 = 4096;
+pub const HTTP_IDLE_TIMEOUT_SEC: u64 = 60;
+pub const QUERY_TIMEOUT_MS: u64 = 5000;
@@ -0,0 +1,138 @@
+//! Minimal DER encoding and decoding, for certificates and OCSP messages
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DER_BOOLEAN: u8 = 0x01;
+pub const DER_INTEGER: u8 = 0x02;
+pub const DER_BIT_STRING: u8 = 0x03;
+pub const DER_OCTET_STRING: u8 = 0x04;
+pub const DER_NULL: u8 = 0x05;
+pub const DER_OID: u8 = 0x06;
+pub const DER_ENUMERATED: u8 = 0x0a;
+pub const DER_UTF8_STRING: u8 = 0x0c;
+pub const DER_UTC_TIME: u8 = 0x17;
+pub const DER_GENERALIZED_TIME: u8 = 0x18;
+pub const DER_SEQUENCE: u8 = 0x30;
+pub const DER_SET: u8 = 0x31;
+pub const DER_DNS_NAME: u8 = 0x82;
+pub const DER_URI: u8 = 0x86;
+pub const DER_IP_ADDRESS: u8 = 0x87;
+pub const DER_CONTEXT_0: u8 = 0xa0;
+pub const DER_CONTEXT_3: u8 = 0xa3;
+
+/// Reads a DER element, returning its tag, its content and the remaining data
+pub fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, der) = der.split_first()?;
+    let (&len, mut der) = der.split_first()?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let len_len = (len & 0x7f) as usize;
+        if len_len == 0 || len_len > 4 || der.len() < len_len {
+            return None;
+        }
+        let len = der[..len_len]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        der = &der[len_len..];
+        len
+    };
+    if der.len() < len {
+        return None;
+    }
+    Some((tag, &der[..len], &der[len..]))
+}
+
+/// Reads a DER element, returning it with its header, and the remaining data
+pub fn der_raw_element(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (_, _, rest) = der_element(der)?;
+    Some((&der[..der.len() - rest.len()], rest))
+}
+
+/// Encodes a DER element
+pub fn der_encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        der.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|&b| b == 0)
+            .collect();
+        der.push(0x80 | len_bytes.len() as u8);
+        der.extend_from_slice(&len_bytes);
+    }
+    der.extend_from_slice(content);
+    der
+}
+
+/// Converts a `GeneralizedTime` such as `20201102101240Z` to a system time
+pub fn generalized_time(time: &[u8]) -> Option<SystemTime> {
+    let digits = time.get(..14)?;
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| {
+        digits[range]
+            .iter()
+            .fold(0i64, |n, &d| n * 10 + (d - b'0') as i64)
+    };
+    let (year, month, day) = (number(0..4), number(4..6), number(6..8));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since the epoch in the proleptic Gregorian calendar
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let secs = days * 86400 + number(8..10) * 3600 + number(10..12) * 60 + number(12..14);
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Encodes a time as a `UTCTime` until 2049, and as a `GeneralizedTime` after, as in RFC 5280
+pub fn der_time(time: SystemTime) -> Vec<u8> {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // Date in the proleptic Gregorian calendar
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let time_of_day = format!(
+        "{:02}{:02}{:02}{:02}{:02}Z",
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    );
+    if year < 2050 {
+        let time = format!("{:02}{}", year % 100, time_of_day);
+        der_encode(DER_UTC_TIME, time.as_bytes())
+    } else {
+        let time = format!("{:04}{}", year, time_of_day);
+        der_encode(DER_GENERALIZED_TIME, time.as_bytes())
+    }
+}
@@ -1,16 +1,47 @@
 use anyhow::{ensure, Error};
 use byteorder::{BigEndian, ByteOrder};
+use std::net::IpAddr;
 
 const DNS_HEADER_SIZE: usize = 12;
 const DNS_MAX_HOSTNAME_SIZE: usize = 256;
 const DNS_MAX_PACKET_SIZE: usize = 4096;
+/// Responses received over TCP can be larger than the packets that are built
+const DNS_MAX_TCP_PACKET_SIZE: usize = 65535;
+const DNS_MAX_UDP_PAYLOAD_SIZE_NO_EDNS: usize = 512;
 const DNS_OFFSET_QUESTION: usize = DNS_HEADER_SIZE;
 const DNS_TYPE_OPT: u16 = 41;
 
 const DNS_PTYPE_PADDING: u16 = 12;
 
-const DNS_RCODE_SERVFAIL: u8 = 2;
-const DNS_RCODE_REFUSED: u8 = 5;
+pub const DNS_OPTION_ECS: u16 = 8;
+pub const DNS_OPTION_COOKIE: u16 = 10;
+
+pub const DNS_CLASS_INET: u16 = 1;
+pub const DNS_TYPE_A: u16 = 1;
+pub const DNS_TYPE_NS: u16 = 2;
+pub const DNS_TYPE_CNAME: u16 = 5;
+pub const DNS_TYPE_TXT: u16 = 16;
+pub const DNS_TYPE_AAAA: u16 = 28;
+pub const DNS_TYPE_RRSIG: u16 = 46;
+pub const DNS_TYPE_SVCB: u16 = 64;
+pub const DNS_TYPE_HTTPS: u16 = 65;
+
+pub const DNS_RCODE_NOERROR: u8 = 0;
+pub const DNS_RCODE_SERVFAIL: u8 = 2;
+pub const DNS_RCODE_NXDOMAIN: u8 = 3;
+pub const DNS_RCODE_NOTIMP: u8 = 4;
+pub const DNS_RCODE_REFUSED: u8 = 5;
+pub const DNS_RCODE_BADCOOKIE: u16 = 23;
+
+const DNS_FLAGS_QR: u16 = 0x8000;
+const DNS_FLAGS_OPCODE_MASK: u16 = 0x7800;
+const DNS_FLAGS_TC: u16 = 0x0200;
+const DNS_FLAGS_RD: u16 = 0x0100;
+const DNS_FLAGS_RA: u16 = 0x0080;
+const DNS_FLAGS_AD: u16 = 0x0020;
+const DNS_FLAGS_CD: u16 = 0x0010;
+
+const DNS_EDNS_FLAGS_DO: u16 = 0x8000;
 
 #[inline]
 pub fn rcode(packet: &[u8]) -> u8 {
@@ -51,6 +82,158 @@ pub fn is_recoverable_error(packet: &[u8]) -> bool {
     rcode == DNS_RCODE_SERVFAIL || rcode == DNS_RCODE_REFUSED
 }
 
+#[inline]
+pub fn is_truncated(packet: &[u8]) -> bool {
+    BigEndian::read_u16(&packet[2..]) & DNS_FLAGS_TC != 0
+}
+
+/// Returns a copy of a response with only its header and question, and the TC bit set,
+/// so that the client retries over TCP
+pub fn truncate(response: &[u8]) -> Result<Vec<u8>, Error> {
+    let question_end = qname_end(response)? + 4;
+    ensure!(question_end <= response.len(), "Short packet");
+    let mut packet = response[..question_end].to_vec();
+    let flags = BigEndian::read_u16(&packet[2..]) | DNS_FLAGS_TC;
+    BigEndian::write_u16(&mut packet[2..], flags);
+    BigEndian::write_u16(&mut packet[6..], 0);
+    BigEndian::write_u16(&mut packet[8..], 0);
+    BigEndian::write_u16(&mut packet[10..], 0);
+    Ok(packet)
+}
+
+/// Returns the size of the largest response that the client can receive over UDP,
+/// as advertised in the OPT record of its query (RFC 6891)
+pub fn max_udp_response_len(query: &[u8]) -> usize {
+    match edns_offset(query) {
+        Ok(Some(edns_offset)) => (BigEndian::read_u16(&query[edns_offset + 2..]) as usize)
+            .clamp(DNS_MAX_UDP_PAYLOAD_SIZE_NO_EDNS, DNS_MAX_PACKET_SIZE),
+        _ => DNS_MAX_UDP_PAYLOAD_SIZE_NO_EDNS,
+    }
+}
+
+/// Asks the server to report whether the response was validated, by setting the AD bit (RFC 6840)
+#[inline]
+pub fn set_authentic_data(packet: &mut [u8]) {
+    let flags = BigEndian::read_u16(&packet[2..]) | DNS_FLAGS_AD;
+    BigEndian::write_u16(&mut packet[2..], flags);
+}
+
+/// Returns `true` if a positive or negative response doesn't have the AD bit set, while
+/// validation was not disabled by the query.
+/// Signatures are not verified: the AD bit can only be relied upon if the server is trusted and
+/// reached over a secure channel.
+pub fn is_unauthenticated(response: &[u8], query: &[u8]) -> Result<bool, Error> {
+    ensure!(
+        response.len() >= DNS_HEADER_SIZE && query.len() >= DNS_HEADER_SIZE,
+        "Short packet"
+    );
+    if is_checking_disabled(query) || BigEndian::read_u16(&response[2..]) & DNS_FLAGS_AD != 0 {
+        return Ok(false);
+    }
+    Ok(matches!(
+        rcode(response),
+        DNS_RCODE_NOERROR | DNS_RCODE_NXDOMAIN
+    ))
+}
+
+/// Returns `true` if the query disabled DNSSEC validation with the CD bit
+#[inline]
+pub fn is_checking_disabled(query: &[u8]) -> bool {
+    BigEndian::read_u16(&query[2..]) & DNS_FLAGS_CD != 0
+}
+
+/// Returns the TTL and data of the records of type `rr_type` in the answer section
+pub fn answer_records(packet: &[u8], rr_type: u16) -> Result<Vec<(u32, &[u8])>, Error> {
+    let offset = qname_end(packet)? + 4;
+    ensure!(offset <= packet.len(), "Short packet");
+    let mut records = vec![];
+    traverse_rrs(packet, offset, ancount(packet) as usize, |offset| {
+        if BigEndian::read_u16(&packet[offset..]) == rr_type {
+            let ttl = BigEndian::read_u32(&packet[offset + 4..]);
+            let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+            let rdata_offset = offset + 10;
+            ensure!(
+                packet.len() - rdata_offset >= rdlen,
+                "Record length would exceed packet length"
+            );
+            records.push((ttl, &packet[rdata_offset..rdata_offset + rdlen]));
+        }
+        Ok(())
+    })?;
+    Ok(records)
+}
+
+/// Changes the type of the question
+pub fn set_qtype(packet: &mut [u8], qtype: u16) -> Result<(), Error> {
+    let offset = qname_end(packet)?;
+    ensure!(packet.len() - offset >= 4, "Short packet");
+    BigEndian::write_u16(&mut packet[offset..], qtype);
+    Ok(())
+}
+
+/// Returns a copy of a query for another name, given in wire format
+pub fn with_qname(packet: &[u8], name: &[u8]) -> Result<Vec<u8>, Error> {
+    let offset = qname_end(packet)?;
+    ensure!(packet.len() - offset >= 4, "Short packet");
+    ensure!(name.len() <= DNS_MAX_HOSTNAME_SIZE, "Name too long");
+    let mut query = Vec::with_capacity(packet.len() - offset + DNS_OFFSET_QUESTION + name.len());
+    query.extend_from_slice(&packet[..DNS_OFFSET_QUESTION]);
+    query.extend_from_slice(name);
+    query.extend_from_slice(&packet[offset..]);
+    Ok(query)
+}
+
+/// Returns `true` for errors and nonexistent names, that may be cached in a negative cache.
+#[inline]
+pub fn is_negative_response(packet: &[u8]) -> bool {
+    is_recoverable_error(packet) || rcode(packet) == DNS_RCODE_NXDOMAIN
+}
+
+/// Removes the records of type `rr_type` from the answer and additional sections.
+/// Names of later records may be compressed with pointers to the removed ones, so records are
+/// only removed if they are only followed by OPT records, whose names are never compressed.
+/// Returns `false`, leaving the packet unchanged, if they can't be removed that way.
+pub fn remove_records(packet: &mut Vec<u8>, rr_type: u16) -> Result<bool, Error> {
+    let mut offset = qname_end(packet)? + 4;
+    ensure!(offset <= packet.len(), "Short packet");
+    let counts = [ancount(packet), nscount(packet), arcount(packet)];
+    let mut kept = Vec::with_capacity(packet.len());
+    kept.extend_from_slice(&packet[..offset]);
+    let mut kept_counts = [0u16; 3];
+    let mut removed = false;
+    for (section, &count) in counts.iter().enumerate() {
+        for _ in 0..count {
+            let rr_offset = offset;
+            offset = skip_name(packet, offset)?;
+            ensure!(packet.len() - offset >= 10, "Short packet");
+            let record_type = BigEndian::read_u16(&packet[offset..]);
+            let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+            offset += 10;
+            ensure!(
+                packet.len() - offset >= rdlen,
+                "Record length would exceed packet length"
+            );
+            offset += rdlen;
+            if record_type == rr_type && section != 1 {
+                removed = true;
+                continue;
+            }
+            if removed && record_type != DNS_TYPE_OPT {
+                return Ok(false);
+            }
+            kept.extend_from_slice(&packet[rr_offset..offset]);
+            kept_counts[section] += 1;
+        }
+    }
+    if removed {
+        BigEndian::write_u16(&mut kept[6..], kept_counts[0]);
+        BigEndian::write_u16(&mut kept[8..], kept_counts[1]);
+        BigEndian::write_u16(&mut kept[10..], kept_counts[2]);
+        *packet = kept;
+    }
+    Ok(true)
+}
+
 fn skip_name(packet: &[u8], offset: usize) -> Result<usize, Error> {
     let packet_len = packet.len();
     ensure!(offset < packet_len - 1, "Short packet");
@@ -80,6 +263,299 @@ fn skip_name(packet: &[u8], offset: usize) -> Result<usize, Error> {
     Ok(offset)
 }
 
+fn qname_end(packet: &[u8]) -> Result<usize, Error> {
+    let packet_len = packet.len();
+    ensure!(packet_len > DNS_OFFSET_QUESTION, "Short packet");
+    ensure!(qdcount(packet) == 1, "No question");
+    let mut offset = DNS_OFFSET_QUESTION;
+    loop {
+        ensure!(offset < packet_len, "Short packet");
+        let label_len = packet[offset] as usize;
+        ensure!(label_len < 0x40, "Compressed or extended label in question");
+        ensure!(
+            packet_len - offset > label_len,
+            "Malformed packet with an out-of-bounds name"
+        );
+        offset += label_len + 1;
+        ensure!(
+            offset - DNS_OFFSET_QUESTION <= DNS_MAX_HOSTNAME_SIZE,
+            "Name too long"
+        );
+        if label_len == 0 {
+            break;
+        }
+    }
+    Ok(offset)
+}
+
+/// Returns the name of the question, lowercased and in presentation format,
+/// without the trailing dot (`.` for the root).
+pub fn qname(packet: &[u8]) -> Result<String, Error> {
+    let end = qname_end(packet)?;
+    let mut name = String::with_capacity(end - DNS_OFFSET_QUESTION);
+    let mut offset = DNS_OFFSET_QUESTION;
+    while offset < end - 1 {
+        let label_len = packet[offset] as usize;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        for &c in &packet[offset + 1..offset + 1 + label_len] {
+            let c = c.to_ascii_lowercase();
+            match c {
+                b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => name.push(c as char),
+                _ => name.push_str(&format!("\\{:03}", c)),
+            }
+        }
+        offset += label_len + 1;
+    }
+    if name.is_empty() {
+        name.push('.');
+    }
+    Ok(name)
+}
+
+/// Returns the question section, as a `(name, type and class)` tuple.
+pub fn question(packet: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let offset = qname_end(packet)?;
+    ensure!(packet.len() - offset >= 4, "Short packet");
+    Ok((
+        &packet[DNS_OFFSET_QUESTION..offset],
+        &packet[offset..offset + 4],
+    ))
+}
+
+/// Checks that `response` is a response to `query`: same transaction ID,
+/// QR bit set, and same question, the name being compared case-insensitively.
+pub fn is_response_to(response: &[u8], query: &[u8]) -> bool {
+    if response.len() < DNS_HEADER_SIZE || query.len() < DNS_HEADER_SIZE {
+        return false;
+    }
+    if response[0..2] != query[0..2] || BigEndian::read_u16(&response[2..]) & DNS_FLAGS_QR == 0 {
+        return false;
+    }
+    match (question(response), question(query)) {
+        (Ok((response_name, response_type_class)), Ok((query_name, query_type_class))) => {
+            response_name.eq_ignore_ascii_case(query_name)
+                && response_type_class == query_type_class
+        }
+        _ => false,
+    }
+}
+
+/// Checks that the question names of `response` and `query` use the same case.
+pub fn has_same_qname_case(response: &[u8], query: &[u8]) -> bool {
+    match (question(response), question(query)) {
+        (Ok((response_name, _)), Ok((query_name, _))) => response_name == query_name,
+        _ => false,
+    }
+}
+
+/// Checks that a response to `query` is well-formed: a single question, the same opcode as the
+/// query, and exactly the number of records announced in the header.
+/// Truncated responses are only checked up to the question.
+pub fn is_well_formed_response(response: &[u8], query: &[u8]) -> bool {
+    let check = || -> Result<bool, Error> {
+        ensure!(query.len() >= DNS_HEADER_SIZE, "Short packet");
+        let opcode = |packet: &[u8]| BigEndian::read_u16(&packet[2..]) & DNS_FLAGS_OPCODE_MASK;
+        let offset = qname_end(response)? + 4;
+        ensure!(response.len() >= offset, "Short packet");
+        if opcode(response) != opcode(query) {
+            return Ok(false);
+        }
+        if is_truncated(response) {
+            return Ok(true);
+        }
+        let rrcount =
+            ancount(response) as usize + nscount(response) as usize + arcount(response) as usize;
+        let end = traverse_rrs(response, offset, rrcount, |_| Ok(()))?;
+        Ok(end == response.len())
+    };
+    check().unwrap_or(false)
+}
+
+/// How strictly responses from upstream servers are checked before being accepted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResponseChecks {
+    /// The question name must use the same case as in the query
+    pub qname_case: bool,
+    /// The response must be well-formed, as checked by `is_well_formed_response()`
+    pub strict: bool,
+}
+
+impl ResponseChecks {
+    /// Checks that `response` is an acceptable response to `query`
+    pub fn accept(&self, response: &[u8], query: &[u8]) -> bool {
+        is_response_to(response, query)
+            && (!self.qname_case || has_same_qname_case(response, query))
+            && (!self.strict || is_well_formed_response(response, query))
+    }
+}
+
+/// Randomly changes the case of the letters of the question name (draft-vixie-dnsext-dns0x20).
+/// Servers copy the question as it was received, so that responses using a different case
+/// can be rejected as spoofed.
+pub fn randomize_qname_case(packet: &mut [u8]) -> Result<(), Error> {
+    let name_end = qname_end(packet)?;
+    // Label lengths are at most 63, so they are never letters
+    for c in &mut packet[DNS_OFFSET_QUESTION..name_end] {
+        if c.is_ascii_alphabetic() && rand::random() {
+            *c ^= 0x20;
+        }
+    }
+    Ok(())
+}
+
+/// Returns a key identifying the queries that can share the same response:
+/// the question with the name in lowercase, the CD and DO bits, and the EDNS Client Subnet option.
+pub fn cache_key(query: &[u8]) -> Result<Vec<u8>, Error> {
+    let (name, type_class) = question(query)?;
+    let mut key = Vec::with_capacity(name.len() + 5);
+    key.extend(name.iter().map(u8::to_ascii_lowercase));
+    key.extend_from_slice(type_class);
+    let checking_disabled = BigEndian::read_u16(&query[2..]) & DNS_FLAGS_CD != 0;
+    let mut dnssec_ok = false;
+    if let Some(edns_offset) = edns_offset(query)? {
+        dnssec_ok = BigEndian::read_u16(&query[edns_offset + 6..]) & DNS_EDNS_FLAGS_DO != 0;
+        for_each_edns_option(query, edns_offset, |code, data| {
+            if code == DNS_OPTION_ECS {
+                key.extend_from_slice(data);
+            }
+        })?;
+    }
+    key.push(checking_disabled as u8 | (dnssec_ok as u8) << 1);
+    Ok(key)
+}
+
+/// Makes a response with the same question as `query` usable as a response to it,
+/// by copying the transaction ID and the name, that may use a different case, from the query.
+pub fn adapt_response(response: &mut [u8], query: &[u8]) -> Result<(), Error> {
+    let (query_name, _) = question(query)?;
+    let (response_name, _) = question(response)?;
+    ensure!(
+        response_name.eq_ignore_ascii_case(query_name),
+        "Different question"
+    );
+    let name_end = DNS_OFFSET_QUESTION + query_name.len();
+    response[..2].copy_from_slice(&query[..2]);
+    response[DNS_OFFSET_QUESTION..name_end].copy_from_slice(query_name);
+    Ok(())
+}
+
+/// Returns the type and class of the question.
+pub fn qtype_qclass(packet: &[u8]) -> Result<(u16, u16), Error> {
+    let offset = qname_end(packet)?;
+    ensure!(packet.len() - offset >= 4, "Short packet");
+    Ok((
+        BigEndian::read_u16(&packet[offset..]),
+        BigEndian::read_u16(&packet[offset + 2..]),
+    ))
+}
+
+const QTYPE_NAMES: [(u16, &str); 24] = [
+    (1, "A"),
+    (2, "NS"),
+    (5, "CNAME"),
+    (6, "SOA"),
+    (12, "PTR"),
+    (13, "HINFO"),
+    (15, "MX"),
+    (16, "TXT"),
+    (28, "AAAA"),
+    (29, "LOC"),
+    (33, "SRV"),
+    (35, "NAPTR"),
+    (39, "DNAME"),
+    (41, "OPT"),
+    (43, "DS"),
+    (46, "RRSIG"),
+    (47, "NSEC"),
+    (48, "DNSKEY"),
+    (50, "NSEC3"),
+    (52, "TLSA"),
+    (64, "SVCB"),
+    (65, "HTTPS"),
+    (255, "ANY"),
+    (257, "CAA"),
+];
+
+/// Parses a record type, given as a mnemonic, in the RFC 3597 generic notation, or as a number.
+pub fn parse_qtype(name: &str) -> Option<u16> {
+    let name = name.trim();
+    if let Some(&(qtype, _)) = QTYPE_NAMES
+        .iter()
+        .find(|&&(_, qtype_name)| qtype_name.eq_ignore_ascii_case(name))
+    {
+        return Some(qtype);
+    }
+    let number = match name.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("TYPE") => &name[4..],
+        _ => name,
+    };
+    number.parse().ok()
+}
+
+/// Returns the mnemonic of a record type, or the RFC 3597 generic notation.
+pub fn qtype_name(qtype: u16) -> String {
+    QTYPE_NAMES
+        .iter()
+        .find(|&&(t, _)| t == qtype)
+        .map(|&(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("TYPE{}", qtype))
+}
+
+const EDNS_OPTION_NAMES: [(u16, &str); 13] = [
+    (3, "NSID"),
+    (5, "DAU"),
+    (6, "DHU"),
+    (7, "N3U"),
+    (8, "ECS"),
+    (9, "EXPIRE"),
+    (10, "COOKIE"),
+    (11, "KEEPALIVE"),
+    (12, "PADDING"),
+    (13, "CHAIN"),
+    (14, "KEY-TAG"),
+    (15, "EDE"),
+    (18, "REPORT-CHANNEL"),
+];
+
+/// Parses an EDNS option code, given as a mnemonic or as a number.
+pub fn parse_edns_option(name: &str) -> Option<u16> {
+    let name = name.trim();
+    EDNS_OPTION_NAMES
+        .iter()
+        .find(|&&(_, option_name)| option_name.eq_ignore_ascii_case(name))
+        .map(|&(code, _)| code)
+        .or_else(|| name.parse().ok())
+}
+
+/// Returns the mnemonic of an EDNS option code, or its number.
+pub fn edns_option_name(code: u16) -> String {
+    EDNS_OPTION_NAMES
+        .iter()
+        .find(|&&(c, _)| c == code)
+        .map(|&(_, name)| name.to_string())
+        .unwrap_or_else(|| code.to_string())
+}
+
+/// Returns `true` if the EDNS option code has been assigned a mnemonic.
+pub fn is_known_edns_option(code: u16) -> bool {
+    EDNS_OPTION_NAMES.iter().any(|&(c, _)| c == code)
+}
+
+/// Returns the mnemonic of a response code, or its number
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        DNS_RCODE_NOERROR => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        DNS_RCODE_SERVFAIL => "SERVFAIL".to_string(),
+        DNS_RCODE_NXDOMAIN => "NXDOMAIN".to_string(),
+        DNS_RCODE_NOTIMP => "NOTIMP".to_string(),
+        DNS_RCODE_REFUSED => "REFUSED".to_string(),
+        _ => rcode.to_string(),
+    }
+}
+
 fn traverse_rrs<F: FnMut(usize) -> Result<(), Error>>(
     packet: &[u8],
     mut offset: usize,
@@ -127,24 +603,31 @@ fn traverse_rrs_mut<F: FnMut(&mut [u8], usize) -> Result<(), Error>>(
 pub fn min_ttl(packet: &[u8], min_ttl: u32, max_ttl: u32, failure_ttl: u32) -> Result<u32, Error> {
     let packet_len = packet.len();
     ensure!(packet_len > DNS_OFFSET_QUESTION, "Short packet");
-    ensure!(packet_len <= DNS_MAX_PACKET_SIZE, "Large packet");
+    ensure!(packet_len <= DNS_MAX_TCP_PACKET_SIZE, "Large packet");
     ensure!(qdcount(packet) == 1, "No question");
     let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?;
     assert!(offset > DNS_OFFSET_QUESTION);
-    ensure!(packet_len - offset > 4, "Short packet");
+    ensure!(packet_len - offset >= 4, "Short packet");
     offset += 4;
     let (ancount, nscount, arcount) = (ancount(packet), nscount(packet), arcount(packet));
     let rrcount = ancount as usize + nscount as usize + arcount as usize;
-    let mut found_min_ttl = if rrcount > 0 { max_ttl } else { failure_ttl };
+    let mut found_min_ttl = max_ttl;
+    let mut found_records = false;
 
     offset = traverse_rrs(packet, offset, rrcount, |offset| {
         let qtype = BigEndian::read_u16(&packet[offset..]);
         let ttl = BigEndian::read_u32(&packet[offset + 4..]);
-        if qtype != DNS_TYPE_OPT && ttl < found_min_ttl {
-            found_min_ttl = ttl;
+        if qtype != DNS_TYPE_OPT {
+            found_records = true;
+            if ttl < found_min_ttl {
+                found_min_ttl = ttl;
+            }
         }
         Ok(())
     })?;
+    if !found_records {
+        found_min_ttl = failure_ttl;
+    }
     if found_min_ttl < min_ttl {
         found_min_ttl = min_ttl;
     }
@@ -152,6 +635,28 @@ pub fn min_ttl(packet: &[u8], min_ttl: u32, max_ttl: u32, failure_ttl: u32) -> R
     Ok(found_min_ttl)
 }
 
+/// Replaces the TTL of every record, except the OPT record, with the value returned by `update`
+pub fn update_ttls<F: FnMut(u32) -> u32>(packet: &mut [u8], mut update: F) -> Result<(), Error> {
+    let packet_len = packet.len();
+    ensure!(packet_len > DNS_OFFSET_QUESTION, "Short packet");
+    ensure!(packet_len <= DNS_MAX_TCP_PACKET_SIZE, "Large packet");
+    ensure!(qdcount(packet) == 1, "No question");
+    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?;
+    ensure!(packet_len - offset >= 4, "Short packet");
+    offset += 4;
+    let (ancount, nscount, arcount) = (ancount(packet), nscount(packet), arcount(packet));
+    let rrcount = ancount as usize + nscount as usize + arcount as usize;
+    traverse_rrs_mut(packet, offset, rrcount, |packet, offset| {
+        let qtype = BigEndian::read_u16(&packet[offset..]);
+        if qtype != DNS_TYPE_OPT {
+            let ttl = BigEndian::read_u32(&packet[offset + 4..]);
+            BigEndian::write_u32(&mut packet[offset + 4..], update(ttl));
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
 fn add_edns_section(packet: &mut Vec<u8>, max_payload_size: u16) -> Result<(), Error> {
     let opt_rr: [u8; 11] = [
         0,
@@ -167,7 +672,7 @@ fn add_edns_section(packet: &mut Vec<u8>, max_payload_size: u16) -> Result<(), E
         0,
     ];
     ensure!(
-        DNS_MAX_PACKET_SIZE - packet.len() >= opt_rr.len(),
+        packet.len() + opt_rr.len() <= DNS_MAX_PACKET_SIZE,
         "Packet would be too large to add a new record"
     );
     arcount_inc(packet)?;
@@ -208,21 +713,44 @@ pub fn set_edns_max_payload_size(packet: &mut Vec<u8>, max_payload_size: u16) ->
     Ok(())
 }
 
-fn padded_len(unpadded_len: usize) -> usize {
-    const BOUNDARIES: [usize; 16] = [
-        64, 128, 192, 256, 320, 384, 512, 704, 768, 896, 960, 1024, 1088, 1152, 2688, 4080,
-    ];
-    BOUNDARIES
-        .iter()
-        .find(|&&boundary| boundary >= unpadded_len)
-        .copied()
-        .unwrap_or(DNS_MAX_PACKET_SIZE)
+/// Asks the server to include DNSSEC records in the response with the DO bit (RFC 3225),
+/// adding an OPT record if required
+pub fn set_dnssec_ok(packet: &mut Vec<u8>) -> Result<(), Error> {
+    let edns_offset = match edns_offset(packet)? {
+        Some(edns_offset) => edns_offset,
+        None => {
+            add_edns_section(packet, DNS_MAX_PACKET_SIZE as _)?;
+            packet.len() - 10
+        }
+    };
+    let flags = BigEndian::read_u16(&packet[edns_offset + 6..]) | DNS_EDNS_FLAGS_DO;
+    BigEndian::write_u16(&mut packet[edns_offset + 6..], flags);
+    Ok(())
 }
 
-pub fn add_edns_padding(packet: &mut Vec<u8>) -> Result<(), Error> {
-    let mut packet_len = packet.len();
+/// Pads a packet to a multiple of `block_size` bytes with an EDNS padding option
+/// (RFC 7830, RFC 8467), replacing any existing padding, and adding an OPT record if required.
+pub fn add_edns_padding(packet: &mut Vec<u8>, block_size: usize) -> Result<(), Error> {
+    ensure!(block_size > 0, "Invalid block size");
+    if edns_offset(packet)?.is_none() {
+        add_edns_section(packet, DNS_MAX_PACKET_SIZE as _)?;
+    }
+    remove_edns_option(packet, DNS_PTYPE_PADDING)?;
+    let unpadded_len = packet.len() + 4;
+    let padding_len = (block_size - unpadded_len % block_size) % block_size;
+    ensure!(
+        unpadded_len + padding_len <= DNS_MAX_PACKET_SIZE,
+        "Packet would be too large to be padded"
+    );
+    let padding = vec![0u8; padding_len];
+    rewrite_edns_options(packet, |_, _| true, Some((DNS_PTYPE_PADDING, &padding)))?;
+    Ok(())
+}
+
+fn edns_offset(packet: &[u8]) -> Result<Option<usize>, Error> {
+    let packet_len = packet.len();
     ensure!(packet_len > DNS_OFFSET_QUESTION, "Short packet");
-    ensure!(packet_len <= DNS_MAX_PACKET_SIZE, "Large packet");
+    ensure!(packet_len <= DNS_MAX_TCP_PACKET_SIZE, "Large packet");
     ensure!(qdcount(packet) == 1, "No question");
     let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?;
     assert!(offset > DNS_OFFSET_QUESTION);
@@ -236,7 +764,7 @@ pub fn add_edns_padding(packet: &mut Vec<u8>) -> Result<(), Error> {
         |_offset| Ok(()),
     )?;
     let mut edns_offset = None;
-    traverse_rrs_mut(packet, offset, arcount as _, |packet, offset| {
+    traverse_rrs(packet, offset, arcount as _, |offset| {
         let qtype = BigEndian::read_u16(&packet[offset..]);
         if qtype == DNS_TYPE_OPT {
             ensure!(edns_offset.is_none(), "Duplicate OPT RR found");
@@ -244,39 +772,215 @@ pub fn add_edns_padding(packet: &mut Vec<u8>) -> Result<(), Error> {
         }
         Ok(())
     })?;
-    let edns_offset = match edns_offset {
+    Ok(edns_offset)
+}
+
+/// Calls `cb` with the code and data of every option of the OPT record at `edns_offset`.
+fn for_each_edns_option<F: FnMut(u16, &[u8])>(
+    packet: &[u8],
+    edns_offset: usize,
+    mut cb: F,
+) -> Result<(), Error> {
+    let edns_rdlen = BigEndian::read_u16(&packet[edns_offset + 8..]) as usize;
+    let rdata_offset = edns_offset + 10;
+    let rdata = &packet[rdata_offset..rdata_offset + edns_rdlen];
+    let mut offset = 0;
+    while offset < edns_rdlen {
+        ensure!(edns_rdlen - offset >= 4, "Truncated EDNS option");
+        let code = BigEndian::read_u16(&rdata[offset..]);
+        let len = BigEndian::read_u16(&rdata[offset + 2..]) as usize;
+        ensure!(edns_rdlen - offset - 4 >= len, "Truncated EDNS option");
+        cb(code, &rdata[offset + 4..offset + 4 + len]);
+        offset += 4 + len;
+    }
+    Ok(())
+}
+
+/// Rebuilds the options of the OPT record, keeping only the ones for which `keep` returns `true`,
+/// and optionally appending a new option. Returns `false` if the packet has no OPT record.
+fn rewrite_edns_options<F: FnMut(u16, &[u8]) -> bool>(
+    packet: &mut Vec<u8>,
+    mut keep: F,
+    append: Option<(u16, &[u8])>,
+) -> Result<bool, Error> {
+    let edns_offset = match edns_offset(packet)? {
         Some(edns_offset) => edns_offset,
-        None => {
-            let edns_offset = packet.len() + 1;
-            add_edns_section(packet, DNS_MAX_PACKET_SIZE as _)?;
-            packet_len = packet.len();
-            edns_offset
-        }
+        None => return Ok(false),
     };
-    let padding_len = padded_len(packet_len) - packet_len;
-    let mut edns_padding_prr = vec![b'X'; 4 + padding_len];
-    BigEndian::write_u16(&mut edns_padding_prr[0..], DNS_PTYPE_PADDING);
-    BigEndian::write_u16(&mut edns_padding_prr[2..], padding_len as u16);
-    let edns_padding_prr_len = edns_padding_prr.len();
-    let edns_rdlen_offset: usize = edns_offset + 8;
-    ensure!(packet_len - edns_rdlen_offset >= 2, "Short packet");
-    let edns_rdlen = BigEndian::read_u16(&packet[edns_rdlen_offset..]);
-    ensure!(
-        edns_offset + edns_rdlen as usize <= packet_len,
-        "Out of range EDNS size"
-    );
-    ensure!(
-        0xffff - edns_rdlen as usize >= edns_padding_prr_len,
-        "EDNS section too large for padding"
-    );
+    let edns_rdlen_offset = edns_offset + 8;
+    let edns_rdlen = BigEndian::read_u16(&packet[edns_rdlen_offset..]) as usize;
+    let rdata_offset = edns_offset + 10;
+    let mut new_rdata = Vec::with_capacity(edns_rdlen);
+    for_each_edns_option(packet, edns_offset, |code, data| {
+        if keep(code, data) {
+            let mut header = [0u8; 4];
+            BigEndian::write_u16(&mut header[0..], code);
+            BigEndian::write_u16(&mut header[2..], data.len() as u16);
+            new_rdata.extend_from_slice(&header);
+            new_rdata.extend_from_slice(data);
+        }
+    })?;
+    if let Some((code, data)) = append {
+        ensure!(data.len() <= 0xffff, "EDNS option too large");
+        let mut header = [0u8; 4];
+        BigEndian::write_u16(&mut header[0..], code);
+        BigEndian::write_u16(&mut header[2..], data.len() as u16);
+        new_rdata.extend_from_slice(&header);
+        new_rdata.extend_from_slice(data);
+    }
+    ensure!(new_rdata.len() <= 0xffff, "EDNS section too large");
     ensure!(
-        DNS_MAX_PACKET_SIZE - packet_len >= edns_padding_prr_len,
+        packet.len() - edns_rdlen + new_rdata.len() <= DNS_MAX_TCP_PACKET_SIZE,
         "Large packet"
     );
-    BigEndian::write_u16(
-        &mut packet[edns_rdlen_offset..],
-        edns_rdlen + edns_padding_prr_len as u16,
-    );
-    packet.extend(&edns_padding_prr);
+    BigEndian::write_u16(&mut packet[edns_rdlen_offset..], new_rdata.len() as u16);
+    packet.splice(rdata_offset..rdata_offset + edns_rdlen, new_rdata);
+    Ok(true)
+}
+
+/// Returns the data of the first EDNS option with the given code, if there is one.
+pub fn edns_option(packet: &[u8], option_code: u16) -> Result<Option<Vec<u8>>, Error> {
+    let edns_offset = match edns_offset(packet)? {
+        Some(edns_offset) => edns_offset,
+        None => return Ok(None),
+    };
+    let mut option = None;
+    for_each_edns_option(packet, edns_offset, |code, data| {
+        if code == option_code && option.is_none() {
+            option = Some(data.to_vec());
+        }
+    })?;
+    Ok(option)
+}
+
+/// Returns the response code, including the upper bits stored in the OPT record (RFC 6891)
+pub fn extended_rcode(packet: &[u8]) -> Result<u16, Error> {
+    let upper_bits = match edns_offset(packet)? {
+        Some(edns_offset) => packet[edns_offset + 4] as u16,
+        None => 0,
+    };
+    Ok(upper_bits << 4 | rcode(packet) as u16)
+}
+
+/// Removes every EDNS option with the given code.
+pub fn remove_edns_option(packet: &mut Vec<u8>, option_code: u16) -> Result<(), Error> {
+    rewrite_edns_options(packet, |code, _| code != option_code, None)?;
     Ok(())
 }
+
+/// Removes the EDNS options for which `keep` returns `false`.
+pub fn retain_edns_options<F: FnMut(u16) -> bool>(
+    packet: &mut Vec<u8>,
+    mut keep: F,
+) -> Result<(), Error> {
+    rewrite_edns_options(packet, |code, _| keep(code), None)?;
+    Ok(())
+}
+
+/// Sets an EDNS option, replacing any previous occurrence and adding an OPT record if required.
+pub fn set_edns_option(packet: &mut Vec<u8>, option_code: u16, data: &[u8]) -> Result<(), Error> {
+    let keep = |code, _: &[u8]| code != option_code;
+    if !rewrite_edns_options(packet, keep, Some((option_code, data)))? {
+        add_edns_section(packet, DNS_MAX_PACKET_SIZE as _)?;
+        rewrite_edns_options(packet, keep, Some((option_code, data)))?;
+    }
+    Ok(())
+}
+
+/// Builds the payload of an EDNS Client Subnet option (RFC 7871) for a source prefix.
+pub fn ecs_option_data(ip: IpAddr, source_prefix_len: u8) -> Vec<u8> {
+    let (family, address): (u16, Vec<u8>) = match ip {
+        IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+    };
+    let source_prefix_len = source_prefix_len.min(address.len() as u8 * 8);
+    let address_len = (source_prefix_len as usize).div_ceil(8);
+    let mut data = vec![0u8; 4 + address_len];
+    BigEndian::write_u16(&mut data[0..], family);
+    data[2] = source_prefix_len;
+    data[4..].copy_from_slice(&address[..address_len]);
+    if !source_prefix_len.is_multiple_of(8) {
+        data[4 + address_len - 1] &= 0xff << (8 - source_prefix_len % 8);
+    }
+    data
+}
+
+/// A record to be added to a synthesized response
+#[derive(Clone, Copy, Debug)]
+pub struct Answer<'t> {
+    /// Owner name, in wire format; the question name if `None`
+    pub name: Option<&'t [u8]>,
+    pub rr_type: u16,
+    pub ttl: u32,
+    pub rdata: &'t [u8],
+}
+
+/// Encodes a name in presentation format into the DNS wire format.
+pub fn encode_name(name: &str) -> Result<Vec<u8>, Error> {
+    let name = name.trim_end_matches('.');
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    if !name.is_empty() {
+        for label in name.split('.') {
+            ensure!(!label.is_empty(), "Empty label");
+            ensure!(label.len() < 0x40, "Long label");
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+    }
+    encoded.push(0);
+    ensure!(encoded.len() <= DNS_MAX_HOSTNAME_SIZE, "Name too long");
+    Ok(encoded)
+}
+
+/// Builds a recursive query for `name` and `qtype`, in the `IN` class.
+pub fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, Error> {
+    let name = encode_name(name)?;
+    let mut packet = vec![0; DNS_HEADER_SIZE];
+    BigEndian::write_u16(&mut packet[0..], id);
+    BigEndian::write_u16(&mut packet[2..], DNS_FLAGS_RD);
+    BigEndian::write_u16(&mut packet[4..], 1);
+    packet.extend_from_slice(&name);
+    let mut type_class = [0u8; 4];
+    BigEndian::write_u16(&mut type_class[0..], qtype);
+    BigEndian::write_u16(&mut type_class[2..], DNS_CLASS_INET);
+    packet.extend_from_slice(&type_class);
+    Ok(packet)
+}
+
+/// Builds a response to `query` with the given rcode and answer records.
+pub fn synthesize_response(
+    query: &[u8],
+    rcode: u8,
+    answers: &[Answer<'_>],
+) -> Result<Vec<u8>, Error> {
+    let question_end = qname_end(query)? + 4;
+    ensure!(question_end <= query.len(), "Short packet");
+    ensure!(answers.len() < 0xffff, "Too many records");
+    let mut packet = Vec::with_capacity(question_end + answers.len() * 32);
+    packet.extend_from_slice(&query[..question_end]);
+    let query_flags = BigEndian::read_u16(&query[2..]);
+    let flags = DNS_FLAGS_QR
+        | DNS_FLAGS_RA
+        | (query_flags & (DNS_FLAGS_OPCODE_MASK | DNS_FLAGS_RD | DNS_FLAGS_CD))
+        | (rcode as u16 & 0x0f);
+    BigEndian::write_u16(&mut packet[2..], flags);
+    BigEndian::write_u16(&mut packet[6..], answers.len() as u16);
+    BigEndian::write_u16(&mut packet[8..], 0);
+    BigEndian::write_u16(&mut packet[10..], 0);
+    for answer in answers {
+        ensure!(answer.rdata.len() <= 0xffff, "Record too large");
+        match answer.name {
+            None => packet.extend_from_slice(&[0xc0, DNS_OFFSET_QUESTION as u8]),
+            Some(name) => packet.extend_from_slice(name),
+        }
+        let mut rr = [0u8; 10];
+        BigEndian::write_u16(&mut rr[0..], answer.rr_type);
+        BigEndian::write_u16(&mut rr[2..], DNS_CLASS_INET);
+        BigEndian::write_u32(&mut rr[4..], answer.ttl);
+        BigEndian::write_u16(&mut rr[8..], answer.rdata.len() as u16);
+        packet.extend_from_slice(&rr);
+        packet.extend_from_slice(answer.rdata);
+    }
+    ensure!(packet.len() <= DNS_MAX_PACKET_SIZE, "Large packet");
+    Ok(packet)
+}
@@ -0,0 +1,64 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Prefix IPv4 addresses are embedded into to synthesize `AAAA` records (RFC 6052, RFC 6147)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dns64Prefix {
+    prefix: Ipv6Addr,
+    prefix_len: u8,
+}
+
+impl Dns64Prefix {
+    /// Returns the IPv6 address an IPv4 address is reachable at through the NAT64 gateway.
+    /// Bits 64 to 71 of the address are always zero.
+    pub fn embed(&self, ipv4: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+        let mut offset = self.prefix_len as usize / 8;
+        for &octet in &ipv4.octets() {
+            if offset == 8 {
+                offset += 1;
+            }
+            octets[offset] = octet;
+            offset += 1;
+        }
+        if self.prefix_len < 96 {
+            octets[8] = 0;
+            octets[offset..].iter_mut().for_each(|octet| *octet = 0);
+        }
+        Ipv6Addr::from(octets)
+    }
+}
+
+impl Default for Dns64Prefix {
+    /// The well-known prefix, `64:ff9b::/96`
+    fn default() -> Self {
+        Dns64Prefix {
+            prefix: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+            prefix_len: 96,
+        }
+    }
+}
+
+impl FromStr for Dns64Prefix {
+    type Err = String;
+
+    /// Parses a prefix such as `64:ff9b::/96`. The length must be 32, 40, 48, 56, 64 or 96,
+    /// and defaults to 96.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let prefix: Ipv6Addr = parts
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("Invalid DNS64 prefix: [{}]", s))?;
+        let prefix_len = match parts.next() {
+            None => 96,
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| [32, 40, 48, 56, 64, 96].contains(prefix_len))
+                .ok_or_else(|| format!("Unsupported DNS64 prefix length: [{}]", s))?,
+        };
+        Ok(Dns64Prefix { prefix, prefix_len })
+    }
+}
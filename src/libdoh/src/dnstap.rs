@@ -0,0 +1,253 @@
+use crate::constants::*;
+use crate::Protocol;
+
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frames
+const FSTRM_CONTROL_ACCEPT: u32 = 0x01;
+const FSTRM_CONTROL_START: u32 = 0x02;
+const FSTRM_CONTROL_READY: u32 = 0x04;
+const FSTRM_CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+const FSTRM_MAX_CONTROL_FRAME_LEN: usize = 512;
+
+// Values from dnstap.proto
+const DNSTAP_TYPE_MESSAGE: u64 = 1;
+const MESSAGE_TYPE_CLIENT_QUERY: u64 = 5;
+const MESSAGE_TYPE_CLIENT_RESPONSE: u64 = 6;
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_FAMILY_INET6: u64 = 2;
+const SOCKET_PROTOCOL_UDP: u64 = 1;
+const SOCKET_PROTOCOL_TCP: u64 = 2;
+const SOCKET_PROTOCOL_DOT: u64 = 3;
+const SOCKET_PROTOCOL_DOH: u64 = 4;
+
+/// Minimal protobuf encoder, for the few field types used by dnstap
+#[derive(Default)]
+struct Protobuf(Vec<u8>);
+
+impl Protobuf {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.varint((field as u64) << 3);
+        self.varint(value);
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        self.varint((field as u64) << 3 | 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.varint((field as u64) << 3 | 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+}
+
+/// Sends client queries and responses to a dnstap collector listening on a Unix socket.
+/// Messages are dropped if the collector is unreachable or cannot keep up.
+#[derive(Debug)]
+pub struct Dnstap {
+    path: PathBuf,
+    redact_client_ip: bool,
+    sender: mpsc::Sender<Vec<u8>>,
+    receiver: Mutex<Option<mpsc::Receiver<Vec<u8>>>>,
+}
+
+impl Dnstap {
+    /// The socket is only connected to once the server is started, and reconnected to
+    /// if the collector goes away. With `redact_client_ip`, client addresses are not sent.
+    pub fn new<P: AsRef<Path>>(path: P, redact_client_ip: bool) -> Self {
+        let (sender, receiver) = mpsc::channel(DNSTAP_QUEUE_SIZE);
+        Dnstap {
+            path: path.as_ref().to_path_buf(),
+            redact_client_ip,
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Logs a query received from a client
+    pub fn log_query(
+        &self,
+        protocol: Protocol,
+        client_ip: Option<IpAddr>,
+        query_time: SystemTime,
+        query: &[u8],
+    ) {
+        let frame = self.encode(protocol, client_ip, query_time, None, query);
+        let _ = self.sender.clone().try_send(frame);
+    }
+
+    /// Logs the response sent to a client, for a query received at `query_time`
+    pub fn log_response(
+        &self,
+        protocol: Protocol,
+        client_ip: Option<IpAddr>,
+        query_time: SystemTime,
+        response: &[u8],
+    ) {
+        let response_time = Some(SystemTime::now());
+        let frame = self.encode(protocol, client_ip, query_time, response_time, response);
+        let _ = self.sender.clone().try_send(frame);
+    }
+
+    /// Returns a `CLIENT_QUERY` message, or a `CLIENT_RESPONSE` message if `response_time` is set
+    fn encode(
+        &self,
+        protocol: Protocol,
+        client_ip: Option<IpAddr>,
+        query_time: SystemTime,
+        response_time: Option<SystemTime>,
+        packet: &[u8],
+    ) -> Vec<u8> {
+        let mut msg = Protobuf::default();
+        let message_type = match response_time {
+            None => MESSAGE_TYPE_CLIENT_QUERY,
+            Some(_) => MESSAGE_TYPE_CLIENT_RESPONSE,
+        };
+        msg.uint(1, message_type);
+        if let Some(client_ip) = client_ip {
+            let (family, address) = match client_ip {
+                IpAddr::V4(ip) => (SOCKET_FAMILY_INET, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (SOCKET_FAMILY_INET6, ip.octets().to_vec()),
+            };
+            msg.uint(2, family);
+            if !self.redact_client_ip {
+                msg.bytes(4, &address);
+            }
+        }
+        let socket_protocol = match protocol {
+            Protocol::Doh => SOCKET_PROTOCOL_DOH,
+            Protocol::Dot => SOCKET_PROTOCOL_DOT,
+            Protocol::Udp => SOCKET_PROTOCOL_UDP,
+            Protocol::Tcp => SOCKET_PROTOCOL_TCP,
+        };
+        msg.uint(3, socket_protocol);
+        let (query_time_sec, query_time_nsec) = unix_time(query_time);
+        msg.uint(8, query_time_sec);
+        msg.fixed32(9, query_time_nsec);
+        match response_time {
+            None => msg.bytes(10, packet),
+            Some(response_time) => {
+                let (response_time_sec, response_time_nsec) = unix_time(response_time);
+                msg.uint(12, response_time_sec);
+                msg.fixed32(13, response_time_nsec);
+                msg.bytes(14, packet);
+            }
+        }
+
+        let mut dnstap = Protobuf::default();
+        dnstap.bytes(2, concat!("libdoh ", env!("CARGO_PKG_VERSION")).as_bytes());
+        dnstap.bytes(14, &msg.0);
+        dnstap.uint(15, DNSTAP_TYPE_MESSAGE);
+        dnstap.0
+    }
+
+    /// Writes the messages to the socket, until the server stops
+    pub async fn run(&self) {
+        let mut receiver = match self.receiver.lock().unwrap().take() {
+            None => return,
+            Some(receiver) => receiver,
+        };
+        let reconnect_interval = Duration::from_secs(DNSTAP_RECONNECT_INTERVAL_SECS);
+        loop {
+            let mut stream = match Self::connect(&self.path).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!(
+                        path = %self.path.display(),
+                        error = %e,
+                        "Unable to connect to the dnstap socket"
+                    );
+                    tokio::time::delay_for(reconnect_interval).await;
+                    continue;
+                }
+            };
+            while let Some(frame) = receiver.recv().await {
+                let mut frames = vec![];
+                push_data_frame(&mut frames, &frame);
+                while let Ok(frame) = receiver.try_recv() {
+                    push_data_frame(&mut frames, &frame);
+                }
+                if let Err(e) = stream.write_all(&frames).await {
+                    tracing::warn!(
+                        path = %self.path.display(),
+                        error = %e,
+                        "Unable to write to the dnstap socket"
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Connects to the socket, and performs the bidirectional Frame Streams handshake
+    async fn connect(path: &Path) -> io::Result<UnixStream> {
+        let mut stream = UnixStream::connect(path).await?;
+        stream
+            .write_all(&control_frame(FSTRM_CONTROL_READY))
+            .await?;
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await?;
+        let escape = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if escape != 0 || !(4..=FSTRM_MAX_CONTROL_FRAME_LEN).contains(&len) {
+            return Err(io::Error::other("Invalid Frame Streams control frame"));
+        }
+        let mut control = vec![0u8; len];
+        stream.read_exact(&mut control).await?;
+        if control[..4] != FSTRM_CONTROL_ACCEPT.to_be_bytes() {
+            return Err(io::Error::other(
+                "The dnstap collector didn't accept the connection",
+            ));
+        }
+        stream
+            .write_all(&control_frame(FSTRM_CONTROL_START))
+            .await?;
+        Ok(stream)
+    }
+}
+
+fn unix_time(time: SystemTime) -> (u64, u32) {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (elapsed.as_secs(), elapsed.subsec_nanos())
+}
+
+fn push_data_frame(frames: &mut Vec<u8>, frame: &[u8]) {
+    frames.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    frames.extend_from_slice(frame);
+}
+
+/// Returns a control frame with the dnstap content type
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut control = vec![];
+    control.extend_from_slice(&control_type.to_be_bytes());
+    control.extend_from_slice(&FSTRM_CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+    control.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    control.extend_from_slice(CONTENT_TYPE);
+    let mut frame = vec![0, 0, 0, 0];
+    frame.extend_from_slice(&(control.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&control);
+    frame
+}
@@ -0,0 +1,186 @@
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+use crate::globals::ClientSlot;
+use crate::{DoH, Protocol};
+
+use futures::prelude::*;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+
+/// Reads a query of up to `max_query_size` bytes, prefixed with its length as in DNS over TCP
+async fn read_query<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_query_size: usize,
+) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+    let len = u16::from_be_bytes(len) as usize;
+    if len > max_query_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The query is larger than the maximum query size",
+        ));
+    }
+    let mut query = vec![0u8; len];
+    reader.read_exact(&mut query).await?;
+    Ok(query)
+}
+
+impl DoH {
+    /// Answers plain DNS queries received over UDP
+    pub async fn start_dns_udp(self, socket: UdpSocket) -> Result<(), DoHError> {
+        let (mut recv_half, mut send_half) = socket.split();
+        let (sender, mut receiver) = mpsc::channel::<(Vec<u8>, SocketAddr)>(DO53_UDP_QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some((response, client_addr)) = receiver.recv().await {
+                let _ = send_half.send_to(&response, &client_addr).await;
+            }
+        });
+        let mut packet = vec![0u8; MAX_DNS_RESPONSE_LEN.max(self.globals.max_query_size + 1)];
+        loop {
+            let (len, client_addr) = match recv_half.recv_from(&mut packet).await {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            if !(MIN_DNS_PACKET_LEN..=self.globals.max_query_size).contains(&len) {
+                continue;
+            }
+            let query = packet[..len].to_vec();
+            let mut doh = self.clone();
+            doh.remote_addr = Some(client_addr);
+            let mut sender = sender.clone();
+            tokio::spawn(async move {
+                let max_response_len = dns::max_udp_response_len(&query);
+                let client_ip = Some(client_addr.ip());
+                let mut response = match doh.answer_query(Protocol::Udp, query, client_ip).await {
+                    None => return,
+                    Some(response) => response,
+                };
+                if response.len() > max_response_len {
+                    response = match dns::truncate(&response) {
+                        Err(_) => return,
+                        Ok(response) => response,
+                    };
+                }
+                let _ = sender.send((response, client_addr)).await;
+            });
+        }
+    }
+
+    /// Answers plain DNS queries received over TCP
+    pub async fn start_dns_tcp(self, mut listener: TcpListener) -> Result<(), DoHError> {
+        loop {
+            self.globals.clients_count.wait_for_slot().await;
+            let stream = match listener.incoming().next().await {
+                None => break,
+                Some(Err(_)) => continue,
+                Some(Ok(stream)) => stream,
+            };
+            let client_slot = self.globals.clients_count.acquire().await;
+            let remote_addr = stream.peer_addr().ok();
+            tokio::spawn(self.clone().serve_dns_stream(
+                stream,
+                remote_addr,
+                Protocol::Tcp,
+                client_slot,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Answers the queries sent over a TCP or TLS connection. Queries are processed
+    /// concurrently, and responses are sent as soon as they are ready, possibly out of order.
+    pub(crate) async fn serve_dns_stream<S>(
+        mut self,
+        stream: S,
+        remote_addr: Option<SocketAddr>,
+        protocol: Protocol,
+        client_slot: ClientSlot,
+    ) where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.remote_addr = remote_addr;
+        let globals = self.globals.clone();
+        let client_ip = remote_addr.map(|remote_addr| remote_addr.ip());
+        let (reader, mut writer) = tokio::io::split(stream);
+        let lifetime_end = Instant::now() + globals.max_connection_lifetime;
+
+        // New queries are no longer read once the connection is idle, or its lifetime is over,
+        // or the server is shutting down. The connection is closed after the queries that
+        // were already received have been answered.
+        let queries = stream::unfold(reader, move |mut reader| {
+            let globals = globals.clone();
+            async move {
+                let next_query = tokio::time::timeout(
+                    globals.header_read_timeout,
+                    read_query(&mut reader, globals.max_query_size),
+                );
+                let lifetime = future::select(
+                    tokio::time::delay_until(lifetime_end.into()),
+                    Box::pin(globals.shutdown.wait()),
+                );
+                let query = match future::select(Box::pin(next_query), lifetime).await {
+                    future::Either::Left((Ok(Ok(query)), _)) => query,
+                    _ => return None,
+                };
+                Some((query, reader))
+            }
+        });
+        let doh = &self;
+        let responses = queries
+            .map(|query| doh.answer_query(protocol, query, client_ip))
+            .buffer_unordered(self.globals.max_connection_queries as usize);
+        futures::pin_mut!(responses);
+        while let Some(response) = responses.next().await {
+            let response = match response {
+                None => break,
+                Some(response) => response,
+            };
+            let mut message = Vec::with_capacity(2 + response.len());
+            message.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            message.extend_from_slice(&response);
+            if writer.write_all(&message).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
+        }
+        drop(client_slot);
+    }
+
+    /// Returns the response to a query, a `SERVFAIL` or `REFUSED` response if it couldn't
+    /// be answered, or `None` if the query is not valid and should be ignored
+    async fn answer_query(
+        &self,
+        protocol: Protocol,
+        query: Vec<u8>,
+        client_ip: Option<IpAddr>,
+    ) -> Option<Vec<u8>> {
+        let method = match protocol {
+            Protocol::Udp => "UDP",
+            Protocol::Tcp => "TCP",
+            _ => "DOT",
+        };
+        let res = self
+            .resolve_query(protocol, method, query.clone(), client_ip)
+            .await;
+        match res {
+            Ok(dns_response) => Some(dns_response.packet),
+            Err(e) => {
+                tracing::debug!(
+                    client = client_ip.map(tracing::field::display),
+                    error = %e,
+                    "Query failed"
+                );
+                let rcode = match e {
+                    DoHError::TooManyRequests | DoHError::QuotaExceeded => dns::DNS_RCODE_REFUSED,
+                    _ => dns::DNS_RCODE_SERVFAIL,
+                };
+                dns::synthesize_response(&query, rcode, &[]).ok()
+            }
+        }
+    }
+}
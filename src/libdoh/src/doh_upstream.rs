@@ -0,0 +1,247 @@
+use crate::bootstrap::BootstrapResolver;
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+use crate::upstream_proxy::UpstreamProxy;
+
+use futures::prelude::*;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client, Request, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ClientConfig;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Resolves the name of the upstream DoH server with the bootstrap resolver if there is one,
+/// or with the system resolver
+#[derive(Clone)]
+struct UpstreamResolver {
+    bootstrap: Option<Arc<BootstrapResolver>>,
+    system: GaiResolver,
+}
+
+impl Service<Name> for UpstreamResolver {
+    type Response = std::vec::IntoIter<IpAddr>;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.system.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let bootstrap = match &self.bootstrap {
+            None => {
+                return self
+                    .system
+                    .call(name)
+                    .map_ok(|addresses| addresses.collect::<Vec<_>>().into_iter())
+                    .map_err(Into::into)
+                    .boxed()
+            }
+            Some(bootstrap) => bootstrap.clone(),
+        };
+        async move { Ok(bootstrap.lookup_ip(name.as_str()).await?.into_iter()) }.boxed()
+    }
+}
+
+/// Opens connections to the upstream DoH server, through the upstream proxy if there is one
+#[derive(Clone)]
+struct UpstreamConnector {
+    http: HttpConnector<UpstreamResolver>,
+    resolver: UpstreamResolver,
+    upstream_proxy: Option<UpstreamProxy>,
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let upstream_proxy = match &self.upstream_proxy {
+            None => return self.http.call(uri).map_err(Into::into).boxed(),
+            Some(upstream_proxy) => upstream_proxy.clone(),
+        };
+        let mut resolver = self.resolver.clone();
+        async move {
+            let host = uri.host().ok_or("Missing host")?;
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let port = uri.port_u16().unwrap_or_else(|| match uri.scheme_str() {
+                Some("http") => 80,
+                _ => 443,
+            });
+            let ip = resolver
+                .call(host.parse()?)
+                .await?
+                .next()
+                .ok_or("No addresses found")?;
+            let target = SocketAddr::new(ip, port);
+            Ok(upstream_proxy.connect(target).await?)
+        }
+        .boxed()
+    }
+}
+
+/// An upstream DoH server (RFC 8484).
+/// Connections are kept open and reused, and queries are multiplexed over HTTP/2 when the
+/// server supports it.
+pub struct DoHUpstream {
+    url: Uri,
+    client: Client<HttpsConnector<UpstreamConnector>>,
+    last_response: Mutex<Option<Instant>>,
+}
+
+impl fmt::Debug for DoHUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DoHUpstream")
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+fn upstream_error(message: &str) -> DoHError {
+    tracing::debug!("Upstream DoH server error: {}", message);
+    DoHError::UpstreamIssue
+}
+
+impl DoHUpstream {
+    /// Connects to the server through `upstream_proxy` if it is set, and resolves its name with
+    /// `bootstrap` if it is set
+    pub fn new(
+        url: Uri,
+        upstream_proxy: Option<UpstreamProxy>,
+        bootstrap: Option<Arc<BootstrapResolver>>,
+    ) -> Self {
+        let resolver = UpstreamResolver {
+            bootstrap,
+            system: GaiResolver::new(),
+        };
+        let mut http = HttpConnector::new_with_resolver(resolver.clone());
+        http.enforce_http(false);
+        http.set_nodelay(true);
+        let mut tls_config = ClientConfig::new();
+        tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        tls_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        let connector = UpstreamConnector {
+            http,
+            resolver,
+            upstream_proxy,
+        };
+        let client = Client::builder().build((connector, tls_config).into());
+        DoHUpstream {
+            url,
+            client,
+            last_response: Mutex::new(None),
+        }
+    }
+
+    /// Parses the URL of an upstream DoH server.
+    /// HTTPS servers must be given by name, as their certificates are checked against it.
+    pub fn parse_url(url: &str) -> Result<Uri, String> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|e| format!("Invalid URL [{}]: {}", url, e))?;
+        let host = match (uri.scheme_str(), uri.host()) {
+            (Some("https"), Some(host)) | (Some("http"), Some(host)) => host,
+            _ => return Err(format!("Expected an http:// or https:// URL: [{}]", url)),
+        };
+        let is_ip = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<std::net::IpAddr>()
+            .is_ok();
+        if uri.scheme_str() == Some("https") && is_ip {
+            return Err(format!(
+                "[{}]: HTTPS servers must be given by name, not by IP address",
+                url
+            ));
+        }
+        Ok(uri)
+    }
+
+    pub fn url(&self) -> &Uri {
+        &self.url
+    }
+
+    /// Sends a query with a POST request, and returns the response
+    pub async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DoHError> {
+        let req = Request::post(self.url.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/dns-message")
+            .header(hyper::header::ACCEPT, "application/dns-message")
+            .body(Body::from(query.to_vec()))
+            .map_err(|_| DoHError::InvalidData)?;
+        self.send(req, query).await
+    }
+
+    /// Sends a query with a GET request, and returns the response
+    pub async fn exchange_get(&self, query: &[u8]) -> Result<Vec<u8>, DoHError> {
+        let separator = if self.url.query().is_some() { '&' } else { '?' };
+        let url = format!(
+            "{}{}{}={}",
+            self.url,
+            separator,
+            DNS_QUERY_PARAM,
+            base64::encode_config(query, base64::URL_SAFE_NO_PAD)
+        );
+        let req = Request::get(url)
+            .header(hyper::header::ACCEPT, "application/dns-message")
+            .body(Body::empty())
+            .map_err(|_| DoHError::InvalidData)?;
+        self.send(req, query).await
+    }
+
+    async fn send(&self, req: Request<Body>, query: &[u8]) -> Result<Vec<u8>, DoHError> {
+        let res = self
+            .client
+            .request(req)
+            .await
+            .map_err(|e| upstream_error(&e.to_string()))?;
+        if res.status() != StatusCode::OK {
+            return Err(upstream_error(&format!("status {}", res.status())));
+        }
+        let content_type = res
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok());
+        if content_type != Some("application/dns-message") {
+            return Err(upstream_error("unexpected content type"));
+        }
+        let mut body = res.into_body();
+        let mut packet = Vec::with_capacity(MAX_DNS_RESPONSE_LEN);
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| upstream_error(&e.to_string()))?;
+            if packet.len() + chunk.len() > 0xffff {
+                return Err(upstream_error("response too large"));
+            }
+            packet.extend_from_slice(&chunk);
+        }
+        if packet.len() < MIN_DNS_PACKET_LEN || !dns::is_response_to(&packet, query) {
+            return Err(upstream_error("invalid response"));
+        }
+        *self.last_response.lock().unwrap() = Some(Instant::now());
+        Ok(packet)
+    }
+
+    /// Returns `true` if the server answered a query within `max_age`
+    pub fn is_reachable(&self, max_age: Duration) -> bool {
+        matches!(*self.last_response.lock().unwrap(), Some(last_response) if last_response.elapsed() <= max_age)
+    }
+}
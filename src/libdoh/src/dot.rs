@@ -0,0 +1,36 @@
+use crate::errors::*;
+use crate::{DoH, Protocol};
+
+use futures::prelude::*;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+impl DoH {
+    /// Accepts DNS-over-TLS connections (RFC 7858)
+    pub async fn start_dot(
+        self,
+        tls_acceptor: TlsAcceptor,
+        mut listener: TcpListener,
+    ) -> Result<(), DoHError> {
+        loop {
+            self.globals.clients_count.wait_for_slot().await;
+            let raw_stream = match listener.incoming().next().await {
+                None => break,
+                Some(Err(_)) => continue,
+                Some(Ok(raw_stream)) => raw_stream,
+            };
+            let client_slot = self.globals.clients_count.acquire().await;
+            let remote_addr = raw_stream.peer_addr().ok();
+            self.set_tcp_keepalive(&raw_stream);
+            let tls_acceptor = tls_acceptor.clone();
+            let mut doh = self.clone();
+            tokio::spawn(async move {
+                if let Some(stream) = doh.tls_handshake(&tls_acceptor, raw_stream).await {
+                    doh.serve_dns_stream(stream, remote_addr, Protocol::Dot, client_slot)
+                        .await;
+                }
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+#[cfg(unix)]
+use crate::sockaddr;
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use tokio::net::TcpStream;
+
+/// Binds a UDP socket to send queries to upstream servers from, and ties it
+/// to a network interface if `bind_device` is set
+pub fn bind_udp(
+    local_bind_address: SocketAddr,
+    bind_device: Option<&str>,
+) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(local_bind_address)?;
+    if let Some(bind_device) = bind_device {
+        set_bind_device(&socket, bind_device)?;
+    }
+    Ok(socket)
+}
+
+/// Connects to an upstream server over TCP from the address of `local_bind_address`,
+/// with a port chosen by the system, and through the `bind_device` interface if set
+#[cfg(unix)]
+pub async fn connect_tcp(
+    local_bind_address: SocketAddr,
+    bind_device: Option<&str>,
+    server_address: SocketAddr,
+) -> io::Result<TcpStream> {
+    use std::os::unix::io::FromRawFd;
+
+    if local_bind_address.ip().is_unspecified() && bind_device.is_none() {
+        return TcpStream::connect(server_address).await;
+    }
+    let family = match server_address {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    if let Some(bind_device) = bind_device {
+        set_bind_device(&stream, bind_device)?;
+    }
+    if !local_bind_address.ip().is_unspecified() {
+        sockaddr::bind(fd, &SocketAddr::new(local_bind_address.ip(), 0))?;
+    }
+    TcpStream::connect_std(stream, &server_address).await
+}
+
+#[cfg(not(unix))]
+pub async fn connect_tcp(
+    _local_bind_address: SocketAddr,
+    bind_device: Option<&str>,
+    server_address: SocketAddr,
+) -> io::Result<TcpStream> {
+    if bind_device.is_some() {
+        return Err(io::Error::other(
+            "Binding to a device is only supported on Linux",
+        ));
+    }
+    TcpStream::connect(server_address).await
+}
+
+/// Sends the traffic of a socket through a network interface or VRF, with `SO_BINDTODEVICE`
+#[cfg(target_os = "linux")]
+fn set_bind_device(socket: &impl std::os::unix::io::AsRawFd, bind_device: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            bind_device.as_ptr() as *const libc::c_void,
+            bind_device.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_bind_device<S>(_socket: &S, _bind_device: &str) -> io::Result<()> {
+    Err(io::Error::other(
+        "Binding to a device is only supported on Linux",
+    ))
+}
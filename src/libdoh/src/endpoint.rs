@@ -0,0 +1,18 @@
+use crate::blocklist::{BlockList, BlockResponse};
+use crate::upstream::Upstreams;
+
+use std::sync::Arc;
+
+/// An additional DoH path, with its own upstream servers, block list, TTL policy and query size limit
+#[derive(Debug)]
+pub struct Endpoint {
+    pub path: String,
+    pub upstreams: Upstreams,
+    pub block_list: Option<Arc<BlockList>>,
+    pub block_response: BlockResponse,
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    pub err_ttl: u32,
+    pub max_query_size: usize,
+    pub max_query_param_len: usize,
+}
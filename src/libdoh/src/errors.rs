@@ -9,6 +9,10 @@ pub enum DoHError {
     TooLarge,
     UpstreamIssue,
     UpstreamTimeout,
+    QueryTimeout,
+    TooManyRequests,
+    QuotaExceeded,
+    RequestTimeout,
     Hyper(hyper::Error),
     Io(io::Error),
 }
@@ -23,22 +27,50 @@ impl std::fmt::Display for DoHError {
             DoHError::TooLarge => write!(fmt, "Too large"),
             DoHError::UpstreamIssue => write!(fmt, "Upstream error"),
             DoHError::UpstreamTimeout => write!(fmt, "Upstream timeout"),
+            DoHError::QueryTimeout => write!(fmt, "Query timeout"),
+            DoHError::TooManyRequests => write!(fmt, "Too many requests"),
+            DoHError::QuotaExceeded => write!(fmt, "Query quota exceeded"),
+            DoHError::RequestTimeout => write!(fmt, "Request timeout"),
             DoHError::Hyper(e) => write!(fmt, "HTTP error: {}", e),
             DoHError::Io(e) => write!(fmt, "IO error: {}", e),
         }
     }
 }
 
-impl From<DoHError> for StatusCode {
-    fn from(e: DoHError) -> StatusCode {
-        match e {
+impl DoHError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
             DoHError::Incomplete => StatusCode::UNPROCESSABLE_ENTITY,
             DoHError::InvalidData => StatusCode::BAD_REQUEST,
             DoHError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
             DoHError::UpstreamIssue => StatusCode::BAD_GATEWAY,
             DoHError::UpstreamTimeout => StatusCode::BAD_GATEWAY,
+            DoHError::QueryTimeout => StatusCode::GATEWAY_TIMEOUT,
+            DoHError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            DoHError::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            DoHError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
             DoHError::Hyper(_) => StatusCode::SERVICE_UNAVAILABLE,
             DoHError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    /// Returns `true` if the query couldn't be answered because of the upstream servers
+    pub fn is_upstream_failure(&self) -> bool {
+        matches!(
+            self,
+            DoHError::UpstreamIssue | DoHError::UpstreamTimeout | DoHError::QueryTimeout
+        )
+    }
+}
+
+impl From<DoHError> for StatusCode {
+    fn from(e: DoHError) -> StatusCode {
+        e.status_code()
+    }
+}
+
+impl From<&DoHError> for StatusCode {
+    fn from(e: &DoHError) -> StatusCode {
+        e.status_code()
+    }
 }
@@ -0,0 +1,45 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Inspects queries before they are answered, and can modify them or answer them directly
+pub trait QueryFilter: Send + Sync {
+    /// Called for every query, before it is looked up in the cache or forwarded.
+    /// Returning a response answers the query with it, skipping the following filters.
+    fn filter_query(&self, query: &mut Vec<u8>, client_ip: Option<IpAddr>) -> Option<Vec<u8>>;
+}
+
+/// Inspects responses before they are sent to clients, and can modify them
+pub trait ResponseFilter: Send + Sync {
+    /// Called for every response, including cached and locally generated responses.
+    fn filter_response(&self, query: &[u8], response: &mut Vec<u8>);
+}
+
+/// The filters registered by the application, run in registration order
+#[derive(Clone, Default)]
+pub struct Filters {
+    pub query_filters: Vec<Arc<dyn QueryFilter>>,
+    pub response_filters: Vec<Arc<dyn ResponseFilter>>,
+}
+
+impl std::fmt::Debug for Filters {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        fmt.debug_struct("Filters")
+            .field("query_filters", &self.query_filters.len())
+            .field("response_filters", &self.response_filters.len())
+            .finish()
+    }
+}
+
+impl Filters {
+    pub fn filter_query(&self, query: &mut Vec<u8>, client_ip: Option<IpAddr>) -> Option<Vec<u8>> {
+        self.query_filters
+            .iter()
+            .find_map(|filter| filter.filter_query(query, client_ip))
+    }
+
+    pub fn filter_response(&self, query: &[u8], response: &mut Vec<u8>) {
+        for filter in &self.response_filters {
+            filter.filter_response(query, response);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use hyper::header::HeaderMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// An IP network, written as `<address>/<prefix length>`, or as a single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    ip: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(&IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix_len)
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let prefix_len = prefix_len as usize;
+    let (bytes, bits) = (prefix_len / 8, prefix_len % 8);
+    if net[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let ip: IpAddr = parts
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("Invalid network address: [{}]", s))?;
+        let max_prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|&prefix_len| prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid network prefix length: [{}]", s))?,
+        };
+        Ok(IpNetwork { ip, prefix_len })
+    }
+}
+
+/// Reverse proxies whose `Forwarded` and `X-Forwarded-For` headers can be trusted
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNetwork>,
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    /// Parses a comma-separated list of networks
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let networks = s
+            .split(',')
+            .filter(|network| !network.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(TrustedProxies { networks })
+    }
+}
+
+impl TrustedProxies {
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// Returns the address of the client that sent a request.
+    /// If the peer is a trusted proxy, forwarding headers are read from the most recent hop,
+    /// until an address that is not a trusted proxy is found.
+    pub fn client_ip(&self, peer_ip: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(&peer_ip) {
+            return peer_ip;
+        }
+        let mut hops = forwarded_for(headers);
+        if hops.is_empty() {
+            hops = x_forwarded_for(headers);
+        }
+        let mut client_ip = peer_ip;
+        for hop in hops.iter().rev() {
+            match hop {
+                None => break,
+                Some(ip) => client_ip = *ip,
+            }
+            if !self.is_trusted(&client_ip) {
+                break;
+            }
+        }
+        client_ip
+    }
+}
+
+/// Parses a node identifier: an IPv4 address or a bracketed IPv6 address, with an optional port
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+}
+
+/// Returns the `for` parameters of the `Forwarded` headers (RFC 7239), in order
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let mut hops = vec![];
+    for value in headers.get_all(hyper::header::FORWARDED) {
+        let value = match value.to_str() {
+            Err(_) => return vec![None],
+            Ok(value) => value,
+        };
+        for element in value.split(',') {
+            for pair in element.split(';') {
+                let mut kv = pair.splitn(2, '=');
+                let key = kv.next().unwrap().trim();
+                if key.eq_ignore_ascii_case("for") {
+                    hops.push(kv.next().and_then(parse_node));
+                }
+            }
+        }
+    }
+    hops
+}
+
+/// Returns the addresses listed in the `X-Forwarded-For` headers, in order
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let mut hops = vec![];
+    for value in headers.get_all("x-forwarded-for") {
+        let value = match value.to_str() {
+            Err(_) => return vec![None],
+            Ok(value) => value,
+        };
+        hops.extend(value.split(',').map(parse_node));
+    }
+    hops
+}
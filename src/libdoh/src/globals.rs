@@ -1,11 +1,55 @@
-use std::net::SocketAddr;
+use crate::access_log::AccessLog;
+use crate::address_filter::AddressFilters;
+use crate::auth::AuthTokens;
+use crate::blocklist::{BlockList, BlockResponse};
+use crate::bootstrap::BootstrapResolver;
+use crate::buffer_pool::BufferPool;
+use crate::cache::Cache;
+use crate::canary::CanaryDomains;
+use crate::constants::*;
+use crate::cors::CorsOrigins;
+#[cfg(unix)]
+use crate::daemon::Daemon;
+use crate::ddr::Ddr;
+use crate::defaults::*;
+use crate::dns;
+use crate::dns64::Dns64Prefix;
+#[cfg(unix)]
+use crate::dnstap::Dnstap;
+use crate::endpoint::Endpoint;
+use crate::filter::Filters;
+use crate::forwarded::TrustedProxies;
+use crate::health::UpstreamHealth;
+use crate::inflight::InflightQueries;
+use crate::landing::Landing;
+use crate::limits::{ClientKeyPolicy, ClientQueries};
+use crate::localzone::LocalZone;
+#[cfg(unix)]
+use crate::privdrop::PrivDrop;
+use crate::profile::Profiles;
+use crate::query_log::QueryLog;
+use crate::quota::Quotas;
+use crate::response_headers::ResponseHeaders;
+use crate::rewrite::RewriteRules;
+use crate::routes::UpstreamRoutes;
+use crate::stats::QueryStats;
+use crate::ttl_overrides::TtlOverrides;
+use crate::udp_pool::UdpPool;
+use crate::upstream::{Upstream, Upstreams};
+use crate::upstream_proxy::UpstreamProxy;
+use crate::watcher::Reloadable;
+
+use futures::future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::runtime;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
 
 #[cfg(feature = "tls")]
-use std::path::PathBuf;
+use crate::doh_upstream::DoHUpstream;
 
 #[derive(Debug)]
 pub struct Globals {
@@ -15,36 +59,565 @@ pub struct Globals {
     #[cfg(feature = "tls")]
     pub tls_cert_key_path: Option<PathBuf>,
 
-    pub listen_address: SocketAddr,
-    pub local_bind_address: SocketAddr,
-    pub server_address: SocketAddr,
+    #[cfg(feature = "tls")]
+    pub tls_client_ca_path: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    pub tls_self_signed: Option<String>,
+
+    #[cfg(feature = "tls")]
+    pub tls_ticket_rotation_interval: Duration,
+
+    #[cfg(feature = "tls")]
+    pub tls_ocsp_stapling: bool,
+
+    #[cfg(feature = "tls")]
+    pub plaintext_listen_addresses: Vec<SocketAddr>,
+
+    #[cfg(feature = "tls")]
+    pub dot_listen_addresses: Vec<SocketAddr>,
+
+    #[cfg(feature = "tls")]
+    pub upstream_doh: Option<DoHUpstream>,
+
+    pub listen_addresses: Vec<SocketAddr>,
+    pub dns_listen_addresses: Vec<SocketAddr>,
+    pub upstreams: Upstreams,
+    pub upstream_routes: UpstreamRoutes,
+    pub upstream_proxy: Option<UpstreamProxy>,
+    pub upstream_resolve_interval: Duration,
+    pub bootstrap: Option<Arc<BootstrapResolver>>,
+    pub bind_device: Option<String>,
+    pub udp_pool: Option<UdpPool>,
+    pub buffer_pool: BufferPool,
     pub path: String,
+    pub endpoints: Vec<Arc<Endpoint>>,
     pub max_clients: usize,
+    pub max_connection_queries: u32,
+    pub max_client_queries: Option<usize>,
+    pub client_queries: ClientQueries,
+    pub client_key_policy: ClientKeyPolicy,
+    pub quotas: Option<Quotas>,
+    pub quota_persist_path: Option<PathBuf>,
     pub timeout: Duration,
+    pub query_timeout: Duration,
+    pub header_read_timeout: Duration,
+    pub http_idle_timeout: Duration,
+    pub body_read_timeout: Duration,
+    pub max_connection_lifetime: Duration,
+    pub max_requests_per_connection: Option<usize>,
+    pub tcp_keepalive: Option<Duration>,
+    pub upstream_timeout: Duration,
+    pub upstream_retries: usize,
+    pub upstream_cookies: bool,
+    pub qname_randomization: bool,
+    pub paranoid_upstream_checks: bool,
+    pub max_udp_size: u16,
     pub clients_count: ClientsCount,
+    pub shutdown: Shutdown,
+    /// Triggered to stop accepting connections, while still serving the existing ones
+    pub drain: Shutdown,
     pub min_ttl: u32,
     pub max_ttl: u32,
     pub err_ttl: u32,
+    pub servfail_on_upstream_error: bool,
+    pub ttl_overrides: TtlOverrides,
+    pub negative_cache: Option<Cache>,
+    pub cache: Option<Cache>,
+    pub cache_persist_path: Option<PathBuf>,
+    pub cache_persist_interval: Duration,
+    pub inflight_queries: InflightQueries,
     pub keepalive: bool,
     pub disable_post: bool,
+    pub max_query_size: usize,
+    pub max_query_param_len: usize,
+    pub auth_tokens: AuthTokens,
+    pub profiles: Profiles,
+    pub cors_origins: Option<CorsOrigins>,
+    pub response_headers: ResponseHeaders,
+    pub landing: Option<Landing>,
+    pub padding: bool,
+    pub problem_details: bool,
+    pub debug_headers: bool,
+    pub dnssec_validate: bool,
+    pub dns64_prefix: Option<Dns64Prefix>,
+    pub denied_qtypes: DeniedQtypes,
+    pub denied_qtype_response: DeniedQtypeResponse,
+    pub address_filters: AddressFilters,
+    pub filters: Filters,
+    pub access_log: Option<AccessLog>,
+    pub trusted_proxies: TrustedProxies,
+    pub ecs_policy: EcsPolicy,
+    pub edns_strip: EdnsStrip,
+    pub local_zone: Option<Reloadable<LocalZone>>,
+    pub canary_domains: CanaryDomains,
+    pub block_private_ptr: bool,
+    pub safe_search: bool,
+    pub loop_protection: bool,
+    pub ddr: Option<Ddr>,
+    pub rewrite_rules: Option<Reloadable<RewriteRules>>,
+    pub block_list: Option<Arc<BlockList>>,
+    pub block_response: BlockResponse,
+    /// Names that are never blocked, whatever block list they are in
+    pub allow_list: Option<Arc<BlockList>>,
+    pub block_list_reload_interval: Duration,
+    /// Interval between downloads of the block lists given by URL
+    pub block_list_update_interval: Duration,
+    pub health_path: Option<String>,
+    pub readiness_path: Option<String>,
+    pub upstream_probe_interval: Duration,
+    pub upstream_health: UpstreamHealth,
+    pub admin_listen_address: Option<SocketAddr>,
+    pub query_stats: Option<QueryStats>,
+    pub query_log: Option<QueryLog>,
+
+    #[cfg(unix)]
+    pub privdrop: PrivDrop,
+    #[cfg(unix)]
+    pub daemon: Daemon,
+    #[cfg(unix)]
+    pub dnstap: Option<Dnstap>,
+    #[cfg(unix)]
+    pub reuseport: usize,
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        Globals {
+            #[cfg(feature = "tls")]
+            tls_cert_path: None,
+            #[cfg(feature = "tls")]
+            tls_cert_key_path: None,
+            #[cfg(feature = "tls")]
+            tls_client_ca_path: None,
+            #[cfg(feature = "tls")]
+            tls_self_signed: None,
+            #[cfg(feature = "tls")]
+            tls_ticket_rotation_interval: Duration::from_secs(TLS_TICKET_ROTATION_INTERVAL_SEC),
+            #[cfg(feature = "tls")]
+            tls_ocsp_stapling: false,
+            #[cfg(feature = "tls")]
+            plaintext_listen_addresses: vec![],
+            #[cfg(feature = "tls")]
+            dot_listen_addresses: vec![],
+            #[cfg(feature = "tls")]
+            upstream_doh: None,
+
+            listen_addresses: vec![LISTEN_ADDRESS.parse().unwrap()],
+            dns_listen_addresses: vec![],
+            upstreams: Upstreams::single(SERVER_ADDRESS.parse().unwrap()),
+            upstream_routes: UpstreamRoutes::default(),
+            upstream_proxy: None,
+            upstream_resolve_interval: Duration::from_secs(UPSTREAM_RESOLVE_INTERVAL_SEC),
+            bootstrap: None,
+            bind_device: None,
+            udp_pool: Some(UdpPool::new(UDP_POOL_SIZE)),
+            buffer_pool: BufferPool::new(MAX_DNS_RESPONSE_LEN, BUFFER_POOL_SIZE),
+            path: PATH.to_string(),
+            endpoints: vec![],
+            max_clients: MAX_CLIENTS,
+            max_connection_queries: MAX_CONNECTION_QUERIES,
+            max_client_queries: None,
+            client_queries: ClientQueries::default(),
+            client_key_policy: ClientKeyPolicy::Ip,
+            quotas: None,
+            quota_persist_path: None,
+            timeout: Duration::from_secs(TIMEOUT_SEC),
+            query_timeout: Duration::from_millis(QUERY_TIMEOUT_MS),
+            header_read_timeout: Duration::from_secs(HEADER_READ_TIMEOUT_SEC),
+            http_idle_timeout: Duration::from_secs(HTTP_IDLE_TIMEOUT_SEC),
+            body_read_timeout: Duration::from_secs(BODY_READ_TIMEOUT_SEC),
+            max_connection_lifetime: Duration::from_secs(MAX_CONNECTION_LIFETIME_SEC),
+            max_requests_per_connection: None,
+            tcp_keepalive: None,
+            upstream_timeout: Duration::from_millis(UPSTREAM_TIMEOUT_MS),
+            upstream_retries: UPSTREAM_RETRIES,
+            upstream_cookies: false,
+            qname_randomization: true,
+            paranoid_upstream_checks: false,
+            max_udp_size: MAX_UDP_SIZE,
+            clients_count: Default::default(),
+            shutdown: Shutdown::default(),
+            drain: Shutdown::default(),
+            min_ttl: MIN_TTL,
+            max_ttl: MAX_TTL,
+            err_ttl: ERR_TTL,
+            servfail_on_upstream_error: false,
+            ttl_overrides: TtlOverrides::default(),
+            negative_cache: Some(Cache::new(NEGATIVE_CACHE_SIZE)),
+            cache: Some(Cache::with_serve_stale(
+                CACHE_SIZE,
+                Duration::from_secs(CACHE_SERVE_STALE_TTL as u64),
+            )),
+            cache_persist_path: None,
+            cache_persist_interval: Duration::from_secs(CACHE_PERSIST_INTERVAL_SEC),
+            inflight_queries: InflightQueries::default(),
+            keepalive: true,
+            disable_post: false,
+            max_query_size: MAX_QUERY_SIZE,
+            max_query_param_len: MAX_DNS_QUERY_PARAM_LEN,
+            auth_tokens: AuthTokens::default(),
+            profiles: Profiles::default(),
+            cors_origins: None,
+            response_headers: ResponseHeaders::default(),
+            landing: None,
+            padding: true,
+            problem_details: false,
+            debug_headers: false,
+            dnssec_validate: false,
+            dns64_prefix: None,
+            denied_qtypes: DeniedQtypes::default(),
+            denied_qtype_response: DeniedQtypeResponse::NotImp,
+            address_filters: AddressFilters::default(),
+            filters: Filters::default(),
+            access_log: None,
+            trusted_proxies: TrustedProxies::default(),
+            ecs_policy: EcsPolicy::Forward,
+            edns_strip: EdnsStrip::default(),
+            local_zone: None,
+            canary_domains: CanaryDomains::default(),
+            block_private_ptr: false,
+            safe_search: false,
+            loop_protection: false,
+            ddr: None,
+            rewrite_rules: None,
+            block_list: None,
+            block_response: BlockResponse::NxDomain,
+            allow_list: None,
+            block_list_reload_interval: Duration::from_secs(BLOCK_LIST_RELOAD_INTERVAL_SEC),
+            block_list_update_interval: Duration::from_secs(BLOCK_LIST_UPDATE_INTERVAL_SEC),
+            health_path: Some(HEALTH_PATH.to_string()),
+            readiness_path: Some(READINESS_PATH.to_string()),
+            upstream_probe_interval: Duration::from_secs(UPSTREAM_PROBE_INTERVAL_SEC),
+            upstream_health: UpstreamHealth::default(),
+            admin_listen_address: None,
+            query_stats: None,
+            query_log: None,
+
+            #[cfg(unix)]
+            privdrop: PrivDrop::default(),
+            #[cfg(unix)]
+            daemon: Daemon::default(),
+            #[cfg(unix)]
+            dnstap: None,
+            #[cfg(unix)]
+            reuseport: 1,
+        }
+    }
+}
 
-    pub runtime_handle: runtime::Handle,
+impl Globals {
+    /// Returns the upstream servers of the main path, of the routed domains
+    /// and of the additional endpoints
+    pub fn all_upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        let route_upstreams = self.upstream_routes.iter().map(|(_, upstreams)| upstreams);
+        let endpoint_upstreams = self.endpoints.iter().map(|endpoint| &endpoint.upstreams);
+        std::iter::once(&self.upstreams)
+            .chain(route_upstreams)
+            .chain(endpoint_upstreams)
+            .flat_map(Upstreams::iter)
+    }
+
+    /// Returns `true` if the upstream servers are only reached over DoH or the loopback interface,
+    /// so that their responses can't be modified on the way
+    pub fn upstreams_are_secure(&self) -> bool {
+        #[cfg(feature = "tls")]
+        let plain_upstreams = self.upstream_doh.is_none();
+        #[cfg(not(feature = "tls"))]
+        let plain_upstreams = true;
+        let route_upstreams = self.upstream_routes.iter().map(|(_, upstreams)| upstreams);
+        let endpoint_upstreams = self.endpoints.iter().map(|endpoint| &endpoint.upstreams);
+        Some(&self.upstreams)
+            .filter(|_| plain_upstreams)
+            .into_iter()
+            .chain(route_upstreams)
+            .chain(endpoint_upstreams)
+            .flat_map(Upstreams::iter)
+            .all(|upstream| upstream.server_address().ip().is_loopback())
+    }
+
+    /// Returns how responses from upstream servers are checked; the case of the question name
+    /// is only randomized in queries sent over UDP
+    pub fn response_checks(&self, over_udp: bool) -> dns::ResponseChecks {
+        dns::ResponseChecks {
+            qname_case: over_udp && self.qname_randomization,
+            strict: self.paranoid_upstream_checks,
+        }
+    }
+}
+
+/// Returns the length of the `dns` parameter of GET queries of up to `max_query_size` bytes,
+/// once encoded as base64url
+pub fn max_query_param_len(max_query_size: usize) -> usize {
+    (max_query_size * 4).div_ceil(3)
+}
+
+/// Returns the wildcard address of the same family as `server_address`, to send queries from
+pub fn unspecified_bind_address(server_address: &SocketAddr) -> SocketAddr {
+    match server_address {
+        SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(s) => SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            0,
+            s.flowinfo(),
+            s.scope_id(),
+        )),
+    }
+}
+
+/// Limits the number of connected clients. Once the limit is reached, accept loops wait for
+/// a client to leave before accepting new connections, that are queued by the kernel meanwhile.
+/// Slots are only taken once connections are accepted, so that loops waiting for connections on
+/// idle sockets don't keep them from the loops of the other sockets.
+#[derive(Debug, Clone)]
+pub struct ClientsCount {
+    slots: Arc<Semaphore>,
+    current: Arc<AtomicUsize>,
+    limit_reached: Arc<AtomicUsize>,
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct ClientsCount(Arc<AtomicUsize>);
+/// A connected client, that leaves when dropped
+#[derive(Debug)]
+pub struct ClientSlot {
+    _permit: OwnedSemaphorePermit,
+    current: Arc<AtomicUsize>,
+}
+
+impl Default for ClientsCount {
+    fn default() -> Self {
+        ClientsCount {
+            slots: Arc::new(Semaphore::new(0)),
+            current: Default::default(),
+            limit_reached: Default::default(),
+        }
+    }
+}
 
 impl ClientsCount {
-    pub fn increment(&self) -> usize {
-        self.0.fetch_add(1, Ordering::Relaxed)
+    /// Allows `max_clients` clients to be connected at the same time.
+    /// No clients can connect until this is called, and it must only be called once, since
+    /// the slots are added to the existing ones: `DoHBuilder::build()` does it for all the
+    /// runtimes sharing the settings.
+    pub(crate) fn set_max_clients(&self, max_clients: usize) {
+        self.slots.add_permits(max_clients);
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Waits until a new client can connect, without taking its slot
+    pub async fn wait_for_slot(&self) {
+        if self.slots.available_permits() == 0 {
+            self.limit_reached.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!("Too many clients, waiting for a client to leave");
+            drop(self.slots.acquire().await);
+        }
     }
 
-    pub fn decrement(&self) -> usize {
-        let mut count;
-        while {
-            count = self.0.load(Ordering::Relaxed);
-            count > 0 && self.0.compare_and_swap(count, count - 1, Ordering::Relaxed) != count
-        } {}
-        count
+    /// Takes a slot for a client that just connected. This only waits if other loops took the
+    /// remaining slots since `wait_for_slot()` returned.
+    pub async fn acquire(&self) -> ClientSlot {
+        let permit = self.slots.clone().acquire_owned().await;
+        self.current.fetch_add(1, Ordering::Relaxed);
+        ClientSlot {
+            _permit: permit,
+            current: self.current.clone(),
+        }
     }
+
+    /// Number of times accept loops had to wait for a client to leave
+    pub fn limit_reached(&self) -> usize {
+        self.limit_reached.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Tells the connections that the server is shutting down
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        let _ = self.sender.broadcast(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Completes once `trigger()` has been called
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.recv().await.is_none() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// What to do with the EDNS Client Subnet information sent to the upstream server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcsPolicy {
+    Strip,
+    Forward,
+    Set { ip: IpAddr, prefix_len: u8 },
+}
+
+impl FromStr for EcsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => return Ok(EcsPolicy::Strip),
+            "forward" => return Ok(EcsPolicy::Forward),
+            _ => {}
+        }
+        let prefix = match s.strip_prefix("set:") {
+            Some(prefix) => prefix,
+            None => return Err(format!("Unsupported ECS policy: [{}]", s)),
+        };
+        let mut parts = prefix.splitn(2, '/');
+        let ip: IpAddr = parts
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("Invalid ECS prefix address: [{}]", prefix))?;
+        let max_prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|&prefix_len| prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid ECS prefix length: [{}]", prefix))?,
+        };
+        Ok(EcsPolicy::Set { ip, prefix_len })
+    }
+}
+
+/// EDNS options removed from client queries before they are forwarded, so that metadata
+/// specific to a client, such as its cookie or its padding, isn't sent to the upstream servers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EdnsStrip {
+    codes: Vec<u16>,
+    /// Every option without a mnemonic
+    unknown: bool,
+    /// Every option
+    all: bool,
+}
+
+impl EdnsStrip {
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty() && !self.unknown && !self.all
+    }
+
+    /// Returns `true` if options with the given code have to be removed
+    pub fn strips(&self, code: u16) -> bool {
+        self.all || self.codes.contains(&code) || (self.unknown && !dns::is_known_edns_option(code))
+    }
+
+    /// The stripped options, as they would be given to `from_str()`
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .codes
+            .iter()
+            .map(|&code| dns::edns_option_name(code))
+            .collect();
+        if self.unknown {
+            names.push("unknown".to_string());
+        }
+        if self.all {
+            names.push("all".to_string());
+        }
+        names
+    }
+}
+
+impl FromStr for EdnsStrip {
+    type Err = String;
+
+    /// Parses a comma-separated list of EDNS options, `unknown` or `all`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut edns_strip = EdnsStrip::default();
+        for option in s
+            .split(',')
+            .map(str::trim)
+            .filter(|option| !option.is_empty())
+        {
+            if option.eq_ignore_ascii_case("unknown") {
+                edns_strip.unknown = true;
+            } else if option.eq_ignore_ascii_case("all") {
+                edns_strip.all = true;
+            } else {
+                let code = dns::parse_edns_option(option)
+                    .ok_or_else(|| format!("Unsupported EDNS option: [{}]", option))?;
+                edns_strip.codes.push(code);
+            }
+        }
+        Ok(edns_strip)
+    }
+}
+
+/// Record types that are answered locally instead of being forwarded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeniedQtypes(Vec<u16>);
+
+impl DeniedQtypes {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, qtype: u16) -> bool {
+        self.0.contains(&qtype)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl FromStr for DeniedQtypes {
+    type Err = String;
+
+    /// Parses a comma-separated list of record types
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let qtypes = s
+            .split(',')
+            .filter(|qtype| !qtype.trim().is_empty())
+            .map(|qtype| {
+                dns::parse_qtype(qtype)
+                    .ok_or_else(|| format!("Unsupported record type: [{}]", qtype))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(DeniedQtypes(qtypes))
+    }
+}
+
+/// Response to queries for a denied record type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeniedQtypeResponse {
+    /// NOTIMP, as if the record type wasn't supported
+    NotImp,
+    /// An empty NOERROR response
+    Empty,
 }
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keeps track of the last time every upstream server answered a query
+#[derive(Debug, Default)]
+pub struct UpstreamHealth {
+    last_responses: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl UpstreamHealth {
+    pub fn record_response(&self, server_address: SocketAddr) {
+        self.last_responses
+            .lock()
+            .unwrap()
+            .insert(server_address, Instant::now());
+    }
+
+    /// Returns the time elapsed since the last response, or `None` if no responses were received
+    pub fn last_response_age(&self, server_address: &SocketAddr) -> Option<Duration> {
+        self.last_responses
+            .lock()
+            .unwrap()
+            .get(server_address)
+            .map(|last_response| last_response.elapsed())
+    }
+
+    /// Returns `true` if the upstream server answered a query within `max_age`
+    pub fn is_reachable(&self, server_address: &SocketAddr, max_age: Duration) -> bool {
+        matches!(self.last_response_age(server_address), Some(age) if age <= max_age)
+    }
+}
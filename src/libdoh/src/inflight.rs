@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+/// A response shared by identical queries: the packet and its TTL, or `None` if the query failed
+pub type InflightResponse = Option<(Vec<u8>, u32)>;
+
+/// Whether a query has to be sent, or an identical query was already sent
+pub enum Inflight {
+    Leader(oneshot::Receiver<InflightResponse>),
+    Follower(oneshot::Receiver<InflightResponse>),
+}
+
+/// Upstream queries waiting for a response, so that identical queries received
+/// in the meantime can share it instead of being sent again.
+#[derive(Debug, Default)]
+pub struct InflightQueries {
+    waiters: Mutex<HashMap<Vec<u8>, Vec<oneshot::Sender<InflightResponse>>>>,
+}
+
+impl InflightQueries {
+    /// Registers a query identified by `key`.
+    /// The first caller for a key is the leader, and has to call `complete()` once the response is received.
+    pub fn join(&self, key: &[u8]) -> Inflight {
+        let (tx, rx) = oneshot::channel();
+        let mut waiters = self.waiters.lock().unwrap();
+        match waiters.get_mut(key) {
+            Some(key_waiters) => {
+                key_waiters.push(tx);
+                Inflight::Follower(rx)
+            }
+            None => {
+                waiters.insert(key.to_vec(), vec![tx]);
+                Inflight::Leader(rx)
+            }
+        }
+    }
+
+    /// Sends the response to all the queries identified by `key`.
+    /// The last query gets the response itself, and the other ones get copies.
+    pub fn complete(&self, key: &[u8], response: InflightResponse) {
+        let mut key_waiters = self.waiters.lock().unwrap().remove(key).unwrap_or_default();
+        let last = match key_waiters.pop() {
+            Some(last) => last,
+            None => return,
+        };
+        for tx in key_waiters {
+            let _ = tx.send(response.clone());
+        }
+        let _ = last.send(response);
+    }
+}
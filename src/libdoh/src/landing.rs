@@ -0,0 +1,74 @@
+use crate::constants::*;
+
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{http, Body, Response, StatusCode};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What web browsers get instead of a `404` status code, when someone opens the URL
+/// of the server
+#[derive(Debug, Clone)]
+pub enum Landing {
+    /// Redirects to the page of the service or of its operator
+    Redirect(HeaderValue),
+    /// Serves a static HTML page
+    Page(Bytes),
+}
+
+impl Landing {
+    pub fn redirect(url: &str) -> Result<Self, String> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("Invalid URL: [{}]", url));
+        }
+        HeaderValue::from_str(url)
+            .map(Landing::Redirect)
+            .map_err(|_| format!("Invalid URL: [{}]", url))
+    }
+
+    /// A page explaining that this is a DoH server, and which path clients have to be
+    /// configured with
+    pub fn info(path: &str) -> Self {
+        Landing::Page(format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>DNS-over-HTTPS server</title></head>\n\
+             <body>\n<h1>DNS-over-HTTPS server</h1>\n\
+             <p>This server resolves DNS queries sent using the DNS-over-HTTPS protocol (RFC 8484). \
+             It doesn't serve web pages.</p>\n\
+             <p>To use it, configure your browser or operating system with the URL of this server, \
+             followed by <code>{}</code>.</p>\n</body>\n</html>\n",
+            path
+        )
+        .into())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Landing::Page(fs::read(path)?.into()))
+    }
+
+    pub fn response(&self) -> Result<Response<Body>, http::Error> {
+        match self {
+            Landing::Redirect(url) => Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, url.clone())
+                .body(Body::empty()),
+            Landing::Page(page) => Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .header(
+                    header::CACHE_CONTROL,
+                    format!("max-age={}", LANDING_PAGE_MAX_AGE_SECS),
+                )
+                .body(Body::from(page.clone())),
+        }
+    }
+}
+
+/// Checks whether a request on a DoH path was sent by a web browser, rather than by a
+/// DoH client: browsers open the URL without a query, and prefer HTML
+pub fn is_browser_request(query: Option<&str>, headers: &HeaderMap) -> bool {
+    query.is_none_or(str::is_empty)
+        && headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+}
@@ -1,32 +1,324 @@
+#![recursion_limit = "256"]
+
+mod access_log;
+mod address_filter;
+mod admin;
+mod auth;
+mod blocklist;
+mod bootstrap;
+mod buffer_pool;
+mod builder;
+mod cache;
+mod canary;
+mod check;
+mod connection;
 mod constants;
+mod cors;
+#[cfg(unix)]
+mod daemon;
+mod ddr;
+pub mod defaults;
+#[cfg(feature = "tls")]
+mod der;
 pub mod dns;
+mod dns64;
+#[cfg(unix)]
+mod dnstap;
+mod do53;
+#[cfg(feature = "tls")]
+mod doh_upstream;
+#[cfg(feature = "tls")]
+mod dot;
+mod egress;
+mod endpoint;
 mod errors;
+mod filter;
+mod forwarded;
 mod globals;
+mod health;
+mod inflight;
+mod landing;
+mod limits;
+mod localzone;
+mod log_level;
+#[cfg(feature = "tls")]
+mod ocsp;
+#[cfg(feature = "tls")]
+mod otlp;
+mod private_ptr;
+#[cfg(unix)]
+mod privdrop;
+mod profile;
+mod query_log;
+mod quota;
+mod response_headers;
+#[cfg(unix)]
+mod reuseport;
+mod rewrite;
+mod routes;
+mod safe_search;
+#[cfg(feature = "tls")]
+mod self_signed;
+#[cfg(unix)]
+mod sockaddr;
+mod stats;
+#[cfg(feature = "tls")]
+mod subscription;
+#[cfg(unix)]
+mod systemd;
+mod tcp;
 #[cfg(feature = "tls")]
 mod tls;
+mod ttl_overrides;
+mod udp_pool;
+mod upstream;
+mod upstream_proxy;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod watcher;
 
+pub use crate::access_log::*;
+pub use crate::address_filter::*;
+pub use crate::auth::*;
+pub use crate::blocklist::*;
+pub use crate::bootstrap::*;
+pub use crate::buffer_pool::*;
+pub use crate::builder::*;
+pub use crate::cache::*;
+pub use crate::canary::*;
+pub use crate::check::*;
+use crate::connection::*;
 use crate::constants::*;
+pub use crate::cors::*;
+#[cfg(unix)]
+pub use crate::daemon::*;
+pub use crate::ddr::*;
+pub use crate::dns64::*;
+#[cfg(unix)]
+pub use crate::dnstap::*;
+#[cfg(feature = "tls")]
+pub use crate::doh_upstream::*;
+pub use crate::endpoint::*;
 pub use crate::errors::*;
+pub use crate::filter::*;
+pub use crate::forwarded::*;
 pub use crate::globals::*;
+pub use crate::health::*;
+pub use crate::inflight::*;
+pub use crate::landing::*;
+pub use crate::limits::*;
+pub use crate::localzone::*;
+pub use crate::log_level::*;
+#[cfg(feature = "tls")]
+pub use crate::ocsp::*;
+#[cfg(feature = "tls")]
+pub use crate::otlp::*;
+pub use crate::private_ptr::*;
+#[cfg(unix)]
+pub use crate::privdrop::*;
+pub use crate::profile::*;
+pub use crate::query_log::*;
+pub use crate::quota::*;
+pub use crate::response_headers::*;
+pub use crate::rewrite::*;
+pub use crate::routes::*;
+pub use crate::safe_search::*;
+pub use crate::stats::*;
+#[cfg(feature = "tls")]
+pub use crate::subscription::*;
+use crate::tcp::*;
+pub use crate::ttl_overrides::*;
+pub use crate::udp_pool::*;
+pub use crate::upstream::*;
+pub use crate::upstream_proxy::*;
+pub use crate::watcher::*;
 
 #[cfg(feature = "tls")]
 use crate::tls::*;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
 
 use futures::prelude::*;
 use futures::task::{Context, Poll};
+use hyper::header::{self, HeaderValue};
 use hyper::http;
 use hyper::server::conn::Http;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::runtime;
+use tracing::Instrument;
 
 #[derive(Clone, Debug)]
 pub struct DoH {
     pub globals: Arc<Globals>,
+    pub remote_addr: Option<SocketAddr>,
+    /// The additional endpoint a request was sent to, `None` for the main path
+    pub endpoint: Option<Arc<Endpoint>>,
+    /// The common name of the TLS client certificate
+    pub client_identity: Option<Arc<str>>,
+    /// The hash of the User-Agent header of a request, when clients are told apart by it
+    pub user_agent_hash: Option<u64>,
+    /// The hash of the bearer token of a request, when queries are counted against quotas
+    pub token_hash: Option<u64>,
+    /// The profile selected by the bearer token of a request
+    pub profile: Option<Arc<Profile>>,
+}
+
+impl DoH {
+    /// Returns a copy of the service for a connection from `remote_addr`,
+    /// to be used when the service is embedded into another server.
+    pub fn with_remote_addr(&self, remote_addr: Option<SocketAddr>) -> Self {
+        DoH {
+            globals: self.globals.clone(),
+            remote_addr,
+            endpoint: None,
+            client_identity: None,
+            user_agent_hash: None,
+            token_hash: None,
+            profile: None,
+        }
+    }
+
+    /// Returns a `MakeService` creating a `DoH` service for every connection
+    pub fn into_make_service(self) -> MakeDoH {
+        MakeDoH { doh: self }
+    }
+}
+
+/// Creates `DoH` services, so that they can be served by a `hyper::Server`
+#[derive(Clone, Debug)]
+pub struct MakeDoH {
+    doh: DoH,
+}
+
+impl<'t, T: ?Sized> hyper::service::Service<&'t T> for MakeDoH {
+    type Response = DoH;
+    type Error = std::convert::Infallible;
+    type Future = future::Ready<Result<DoH, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: &'t T) -> Self::Future {
+        future::ready(Ok(self.doh.clone()))
+    }
+}
+
+/// Sockets and certificates set up by `DoH::bind()`
+pub struct Listeners {
+    /// Listeners, and whether TLS should be used on them if it is configured
+    listeners: Vec<(std::net::TcpListener, bool)>,
+    admin_listener: Option<std::net::TcpListener>,
+    /// Sockets for plain DNS, over UDP and TCP
+    dns_listeners: Vec<(std::net::UdpSocket, std::net::TcpListener)>,
+    #[cfg(feature = "tls")]
+    dot_listeners: Vec<std::net::TcpListener>,
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<TlsAcceptor>,
+    #[cfg(feature = "tls")]
+    ocsp_stapler: Option<Arc<OcspStapler>>,
+    #[cfg(feature = "tls")]
+    client_ca: Option<Arc<ClientCa>>,
+}
+
+impl Listeners {
+    /// Splits the sockets into `count` shares, to be served by different runtimes.
+    /// Sockets are dealt in turn, so that with `reuseport` set to `count`, every share gets
+    /// one of the sockets bound to each listen address. The admin socket, and the tasks
+    /// refreshing the certificates, stay in the first share.
+    pub fn split(mut self, count: usize) -> Vec<Listeners> {
+        let mut shares: Vec<_> = (1..count.max(1))
+            .map(|_| Listeners {
+                listeners: vec![],
+                admin_listener: None,
+                dns_listeners: vec![],
+                #[cfg(feature = "tls")]
+                dot_listeners: vec![],
+                #[cfg(feature = "tls")]
+                tls_acceptor: self.tls_acceptor.clone(),
+                #[cfg(feature = "tls")]
+                ocsp_stapler: None,
+                #[cfg(feature = "tls")]
+                client_ca: None,
+            })
+            .collect();
+        if !shares.is_empty() {
+            let count = shares.len() + 1;
+            deal(&mut self.listeners, &mut shares, count, |share| {
+                &mut share.listeners
+            });
+            deal(&mut self.dns_listeners, &mut shares, count, |share| {
+                &mut share.dns_listeners
+            });
+            #[cfg(feature = "tls")]
+            deal(&mut self.dot_listeners, &mut shares, count, |share| {
+                &mut share.dot_listeners
+            });
+        }
+        shares.insert(0, self);
+        shares
+    }
+}
+
+/// Moves every `count`th socket of `sockets`, starting from the second one, to each share
+fn deal<T>(
+    sockets: &mut Vec<T>,
+    shares: &mut [Listeners],
+    count: usize,
+    field: impl Fn(&mut Listeners) -> &mut Vec<T>,
+) {
+    let mut kept = vec![];
+    for (i, socket) in sockets.drain(..).enumerate() {
+        match i % count {
+            0 => kept.push(socket),
+            n => field(&mut shares[n - 1]).push(socket),
+        }
+    }
+    *sockets = kept;
+}
+
+fn bind_listener(listen_address: &SocketAddr) -> Result<std::net::TcpListener, DoHError> {
+    let listener = std::net::TcpListener::bind(listen_address).map_err(DoHError::Io)?;
+    listener.set_nonblocking(true).map_err(DoHError::Io)?;
+    Ok(listener)
+}
+
+/// Protocol a query was received over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// DNS over HTTPS, or over plain HTTP
+    Doh,
+    /// DNS over TLS
+    Dot,
+    /// Plain DNS over UDP
+    Udp,
+    /// Plain DNS over TCP
+    Tcp,
+}
+
+struct DnsResponse {
+    packet: Vec<u8>,
+    ttl: u32,
+    /// Number of seconds the response spent in the cache
+    age: u32,
+    source: ResponseSource,
+}
+
+/// Where a response comes from, reported in the debug headers
+#[derive(Debug, Clone, Copy)]
+enum ResponseSource {
+    /// Answered by the server itself, such as a blocked name or a local record
+    Local,
+    Cache,
+    /// Received from an upstream server, after waiting for the given time
+    Upstream(Duration),
 }
 
 fn http_error(status_code: StatusCode) -> Result<Response<Body>, http::Error> {
@@ -37,6 +329,18 @@ fn http_error(status_code: StatusCode) -> Result<Response<Body>, http::Error> {
     Ok(response)
 }
 
+/// DNS messages are always sent as they are: a content-coding applied to them, by hyper or
+/// by a layer added later, would break clients and intermediaries that don't expect one
+fn refuse_content_coding(response: &mut Response<Body>) {
+    let headers = response.headers_mut();
+    let is_dns_message = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/dns-message");
+    if is_dns_message {
+        headers.remove(hyper::header::CONTENT_ENCODING);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct LocalExecutor {
     runtime_handle: runtime::Handle,
@@ -58,247 +362,2435 @@ where
     }
 }
 
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, http::Error>> + Send>>;
+
 impl hyper::service::Service<http::Request<Body>> for DoH {
     type Response = Response<Body>;
     type Error = http::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    type Future = ResponseFuture;
 
     fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let allowed_origin = match &self.globals.cors_origins {
+            Some(cors_origins) => cors_origins.allowed_origin(req.headers()),
+            None => None,
+        };
+        let globals = self.globals.clone();
+        Box::pin(self.route(req).map_ok(move |mut response| {
+            refuse_content_coding(&mut response);
+            globals.response_headers.apply(&mut response);
+            if let Some(allowed_origin) = allowed_origin {
+                add_cors_headers(&mut response, allowed_origin);
+            }
+            response
+        }))
+    }
+}
+
+impl DoH {
+    fn route(&mut self, req: Request<Body>) -> ResponseFuture {
         let globals = &self.globals;
-        if req.uri().path() != globals.path {
-            return Box::pin(async { http_error(StatusCode::NOT_FOUND) });
+        let path = Some(req.uri().path());
+        if path == globals.health_path.as_deref() {
+            return Box::pin(async { Self::health_response(StatusCode::OK, "OK") });
+        }
+        if path == globals.readiness_path.as_deref() {
+            let res = self.readiness_response();
+            return Box::pin(async { res });
+        }
+        let endpoint = if req.uri().path() == globals.path {
+            None
+        } else {
+            match globals
+                .endpoints
+                .iter()
+                .find(|endpoint| endpoint.path == req.uri().path())
+            {
+                Some(endpoint) => Some(endpoint.clone()),
+                None => match &globals.landing {
+                    Some(landing)
+                        if req.method() == Method::GET || req.method() == Method::HEAD =>
+                    {
+                        let res = landing.response();
+                        return Box::pin(async { res });
+                    }
+                    _ => return Box::pin(async { http_error(StatusCode::NOT_FOUND) }),
+                },
+            }
+        };
+        if let Some(landing) = &globals.landing {
+            if req.method() == Method::GET && is_browser_request(req.uri().query(), req.headers()) {
+                let res = landing.response();
+                return Box::pin(async { res });
+            }
+        }
+        // Preflight requests never include credentials
+        if req.method() == Method::OPTIONS && globals.cors_origins.is_some() {
+            let res = preflight_response(globals.disable_post);
+            return Box::pin(async { Ok(res) });
+        }
+        if !globals.auth_tokens.is_empty() && !globals.auth_tokens.is_authorized(req.headers()) {
+            return Box::pin(async { Self::unauthorized_response() });
+        }
+        let mut self_inner = self.clone();
+        self_inner.endpoint = endpoint;
+        if globals.client_key_policy.uses_user_agent() {
+            self_inner.user_agent_hash = req
+                .headers()
+                .get(hyper::header::USER_AGENT)
+                .map(|user_agent| user_agent_hash(user_agent.as_bytes()));
         }
-        let self_inner = self.clone();
-        match *req.method() {
-            Method::POST => Box::pin(async move { self_inner.serve_post(req).await }),
-            Method::GET => Box::pin(async move { self_inner.serve_get(req).await }),
+        if !globals.profiles.is_empty() {
+            self_inner.profile = globals.profiles.get(req.headers());
+        }
+        // Tokens can only tell clients apart if they are checked
+        if globals.quotas.is_some() && !globals.auth_tokens.is_empty() {
+            self_inner.token_hash =
+                request_token(req.headers()).map(|token| token_hash(token.as_bytes()));
+        }
+        let client_ip = self.client_ip(&req);
+        let span = tracing::info_span!(
+            "request",
+            otel.kind = "server",
+            http.method = %req.method(),
+            http.target = req.uri().path(),
+            client = client_ip.map(tracing::field::display),
+            http.status_code = tracing::field::Empty,
+        );
+        let res: ResponseFuture = match *req.method() {
+            Method::POST => Box::pin(async move { self_inner.serve_post(req, client_ip).await }),
+            Method::GET => Box::pin(async move { self_inner.serve_get(req, client_ip).await }),
             _ => Box::pin(async { http_error(StatusCode::METHOD_NOT_ALLOWED) }),
+        };
+        Box::pin(
+            res.inspect_ok(|response| {
+                tracing::Span::current().record("http.status_code", response.status().as_u16());
+            })
+            .instrument(span),
+        )
+    }
+
+    /// Returns the path of the endpoint. Queries that were not received over DoH use the main path.
+    fn path(&self) -> &str {
+        match &self.endpoint {
+            Some(endpoint) => &endpoint.path,
+            None => &self.globals.path,
         }
     }
-}
 
-impl DoH {
-    async fn serve_post(&self, req: Request<Body>) -> Result<Response<Body>, http::Error> {
+    /// Returns the upstream servers queries are forwarded to
+    fn upstreams(&self) -> &Upstreams {
+        match &self.endpoint {
+            Some(endpoint) => &endpoint.upstreams,
+            None => &self.globals.upstreams,
+        }
+    }
+
+    /// Returns the upstream servers of the domain of a query, if it has a routing rule.
+    /// Queries sent to additional endpoints are never routed.
+    fn routed_upstreams(&self, query: &[u8]) -> Option<&Upstreams> {
+        let upstream_routes = &self.globals.upstream_routes;
+        if self.endpoint.is_some() || upstream_routes.is_empty() {
+            return None;
+        }
+        upstream_routes.get(&dns::qname(query).ok()?)
+    }
+
+    /// Returns the maximum size of queries, and the maximum length of the `dns` parameter of GET queries
+    fn query_size_limits(&self) -> (usize, usize) {
+        match &self.endpoint {
+            Some(endpoint) => (endpoint.max_query_size, endpoint.max_query_param_len),
+            None => (
+                self.globals.max_query_size,
+                self.globals.max_query_param_len,
+            ),
+        }
+    }
+
+    /// Returns the minimum, maximum and error TTLs
+    fn ttls(&self) -> (u32, u32, u32) {
+        match &self.endpoint {
+            Some(endpoint) => (endpoint.min_ttl, endpoint.max_ttl, endpoint.err_ttl),
+            None => (
+                self.globals.min_ttl,
+                self.globals.max_ttl,
+                self.globals.err_ttl,
+            ),
+        }
+    }
+
+    /// Returns the TTLs overriding the ones of the path for the zone of the query, if any
+    fn ttl_override(&self, query: &[u8]) -> Option<&TtlOverride> {
+        let ttl_overrides = &self.globals.ttl_overrides;
+        if ttl_overrides.is_empty() {
+            return None;
+        }
+        ttl_overrides.get(&dns::qname(query).ok()?)
+    }
+
+    fn block_list(&self) -> Option<(&BlockList, BlockResponse)> {
+        if let Some(profile) = &self.profile {
+            if let Some(block_list) = &profile.block_list {
+                return Some((block_list, profile.block_response));
+            }
+        }
+        match &self.endpoint {
+            Some(endpoint) => endpoint
+                .block_list
+                .as_deref()
+                .map(|block_list| (block_list, endpoint.block_response)),
+            None => self
+                .globals
+                .block_list
+                .as_deref()
+                .map(|block_list| (block_list, self.globals.block_response)),
+        }
+    }
+
+    /// Returns the rewrite rules of the profile of the request, or the ones of the server
+    fn rewrite_rules(&self) -> Option<Arc<RewriteRules>> {
+        match self
+            .profile
+            .as_ref()
+            .and_then(|profile| profile.rewrite_rules.clone())
+        {
+            Some(rewrite_rules) => Some(rewrite_rules),
+            None => self.globals.rewrite_rules.as_ref().map(Reloadable::get),
+        }
+    }
+
+    /// Returns `true` if queries for search engines are answered with their safe search variants
+    fn safe_search(&self) -> bool {
+        match self
+            .profile
+            .as_ref()
+            .and_then(|profile| profile.safe_search)
+        {
+            Some(safe_search) => safe_search,
+            None => self.globals.safe_search,
+        }
+    }
+
+    /// Returns the key identifying the queries that can share a response.
+    /// Keys of additional endpoints are prefixed with their path, as they use different upstream servers.
+    fn cache_key(&self, query: &[u8]) -> Result<Vec<u8>, DoHError> {
+        let key = dns::cache_key(query).map_err(|_| DoHError::InvalidData)?;
+        let endpoint = match &self.endpoint {
+            None => return Ok(key),
+            Some(endpoint) => endpoint,
+        };
+        let mut prefixed_key = Vec::with_capacity(endpoint.path.len() + 2 + key.len());
+        prefixed_key.push(0xff);
+        prefixed_key.extend_from_slice(endpoint.path.as_bytes());
+        prefixed_key.push(0);
+        prefixed_key.extend_from_slice(&key);
+        Ok(prefixed_key)
+    }
+
+    /// Returns the address of the client, as reported by trusted proxies if the peer is one of them
+    fn client_ip(&self, req: &Request<Body>) -> Option<IpAddr> {
+        let peer_ip = self.remote_addr?.ip();
+        Some(
+            self.globals
+                .trusted_proxies
+                .client_ip(peer_ip, req.headers()),
+        )
+    }
+
+    fn health_response(status_code: StatusCode, body: &str) -> Result<Response<Body>, http::Error> {
+        Response::builder()
+            .status(status_code)
+            .header(hyper::header::CONTENT_TYPE, "text/plain")
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(Body::from(format!("{}\n", body)))
+    }
+
+    /// Rejects a request, describing why in a problem details body (RFC 7807) if enabled
+    fn reject(
+        &self,
+        client_ip: Option<IpAddr>,
+        status_code: StatusCode,
+        detail: &str,
+    ) -> Result<Response<Body>, http::Error> {
+        tracing::debug!(
+            client = client_ip.map(tracing::field::display),
+            status = status_code.as_u16(),
+            "Request rejected: {}",
+            detail
+        );
+        if !self.globals.problem_details {
+            return http_error(status_code);
+        }
+        let problem = serde_json::json!({
+            "type": "about:blank",
+            "title": status_code.canonical_reason(),
+            "status": status_code.as_u16(),
+            "detail": detail,
+        });
+        Response::builder()
+            .status(status_code)
+            .header(hyper::header::CONTENT_TYPE, "application/problem+json")
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(Body::from(problem.to_string()))
+    }
+
+    /// Rejects a query larger than the maximum query size. With HTTP/1, the connection is
+    /// closed, instead of reading the rest of the body.
+    fn reject_too_large(
+        &self,
+        client_ip: Option<IpAddr>,
+        close_connection: bool,
+    ) -> Result<Response<Body>, http::Error> {
+        let mut response = self.reject(
+            client_ip,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "The query is larger than the maximum query size",
+        )?;
+        if close_connection {
+            response.headers_mut().insert(
+                hyper::header::CONNECTION,
+                hyper::header::HeaderValue::from_static("close"),
+            );
+        }
+        Ok(response)
+    }
+
+    fn unauthorized_response() -> Result<Response<Body>, http::Error> {
+        Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(hyper::header::WWW_AUTHENTICATE, "Bearer")
+            .body(Body::empty())
+    }
+
+    /// Reports whether the upstream server answered a query recently
+    fn readiness_response(&self) -> Result<Response<Body>, http::Error> {
+        let globals = &self.globals;
+        let max_age = globals.upstream_probe_interval * 2 + globals.timeout;
+        #[cfg(feature = "tls")]
+        let upstreams_reachable = match &globals.upstream_doh {
+            Some(upstream_doh) => upstream_doh.is_reachable(max_age),
+            None => self.upstreams_reachable(max_age),
+        };
+        #[cfg(not(feature = "tls"))]
+        let upstreams_reachable = self.upstreams_reachable(max_age);
+        if globals.drain.is_triggered() {
+            Self::health_response(StatusCode::SERVICE_UNAVAILABLE, "Draining")
+        } else if upstreams_reachable {
+            Self::health_response(StatusCode::OK, "OK")
+        } else {
+            Self::health_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Upstream server unreachable",
+            )
+        }
+    }
+
+    fn upstreams_reachable(&self, max_age: Duration) -> bool {
+        let globals = &self.globals;
+        globals.upstreams.iter().any(|upstream| {
+            globals
+                .upstream_health
+                .is_reachable(&upstream.server_address(), max_age)
+        })
+    }
+
+    async fn serve_post(
+        &self,
+        req: Request<Body>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Response<Body>, http::Error> {
         if self.globals.disable_post {
-            return http_error(StatusCode::METHOD_NOT_ALLOWED);
+            return self.reject(
+                client_ip,
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST requests are disabled",
+            );
         }
-        if let Err(response) = Self::check_content_type(&req) {
-            return Ok(response);
+        if let Err((status_code, detail)) = Self::check_content_type(&req) {
+            return self.reject(client_ip, status_code, detail);
         }
-        match self.read_body_and_proxy(req.into_body()).await {
-            Err(e) => http_error(StatusCode::from(e)),
-            Ok(res) => Ok(res),
+        if let Err((status_code, detail)) = Self::check_accept(&req) {
+            return self.reject(client_ip, status_code, detail);
+        }
+        let content_length = req
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|content_length| content_length.to_str().ok()?.parse::<usize>().ok());
+        let close_connection = req.version() < http::Version::HTTP_2;
+        let (max_query_size, _) = self.query_size_limits();
+        if matches!(content_length, Some(content_length) if content_length > max_query_size) {
+            return self.reject_too_large(client_ip, close_connection);
+        }
+        let read_body = self
+            .read_body(req.into_body(), max_query_size)
+            .instrument(tracing::info_span!("read_body"));
+        match read_body.await {
+            Err(DoHError::TooLarge) => self.reject_too_large(client_ip, close_connection),
+            Err(e) => self.reject(client_ip, StatusCode::from(&e), &e.to_string()),
+            Ok(query) => self.serve_dns_query("POST", query, client_ip).await,
         }
     }
 
-    async fn serve_get(&self, req: Request<Body>) -> Result<Response<Body>, http::Error> {
+    async fn serve_get(
+        &self,
+        req: Request<Body>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Response<Body>, http::Error> {
+        if let Err((status_code, detail)) = Self::check_accept(&req) {
+            return self.reject(client_ip, status_code, detail);
+        }
         let query = req.uri().query().unwrap_or("");
         let mut question_str = None;
         for parts in query.split('&') {
-            let mut kv = parts.split('=');
+            let mut kv = parts.splitn(2, '=');
             if let Some(k) = kv.next() {
                 if k == DNS_QUERY_PARAM {
                     question_str = kv.next();
+                } else if k == CONTENT_TYPE_QUERY_PARAM {
+                    // Content type of the `dns` parameter, from early drafts of RFC 8484
+                    match kv.next() {
+                        None | Some("") => {}
+                        Some(ct) if ct.eq_ignore_ascii_case("application/dns-message") => {}
+                        Some(ct) if ct.eq_ignore_ascii_case("application%2Fdns-message") => {}
+                        Some(_) => {
+                            return self.reject(
+                                client_ip,
+                                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                                "The ct parameter must be application/dns-message",
+                            )
+                        }
+                    }
                 }
             }
         }
-        let question = match question_str.and_then(|question_str| {
-            base64::decode_config(question_str, base64::URL_SAFE_NO_PAD).ok()
-        }) {
-            Some(question) => question,
-            _ => {
-                return http_error(StatusCode::BAD_REQUEST);
+        let question_str = match question_str {
+            Some(question_str) => question_str,
+            None => {
+                return self.reject(client_ip, StatusCode::BAD_REQUEST, "Missing dns parameter")
+            }
+        };
+        let (_, max_query_param_len) = self.query_size_limits();
+        if question_str.len() > max_query_param_len {
+            return self.reject(
+                client_ip,
+                StatusCode::URI_TOO_LONG,
+                "The dns parameter is longer than the maximum query size",
+            );
+        }
+        // Padding characters must not be included (RFC 8484 section 6)
+        if question_str.ends_with('=') {
+            return self.reject(
+                client_ip,
+                StatusCode::BAD_REQUEST,
+                "The dns parameter must not include padding",
+            );
+        }
+        let question = match base64::decode_config(question_str, base64::URL_SAFE_NO_PAD) {
+            Ok(question) => question,
+            Err(e) => {
+                let detail = format!("The dns parameter is not valid base64url: {}", e);
+                return self.reject(client_ip, StatusCode::BAD_REQUEST, &detail);
+            }
+        };
+        self.serve_dns_query("GET", question, client_ip).await
+    }
+
+    async fn serve_dns_query(
+        &self,
+        method: &str,
+        query: Vec<u8>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Response<Body>, http::Error> {
+        match self
+            .resolve_query(Protocol::Doh, method, query, client_ip)
+            .await
+        {
+            Err(e) => self.reject(client_ip, StatusCode::from(&e), &e.to_string()),
+            Ok(dns_response) => {
+                let _span = tracing::info_span!("build_response").entered();
+                Ok(self.build_response(dns_response))
+            }
+        }
+    }
+
+    /// Answers a query, whatever the protocol it was received over.
+    /// The query and the response are logged and counted here.
+    async fn resolve_query(
+        &self,
+        protocol: Protocol,
+        method: &str,
+        query: Vec<u8>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<DnsResponse, DoHError> {
+        let log_queries = self
+            .profile
+            .as_ref()
+            .is_none_or(|profile| profile.log_queries);
+        let access_log = self.globals.access_log.as_ref().filter(|_| log_queries);
+        let query_log = self.globals.query_log.as_ref().filter(|_| log_queries);
+        let logged_query = if access_log.is_some() || query_log.is_some() {
+            Some(query.clone())
+        } else {
+            None
+        };
+        let start = Instant::now();
+        #[cfg(unix)]
+        let dnstap = self.globals.dnstap.as_ref().filter(|_| log_queries);
+        #[cfg(unix)]
+        let query_time = SystemTime::now();
+        #[cfg(unix)]
+        if let Some(dnstap) = dnstap {
+            dnstap.log_query(protocol, client_ip, query_time, &query);
+        }
+        #[cfg(not(unix))]
+        let _ = protocol;
+        let query_stats = self.globals.query_stats.as_ref();
+        if let Some(query_stats) = query_stats {
+            if let (Ok(qname), Ok((qtype, _))) = (dns::qname(&query), dns::qtype_qclass(&query)) {
+                query_stats.record_query(qname, qtype, self.path());
             }
+        }
+        let servfail_query = if self.globals.servfail_on_upstream_error {
+            Some(query.clone())
+        } else {
+            None
         };
-        match self.proxy(question).await {
-            Err(e) => http_error(StatusCode::from(e)),
-            Ok(res) => Ok(res),
+        let mut res = self.proxy(query, client_ip).await;
+        if let (Some(query), Err(e)) = (&servfail_query, &res) {
+            if e.is_upstream_failure() {
+                res = self.servfail_response(query);
+            }
+        }
+        // Padding is only useful on encrypted connections (RFC 8467)
+        if let (true, Protocol::Doh | Protocol::Dot, Ok(dns_response)) =
+            (self.globals.padding, protocol, &mut res)
+        {
+            dns::add_edns_padding(&mut dns_response.packet, RESPONSE_PADDING_BLOCK_SIZE).ok();
+        }
+        if let Some(query_stats) = query_stats {
+            query_stats.record_rcode(res.as_ref().ok().map(|res| dns::rcode(&res.packet)));
+        }
+        #[cfg(unix)]
+        if let (Some(dnstap), Ok(dns_response)) = (dnstap, &res) {
+            dnstap.log_response(protocol, client_ip, query_time, &dns_response.packet);
         }
+        if let Some(access_log) = access_log {
+            let (response, status) = match &res {
+                Ok(dns_response) => (Some(dns_response.packet.as_slice()), StatusCode::OK),
+                Err(e) => (None, e.status_code()),
+            };
+            access_log.log(&AccessLogEntry {
+                client_ip,
+                client_identity: self.client_identity.as_deref(),
+                method,
+                query: logged_query.as_deref(),
+                response,
+                status: status.as_u16(),
+                upstream_latency: Some(start.elapsed()),
+            });
+        }
+        if let (Some(query_log), Some(query)) = (query_log, &logged_query) {
+            if let (Ok(qname), Ok((qtype, _))) = (dns::qname(query), dns::qtype_qclass(query)) {
+                let (rcode, status) = match &res {
+                    Ok(dns_response) => (Some(dns::rcode(&dns_response.packet)), StatusCode::OK),
+                    Err(e) => (None, e.status_code()),
+                };
+                query_log.record(QueryLogEntry {
+                    time: SystemTime::now(),
+                    client_ip,
+                    client_identity: self.client_identity.clone(),
+                    method: method.to_string(),
+                    qname,
+                    qtype,
+                    rcode,
+                    status: status.as_u16(),
+                    latency: start.elapsed(),
+                });
+            }
+        }
+        res
     }
 
-    fn check_content_type(req: &Request<Body>) -> Result<(), Response<Body>> {
+    /// Synthesizes a `SERVFAIL` response for a query the upstream servers failed to answer,
+    /// that clients can cache for the error TTL
+    fn servfail_response(&self, query: &[u8]) -> Result<DnsResponse, DoHError> {
+        let (_, _, err_ttl) = self.ttls();
+        let packet = dns::synthesize_response(query, dns::DNS_RCODE_SERVFAIL, &[])
+            .map_err(|_| DoHError::InvalidData)?;
+        Ok(DnsResponse {
+            packet,
+            ttl: err_ttl,
+            age: 0,
+            source: ResponseSource::Local,
+        })
+    }
+
+    fn check_content_type(req: &Request<Body>) -> Result<(), (StatusCode, &'static str)> {
         let headers = req.headers();
         let content_type = match headers.get(hyper::header::CONTENT_TYPE) {
-            None => {
-                let response = Response::builder()
-                    .status(StatusCode::NOT_ACCEPTABLE)
-                    .body(Body::empty())
-                    .unwrap();
-                return Err(response);
-            }
+            None => return Err((StatusCode::NOT_ACCEPTABLE, "Missing Content-Type header")),
             Some(content_type) => content_type.to_str(),
         };
         let content_type = match content_type {
-            Err(_) => {
-                let response = Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Body::empty())
-                    .unwrap();
-                return Err(response);
-            }
+            Err(_) => return Err((StatusCode::BAD_REQUEST, "Invalid Content-Type header")),
             Ok(content_type) => content_type.to_lowercase(),
         };
         if content_type != "application/dns-message" {
-            let response = Response::builder()
-                .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
-                .body(Body::empty())
-                .unwrap();
-            return Err(response);
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "The content type must be application/dns-message",
+            ));
         }
         Ok(())
     }
 
-    async fn read_body_and_proxy(&self, mut body: Body) -> Result<Response<Body>, DoHError> {
-        let mut sum_size = 0;
-        let mut query = vec![];
-        while let Some(chunk) = body.next().await {
-            let chunk = chunk.map_err(|_| DoHError::TooLarge)?;
-            sum_size += chunk.len();
-            if sum_size >= MAX_DNS_QUESTION_LEN {
-                return Err(DoHError::TooLarge);
+    /// Checks that the client accepts DNS messages, if it sent an `Accept` header
+    fn check_accept(req: &Request<Body>) -> Result<(), (StatusCode, &'static str)> {
+        let mut accepts = req
+            .headers()
+            .get_all(hyper::header::ACCEPT)
+            .iter()
+            .peekable();
+        if accepts.peek().is_none() {
+            return Ok(());
+        }
+        for accept in accepts {
+            let accept = accept
+                .to_str()
+                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid Accept header"))?;
+            for media_range in accept.split(',') {
+                let mut params = media_range.split(';').map(str::trim);
+                let media_type = params.next().unwrap_or("").to_lowercase();
+                let rejected = params.any(|param| {
+                    matches!(param.strip_prefix("q=").map(str::parse::<f32>), Some(Ok(q)) if q <= 0.0)
+                });
+                if !rejected
+                    && matches!(
+                        media_type.as_str(),
+                        "application/dns-message" | "application/*" | "*/*"
+                    )
+                {
+                    return Ok(());
+                }
             }
-            query.extend(chunk);
         }
-        let response = self.proxy(query).await?;
-        Ok(response)
+        Err((
+            StatusCode::NOT_ACCEPTABLE,
+            "The Accept header doesn't include application/dns-message",
+        ))
+    }
+
+    async fn read_body(&self, mut body: Body, max_query_size: usize) -> Result<Vec<u8>, DoHError> {
+        // The body is assembled in a pooled buffer, that can hold queries of any size, and is
+        // only copied once it is complete
+        let read_body = async {
+            let mut query = self.globals.buffer_pool.get();
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.map_err(|_| DoHError::Incomplete)?;
+                if query.len() + chunk.len() > max_query_size || !query.extend_from_slice(&chunk) {
+                    return Err(DoHError::TooLarge);
+                }
+            }
+            Ok(query.to_vec())
+        };
+        tokio::time::timeout(self.globals.body_read_timeout, read_body)
+            .await
+            .map_err(|_| DoHError::RequestTimeout)?
     }
 
-    async fn proxy(&self, query: Vec<u8>) -> Result<Response<Body>, DoHError> {
-        let proxy_timeout = self.globals.timeout;
-        let timeout_res = tokio::time::timeout(proxy_timeout, self._proxy(query)).await;
-        timeout_res.map_err(|_| DoHError::UpstreamTimeout)?
+    async fn proxy(
+        &self,
+        query: Vec<u8>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<DnsResponse, DoHError> {
+        let globals = &self.globals;
+        let _client_query = match (client_ip, globals.max_client_queries) {
+            (Some(client_ip), Some(max_client_queries)) => {
+                let client_key = globals
+                    .client_key_policy
+                    .key(client_ip, self.user_agent_hash);
+                Some(
+                    globals
+                        .client_queries
+                        .acquire(client_key, max_client_queries)
+                        .ok_or(DoHError::TooManyRequests)?,
+                )
+            }
+            _ => None,
+        };
+        if let Some(quotas) = &globals.quotas {
+            let quota_key = match (self.token_hash, client_ip) {
+                (Some(token_hash), _) => Some(QuotaKey::Token(token_hash)),
+                (None, Some(client_ip)) => Some(QuotaKey::Ip(client_ip)),
+                (None, None) => None,
+            };
+            if let Some(quota_key) = quota_key {
+                if !quotas.consume(quota_key) {
+                    return Err(DoHError::QuotaExceeded);
+                }
+            }
+        }
+        // Every query has its own deadline, that doesn't depend on the connection it was sent on
+        let timeout_res =
+            tokio::time::timeout(globals.query_timeout, self._proxy(query, client_ip)).await;
+        timeout_res.map_err(|_| DoHError::QueryTimeout)?
     }
 
-    async fn _proxy(&self, mut query: Vec<u8>) -> Result<Response<Body>, DoHError> {
+    async fn _proxy(
+        &self,
+        mut query: Vec<u8>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<DnsResponse, DoHError> {
         if query.len() < MIN_DNS_PACKET_LEN {
             return Err(DoHError::Incomplete);
         }
-        let _ = dns::set_edns_max_payload_size(&mut query, MAX_DNS_RESPONSE_LEN as _);
-        let globals = &self.globals;
-        let mut socket = UdpSocket::bind(&globals.local_bind_address)
-            .await
-            .map_err(DoHError::Io)?;
-        let expected_server_address = globals.server_address;
-        let (min_ttl, max_ttl, err_ttl) = (globals.min_ttl, globals.max_ttl, globals.err_ttl);
-        socket
-            .send_to(&query, &globals.server_address)
-            .map_err(DoHError::Io)
-            .await?;
-        let mut packet = vec![0; MAX_DNS_RESPONSE_LEN];
-        let (len, response_server_address) =
-            socket.recv_from(&mut packet).map_err(DoHError::Io).await?;
-        if len < MIN_DNS_PACKET_LEN || expected_server_address != response_server_address {
-            return Err(DoHError::UpstreamIssue);
-        }
-        packet.truncate(len);
-        let ttl = if dns::is_recoverable_error(&packet) {
-            err_ttl
+        let filters = &self.globals.filters;
+        let filtered_response = filters.filter_query(&mut query, client_ip);
+        let filtered_query = if filters.response_filters.is_empty() {
+            None
         } else {
-            match dns::min_ttl(&packet, min_ttl, max_ttl, err_ttl) {
-                Err(_) => return Err(DoHError::UpstreamIssue),
-                Ok(ttl) => ttl,
-            }
+            Some(query.clone())
         };
-        dns::add_edns_padding(&mut packet)
-            .map_err(|_| DoHError::TooLarge)
-            .ok();
-        let packet_len = packet.len();
-        let response = Response::builder()
-            .header(hyper::header::CONTENT_LENGTH, packet_len)
-            .header(hyper::header::CONTENT_TYPE, "application/dns-message")
-            .header(
-                hyper::header::CACHE_CONTROL,
-                format!(
-                    "max-age={}, stale-if-error={}, stale-while-revalidate={}",
-                    ttl, STALE_IF_ERROR_SECS, STALE_WHILE_REVALIDATE_SECS
-                )
-                .as_str(),
+        let qtype = if self.globals.address_filters.is_empty() {
+            None
+        } else {
+            Some(
+                dns::qtype_qclass(&query)
+                    .map_err(|_| DoHError::InvalidData)?
+                    .0,
             )
-            .body(Body::from(packet))
-            .unwrap();
-        Ok(response)
+        };
+        let mut dns_response = match filtered_response {
+            Some(packet) => DnsResponse {
+                packet,
+                ttl: LOCAL_RESPONSE_TTL,
+                age: 0,
+                source: ResponseSource::Local,
+            },
+            None => match self.local_response(&query, client_ip)? {
+                Some(dns_response) => dns_response,
+                None => match self.safe_search_name(&query)? {
+                    Some(safe_name) => self.safe_search_response(query, safe_name).await?,
+                    None => self.upstream_exchange(query).await?,
+                },
+            },
+        };
+        if let Some(qtype) = qtype {
+            self.strip_addresses(qtype, client_ip, &mut dns_response)?;
+        }
+        if let Some(query) = &filtered_query {
+            filters.filter_response(query, &mut dns_response.packet);
+        }
+        Ok(dns_response)
     }
 
-    async fn client_serve<I>(self, stream: I, server: Http<LocalExecutor>)
-    where
-        I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-    {
-        let clients_count = self.globals.clients_count.clone();
-        if clients_count.increment() > self.globals.max_clients {
-            clients_count.decrement();
-            return;
-        }
-        self.globals.runtime_handle.clone().spawn(async move {
-            tokio::time::timeout(
-                self.globals.timeout + Duration::from_secs(1),
-                server.serve_connection(stream, self),
-            )
-            .await
-            .ok();
-            clients_count.decrement();
-        });
+    fn empty_response(query: &[u8], rcode: u8) -> Result<DnsResponse, DoHError> {
+        let packet =
+            dns::synthesize_response(query, rcode, &[]).map_err(|_| DoHError::InvalidData)?;
+        Ok(DnsResponse {
+            packet,
+            ttl: LOCAL_RESPONSE_TTL,
+            age: 0,
+            source: ResponseSource::Local,
+        })
     }
 
-    async fn start_without_tls(
-        self,
-        mut listener: TcpListener,
-        server: Http<LocalExecutor>,
+    /// Removes the A or AAAA records that the address filters hide from the client.
+    /// If they can't be removed from an answer to a query for that type, the answer is
+    /// replaced with an empty response.
+    fn strip_addresses(
+        &self,
+        qtype: u16,
+        client_ip: Option<IpAddr>,
+        dns_response: &mut DnsResponse,
     ) -> Result<(), DoHError> {
-        let listener_service = async {
-            while let Some(stream) = listener.incoming().next().await {
-                let stream = match stream {
-                    Ok(stream) => stream,
-                    Err(_) => continue,
-                };
-                self.clone().client_serve(stream, server.clone()).await;
+        let address_filters = &self.globals.address_filters;
+        for rr_type in IntoIterator::into_iter([dns::DNS_TYPE_A, dns::DNS_TYPE_AAAA]) {
+            if address_filters.matching(rr_type, client_ip) != Some(AddressFilterMode::Strip) {
+                continue;
             }
-            Ok(()) as Result<(), DoHError>
-        };
-        listener_service.await?;
+            let removed = dns::remove_records(&mut dns_response.packet, rr_type)
+                .map_err(|_| DoHError::InvalidData)?;
+            if !removed && qtype == rr_type {
+                let mut empty_response =
+                    Self::empty_response(&dns_response.packet, dns::rcode(&dns_response.packet))?;
+                empty_response.ttl = dns_response.ttl;
+                empty_response.age = dns_response.age;
+                empty_response.source = dns_response.source;
+                *dns_response = empty_response;
+            }
+        }
         Ok(())
     }
 
-    pub async fn entrypoint(self) -> Result<(), DoHError> {
-        let listen_address = self.globals.listen_address;
-        let listener = TcpListener::bind(&listen_address)
-            .await
-            .map_err(DoHError::Io)?;
-        let path = &self.globals.path;
-
-        #[cfg(feature = "tls")]
-        let tls_acceptor = match (&self.globals.tls_cert_path, &self.globals.tls_cert_key_path) {
-            (Some(tls_cert_path), Some(tls_cert_key_path)) => {
-                Some(create_tls_acceptor(tls_cert_path, tls_cert_key_path).unwrap())
+    fn local_response(
+        &self,
+        query: &[u8],
+        client_ip: Option<IpAddr>,
+    ) -> Result<Option<DnsResponse>, DoHError> {
+        let globals = &self.globals;
+        if !globals.denied_qtypes.is_empty() {
+            let (qtype, _) = dns::qtype_qclass(query).map_err(|_| DoHError::InvalidData)?;
+            if globals.denied_qtypes.contains(qtype) {
+                let rcode = match globals.denied_qtype_response {
+                    DeniedQtypeResponse::NotImp => dns::DNS_RCODE_NOTIMP,
+                    DeniedQtypeResponse::Empty => dns::DNS_RCODE_NOERROR,
+                };
+                return Self::empty_response(query, rcode).map(Some);
             }
-            _ => None,
-        };
-        #[cfg(not(feature = "tls"))]
-        let tls_acceptor: Option<()> = None;
-
-        if tls_acceptor.is_some() {
-            println!("Listening on https://{}{}", listen_address, path);
-        } else {
-            println!("Listening on http://{}{}", listen_address, path);
         }
-
-        let mut server = Http::new();
-        server.http1_keep_alive(self.globals.keepalive);
-        server.pipeline_flush(true);
-        let executor = LocalExecutor::new(self.globals.runtime_handle.clone());
-        let server = server.with_executor(executor);
-
-        #[cfg(feature = "tls")]
+        if !globals.address_filters.is_empty() {
+            let (qtype, _) = dns::qtype_qclass(query).map_err(|_| DoHError::InvalidData)?;
+            if globals.address_filters.matching(qtype, client_ip) == Some(AddressFilterMode::Empty)
+            {
+                return Self::empty_response(query, dns::DNS_RCODE_NOERROR).map(Some);
+            }
+        }
+        let block_list = self.block_list();
+        let rewrite_rules = self.rewrite_rules();
+        if globals.canary_domains.is_empty()
+            && globals.ddr.is_none()
+            && globals.local_zone.is_none()
+            && rewrite_rules.is_none()
+            && !globals.block_private_ptr
+            && !globals.loop_protection
+            && block_list.is_none()
         {
-            if let Some(tls_acceptor) = tls_acceptor {
-                self.start_with_tls(tls_acceptor, listener, server).await?;
-                return Ok(());
+            return Ok(None);
+        }
+        let qname = dns::qname(query).map_err(|_| DoHError::InvalidData)?;
+        if let Some(canary_response) = globals.canary_domains.get(&qname) {
+            return Self::canary_response(query, canary_response).map(Some);
+        }
+        if let Some(ddr) = &globals.ddr {
+            let (qtype, _) = dns::qtype_qclass(query).map_err(|_| DoHError::InvalidData)?;
+            if let Some(records) = ddr.lookup(&qname, qtype) {
+                return Self::ddr_response(query, qtype, records).map(Some);
             }
         }
-        self.start_without_tls(listener, server).await?;
-        Ok(())
+        if let Some(local_zone) = &globals.local_zone {
+            let local_zone = local_zone.get();
+            let (qtype, _) = dns::qtype_qclass(query).map_err(|_| DoHError::InvalidData)?;
+            if let Some(local_answer) = local_zone.lookup(&qname, qtype) {
+                return Self::local_zone_response(query, &local_answer).map(Some);
+            }
+        }
+        if globals.loop_protection {
+            if let Some(addresses) = self.loop_protected_addresses(&qname) {
+                return Self::loop_protection_response(query, &addresses).map(Some);
+            }
+        }
+        if let Some(rewrite_rules) = rewrite_rules {
+            if let Some(addresses) = rewrite_rules.lookup(&qname) {
+                return Self::rewritten_response(query, addresses, rewrite_rules.ttl()).map(Some);
+            }
+        }
+        if globals.block_private_ptr && is_private_reverse_name(&qname) {
+            let packet = dns::synthesize_response(query, dns::DNS_RCODE_NXDOMAIN, &[])
+                .map_err(|_| DoHError::InvalidData)?;
+            return Ok(Some(DnsResponse {
+                packet,
+                ttl: LOCAL_RESPONSE_TTL,
+                age: 0,
+                source: ResponseSource::Local,
+            }));
+        }
+        if let Some((block_list, block_response)) = block_list {
+            if let Some(action) = block_list.lookup(&qname) {
+                let allowed = globals
+                    .allow_list
+                    .as_ref()
+                    .is_some_and(|allow_list| allow_list.lookup(&qname).is_some());
+                if !allowed {
+                    return Self::blocked_response(query, &action, block_response).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the name of the safe search variant of the name of a query, if safe search is
+    /// enforced
+    fn safe_search_name(&self, query: &[u8]) -> Result<Option<&'static str>, DoHError> {
+        if !self.safe_search() {
+            return Ok(None);
+        }
+        let qname = dns::qname(query).map_err(|_| DoHError::InvalidData)?;
+        Ok(safe_search_name(&qname))
+    }
+
+    /// Answers with a CNAME record pointing to the safe search variant of a search engine,
+    /// followed by its addresses, resolved using the upstream servers
+    async fn safe_search_response(
+        &self,
+        query: Vec<u8>,
+        safe_name: &str,
+    ) -> Result<DnsResponse, DoHError> {
+        let (qtype, _) = dns::qtype_qclass(&query).map_err(|_| DoHError::InvalidData)?;
+        let target = dns::encode_name(safe_name).map_err(|_| DoHError::InvalidData)?;
+        let cname = dns::Answer {
+            name: None,
+            rr_type: dns::DNS_TYPE_CNAME,
+            ttl: LOCAL_RESPONSE_TTL,
+            rdata: &target,
+        };
+        // Other record types, such as HTTPS, could point clients to the unfiltered service
+        if qtype != dns::DNS_TYPE_A && qtype != dns::DNS_TYPE_AAAA {
+            let packet = dns::synthesize_response(&query, dns::DNS_RCODE_NOERROR, &[cname])
+                .map_err(|_| DoHError::InvalidData)?;
+            return Ok(DnsResponse {
+                packet,
+                ttl: LOCAL_RESPONSE_TTL,
+                age: 0,
+                source: ResponseSource::Local,
+            });
+        }
+        let safe_query = dns::with_qname(&query, &target).map_err(|_| DoHError::InvalidData)?;
+        let safe_response = self.upstream_exchange(safe_query).await?;
+        let records = dns::answer_records(&safe_response.packet, qtype)
+            .map_err(|_| DoHError::UpstreamIssue)?;
+        let mut answers = Vec::with_capacity(records.len() + 1);
+        answers.push(cname);
+        answers.extend(records.into_iter().map(|(ttl, rdata)| dns::Answer {
+            name: Some(&target),
+            rr_type: qtype,
+            ttl,
+            rdata,
+        }));
+        let packet = dns::synthesize_response(&query, dns::rcode(&safe_response.packet), &answers)
+            .map_err(|_| DoHError::UpstreamIssue)?;
+        Ok(DnsResponse {
+            packet,
+            ttl: safe_response.ttl,
+            age: safe_response.age,
+            source: safe_response.source,
+        })
+    }
+
+    fn canary_response(
+        query: &[u8],
+        canary_response: &CanaryResponse,
+    ) -> Result<DnsResponse, DoHError> {
+        let rcode = match canary_response {
+            CanaryResponse::NxDomain => dns::DNS_RCODE_NXDOMAIN,
+            CanaryResponse::NoData => dns::DNS_RCODE_NOERROR,
+            CanaryResponse::Addresses(addresses) => {
+                return Self::rewritten_response(query, addresses, LOCAL_RESPONSE_TTL)
+            }
+        };
+        let packet =
+            dns::synthesize_response(query, rcode, &[]).map_err(|_| DoHError::InvalidData)?;
+        Ok(DnsResponse {
+            packet,
+            ttl: LOCAL_RESPONSE_TTL,
+            age: 0,
+            source: ResponseSource::Local,
+        })
+    }
+
+    fn ddr_response(
+        query: &[u8],
+        qtype: u16,
+        records: &[Vec<u8>],
+    ) -> Result<DnsResponse, DoHError> {
+        let answers: Vec<_> = records
+            .iter()
+            .map(|rdata| dns::Answer {
+                name: None,
+                rr_type: qtype,
+                ttl: LOCAL_RESPONSE_TTL,
+                rdata,
+            })
+            .collect();
+        let packet = dns::synthesize_response(query, dns::DNS_RCODE_NOERROR, &answers)
+            .map_err(|_| DoHError::InvalidData)?;
+        Ok(DnsResponse {
+            packet,
+            ttl: LOCAL_RESPONSE_TTL,
+            age: 0,
+            source: ResponseSource::Local,
+        })
+    }
+
+    fn local_zone_response(
+        query: &[u8],
+        local_answer: &LocalAnswer<'_>,
+    ) -> Result<DnsResponse, DoHError> {
+        let answers: Vec<_> = local_answer
+            .iter()
+            .map(|&(name, record)| dns::Answer {
+                name: Some(name),
+                rr_type: record.rr_type,
+                ttl: record.ttl,
+                rdata: &record.rdata,
+            })
+            .collect();
+        let packet = dns::synthesize_response(query, dns::DNS_RCODE_NOERROR, &answers)
+            .map_err(|_| DoHError::InvalidData)?;
+        let ttl = answers
+            .iter()
+            .map(|answer| answer.ttl)
+            .min()
+            .unwrap_or(LOCAL_RESPONSE_TTL);
+        Ok(DnsResponse {
+            packet,
+            ttl,
+            age: 0,
+            source: ResponseSource::Local,
+        })
+    }
+
+    /// Answers with the addresses of the query family, or with an empty response
+    /// for other record types
+    fn rewritten_response(
+        query: &[u8],
+        addresses: &[IpAddr],
+        ttl: u32,
+    ) -> Result<DnsResponse, DoHError> {
+        let (qtype, _) = dns::qtype_qclass(query).map_err(|_| DoHError::InvalidData)?;
+        let rdatas: Vec<Vec<u8>> = addresses
+            .iter()
+            .filter_map(|address| match (address, qtype) {
+                (IpAddr::V4(ip), dns::DNS_TYPE_A) => Some(ip.octets().to_vec()),
+                (IpAddr::V6(ip), dns::DNS_TYPE_AAAA) => Some(ip.octets().to_vec()),
+                _ => None,
+            })
+            .collect();
+        let answers: Vec<_> = rdatas
+            .iter()
+            .map(|rdata| dns::Answer {
+                name: None,
+                rr_type: qtype,
+                ttl,
+                rdata,
+            })
+            .collect();
+        let packet = dns::synthesize_response(query, dns::DNS_RCODE_NOERROR, &answers)
+            .map_err(|_| DoHError::InvalidData)?;
+        Ok(DnsResponse {
+            packet,
+            ttl,
+            age: 0,
+            source: ResponseSource::Local,
+        })
+    }
+
+    /// Returns the addresses known for `qname` if the proxy depends on that name, so that
+    /// resolving it through the proxy could loop: the name of an upstream server, or the name
+    /// of the server itself
+    fn loop_protected_addresses(&self, qname: &str) -> Option<Vec<IpAddr>> {
+        let globals = &self.globals;
+        let is_qname = |name: &str| name.trim_end_matches('.').eq_ignore_ascii_case(qname);
+        let mut protected = false;
+        let mut addresses = vec![];
+        for upstream in globals.all_upstreams() {
+            let server_name = match &upstream.server_name {
+                Some(server_name) => server_name,
+                None => continue,
+            };
+            let host = server_name
+                .rsplit_once(':')
+                .map_or(server_name.as_str(), |(host, _)| host);
+            if is_qname(host.trim_start_matches('[').trim_end_matches(']')) {
+                protected = true;
+                addresses.push(upstream.server_address().ip());
+                addresses.extend(upstream.fallback_address().map(|address| address.ip()));
+            }
+        }
+        #[cfg(feature = "tls")]
+        {
+            let upstream_doh_host = globals
+                .upstream_doh
+                .as_ref()
+                .and_then(|upstream_doh| upstream_doh.url().host());
+            protected |= upstream_doh_host.is_some_and(is_qname);
+            protected |= globals.tls_self_signed.as_deref().is_some_and(is_qname);
+        }
+        protected |= globals
+            .ddr
+            .as_ref()
+            .is_some_and(|ddr| is_qname(ddr.hostname()));
+        if !protected {
+            return None;
+        }
+        addresses.sort_unstable();
+        addresses.dedup();
+        Some(addresses)
+    }
+
+    /// Answers a query for a name the proxy depends on with its known addresses, or refuses
+    /// it if there are none, so that the query isn't sent to an upstream server
+    fn loop_protection_response(
+        query: &[u8],
+        addresses: &[IpAddr],
+    ) -> Result<DnsResponse, DoHError> {
+        if !addresses.is_empty() {
+            return Self::rewritten_response(query, addresses, LOCAL_RESPONSE_TTL);
+        }
+        let packet = dns::synthesize_response(query, dns::DNS_RCODE_REFUSED, &[])
+            .map_err(|_| DoHError::InvalidData)?;
+        Ok(DnsResponse {
+            packet,
+            ttl: LOCAL_RESPONSE_TTL,
+            age: 0,
+            source: ResponseSource::Local,
+        })
+    }
+
+    /// Answers a query blocked by a rule, `block_response` being the response of rules
+    /// without an action
+    fn blocked_response(
+        query: &[u8],
+        action: &BlockAction,
+        block_response: BlockResponse,
+    ) -> Result<DnsResponse, DoHError> {
+        let action = match (action, block_response) {
+            (BlockAction::Default, BlockResponse::NxDomain) => &BlockAction::NxDomain,
+            (BlockAction::Default, BlockResponse::Null) => &BlockAction::Null,
+            (action, _) => action,
+        };
+        let packet = match action {
+            BlockAction::Redirect(addresses) => {
+                return Self::rewritten_response(query, addresses, LOCAL_RESPONSE_TTL)
+            }
+            BlockAction::Default | BlockAction::NxDomain => {
+                dns::synthesize_response(query, dns::DNS_RCODE_NXDOMAIN, &[])
+            }
+            BlockAction::Refused => dns::synthesize_response(query, dns::DNS_RCODE_REFUSED, &[]),
+            BlockAction::Null => {
+                let (qtype, _) = dns::qtype_qclass(query).map_err(|_| DoHError::InvalidData)?;
+                let null_ip: &[u8] = match qtype {
+                    dns::DNS_TYPE_A => &[0; 4],
+                    dns::DNS_TYPE_AAAA => &[0; 16],
+                    _ => &[],
+                };
+                if null_ip.is_empty() {
+                    dns::synthesize_response(query, dns::DNS_RCODE_NOERROR, &[])
+                } else {
+                    dns::synthesize_response(
+                        query,
+                        dns::DNS_RCODE_NOERROR,
+                        &[dns::Answer {
+                            name: None,
+                            rr_type: qtype,
+                            ttl: LOCAL_RESPONSE_TTL,
+                            rdata: null_ip,
+                        }],
+                    )
+                }
+            }
+        }
+        .map_err(|_| DoHError::InvalidData)?;
+        Ok(DnsResponse {
+            packet,
+            ttl: LOCAL_RESPONSE_TTL,
+            age: 0,
+            source: ResponseSource::Local,
+        })
+    }
+
+    async fn upstream_exchange(&self, mut query: Vec<u8>) -> Result<DnsResponse, DoHError> {
+        let globals = &self.globals;
+        let _ = dns::set_edns_max_payload_size(&mut query, globals.max_udp_size);
+        if !globals.edns_strip.is_empty() {
+            dns::retain_edns_options(&mut query, |code| !globals.edns_strip.strips(code))
+                .map_err(|_| DoHError::InvalidData)?;
+        }
+        match globals.ecs_policy {
+            EcsPolicy::Forward => {}
+            EcsPolicy::Strip => dns::remove_edns_option(&mut query, dns::DNS_OPTION_ECS)
+                .map_err(|_| DoHError::InvalidData)?,
+            EcsPolicy::Set { ip, prefix_len } => dns::set_edns_option(
+                &mut query,
+                dns::DNS_OPTION_ECS,
+                &dns::ecs_option_data(ip, prefix_len),
+            )
+            .map_err(|_| DoHError::InvalidData)?,
+        }
+        // The CD bit is kept as set by the client: validating clients get the records even if
+        // the upstream server couldn't validate them, and check them themselves
+        if globals.dnssec_validate {
+            dns::set_authentic_data(&mut query);
+            dns::set_dnssec_ok(&mut query).map_err(|_| DoHError::InvalidData)?;
+        }
+        if globals.padding {
+            dns::add_edns_padding(&mut query, QUERY_PADDING_BLOCK_SIZE)
+                .map_err(|_| DoHError::TooLarge)
+                .ok();
+        }
+        let cache_key = if globals.cache.is_some() || globals.negative_cache.is_some() {
+            Some(self.cache_key(&query)?)
+        } else {
+            None
+        };
+        if let Some(key) = &cache_key {
+            if let Some(negative_cache) = &globals.negative_cache {
+                if let Some((mut packet, ttl, age)) = negative_cache.get(key) {
+                    self.record_cache_lookup(true);
+                    dns::adapt_response(&mut packet, &query)
+                        .map_err(|_| DoHError::UpstreamIssue)?;
+                    dns::update_ttls(&mut packet, |record_ttl| record_ttl.saturating_sub(age))
+                        .map_err(|_| DoHError::UpstreamIssue)?;
+                    return Ok(DnsResponse {
+                        packet,
+                        ttl,
+                        age,
+                        source: ResponseSource::Cache,
+                    });
+                }
+            }
+            if let Some(cache) = &globals.cache {
+                let lookup = cache.lookup(key);
+                self.record_cache_lookup(!matches!(lookup, CacheLookup::Miss));
+                match lookup {
+                    CacheLookup::Miss => {}
+                    CacheLookup::Fresh(mut packet, ttl, age, prefetch) => {
+                        if prefetch {
+                            let doh = self.clone();
+                            let prefetch_query = query.clone();
+                            let key = key.clone();
+                            tokio::spawn(async move {
+                                let _ = doh.upstream_query(prefetch_query, Some(key)).await;
+                            });
+                        }
+                        dns::adapt_response(&mut packet, &query)
+                            .map_err(|_| DoHError::UpstreamIssue)?;
+                        // Clients would otherwise cache the response for longer than the
+                        // upstream server allowed
+                        dns::update_ttls(&mut packet, |record_ttl| record_ttl.saturating_sub(age))
+                            .map_err(|_| DoHError::UpstreamIssue)?;
+                        return Ok(DnsResponse {
+                            packet,
+                            ttl,
+                            age,
+                            source: ResponseSource::Cache,
+                        });
+                    }
+                    CacheLookup::Stale(packet, age, refresh) => {
+                        let key = key.clone();
+                        return self.serve_stale(query, key, packet, age, refresh).await;
+                    }
+                }
+            }
+        }
+        self.coalesced_upstream_query(query, cache_key).await
+    }
+
+    fn record_cache_lookup(&self, hit: bool) {
+        if let Some(query_stats) = &self.globals.query_stats {
+            query_stats.record_cache_lookup(hit);
+        }
+    }
+
+    /// Counts a query sent to an upstream server, if queries are broken down by upstream server
+    fn record_upstream_query(&self, query: &[u8], upstream: impl FnOnce() -> String) {
+        if let Some(query_stats) = &self.globals.query_stats {
+            if query_stats.counts_upstream_queries() {
+                let qtype = dns::qtype_qclass(query).map_or(0, |(qtype, _)| qtype);
+                query_stats.record_upstream_query(self.path(), qtype, &upstream());
+            }
+        }
+    }
+
+    /// Sends a query to the upstream server, unless an identical query is already waiting for
+    /// a response, in which case that response is shared.
+    async fn coalesced_upstream_query(
+        &self,
+        query: Vec<u8>,
+        cache_key: Option<Vec<u8>>,
+    ) -> Result<DnsResponse, DoHError> {
+        let key = match &cache_key {
+            Some(key) => key.clone(),
+            None => self.cache_key(&query)?,
+        };
+        let start = Instant::now();
+        let rx = match self.globals.inflight_queries.join(&key) {
+            Inflight::Follower(rx) => rx,
+            Inflight::Leader(rx) => {
+                // The query is sent from a separate task, so that the other clients still get
+                // a response if the client that sent it first goes away.
+                let doh = self.clone();
+                let leader_query = query.clone();
+                tokio::spawn(
+                    async move {
+                        let res = doh.upstream_query(leader_query, cache_key).await;
+                        let response = res.ok().map(|res| (res.packet, res.ttl));
+                        doh.globals.inflight_queries.complete(&key, response);
+                    }
+                    .in_current_span(),
+                );
+                rx
+            }
+        };
+        match rx.await {
+            Ok(Some((mut packet, ttl))) => {
+                dns::adapt_response(&mut packet, &query).map_err(|_| DoHError::UpstreamIssue)?;
+                Ok(DnsResponse {
+                    packet,
+                    ttl,
+                    age: 0,
+                    source: ResponseSource::Upstream(start.elapsed()),
+                })
+            }
+            _ => Err(DoHError::UpstreamIssue),
+        }
+    }
+
+    /// Sends a query to the upstream server, and caches the response if `cache_key` is set
+    async fn upstream_query(
+        &self,
+        query: Vec<u8>,
+        cache_key: Option<Vec<u8>>,
+    ) -> Result<DnsResponse, DoHError> {
+        let globals = &self.globals;
+        let (min_ttl, max_ttl, err_ttl) = self.ttls();
+        let (min_ttl, max_ttl) = match self.ttl_override(&query) {
+            Some(ttl_override) => ttl_override.apply(min_ttl, max_ttl),
+            None => (min_ttl, max_ttl),
+        };
+        let upstreams = self
+            .routed_upstreams(&query)
+            .unwrap_or_else(|| self.upstreams())
+            .order();
+        let start = Instant::now();
+        let mut packet = self
+            .exchange_with_retries(&upstreams, &query)
+            .instrument(tracing::info_span!(
+                "upstream_exchange",
+                otel.kind = "client"
+            ))
+            .await?;
+        let rtt = start.elapsed();
+        if globals.ecs_policy != EcsPolicy::Forward {
+            dns::remove_edns_option(&mut packet, dns::DNS_OPTION_ECS)
+                .map_err(|_| DoHError::UpstreamIssue)?;
+        }
+        if globals.dnssec_validate
+            && dns::is_unauthenticated(&packet, &query).map_err(|_| DoHError::UpstreamIssue)?
+        {
+            packet = dns::synthesize_response(&query, dns::DNS_RCODE_SERVFAIL, &[])
+                .map_err(|_| DoHError::UpstreamIssue)?;
+        }
+        if let Some(dns64_prefix) = &globals.dns64_prefix {
+            if let Some(synthesized) = self
+                .dns64_response(dns64_prefix, &upstreams, &query, &packet)
+                .await?
+            {
+                packet = synthesized;
+            }
+        }
+        let ttl = if dns::is_recoverable_error(&packet) {
+            err_ttl
+        } else {
+            match dns::min_ttl(&packet, min_ttl, max_ttl, err_ttl) {
+                Err(_) => return Err(DoHError::UpstreamIssue),
+                Ok(ttl) => ttl,
+            }
+        };
+        if let Some(key) = cache_key {
+            if dns::is_negative_response(&packet) {
+                if let Some(negative_cache) = &globals.negative_cache {
+                    negative_cache.insert(key, packet.clone(), err_ttl);
+                }
+            } else if let Some(cache) = &globals.cache {
+                if !dns::is_truncated(&packet) {
+                    cache.insert(key, packet.clone(), ttl);
+                }
+            }
+        }
+        Ok(DnsResponse {
+            packet,
+            ttl,
+            age: 0,
+            source: ResponseSource::Upstream(rtt),
+        })
+    }
+
+    /// Sends a query to the upstream servers, retransmitting it to the next server,
+    /// if there are several, after a timeout
+    async fn exchange_with_retries(
+        &self,
+        upstreams: &[&Upstream],
+        query: &[u8],
+    ) -> Result<Vec<u8>, DoHError> {
+        #[cfg(feature = "tls")]
+        {
+            if let (Some(upstream_doh), None) = (&self.globals.upstream_doh, &self.endpoint) {
+                if self.routed_upstreams(query).is_none() {
+                    return self.upstream_doh_exchange(upstream_doh, query).await;
+                }
+            }
+        }
+        let mut retries = 0;
+        loop {
+            let upstream = upstreams[retries % upstreams.len()];
+            let res = if self.globals.upstream_cookies {
+                self.cookie_exchange_with(upstream, query).await
+            } else {
+                self.upstream_exchange_with(upstream, query).await
+            };
+            match res {
+                Err(DoHError::UpstreamTimeout) if retries < self.globals.upstream_retries => {
+                    retries += 1
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Sends a query to the upstream DoH server, and sends it again after a timeout
+    #[cfg(feature = "tls")]
+    async fn upstream_doh_exchange(
+        &self,
+        upstream_doh: &DoHUpstream,
+        query: &[u8],
+    ) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        self.record_upstream_query(query, || upstream_doh.url().to_string());
+        let mut retries = 0;
+        loop {
+            match tokio::time::timeout(globals.upstream_timeout, upstream_doh.exchange(query)).await
+            {
+                Ok(res) => return res,
+                Err(_) if retries < globals.upstream_retries => retries += 1,
+                Err(_) => {
+                    tracing::debug!(
+                        upstream = %upstream_doh.url(),
+                        "No response from the upstream DoH server"
+                    );
+                    return Err(DoHError::UpstreamTimeout);
+                }
+            }
+        }
+    }
+
+    /// Synthesizes `AAAA` records from the `A` records of a name that has no `AAAA` records,
+    /// unless the client disabled DNSSEC validation (RFC 6147)
+    async fn dns64_response(
+        &self,
+        dns64_prefix: &Dns64Prefix,
+        upstreams: &[&Upstream],
+        query: &[u8],
+        response: &[u8],
+    ) -> Result<Option<Vec<u8>>, DoHError> {
+        let (qtype, _) = dns::qtype_qclass(query).map_err(|_| DoHError::InvalidData)?;
+        if qtype != dns::DNS_TYPE_AAAA
+            || dns::rcode(response) != dns::DNS_RCODE_NOERROR
+            || dns::is_checking_disabled(query)
+            || !dns::answer_records(response, dns::DNS_TYPE_AAAA)
+                .map_err(|_| DoHError::UpstreamIssue)?
+                .is_empty()
+        {
+            return Ok(None);
+        }
+        let mut a_query = query.to_vec();
+        dns::set_qtype(&mut a_query, dns::DNS_TYPE_A).map_err(|_| DoHError::InvalidData)?;
+        let a_response = self.exchange_with_retries(upstreams, &a_query).await?;
+        if dns::rcode(&a_response) != dns::DNS_RCODE_NOERROR
+            || (self.globals.dnssec_validate
+                && dns::is_unauthenticated(&a_response, &a_query)
+                    .map_err(|_| DoHError::UpstreamIssue)?)
+        {
+            return Ok(None);
+        }
+        let addresses: Vec<_> = dns::answer_records(&a_response, dns::DNS_TYPE_A)
+            .map_err(|_| DoHError::UpstreamIssue)?
+            .into_iter()
+            .filter(|(_, rdata)| rdata.len() == 4)
+            .map(|(ttl, rdata)| {
+                let ipv4 = Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]);
+                (ttl, dns64_prefix.embed(ipv4).octets())
+            })
+            .collect();
+        if addresses.is_empty() {
+            return Ok(None);
+        }
+        let answers: Vec<_> = addresses
+            .iter()
+            .map(|(ttl, ipv6)| dns::Answer {
+                name: None,
+                rr_type: dns::DNS_TYPE_AAAA,
+                ttl: *ttl,
+                rdata: ipv6,
+            })
+            .collect();
+        let packet = dns::synthesize_response(query, dns::DNS_RCODE_NOERROR, &answers)
+            .map_err(|_| DoHError::UpstreamIssue)?;
+        Ok(Some(packet))
+    }
+
+    /// Refreshes an expired cache entry in the background, and serves the stale response
+    /// if a fresh one cannot be received in time (RFC 8767)
+    async fn serve_stale(
+        &self,
+        query: Vec<u8>,
+        cache_key: Vec<u8>,
+        mut stale_packet: Vec<u8>,
+        age: u32,
+        refresh: bool,
+    ) -> Result<DnsResponse, DoHError> {
+        if refresh {
+            let doh = self.clone();
+            let refresh_query = query.clone();
+            let refresh = async move { doh.upstream_query(refresh_query, Some(cache_key)).await };
+            let mut refresh = tokio::spawn(refresh);
+            let refresh_timeout = Duration::from_millis(STALE_REFRESH_TIMEOUT_MS);
+            if let Ok(Ok(Ok(dns_response))) =
+                tokio::time::timeout(refresh_timeout, &mut refresh).await
+            {
+                if !dns::is_recoverable_error(&dns_response.packet) {
+                    return Ok(dns_response);
+                }
+            }
+        }
+        dns::adapt_response(&mut stale_packet, &query).map_err(|_| DoHError::UpstreamIssue)?;
+        dns::update_ttls(&mut stale_packet, |_| STALE_ANSWER_TTL)
+            .map_err(|_| DoHError::UpstreamIssue)?;
+        Ok(DnsResponse {
+            packet: stale_packet,
+            ttl: STALE_ANSWER_TTL,
+            age,
+            source: ResponseSource::Cache,
+        })
+    }
+
+    /// Sends a query to an upstream server, and waits for its response
+    async fn upstream_exchange_with(
+        &self,
+        upstream: &Upstream,
+        query: &[u8],
+    ) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        let start = Instant::now();
+        self.record_upstream_query(query, || {
+            upstream
+                .server_name
+                .clone()
+                .unwrap_or_else(|| upstream.server_address().to_string())
+        });
+        let reduced_query;
+        let query = if upstream.is_udp_size_reduced() {
+            let mut query = query.to_vec();
+            dns::set_edns_max_payload_size(&mut query, UPSTREAM_REDUCED_UDP_SIZE)
+                .map_err(|_| DoHError::InvalidData)?;
+            reduced_query = query;
+            &reduced_query[..]
+        } else {
+            query
+        };
+        let exchange = async {
+            if let Some(upstream_proxy) = &globals.upstream_proxy {
+                let mut stream = upstream_proxy.connect(upstream.server_address()).await?;
+                return tcp_exchange(&mut stream, query, globals.response_checks(false)).await;
+            }
+            let (packet, address) = match upstream.race_addresses() {
+                (address, None) => (
+                    self.udp_exchange_with(upstream, address, query).await?,
+                    address,
+                ),
+                (first_address, Some(second_address)) => {
+                    // Happy eyeballs: the other address family is tried if the first address
+                    // doesn't answer quickly enough, and the first response wins (RFC 8305)
+                    let first = self.udp_exchange_with(upstream, first_address, query);
+                    let second = async {
+                        tokio::time::delay_for(Duration::from_millis(HAPPY_EYEBALLS_DELAY_MS))
+                            .await;
+                        self.udp_exchange_with(upstream, second_address, query)
+                            .await
+                    };
+                    futures::pin_mut!(first, second);
+                    let (packet, address) = match future::select(first, second).await {
+                        future::Either::Left((Ok(packet), _)) => (packet, first_address),
+                        future::Either::Right((Ok(packet), _)) => (packet, second_address),
+                        future::Either::Left((Err(_), second)) => (second.await?, second_address),
+                        future::Either::Right((Err(_), first)) => (first.await?, first_address),
+                    };
+                    upstream.record_answering_address(address);
+                    (packet, address)
+                }
+            };
+            if !dns::is_truncated(&packet) {
+                return Ok(packet);
+            }
+            // The response didn't fit in the advertised UDP payload size
+            tracing::debug!(
+                upstream = %address,
+                "Truncated response, query sent again over TCP"
+            );
+            let mut stream = egress::connect_tcp(
+                upstream.local_bind_address(&address),
+                globals.bind_device.as_deref(),
+                address,
+            )
+            .await
+            .map_err(DoHError::Io)?;
+            tcp_exchange(&mut stream, query, globals.response_checks(false)).await
+        };
+        match tokio::time::timeout(globals.upstream_timeout, exchange).await {
+            Ok(res) => {
+                let packet = res?;
+                let rtt = start.elapsed();
+                tracing::debug!(
+                    upstream = %upstream.server_address(),
+                    qname = %dns::qname(query).unwrap_or_default(),
+                    qtype = dns::qtype_qclass(query).map_or(0, |(qtype, _)| qtype),
+                    rcode = dns::rcode(&packet),
+                    rtt_ms = rtt.as_millis() as u64,
+                    "Query forwarded"
+                );
+                upstream.record_response(rtt);
+                globals
+                    .upstream_health
+                    .record_response(upstream.server_address());
+                Ok(packet)
+            }
+            Err(_) => {
+                upstream.record_timeout();
+                tracing::debug!(
+                    upstream = %upstream.server_address(),
+                    "No response from the upstream server"
+                );
+                // Large responses may be fragmented, and fragments dropped along the way
+                // (DNS Flag Day 2020): the next queries advertise a smaller payload size
+                if globals.max_udp_size > UPSTREAM_REDUCED_UDP_SIZE
+                    && globals.upstream_proxy.is_none()
+                    && upstream.reduce_udp_size()
+                {
+                    tracing::debug!(
+                        upstream = %upstream.server_address(),
+                        max_udp_size = UPSTREAM_REDUCED_UDP_SIZE,
+                        "Reduced the UDP payload size advertised to the upstream server"
+                    );
+                }
+                Err(DoHError::UpstreamTimeout)
+            }
+        }
+    }
+
+    /// Sends a query with a DNS cookie (RFC 7873), and sends it again if the server asks for
+    /// its new server cookie to be used. The cookie is removed from the response.
+    async fn cookie_exchange_with(
+        &self,
+        upstream: &Upstream,
+        query: &[u8],
+    ) -> Result<Vec<u8>, DoHError> {
+        let mut query = query.to_vec();
+        let mut resent = false;
+        loop {
+            dns::set_edns_option(&mut query, dns::DNS_OPTION_COOKIE, &upstream.cookie())
+                .map_err(|_| DoHError::InvalidData)?;
+            let mut packet = self.upstream_exchange_with(upstream, &query).await?;
+            let cookie = dns::edns_option(&packet, dns::DNS_OPTION_COOKIE)
+                .map_err(|_| DoHError::UpstreamIssue)?;
+            if let Some(cookie) = cookie {
+                if !upstream.record_cookie(&cookie) {
+                    tracing::debug!(
+                        upstream = %upstream.server_address(),
+                        "Response with a client cookie that doesn't match, ignored"
+                    );
+                    return Err(DoHError::UpstreamIssue);
+                }
+                dns::remove_edns_option(&mut packet, dns::DNS_OPTION_COOKIE)
+                    .map_err(|_| DoHError::UpstreamIssue)?;
+                if !resent && matches!(dns::extended_rcode(&packet), Ok(dns::DNS_RCODE_BADCOOKIE)) {
+                    resent = true;
+                    continue;
+                }
+            }
+            return Ok(packet);
+        }
+    }
+
+    /// Sends a query to one of the addresses of an upstream server
+    async fn udp_exchange_with(
+        &self,
+        upstream: &Upstream,
+        server_address: SocketAddr,
+        query: &[u8],
+    ) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        let local_bind_address = upstream.local_bind_address(&server_address);
+        let randomized_query;
+        let sent_query = if globals.qname_randomization {
+            let mut query = query.to_vec();
+            dns::randomize_qname_case(&mut query).map_err(|_| DoHError::InvalidData)?;
+            randomized_query = query;
+            &randomized_query[..]
+        } else {
+            query
+        };
+        let mut response = match &globals.udp_pool {
+            Some(udp_pool) => {
+                udp_pool
+                    .exchange(
+                        sent_query,
+                        local_bind_address,
+                        globals.bind_device.as_deref(),
+                        server_address,
+                        globals.response_checks(true),
+                    )
+                    .await?
+            }
+            None => {
+                Self::udp_exchange(
+                    &globals.buffer_pool,
+                    local_bind_address,
+                    globals.bind_device.as_deref(),
+                    server_address,
+                    sent_query,
+                    globals.response_checks(true),
+                )
+                .await?
+            }
+        };
+        if globals.qname_randomization {
+            dns::adapt_response(&mut response, query).map_err(|_| DoHError::UpstreamIssue)?;
+        }
+        Ok(response)
+    }
+
+    async fn udp_exchange(
+        buffer_pool: &BufferPool,
+        local_bind_address: SocketAddr,
+        bind_device: Option<&str>,
+        expected_server_address: SocketAddr,
+        query: &[u8],
+        checks: dns::ResponseChecks,
+    ) -> Result<Vec<u8>, DoHError> {
+        let mut socket = egress::bind_udp(local_bind_address, bind_device)
+            .and_then(UdpSocket::from_std)
+            .map_err(DoHError::Io)?;
+        socket
+            .send_to(query, &expected_server_address)
+            .map_err(DoHError::Io)
+            .await?;
+        let mut packet = buffer_pool.get();
+        loop {
+            let (len, response_server_address) = socket
+                .recv_from(packet.recv_buffer())
+                .map_err(DoHError::Io)
+                .await?;
+            packet.set_len(len);
+            if len >= MIN_DNS_PACKET_LEN
+                && expected_server_address == response_server_address
+                && checks.accept(&packet, query)
+            {
+                return Ok(response_packet(&packet));
+            }
+        }
+    }
+
+    fn build_response(&self, dns_response: DnsResponse) -> Response<Body> {
+        let DnsResponse {
+            packet,
+            ttl,
+            age,
+            source,
+        } = dns_response;
+        let expires = SystemTime::now() + Duration::from_secs(ttl as u64);
+        let rcode = dns::rcode(&packet);
+        // The packet becomes the body without being copied, and the headers are inserted as
+        // values that don't have to be parsed again
+        let packet_len = packet.len();
+        let mut response = Response::new(Body::from(packet));
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(packet_len));
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/dns-message"),
+        );
+        // HTTP caches subtract the age from `max-age`, so it covers the time already spent in
+        // the cache in addition to the remaining TTL. `no-transform` keeps proxies from
+        // recompressing or otherwise altering the DNS message.
+        let cache_control = format!(
+            "max-age={}, no-transform, stale-if-error={}, stale-while-revalidate={}",
+            ttl.saturating_add(age),
+            STALE_IF_ERROR_SECS,
+            STALE_WHILE_REVALIDATE_SECS
+        );
+        if let Ok(cache_control) = HeaderValue::try_from(cache_control) {
+            headers.insert(header::CACHE_CONTROL, cache_control);
+        }
+        if let Ok(expires) = HeaderValue::try_from(httpdate::fmt_http_date(expires)) {
+            headers.insert(header::EXPIRES, expires);
+        }
+        if age > 0 {
+            headers.insert(header::AGE, HeaderValue::from(age));
+        }
+        if self.globals.debug_headers {
+            if let Ok(rcode_name) = HeaderValue::try_from(dns::rcode_name(rcode)) {
+                headers.insert("X-DNS-Rcode", rcode_name);
+            }
+            match source {
+                ResponseSource::Local => {}
+                ResponseSource::Cache => {
+                    headers.insert("X-Cache", HeaderValue::from_static("HIT"));
+                }
+                ResponseSource::Upstream(rtt) => {
+                    headers.insert("X-Cache", HeaderValue::from_static("MISS"));
+                    headers.insert(
+                        "X-Upstream-RTT-ms",
+                        HeaderValue::from(rtt.as_millis() as u64),
+                    );
+                }
+            }
+        }
+        response
+    }
+
+    async fn client_serve<I>(
+        mut self,
+        stream: I,
+        remote_addr: Option<SocketAddr>,
+        server: Http<LocalExecutor>,
+        client_slot: ClientSlot,
+    ) where
+        I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        self.remote_addr = remote_addr;
+        tracing::trace!(
+            client = remote_addr.map(tracing::field::display),
+            "Connection accepted"
+        );
+        let globals = self.globals.clone();
+        let activity = Arc::new(ConnectionActivity::default());
+        let max_requests = globals.max_requests_per_connection.unwrap_or(usize::MAX);
+        let service = {
+            let activity = activity.clone();
+            hyper::service::service_fn(move |req: Request<Body>| {
+                let request = activity.start_request();
+                // With HTTP/1, the connection may be closed as soon as the last response is sent
+                let last_request =
+                    request.served >= max_requests && req.version() < http::Version::HTTP_2;
+                let mut doh = self.clone();
+                hyper::service::Service::call(&mut doh, req).map(move |res| {
+                    drop(request);
+                    res.map(|mut response| {
+                        if last_request {
+                            response.headers_mut().insert(
+                                hyper::header::CONNECTION,
+                                hyper::header::HeaderValue::from_static("close"),
+                            );
+                        }
+                        response
+                    })
+                })
+            })
+        };
+        tokio::spawn(async move {
+            let connection = server.serve_connection(stream, service);
+            futures::pin_mut!(connection);
+            let idle_timeout = Box::pin(
+                activity
+                    .clone()
+                    .idle_timeout(globals.header_read_timeout, globals.http_idle_timeout),
+            );
+            let lifetime = future::select(
+                future::select(
+                    tokio::time::delay_for(globals.max_connection_lifetime),
+                    Box::pin(activity.served(max_requests)),
+                ),
+                Box::pin(globals.shutdown.wait()),
+            );
+            let deadlines = future::select(idle_timeout, lifetime);
+            // Idle connections are dropped right away, but when the lifetime is over, after the
+            // maximum number of requests or when the server is shutting down, the requests being
+            // processed are answered before the connection is closed.
+            if let future::Either::Right((future::Either::Right(_), _)) =
+                future::select(connection.as_mut(), deadlines).await
+            {
+                connection.as_mut().graceful_shutdown();
+                let _ = tokio::time::timeout(globals.timeout, connection).await;
+            }
+            drop(client_slot);
+        });
+    }
+
+    /// Enables TCP keepalive on an accepted connection, if it was configured
+    pub(crate) fn set_tcp_keepalive(&self, stream: &TcpStream) {
+        if let Some(tcp_keepalive) = self.globals.tcp_keepalive {
+            if let Err(e) = stream.set_keepalive(Some(tcp_keepalive)) {
+                tracing::debug!(error = %e, "Unable to enable TCP keepalive");
+            }
+        }
+    }
+
+    async fn start_without_tls(
+        self,
+        mut listener: TcpListener,
+        server: Http<LocalExecutor>,
+    ) -> Result<(), DoHError> {
+        let listener_service = async {
+            loop {
+                // Connections are left in the queue of the socket while there are too many clients
+                self.globals.clients_count.wait_for_slot().await;
+                let stream = match listener.incoming().next().await {
+                    None => break,
+                    Some(Err(_)) => continue,
+                    Some(Ok(stream)) => stream,
+                };
+                let client_slot = self.globals.clients_count.acquire().await;
+                let remote_addr = stream.peer_addr().ok();
+                self.set_tcp_keepalive(&stream);
+                self.clone()
+                    .client_serve(stream, remote_addr, server.clone(), client_slot)
+                    .await;
+            }
+            Ok(()) as Result<(), DoHError>
+        };
+        listener_service.await?;
+        Ok(())
+    }
+
+    /// Returns the block lists of the main path, of the other paths and of the profiles
+    fn block_lists(&self) -> Vec<Arc<BlockList>> {
+        let globals = &self.globals;
+        let endpoint_block_lists = globals
+            .endpoints
+            .iter()
+            .filter_map(|endpoint| endpoint.block_list.as_ref());
+        let profile_block_lists = globals
+            .profiles
+            .iter()
+            .filter_map(|(profile, _)| profile.block_list.as_ref());
+        let mut block_lists: Vec<Arc<BlockList>> = vec![];
+        for block_list in globals
+            .block_list
+            .iter()
+            .chain(endpoint_block_lists)
+            .chain(profile_block_lists)
+        {
+            if !block_lists
+                .iter()
+                .any(|previous| Arc::ptr_eq(previous, block_list))
+            {
+                block_lists.push(block_list.clone());
+            }
+        }
+        block_lists
+    }
+
+    /// Returns a watcher reloading the files that were loaded at startup when they change
+    fn file_watcher(&self) -> FileWatcher {
+        let globals = &self.globals;
+        let mut file_watcher = FileWatcher::default();
+        for block_list in self.block_lists() {
+            let path = match block_list.path() {
+                None => continue,
+                Some(path) => path.to_path_buf(),
+            };
+            file_watcher.watch(&path, "block list", move || block_list.reload());
+        }
+        if let Some(allow_list) = &globals.allow_list {
+            if let Some(path) = allow_list.path() {
+                let allow_list = allow_list.clone();
+                file_watcher.watch(path, "allow list", move || allow_list.reload());
+            }
+        }
+        if let Some(path) = globals.local_zone.as_ref().and_then(Reloadable::path) {
+            let globals = globals.clone();
+            file_watcher.watch(path, "local zone", move || {
+                globals
+                    .local_zone
+                    .as_ref()
+                    .map_or(Ok(()), Reloadable::reload)
+            });
+        }
+        if let Some(path) = globals.rewrite_rules.as_ref().and_then(Reloadable::path) {
+            let globals = globals.clone();
+            file_watcher.watch(path, "rewrite rules", move || {
+                globals
+                    .rewrite_rules
+                    .as_ref()
+                    .map_or(Ok(()), Reloadable::reload)
+            });
+        }
+        if let Some(path) = globals.auth_tokens.path() {
+            let globals = globals.clone();
+            file_watcher.watch(path, "tokens", move || globals.auth_tokens.reload());
+        }
+        file_watcher
+    }
+
+    /// Saves the cache periodically
+    async fn persist_cache(globals: Arc<Globals>) {
+        let mut interval = tokio::time::interval(globals.cache_persist_interval);
+        // The cache was just loaded
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            Self::save_cache(&globals);
+        }
+    }
+
+    fn save_cache(globals: &Globals) {
+        if let (Some(cache), Some(path)) = (&globals.cache, &globals.cache_persist_path) {
+            if let Err(e) = cache.save(path) {
+                tracing::warn!(path = %path.display(), error = %e, "Unable to save the cache");
+            }
+        }
+    }
+
+    /// Saves the numbers of queries counted against quotas periodically, and forgets the
+    /// clients that didn't send any queries this month
+    async fn persist_quotas(globals: Arc<Globals>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(QUOTA_PERSIST_INTERVAL_SECS));
+        // The quotas were just loaded
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Some(quotas) = &globals.quotas {
+                quotas.prune();
+            }
+            Self::save_quotas(&globals);
+        }
+    }
+
+    fn save_quotas(globals: &Globals) {
+        if let (Some(quotas), Some(path)) = (&globals.quotas, &globals.quota_persist_path) {
+            if let Err(e) = quotas.save(path) {
+                tracing::warn!(path = %path.display(), error = %e, "Unable to save the quotas");
+            }
+        }
+    }
+
+    /// Resolves the names of the upstream servers again, so that address changes are noticed
+    /// without restarting
+    async fn resolve_upstreams(globals: Arc<Globals>) {
+        let mut interval = tokio::time::interval(globals.upstream_resolve_interval);
+        // The names were already resolved at startup
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            for upstream in globals.all_upstreams() {
+                let server_name = match &upstream.server_name {
+                    None => continue,
+                    Some(server_name) => server_name,
+                };
+                match upstream
+                    .refresh_addresses(globals.bootstrap.as_deref())
+                    .await
+                {
+                    Ok(false) => {}
+                    Ok(true) => tracing::info!(
+                        server_name = server_name.as_str(),
+                        upstream = %upstream.server_address(),
+                        "Upstream server address changed"
+                    ),
+                    Err(e) => tracing::warn!(
+                        server_name = server_name.as_str(),
+                        error = %e,
+                        "Unable to resolve the upstream server"
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Queries the upstream servers that didn't answer any queries for a probe interval
+    async fn probe_upstream(self) {
+        let globals = self.globals.clone();
+        let mut interval = tokio::time::interval(globals.upstream_probe_interval);
+        loop {
+            interval.tick().await;
+            #[cfg(feature = "tls")]
+            {
+                if let Some(upstream_doh) = &globals.upstream_doh {
+                    if !upstream_doh.is_reachable(globals.upstream_probe_interval) {
+                        if let Ok(query) = dns::build_query(rand::random(), ".", dns::DNS_TYPE_NS) {
+                            let _ = self.upstream_doh_exchange(upstream_doh, &query).await;
+                        }
+                    }
+                    continue;
+                }
+            }
+            for upstream in globals.upstreams.iter() {
+                if globals
+                    .upstream_health
+                    .is_reachable(&upstream.server_address(), globals.upstream_probe_interval)
+                {
+                    continue;
+                }
+                let query = match dns::build_query(rand::random(), ".", dns::DNS_TYPE_NS) {
+                    Ok(query) => query,
+                    Err(_) => return,
+                };
+                let _ = self.upstream_exchange_with(upstream, &query).await;
+            }
+        }
+    }
+
+    /// Binds the sockets accepting connections on `listen_address`: a single socket,
+    /// or several sockets sharing the address with `SO_REUSEPORT`
+    fn bind_listeners(
+        &self,
+        listen_address: &SocketAddr,
+    ) -> Result<Vec<std::net::TcpListener>, DoHError> {
+        #[cfg(unix)]
+        if self.globals.reuseport > 1 {
+            let listener = reuseport::bind_reuseport(listen_address).map_err(DoHError::Io)?;
+            // Same port for every socket, even if the port was chosen by the system
+            let listen_address = listener.local_addr().map_err(DoHError::Io)?;
+            let mut listeners = vec![listener];
+            for _ in 1..self.globals.reuseport {
+                listeners.push(reuseport::bind_reuseport(&listen_address).map_err(DoHError::Io)?);
+            }
+            return Ok(listeners);
+        }
+        Ok(vec![bind_listener(listen_address)?])
+    }
+
+    /// Binds the listening sockets and loads the certificates.
+    /// This doesn't require a runtime, so that privileges can be dropped before the runtime
+    /// starts its worker threads.
+    pub fn bind(&self) -> Result<Listeners, DoHError> {
+        #[cfg(unix)]
+        let activated_listeners = systemd::listen_fds().map_err(DoHError::Io)?;
+        #[cfg(not(unix))]
+        let activated_listeners: Vec<std::net::TcpListener> = vec![];
+
+        let mut listeners = vec![];
+        if activated_listeners.is_empty() {
+            for listen_address in &self.globals.listen_addresses {
+                for listener in self.bind_listeners(listen_address)? {
+                    listeners.push((listener, true));
+                }
+            }
+        } else {
+            for listener in activated_listeners {
+                listeners.push((listener, true));
+            }
+        }
+        #[cfg(feature = "tls")]
+        for listen_address in &self.globals.plaintext_listen_addresses {
+            for listener in self.bind_listeners(listen_address)? {
+                listeners.push((listener, false));
+            }
+        }
+        let mut dns_listeners = vec![];
+        for listen_address in &self.globals.dns_listen_addresses {
+            let listener = bind_listener(listen_address)?;
+            // Same port for both protocols, even if the port was chosen by the system
+            let listen_address = listener.local_addr().map_err(DoHError::Io)?;
+            let udp_socket = std::net::UdpSocket::bind(listen_address).map_err(DoHError::Io)?;
+            udp_socket.set_nonblocking(true).map_err(DoHError::Io)?;
+            dns_listeners.push((udp_socket, listener));
+        }
+        #[cfg(feature = "tls")]
+        let mut dot_listeners = vec![];
+        #[cfg(feature = "tls")]
+        for listen_address in &self.globals.dot_listen_addresses {
+            dot_listeners.extend(self.bind_listeners(listen_address)?);
+        }
+        let admin_listener = match &self.globals.admin_listen_address {
+            Some(admin_listen_address) => Some(bind_listener(admin_listen_address)?),
+            None => None,
+        };
+
+        #[cfg(feature = "tls")]
+        let client_ca = match &self.globals.tls_client_ca_path {
+            Some(tls_client_ca_path) => Some(Arc::new(
+                ClientCa::load(tls_client_ca_path).map_err(DoHError::Io)?,
+            )),
+            None => None,
+        };
+        #[cfg(feature = "tls")]
+        let (tls_acceptor, ocsp_stapler) =
+            match (&self.globals.tls_cert_path, &self.globals.tls_cert_key_path) {
+                (Some(tls_cert_path), Some(tls_cert_key_path)) => {
+                    let (tls_acceptor, ocsp_stapler) = create_tls_acceptor(
+                        tls_cert_path,
+                        tls_cert_key_path,
+                        client_ca.clone(),
+                        self.globals.tls_ticket_rotation_interval,
+                        self.globals.tls_ocsp_stapling,
+                    )
+                    .map_err(DoHError::Io)?;
+                    (Some(tls_acceptor), ocsp_stapler)
+                }
+                _ => match &self.globals.tls_self_signed {
+                    Some(hostname) => {
+                        let tls_acceptor = create_self_signed_tls_acceptor(
+                            hostname,
+                            client_ca.clone(),
+                            self.globals.tls_ticket_rotation_interval,
+                        )
+                        .map_err(DoHError::Io)?;
+                        (Some(tls_acceptor), None)
+                    }
+                    None => (None, None),
+                },
+            };
+        #[cfg(feature = "tls")]
+        if !dot_listeners.is_empty() && tls_acceptor.is_none() {
+            return Err(DoHError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "DNS-over-TLS requires a TLS certificate",
+            )));
+        }
+
+        Ok(Listeners {
+            listeners,
+            admin_listener,
+            dns_listeners,
+            #[cfg(feature = "tls")]
+            dot_listeners,
+            #[cfg(feature = "tls")]
+            tls_acceptor,
+            #[cfg(feature = "tls")]
+            ocsp_stapler,
+            #[cfg(feature = "tls")]
+            client_ca,
+        })
+    }
+
+    /// Accepts connections on the sockets returned by `bind()`
+    pub async fn serve(self, listeners: Listeners) -> Result<(), DoHError> {
+        self.serve_until(listeners, future::pending()).await
+    }
+
+    /// Accepts connections on the sockets returned by `bind()`, until `shutdown` completes.
+    /// Connections are then closed once the queries being processed have been answered,
+    /// waiting at most for the query timeout.
+    pub async fn serve_until<F>(self, mut listeners: Listeners, shutdown: F) -> Result<(), DoHError>
+    where
+        F: Future<Output = ()>,
+    {
+        #[cfg(feature = "tls")]
+        if let Some(ocsp_stapler) = listeners.ocsp_stapler.take() {
+            tokio::spawn(async move { ocsp_stapler.run().await });
+        }
+
+        let accept_loops = self.accept_loops(&mut listeners)?;
+
+        if let Some(access_log) = &self.globals.access_log {
+            access_log.start().map_err(DoHError::Io)?;
+        }
+
+        #[cfg(feature = "tls")]
+        for block_list in self
+            .block_lists()
+            .into_iter()
+            .chain(self.globals.allow_list.clone())
+        {
+            if block_list.url().is_some() {
+                let subscription = BlockListSubscription::new(block_list);
+                tokio::spawn(subscription.run(self.globals.block_list_update_interval));
+            }
+        }
+
+        #[allow(unused_mut)]
+        let mut file_watcher = self.file_watcher();
+        #[cfg(feature = "tls")]
+        if let Some(client_ca) = listeners.client_ca {
+            let client_ca_path = client_ca.path().to_path_buf();
+            file_watcher.watch(
+                &client_ca_path,
+                "client certificate authorities",
+                move || client_ca.reload(),
+            );
+        }
+        if !file_watcher.is_empty() {
+            if let Err(e) = file_watcher.start(self.globals.block_list_reload_interval) {
+                tracing::warn!(error = %e, "Unable to watch files for changes");
+            }
+        }
+
+        if self.globals.readiness_path.is_some() {
+            tokio::spawn(self.clone().probe_upstream());
+        }
+
+        if let (Some(cache), Some(path)) = (&self.globals.cache, &self.globals.cache_persist_path) {
+            match cache.load(path) {
+                Ok(count) => {
+                    tracing::info!(path = %path.display(), "Loaded {} cached responses", count)
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "Unable to load the cache")
+                }
+            }
+            if self.globals.cache_persist_interval > Duration::default() {
+                tokio::spawn(Self::persist_cache(self.globals.clone()));
+            }
+        }
+
+        if let Some(quotas) = &self.globals.quotas {
+            if let Some(path) = &self.globals.quota_persist_path {
+                match quotas.load(path) {
+                    Ok(count) => {
+                        tracing::info!(path = %path.display(), "Loaded the quotas of {} clients", count)
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        tracing::warn!(path = %path.display(), error = %e, "Unable to load the quotas")
+                    }
+                }
+            }
+            tokio::spawn(Self::persist_quotas(self.globals.clone()));
+        }
+
+        let has_server_names = self
+            .globals
+            .all_upstreams()
+            .any(|upstream| upstream.server_name.is_some());
+        if has_server_names && self.globals.upstream_resolve_interval > Duration::default() {
+            tokio::spawn(Self::resolve_upstreams(self.globals.clone()));
+        }
+
+        #[cfg(unix)]
+        if let Some(dnstap) = &self.globals.dnstap {
+            tracing::info!("Sending dnstap messages to {}", dnstap.path().display());
+            let globals = self.globals.clone();
+            tokio::spawn(async move {
+                if let Some(dnstap) = &globals.dnstap {
+                    dnstap.run().await;
+                }
+            });
+        }
+
+        if let Some(admin_listener) = listeners.admin_listener {
+            let listener = TcpListener::from_std(admin_listener).map_err(DoHError::Io)?;
+            let admin_listen_address = listener.local_addr().map_err(DoHError::Io)?;
+            tracing::info!("Admin API listening on http://{}", admin_listen_address);
+            tokio::spawn(admin::serve_admin(self.globals.clone(), listener));
+        }
+
+        if self.globals.dnssec_validate && !self.globals.upstreams_are_secure() {
+            tracing::warn!(
+                "DNSSEC validation relies on the AD bit set by upstream servers that are not reached \
+                 over DoH or the loopback interface: it can be forged by anyone able to modify \
+                 their responses"
+            );
+        }
+
+        #[cfg(unix)]
+        if let Err(e) = systemd::notify_ready() {
+            tracing::warn!(error = %e, "Unable to notify the service manager that the server is ready");
+        }
+
+        let accept_loops = future::try_join_all(accept_loops);
+        futures::pin_mut!(shutdown);
+        let stop = future::select(shutdown.as_mut(), Box::pin(self.globals.drain.wait()));
+        if let future::Either::Left((res, _)) = future::select(accept_loops, stop).await {
+            return res.map(|_| ());
+        }
+        // The listening sockets are closed at this point. When draining, the existing
+        // connections keep being served until the server is actually stopped.
+        if self.globals.drain.is_triggered() {
+            tracing::info!("Draining: new connections are no longer accepted");
+            shutdown.await;
+        }
+        self.globals.shutdown.trigger();
+        self.wait_for_clients().await;
+        Self::save_cache(&self.globals);
+        Self::save_quotas(&self.globals);
+        Ok(())
+    }
+
+    /// Accepts connections on a share of the sockets returned by `Listeners::split()`, on the
+    /// runtime of a worker. The server stops accepting connections when `serve_until()`, that
+    /// serves the first share on another runtime, stops.
+    pub async fn serve_worker(self, mut listeners: Listeners) -> Result<(), DoHError> {
+        let accept_loops = future::try_join_all(self.accept_loops(&mut listeners)?);
+        let stop = future::select(
+            Box::pin(self.globals.shutdown.wait()),
+            Box::pin(self.globals.drain.wait()),
+        );
+        if let future::Either::Left((res, _)) = future::select(accept_loops, stop).await {
+            return res.map(|_| ());
+        }
+        self.globals.shutdown.wait().await;
+        self.wait_for_clients().await;
+        Ok(())
+    }
+
+    /// Waits for the queries being processed to be answered, at most for the query timeout
+    async fn wait_for_clients(&self) {
+        let deadline = Instant::now() + self.globals.timeout;
+        while self.globals.clients_count.current() > 0 && Instant::now() < deadline {
+            tokio::time::delay_for(Duration::from_millis(SHUTDOWN_POLL_INTERVAL_MS)).await;
+        }
+    }
+
+    /// Starts accepting connections and DNS queries on the sockets of `listeners`
+    fn accept_loops(
+        &self,
+        listeners: &mut Listeners,
+    ) -> Result<Vec<future::BoxFuture<'static, Result<(), DoHError>>>, DoHError> {
+        let path = &self.globals.path;
+        #[cfg(feature = "tls")]
+        let tls_acceptor = listeners.tls_acceptor.clone();
+        #[cfg(not(feature = "tls"))]
+        let tls_acceptor: Option<()> = None;
+
+        let mut server = Http::new();
+        server.http1_keep_alive(self.globals.keepalive);
+        server.http2_max_concurrent_streams(self.globals.max_connection_queries);
+        server.pipeline_flush(true);
+        let executor = LocalExecutor::new(runtime::Handle::current());
+        let server = server.with_executor(executor);
+
+        let mut accept_loops = Vec::with_capacity(listeners.listeners.len());
+        for (listener, use_tls) in listeners.listeners.drain(..) {
+            let listener = TcpListener::from_std(listener).map_err(DoHError::Io)?;
+            let listen_address = listener.local_addr().map_err(DoHError::Io)?;
+            let tls_acceptor = tls_acceptor.as_ref().filter(|_| use_tls).cloned();
+            if tls_acceptor.is_some() {
+                tracing::info!("Listening on https://{}{}", listen_address, path);
+            } else {
+                tracing::info!("Listening on http://{}{}", listen_address, path);
+            }
+            #[cfg(feature = "tls")]
+            {
+                if let Some(tls_acceptor) = tls_acceptor {
+                    accept_loops.push(
+                        self.clone()
+                            .start_with_tls(tls_acceptor, listener, server.clone())
+                            .boxed(),
+                    );
+                    continue;
+                }
+            }
+            accept_loops.push(
+                self.clone()
+                    .start_without_tls(listener, server.clone())
+                    .boxed(),
+            );
+        }
+        for (udp_socket, listener) in listeners.dns_listeners.drain(..) {
+            let udp_socket = UdpSocket::from_std(udp_socket).map_err(DoHError::Io)?;
+            let listener = TcpListener::from_std(listener).map_err(DoHError::Io)?;
+            let listen_address = listener.local_addr().map_err(DoHError::Io)?;
+            tracing::info!(
+                "Listening on udp://{} and tcp://{}",
+                listen_address,
+                listen_address
+            );
+            accept_loops.push(self.clone().start_dns_udp(udp_socket).boxed());
+            accept_loops.push(self.clone().start_dns_tcp(listener).boxed());
+        }
+        #[cfg(feature = "tls")]
+        for listener in listeners.dot_listeners.drain(..) {
+            let listener = TcpListener::from_std(listener).map_err(DoHError::Io)?;
+            let listen_address = listener.local_addr().map_err(DoHError::Io)?;
+            if let Some(tls_acceptor) = &tls_acceptor {
+                tracing::info!("Listening on tls://{}", listen_address);
+                accept_loops.push(
+                    self.clone()
+                        .start_dot(tls_acceptor.clone(), listener)
+                        .boxed(),
+                );
+            }
+        }
+        Ok(accept_loops)
+    }
+
+    pub async fn entrypoint(self) -> Result<(), DoHError> {
+        let listeners = self.bind()?;
+        self.serve(listeners).await
     }
 }
@@ -0,0 +1,168 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// How the queries of a client are told apart from the queries of other clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientKeyPolicy {
+    /// The IP address
+    Ip,
+    /// The IP address and the User-Agent header, so that clients sharing an address behind
+    /// a NAT don't share their limit. Queries without a User-Agent, such as DNS-over-TLS
+    /// queries, are only told apart by their address.
+    IpUserAgent,
+    /// The network of the address, for clients using many addresses of the same network
+    Prefix {
+        v4_prefix_len: u8,
+        v6_prefix_len: u8,
+    },
+}
+
+impl ClientKeyPolicy {
+    pub fn uses_user_agent(&self) -> bool {
+        *self == ClientKeyPolicy::IpUserAgent
+    }
+
+    /// Returns the key of a client, with the hash of its User-Agent header if it has one
+    pub fn key(&self, client_ip: IpAddr, user_agent_hash: Option<u64>) -> ClientKey {
+        match *self {
+            ClientKeyPolicy::Ip => ClientKey {
+                ip: client_ip,
+                user_agent_hash: None,
+            },
+            ClientKeyPolicy::IpUserAgent => ClientKey {
+                ip: client_ip,
+                user_agent_hash,
+            },
+            ClientKeyPolicy::Prefix {
+                v4_prefix_len,
+                v6_prefix_len,
+            } => {
+                let ip = match client_ip {
+                    IpAddr::V4(ip) => {
+                        let mask = u32::MAX.checked_shl(32 - v4_prefix_len as u32).unwrap_or(0);
+                        IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+                    }
+                    IpAddr::V6(ip) => {
+                        let mask = u128::MAX
+                            .checked_shl(128 - v6_prefix_len as u32)
+                            .unwrap_or(0);
+                        IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+                    }
+                };
+                ClientKey {
+                    ip,
+                    user_agent_hash: None,
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ClientKeyPolicy {
+    type Err = String;
+
+    /// Parses `ip`, `ip+user-agent`, or `prefix` optionally followed by `:<IPv4 length>/<IPv6 length>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => return Ok(ClientKeyPolicy::Ip),
+            "ip+user-agent" => return Ok(ClientKeyPolicy::IpUserAgent),
+            "prefix" => {
+                return Ok(ClientKeyPolicy::Prefix {
+                    v4_prefix_len: 24,
+                    v6_prefix_len: 56,
+                })
+            }
+            _ => {}
+        }
+        let prefix_lens = s
+            .strip_prefix("prefix:")
+            .ok_or_else(|| format!("Unsupported client key: [{}]", s))?;
+        let invalid = || format!("Invalid prefix lengths: [{}]", prefix_lens);
+        let (v4_prefix_len, v6_prefix_len) = prefix_lens.split_once('/').ok_or_else(invalid)?;
+        let v4_prefix_len = v4_prefix_len
+            .parse()
+            .ok()
+            .filter(|&prefix_len| prefix_len <= 32)
+            .ok_or_else(invalid)?;
+        let v6_prefix_len = v6_prefix_len
+            .parse()
+            .ok()
+            .filter(|&prefix_len| prefix_len <= 128)
+            .ok_or_else(invalid)?;
+        Ok(ClientKeyPolicy::Prefix {
+            v4_prefix_len,
+            v6_prefix_len,
+        })
+    }
+}
+
+/// What the queries of a client are counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientKey {
+    ip: IpAddr,
+    user_agent_hash: Option<u64>,
+}
+
+/// Hashes a User-Agent header, so that it doesn't have to be kept
+pub fn user_agent_hash(user_agent: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    user_agent.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Counts the queries being processed for every client
+#[derive(Debug, Default)]
+pub struct ClientQueries {
+    counts: Mutex<HashMap<ClientKey, usize>>,
+}
+
+/// A query being processed, that stops being counted when dropped
+#[derive(Debug)]
+pub struct ClientQueryGuard<'t> {
+    client_queries: &'t ClientQueries,
+    client_key: ClientKey,
+}
+
+impl ClientQueries {
+    /// Counts a new query from a client, unless `max_queries` are already being processed
+    pub fn acquire(
+        &self,
+        client_key: ClientKey,
+        max_queries: usize,
+    ) -> Option<ClientQueryGuard<'_>> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(client_key).or_insert(0);
+        if *count >= max_queries {
+            return None;
+        }
+        *count += 1;
+        Some(ClientQueryGuard {
+            client_queries: self,
+            client_key,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.counts.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for ClientQueryGuard<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.client_queries.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.client_key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.client_key);
+            }
+        }
+    }
+}
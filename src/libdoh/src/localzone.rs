@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use crate::constants::*;
+use crate::dns;
+
+const MAX_CNAME_CHAIN_LEN: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct LocalRecord {
+    pub rr_type: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+    target: Option<String>,
+}
+
+/// A local answer, as a list of `(owner name, record)` tuples
+pub type LocalAnswer<'t> = Vec<(&'t [u8], &'t LocalRecord)>;
+
+#[derive(Clone, Debug)]
+struct LocalName {
+    encoded_name: Vec<u8>,
+    records: Vec<LocalRecord>,
+}
+
+/// Static records, answered directly instead of being forwarded to the upstream server
+#[derive(Clone, Debug, Default)]
+pub struct LocalZone {
+    names: HashMap<String, LocalName>,
+}
+
+fn parse_txt(value: &str) -> Result<Vec<u8>, String> {
+    let mut strings = vec![];
+    let value = value.trim();
+    if value.starts_with('"') {
+        let mut rest = value;
+        while !rest.is_empty() {
+            let quoted = rest
+                .strip_prefix('"')
+                .ok_or_else(|| format!("Unexpected text: [{}]", rest))?;
+            let end = quoted
+                .find('"')
+                .ok_or_else(|| "Unterminated string".to_string())?;
+            strings.push(&quoted[..end]);
+            rest = quoted[end + 1..].trim_start();
+        }
+    } else {
+        strings.push(value);
+    }
+    let mut rdata = vec![];
+    for string in strings {
+        let string = string.as_bytes();
+        if string.is_empty() {
+            rdata.push(0);
+        }
+        for chunk in string.chunks(255) {
+            rdata.push(chunk.len() as u8);
+            rdata.extend_from_slice(chunk);
+        }
+    }
+    Ok(rdata)
+}
+
+fn parse_record(line: &str) -> Result<(String, LocalRecord), String> {
+    let mut tokens = line.splitn(2, char::is_whitespace);
+    let name = tokens
+        .next()
+        .unwrap()
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    let mut rest = tokens.next().unwrap_or("").trim_start();
+    let mut ttl = LOCAL_RESPONSE_TTL;
+    let mut tokens = rest.splitn(2, char::is_whitespace);
+    let mut rr_type = tokens.next().unwrap_or("").to_ascii_uppercase();
+    if let Ok(explicit_ttl) = rr_type.parse() {
+        ttl = explicit_ttl;
+        rest = tokens.next().unwrap_or("").trim_start();
+        tokens = rest.splitn(2, char::is_whitespace);
+        rr_type = tokens.next().unwrap_or("").to_ascii_uppercase();
+    }
+    let value = tokens.next().unwrap_or("").trim();
+    if value.is_empty() {
+        return Err("Missing record value".to_string());
+    }
+    let mut target = None;
+    let (rr_type, rdata) = match rr_type.as_str() {
+        "A" => {
+            let ip: Ipv4Addr = value
+                .parse()
+                .map_err(|_| format!("Invalid IPv4 address: [{}]", value))?;
+            (dns::DNS_TYPE_A, ip.octets().to_vec())
+        }
+        "AAAA" => {
+            let ip: Ipv6Addr = value
+                .parse()
+                .map_err(|_| format!("Invalid IPv6 address: [{}]", value))?;
+            (dns::DNS_TYPE_AAAA, ip.octets().to_vec())
+        }
+        "TXT" => (dns::DNS_TYPE_TXT, parse_txt(value)?),
+        "CNAME" => {
+            let value = value.trim_end_matches('.').to_ascii_lowercase();
+            let rdata = dns::encode_name(&value).map_err(|e| e.to_string())?;
+            target = Some(value);
+            (dns::DNS_TYPE_CNAME, rdata)
+        }
+        _ => return Err(format!("Unsupported record type: [{}]", rr_type)),
+    };
+    Ok((
+        name,
+        LocalRecord {
+            rr_type,
+            ttl,
+            rdata,
+            target,
+        },
+    ))
+}
+
+impl LocalZone {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses records in the `<name> [<ttl>] <type> <value>` format, one per line
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut zone = LocalZone::default();
+        for (line_nb, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let (name, record) = parse_record(line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: {}", line_nb + 1, e),
+                )
+            })?;
+            let encoded_name = dns::encode_name(&name).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: {}", line_nb + 1, e),
+                )
+            })?;
+            zone.names
+                .entry(name)
+                .or_insert_with(|| LocalName {
+                    encoded_name,
+                    records: vec![],
+                })
+                .records
+                .push(record);
+        }
+        Ok(zone)
+    }
+
+    /// Returns the local records for a question, following CNAME records within the zone,
+    /// or `None` if the name is not part of the zone.
+    pub fn lookup(&self, qname: &str, qtype: u16) -> Option<LocalAnswer<'_>> {
+        let mut local_name = self.names.get(qname)?;
+        let mut answer = vec![];
+        for _ in 0..MAX_CNAME_CHAIN_LEN {
+            let matching: Vec<_> = local_name
+                .records
+                .iter()
+                .filter(|record| record.rr_type == qtype)
+                .collect();
+            if !matching.is_empty() {
+                answer.extend(
+                    matching
+                        .into_iter()
+                        .map(|record| (local_name.encoded_name.as_slice(), record)),
+                );
+                break;
+            }
+            let cname = match local_name
+                .records
+                .iter()
+                .find(|record| record.rr_type == dns::DNS_TYPE_CNAME)
+            {
+                None => break,
+                Some(cname) => cname,
+            };
+            answer.push((local_name.encoded_name.as_slice(), cname));
+            local_name = match cname
+                .target
+                .as_ref()
+                .and_then(|target| self.names.get(target))
+            {
+                None => break,
+                Some(local_name) => local_name,
+            };
+        }
+        Some(answer)
+    }
+}
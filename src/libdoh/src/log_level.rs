@@ -0,0 +1,26 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::level_filters::LevelFilter;
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::OFF,
+    LevelFilter::ERROR,
+    LevelFilter::WARN,
+    LevelFilter::INFO,
+    LevelFilter::DEBUG,
+    LevelFilter::TRACE,
+];
+
+/// Index of the maximum log level in `LEVELS`
+static MAX_LOG_LEVEL: AtomicUsize = AtomicUsize::new(3);
+
+/// Returns the maximum level of the log messages.
+/// Subscribers installed by applications are expected to honor it, as it can be changed
+/// at runtime with the administration API.
+pub fn max_log_level() -> LevelFilter {
+    LEVELS[MAX_LOG_LEVEL.load(Ordering::Relaxed)]
+}
+
+pub fn set_max_log_level(level: LevelFilter) {
+    let index = LEVELS.iter().position(|&l| l == level).unwrap_or(3);
+    MAX_LOG_LEVEL.store(index, Ordering::Relaxed);
+}
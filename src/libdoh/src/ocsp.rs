@@ -0,0 +1,270 @@
+use crate::constants::*;
+use crate::der::*;
+
+use hyper::{Body, Client, Request, Uri};
+use ring::digest;
+use std::io;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{ClientHello, ResolvesServerCert};
+
+const OID_AUTHORITY_INFO_ACCESS: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+const OID_AD_OCSP: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+
+const OCSP_CERT_STATUS_GOOD: u8 = 0x80;
+
+fn invalid_cert(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Returns the fields of the `TBSCertificate` of a DER-encoded certificate,
+/// starting with the serial number
+fn tbs_fields(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(cert)?;
+    match der_element(tbs)? {
+        (DER_CONTEXT_0, _, rest) => Some(rest),
+        _ => Some(tbs),
+    }
+}
+
+/// Returns the URL of the OCSP responder from the Authority Information Access extension
+fn responder_url(extensions: &[u8]) -> Option<String> {
+    let (_, mut extensions, _) = der_element(extensions)?;
+    while !extensions.is_empty() {
+        let (_, extension, rest) = der_element(extensions)?;
+        extensions = rest;
+        let (_, oid, extension) = der_element(extension)?;
+        if oid != OID_AUTHORITY_INFO_ACCESS {
+            continue;
+        }
+        let (mut tag, mut value, rest) = der_element(extension)?;
+        if tag == DER_BOOLEAN {
+            // critical flag
+            let (next_tag, next_value, _) = der_element(rest)?;
+            tag = next_tag;
+            value = next_value;
+        }
+        if tag != DER_OCTET_STRING {
+            return None;
+        }
+        let (_, mut descriptions, _) = der_element(value)?;
+        while !descriptions.is_empty() {
+            let (_, description, rest) = der_element(descriptions)?;
+            descriptions = rest;
+            let (_, method, location) = der_element(description)?;
+            let (tag, location, _) = der_element(location)?;
+            if method == OID_AD_OCSP && tag == DER_URI {
+                return Some(String::from_utf8_lossy(location).into_owned());
+            }
+        }
+    }
+    None
+}
+
+/// Returns the DER-encoded `CertID` identifying `cert` in OCSP requests and responses,
+/// and the URL of the OCSP responder
+fn cert_id(cert: &[u8], issuer: &[u8]) -> Option<(Vec<u8>, String)> {
+    let fields = tbs_fields(cert)?;
+    let (serial, fields) = der_raw_element(fields)?;
+    let fields = der_element(fields)?.2; // signature algorithm
+    let (issuer_name, fields) = der_raw_element(fields)?;
+    // validity, subject, subject public key info, and optional unique identifiers
+    let mut fields = der_element(fields)?.2;
+    let mut url = None;
+    while !fields.is_empty() {
+        let (tag, extensions, rest) = der_element(fields)?;
+        fields = rest;
+        if tag == DER_CONTEXT_3 {
+            url = responder_url(extensions);
+        }
+    }
+    let url = url?;
+
+    let issuer_fields = tbs_fields(issuer)?;
+    let issuer_fields = der_element(issuer_fields)?.2; // serial number
+    let issuer_fields = der_element(issuer_fields)?.2; // signature algorithm
+    let issuer_fields = der_element(issuer_fields)?.2; // issuer
+    let issuer_fields = der_element(issuer_fields)?.2; // validity
+    let issuer_fields = der_element(issuer_fields)?.2; // subject
+    let (_, spki, _) = der_element(issuer_fields)?;
+    let spki = der_element(spki)?.2; // algorithm
+    let (_, public_key, _) = der_element(spki)?;
+    let public_key = public_key.get(1..)?; // unused bits
+
+    let mut hash_algorithm = der_encode(DER_OID, OID_SHA1);
+    hash_algorithm.extend_from_slice(&der_encode(DER_NULL, &[]));
+    let name_hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, issuer_name);
+    let key_hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, public_key);
+    let mut cert_id = der_encode(DER_SEQUENCE, &hash_algorithm);
+    cert_id.extend_from_slice(&der_encode(DER_OCTET_STRING, name_hash.as_ref()));
+    cert_id.extend_from_slice(&der_encode(DER_OCTET_STRING, key_hash.as_ref()));
+    if serial.first() != Some(&DER_INTEGER) {
+        return None;
+    }
+    cert_id.extend_from_slice(serial);
+    Some((der_encode(DER_SEQUENCE, &cert_id), url))
+}
+
+/// Checks that an OCSP response is successful and says that the certificate identified by
+/// `cert_id` is good, and returns the time after which it has to be replaced, if any
+fn check_response(response: &[u8], cert_id: &[u8]) -> Result<Option<SystemTime>, String> {
+    let invalid = || "Invalid OCSP response".to_string();
+    let (_, response, _) = der_element(response).ok_or_else(invalid)?;
+    let (tag, status, response) = der_element(response).ok_or_else(invalid)?;
+    if tag != DER_ENUMERATED || status != [0] {
+        return Err(format!("OCSP responder error {:?}", status));
+    }
+    let (_, response_bytes, _) = der_element(response).ok_or_else(invalid)?;
+    let (_, response_bytes, _) = der_element(response_bytes).ok_or_else(invalid)?;
+    let response_bytes = der_element(response_bytes).ok_or_else(invalid)?.2; // response type
+    let (_, basic, _) = der_element(response_bytes).ok_or_else(invalid)?;
+    let (_, basic, _) = der_element(basic).ok_or_else(invalid)?;
+    let (_, tbs, _) = der_element(basic).ok_or_else(invalid)?;
+    let mut tbs = tbs;
+    if der_element(tbs).ok_or_else(invalid)?.0 == DER_CONTEXT_0 {
+        tbs = der_element(tbs).ok_or_else(invalid)?.2; // version
+    }
+    let tbs = der_element(tbs).ok_or_else(invalid)?.2; // responder ID
+    let tbs = der_element(tbs).ok_or_else(invalid)?.2; // produced at
+    let (_, mut responses, _) = der_element(tbs).ok_or_else(invalid)?;
+    while !responses.is_empty() {
+        let (_, single_response, rest) = der_element(responses).ok_or_else(invalid)?;
+        responses = rest;
+        let (response_cert_id, single_response) =
+            der_raw_element(single_response).ok_or_else(invalid)?;
+        if response_cert_id != cert_id {
+            continue;
+        }
+        let (cert_status, _, single_response) = der_element(single_response).ok_or_else(invalid)?;
+        if cert_status != OCSP_CERT_STATUS_GOOD {
+            return Err("The certificate is not valid according to the OCSP responder".into());
+        }
+        let (tag, _, single_response) = der_element(single_response).ok_or_else(invalid)?;
+        if tag != DER_GENERALIZED_TIME {
+            return Err(invalid());
+        }
+        return match der_element(single_response) {
+            Some((DER_CONTEXT_0, next_update, _)) => {
+                let (_, next_update, _) = der_element(next_update).ok_or_else(invalid)?;
+                Ok(Some(generalized_time(next_update).ok_or_else(invalid)?))
+            }
+            _ => Ok(None),
+        };
+    }
+    Err("The OCSP response is for a different certificate".into())
+}
+
+/// Staples OCSP responses, fetched from the responder of the certificate, to the
+/// certificate sent during handshakes
+pub struct OcspStapler {
+    certified_key: RwLock<CertifiedKey>,
+    responder_url: Uri,
+    cert_id: Vec<u8>,
+}
+
+impl ResolvesServerCert for OcspStapler {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<CertifiedKey> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+impl OcspStapler {
+    /// The certificate chain must include the certificate of the issuer,
+    /// right after the server certificate
+    pub fn new(certified_key: CertifiedKey) -> io::Result<Self> {
+        let (cert, issuer) =
+            match certified_key.cert.as_slice() {
+                [cert, issuer, ..] => (cert, issuer),
+                _ => return Err(invalid_cert(
+                    "OCSP stapling requires the issuer certificate to be in the certificate file",
+                )),
+            };
+        let (cert_id, responder_url) = cert_id(&cert.0, &issuer.0).ok_or_else(|| {
+            invalid_cert("The certificate doesn't include the address of an OCSP responder")
+        })?;
+        let responder_url: Uri = responder_url
+            .parse()
+            .ok()
+            .filter(|url: &Uri| url.scheme_str() == Some("http"))
+            .ok_or_else(|| {
+                invalid_cert(&format!(
+                    "Unsupported OCSP responder address [{}]",
+                    responder_url
+                ))
+            })?;
+        Ok(OcspStapler {
+            certified_key: RwLock::new(certified_key),
+            responder_url,
+            cert_id,
+        })
+    }
+
+    /// Returns the OCSP response and the time after which it has to be replaced
+    async fn fetch(&self) -> Result<(Vec<u8>, Option<SystemTime>), String> {
+        let mut request = der_encode(DER_SEQUENCE, &self.cert_id); // Request
+        request = der_encode(DER_SEQUENCE, &request); // requestList
+        request = der_encode(DER_SEQUENCE, &request); // TBSRequest
+        request = der_encode(DER_SEQUENCE, &request); // OCSPRequest
+        let req = Request::post(self.responder_url.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/ocsp-request")
+            .body(Body::from(request))
+            .map_err(|e| e.to_string())?;
+        let res = Client::new()
+            .request(req)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("status {}", res.status()));
+        }
+        let response = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        let next_update = check_response(&response, &self.cert_id)?;
+        Ok((response.to_vec(), next_update))
+    }
+
+    /// Fetches a new OCSP response when half of the validity period of the previous one
+    /// has elapsed. The response is removed if it expires before it could be replaced.
+    pub async fn run(&self) {
+        let retry_interval = Duration::from_secs(OCSP_RETRY_INTERVAL_SECS);
+        let fetch_timeout = Duration::from_secs(OCSP_FETCH_TIMEOUT_SECS);
+        let mut expiration = None;
+        loop {
+            let res = tokio::time::timeout(fetch_timeout, self.fetch())
+                .await
+                .unwrap_or_else(|_| Err("timeout".to_string()));
+            let delay = match res {
+                Ok((response, next_update)) => {
+                    tracing::debug!(responder = %self.responder_url, "OCSP response updated");
+                    self.certified_key.write().unwrap().ocsp = Some(response);
+                    expiration = next_update;
+                    match next_update {
+                        None => Duration::from_secs(OCSP_REFRESH_INTERVAL_SECS),
+                        Some(next_update) => {
+                            next_update
+                                .duration_since(SystemTime::now())
+                                .unwrap_or_default()
+                                / 2
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        responder = %self.responder_url,
+                        error = %e,
+                        "Unable to fetch an OCSP response"
+                    );
+                    if matches!(expiration, Some(expiration) if expiration <= SystemTime::now()) {
+                        self.certified_key.write().unwrap().ocsp = None;
+                        expiration = None;
+                    }
+                    retry_interval
+                }
+            };
+            tokio::time::delay_for(delay.max(retry_interval)).await;
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::constants::*;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_rustls::rustls::ClientConfig;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+const SPAN_KIND_CLIENT: u8 = 3;
+
+/// A span being recorded
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    name: &'static str,
+    kind: u8,
+    start: SystemTime,
+    attributes: Vec<Value>,
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    nanos.to_string()
+}
+
+impl SpanData {
+    fn push(&mut self, field: &Field, value: Value) {
+        self.attributes
+            .push(json!({ "key": field.name(), "value": value }));
+    }
+
+    /// Returns the span in the JSON encoding of OTLP
+    fn into_json(self, end: SystemTime) -> Value {
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.trace_id),
+            "spanId": format!("{:016x}", self.span_id),
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": self.attributes,
+        });
+        if let Some(parent_span_id) = self.parent_span_id {
+            span["parentSpanId"] = json!(format!("{:016x}", parent_span_id));
+        }
+        span
+    }
+}
+
+impl Visit for SpanData {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, json!({ "stringValue": format!("{:?}", value) }));
+    }
+
+    /// The `otel.kind` field sets the kind of the span, as with `tracing-opentelemetry`
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "otel.kind" {
+            self.kind = match value {
+                "server" => SPAN_KIND_SERVER,
+                "client" => SPAN_KIND_CLIENT,
+                _ => SPAN_KIND_INTERNAL,
+            };
+            return;
+        }
+        self.push(field, json!({ "stringValue": value }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, json!({ "boolValue": value }));
+    }
+}
+
+/// A layer for tracing subscribers, recording the spans to be sent by an `OtlpExporter`.
+/// Spans are dropped if the exporter cannot keep up.
+pub struct OtlpLayer {
+    sender: SyncSender<Value>,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            None => return,
+            Some(span) => span,
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|parent| (parent.trace_id, parent.span_id))
+        });
+        let mut data = SpanData {
+            trace_id: parent.map_or_else(rand::random, |(trace_id, _)| trace_id),
+            span_id: rand::random(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            kind: SPAN_KIND_INTERNAL,
+            start: SystemTime::now(),
+            attributes: vec![],
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let data = ctx
+            .span(&id)
+            .and_then(|span| span.extensions_mut().remove::<SpanData>());
+        if let Some(data) = data {
+            let _ = self.sender.try_send(data.into_json(SystemTime::now()));
+        }
+    }
+}
+
+/// Sends the spans recorded by an `OtlpLayer` to an OpenTelemetry collector, using the
+/// OTLP/HTTP protocol with the JSON encoding
+pub struct OtlpExporter {
+    receiver: Receiver<Value>,
+    collector: Collector,
+}
+
+/// Connection to an OpenTelemetry collector
+struct Collector {
+    endpoint: Uri,
+    service_name: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl OtlpExporter {
+    /// Returns the layer recording the spans, and the exporter sending them to `endpoint`,
+    /// such as `http://127.0.0.1:4318/v1/traces`
+    pub fn new(endpoint: Uri, service_name: &str) -> (OtlpLayer, OtlpExporter) {
+        let (sender, receiver) = mpsc::sync_channel(OTLP_QUEUE_SIZE);
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let mut tls_config = ClientConfig::new();
+        tls_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        let client = Client::builder().build((http, tls_config).into());
+        let collector = Collector {
+            endpoint,
+            service_name: service_name.to_string(),
+            client,
+        };
+        let exporter = OtlpExporter {
+            receiver,
+            collector,
+        };
+        (OtlpLayer { sender }, exporter)
+    }
+
+    /// Sends the recorded spans periodically
+    pub async fn run(self) {
+        let OtlpExporter {
+            receiver,
+            collector,
+        } = self;
+        let mut interval = tokio::time::interval(Duration::from_millis(OTLP_EXPORT_INTERVAL_MS));
+        loop {
+            interval.tick().await;
+            loop {
+                let spans: Vec<_> = receiver.try_iter().take(OTLP_MAX_BATCH_SIZE).collect();
+                if spans.is_empty() {
+                    break;
+                }
+                let is_full_batch = spans.len() == OTLP_MAX_BATCH_SIZE;
+                if let Err(e) = collector.export(spans).await {
+                    tracing::debug!("Unable to export spans to [{}]: {}", collector.endpoint, e);
+                    break;
+                }
+                if !is_full_batch {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Collector {
+    async fn export(&self, spans: Vec<Value>) -> Result<(), String> {
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": self.service_name },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "libdoh" },
+                    "spans": spans,
+                }],
+            }],
+        });
+        let req = Request::post(self.endpoint.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .map_err(|e| e.to_string())?;
+        let res = self.client.request(req).await.map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("status {}", res.status()));
+        }
+        Ok(())
+    }
+}
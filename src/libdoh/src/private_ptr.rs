@@ -0,0 +1,33 @@
+/// Returns `true` if `qname` is in the reverse zone of an address range that is only meaningful
+/// within a local network (RFC 6303): private IPv4 networks (RFC 1918), link-local addresses,
+/// and unique local IPv6 addresses. `qname` has to be in lowercase.
+pub fn is_private_reverse_name(qname: &str) -> bool {
+    let name = qname.trim_end_matches('.');
+    if let Some(reversed) = strip_zone(name, "in-addr.arpa") {
+        let mut octets = reversed.rsplit('.');
+        return match (octets.next(), octets.next()) {
+            (Some("10"), _) => true,
+            (Some("172"), Some(octet)) => octet
+                .parse::<u8>()
+                .is_ok_and(|octet| (16..=31).contains(&octet)),
+            (Some("192"), Some("168")) | (Some("169"), Some("254")) => true,
+            _ => false,
+        };
+    }
+    if let Some(reversed) = strip_zone(name, "ip6.arpa") {
+        let mut nibbles = reversed.rsplit('.');
+        return match (nibbles.next(), nibbles.next(), nibbles.next()) {
+            // fc00::/7
+            (Some("f"), Some("c" | "d"), _) => true,
+            // fe80::/10
+            (Some("f"), Some("e"), Some("8" | "9" | "a" | "b")) => true,
+            _ => false,
+        };
+    }
+    false
+}
+
+/// Returns the labels of `name` that come before `zone`, if `name` is a subdomain of `zone`
+fn strip_zone<'t>(name: &'t str, zone: &str) -> Option<&'t str> {
+    name.strip_suffix(zone)?.strip_suffix('.')
+}
@@ -0,0 +1,90 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/// User, group and root directory to switch to once the listening sockets are bound
+#[derive(Debug, Clone, Default)]
+pub struct PrivDrop {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<PathBuf>,
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Unknown {}: [{}]", kind, name),
+    )
+}
+
+fn cstring(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Returns the user and primary group ids of a user
+fn user_ids(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let user_c = cstring(user)?;
+    let passwd = unsafe { libc::getpwnam(user_c.as_ptr()) };
+    if passwd.is_null() {
+        return Err(not_found("user", user));
+    }
+    let passwd = unsafe { &*passwd };
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+fn group_id(group: &str) -> io::Result<libc::gid_t> {
+    let group_c = cstring(group)?;
+    let group_entry = unsafe { libc::getgrnam(group_c.as_ptr()) };
+    if group_entry.is_null() {
+        return Err(not_found("group", group));
+    }
+    Ok(unsafe { &*group_entry }.gr_gid)
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl PrivDrop {
+    pub fn is_enabled(&self) -> bool {
+        self.user.is_some() || self.group.is_some() || self.chroot.is_some()
+    }
+
+    /// Changes the root directory, then the group and the user, so that the privileges cannot
+    /// be regained.
+    /// This must be called before other threads are started, as they would keep running
+    /// with the previous privileges.
+    pub fn apply(&self) -> io::Result<()> {
+        // Names are resolved first, as the user database may not be reachable after `chroot()`
+        let user_ids = self.user.as_deref().map(user_ids).transpose()?;
+        let gid = match (&self.group, user_ids) {
+            (Some(group), _) => Some(group_id(group)?),
+            (None, Some((_, gid))) => Some(gid),
+            (None, None) => None,
+        };
+        if let Some(chroot) = &self.chroot {
+            let chroot = CString::new(chroot.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            check(unsafe { libc::chroot(chroot.as_ptr()) })?;
+            let root = CStr::from_bytes_with_nul(b"/\0").unwrap();
+            check(unsafe { libc::chdir(root.as_ptr()) })?;
+        }
+        if let Some(gid) = gid {
+            check(unsafe { libc::setgroups(1, &gid) })?;
+            check(unsafe { libc::setgid(gid) })?;
+        }
+        if let Some((uid, _)) = user_ids {
+            check(unsafe { libc::setuid(uid) })?;
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(io::Error::other(
+                    "Root privileges could be regained after switching users",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
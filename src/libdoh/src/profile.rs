@@ -0,0 +1,67 @@
+use crate::auth::{constant_time_eq, request_token};
+use crate::blocklist::{BlockList, BlockResponse};
+use crate::rewrite::RewriteRules;
+
+use hyper::header::HeaderMap;
+use std::sync::Arc;
+
+/// Filtering and logging settings for the clients authorized with some tokens, so that
+/// a single server can apply different policies to different users.
+/// Settings that are not set are the ones of the path the query was sent to.
+#[derive(Debug)]
+pub struct Profile {
+    pub name: String,
+    pub block_list: Option<Arc<BlockList>>,
+    pub block_response: BlockResponse,
+    pub rewrite_rules: Option<Arc<RewriteRules>>,
+    pub safe_search: Option<bool>,
+    /// Whether queries are written to the access log and to dnstap
+    pub log_queries: bool,
+}
+
+/// Profiles selected by the bearer token of a request
+#[derive(Debug, Clone, Default)]
+pub struct Profiles {
+    tokens: Vec<(Vec<u8>, Arc<Profile>)>,
+}
+
+impl Profiles {
+    /// Selects `profile` for the requests authorized with `token`
+    pub fn add(&mut self, token: &str, profile: Arc<Profile>) {
+        self.tokens.push((token.as_bytes().to_vec(), profile));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Returns the profiles, and how many tokens select each of them
+    pub fn iter(&self) -> impl Iterator<Item = (&Profile, usize)> {
+        let mut profiles: Vec<(&Profile, usize)> = vec![];
+        for (_, profile) in &self.tokens {
+            match profiles
+                .iter_mut()
+                .find(|(previous, _)| std::ptr::eq(*previous, profile.as_ref()))
+            {
+                Some((_, count)) => *count += 1,
+                None => profiles.push((profile, 1)),
+            }
+        }
+        profiles.into_iter()
+    }
+
+    /// Returns the profile of the token sent with a request, comparing tokens in constant time
+    pub fn get(&self, headers: &HeaderMap) -> Option<Arc<Profile>> {
+        let token = request_token(headers)?.as_bytes();
+        self.tokens
+            .iter()
+            .fold(None, |found, (expected, profile)| {
+                if constant_time_eq(expected, token) {
+                    Some(profile)
+                } else {
+                    found
+                }
+            })
+            .cloned()
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A query kept in the query log
+#[derive(Debug, Clone)]
+pub struct QueryLogEntry {
+    pub time: SystemTime,
+    pub client_ip: Option<IpAddr>,
+    pub client_identity: Option<Arc<str>>,
+    /// The HTTP method, or the protocol of queries that were not sent over HTTP
+    pub method: String,
+    /// In lowercase, without the trailing dot
+    pub qname: String,
+    pub qtype: u16,
+    pub rcode: Option<u8>,
+    pub status: u16,
+    pub latency: Duration,
+}
+
+/// The last queries, kept in memory so that operators can look up what a client just resolved
+/// without setting up an access log
+#[derive(Debug)]
+pub struct QueryLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<QueryLogEntry>>,
+}
+
+impl QueryLog {
+    /// Keeps the last `capacity` queries, nothing being kept if it is 0
+    pub fn new(capacity: usize) -> Self {
+        QueryLog {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Adds a query, replacing the oldest one if the log is full
+    pub fn record(&self, entry: QueryLogEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Returns the most recent queries first, at most `limit` of them.
+    /// With `name`, only the queries for that name and its subdomains are returned, and with
+    /// `client_ip`, only the queries of that client.
+    pub fn search(
+        &self,
+        name: Option<&str>,
+        client_ip: Option<IpAddr>,
+        limit: usize,
+    ) -> Vec<QueryLogEntry> {
+        let name = name.map(|name| name.trim_end_matches('.').to_ascii_lowercase());
+        let matches_name = |qname: &str| match &name {
+            None => true,
+            Some(name) => {
+                qname == name
+                    || (qname.ends_with(name.as_str())
+                        && qname[..qname.len() - name.len()].ends_with('.'))
+            }
+        };
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rev()
+            .filter(|entry| client_ip.is_none() || entry.client_ip == client_ip)
+            .filter(|entry| matches_name(&entry.qname))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SNAPSHOT_HEADER: &str = "# doh-proxy query quotas v1";
+
+/// What the queries counted against a quota are attributed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuotaKey {
+    /// The hash of the bearer token a request was authorized with
+    Token(u64),
+    /// The address of a client that didn't send a token
+    Ip(IpAddr),
+}
+
+impl fmt::Display for QuotaKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaKey::Token(hash) => write!(f, "token:{:016x}", hash),
+            QuotaKey::Ip(ip) => write!(f, "ip:{}", ip),
+        }
+    }
+}
+
+impl FromStr for QuotaKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid quota key: [{}]", s);
+        match s.split_once(':').ok_or_else(invalid)? {
+            ("token", hash) => u64::from_str_radix(hash, 16)
+                .map(QuotaKey::Token)
+                .map_err(|_| invalid()),
+            ("ip", ip) => ip.parse().map(QuotaKey::Ip).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Hashes a bearer token, so that it doesn't have to be kept or written to the quota file.
+/// FNV-1a is used, as the hashes have to stay the same across versions.
+pub fn token_hash(token: &[u8]) -> u64 {
+    token.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The queries of a client during the current day and month
+#[derive(Debug, Clone, Copy, Default)]
+struct QuotaUsage {
+    day: u32,
+    day_queries: u64,
+    month: u32,
+    month_queries: u64,
+}
+
+/// Daily and monthly query quotas, counted per token or per client address.
+/// Days and months are in UTC.
+#[derive(Debug, Default)]
+pub struct Quotas {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    usage: Mutex<HashMap<QuotaKey, QuotaUsage>>,
+}
+
+impl Quotas {
+    pub fn new(daily: Option<u64>, monthly: Option<u64>) -> Self {
+        Quotas {
+            daily,
+            monthly,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn daily(&self) -> Option<u64> {
+        self.daily
+    }
+
+    pub fn monthly(&self) -> Option<u64> {
+        self.monthly
+    }
+
+    /// Counts a query, unless the daily or the monthly quota of the client is exhausted
+    pub fn consume(&self, key: QuotaKey) -> bool {
+        let (day, month) = periods(SystemTime::now());
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(key).or_default();
+        if usage.day != day {
+            usage.day = day;
+            usage.day_queries = 0;
+        }
+        if usage.month != month {
+            usage.month = month;
+            usage.month_queries = 0;
+        }
+        if self.daily.is_some_and(|daily| usage.day_queries >= daily)
+            || self
+                .monthly
+                .is_some_and(|monthly| usage.month_queries >= monthly)
+        {
+            return false;
+        }
+        usage.day_queries += 1;
+        usage.month_queries += 1;
+        true
+    }
+
+    /// Forgets the clients that didn't send any queries this month
+    pub fn prune(&self) {
+        let (_, month) = periods(SystemTime::now());
+        self.usage
+            .lock()
+            .unwrap()
+            .retain(|_, usage| usage.month == month);
+    }
+
+    /// Writes the number of queries of every client to a file, so that they are still counted
+    /// after a restart, and returns how many clients were written.
+    /// The file is replaced atomically, so that it is never left incomplete.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let mut snapshot = format!("{}\n", SNAPSHOT_HEADER);
+        let count = {
+            let usage = self.usage.lock().unwrap();
+            for (key, usage) in usage.iter() {
+                snapshot.push_str(&format!(
+                    "{} {} {} {} {}\n",
+                    key, usage.day, usage.day_queries, usage.month, usage.month_queries
+                ));
+            }
+            usage.len()
+        };
+        let path = path.as_ref();
+        let mut tmp_path = OsString::from(path);
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::write(&tmp_path, &snapshot)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(count)
+    }
+
+    /// Loads the numbers of queries written by `save()`, and returns how many clients were loaded
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let snapshot = fs::read_to_string(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid quota file");
+        let mut lines = snapshot.lines();
+        if lines.next() != Some(SNAPSHOT_HEADER) {
+            return Err(invalid());
+        }
+        let mut usage = self.usage.lock().unwrap();
+        let mut count = 0;
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let parts: Vec<_> = line.split_whitespace().collect();
+            let (key, day, day_queries, month, month_queries) = match parts[..] {
+                [key, day, day_queries, month, month_queries] => {
+                    (key, day, day_queries, month, month_queries)
+                }
+                _ => return Err(invalid()),
+            };
+            let key = key.parse().map_err(|_| invalid())?;
+            let loaded = QuotaUsage {
+                day: day.parse().map_err(|_| invalid())?,
+                day_queries: day_queries.parse().map_err(|_| invalid())?,
+                month: month.parse().map_err(|_| invalid())?,
+                month_queries: month_queries.parse().map_err(|_| invalid())?,
+            };
+            usage.insert(key, loaded);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn len(&self) -> usize {
+        self.usage.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Returns the number of days and months since the Unix epoch, in UTC
+fn periods(now: SystemTime) -> (u32, u32) {
+    let days = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400;
+    // Converts days to a civil date, using the algorithm from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    let months = (year - 1970) * 12 + month - 1;
+    (days as u32, months as u32)
+}
@@ -0,0 +1,85 @@
+use crate::constants::*;
+
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Body, Response};
+
+/// Headers that are set by the server itself, and that can't be overridden
+const MANAGED_HEADERS: &[HeaderName] = &[
+    header::CONNECTION,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_TYPE,
+    header::TRANSFER_ENCODING,
+];
+
+/// Static headers added to every response, such as `Server` or `Strict-Transport-Security`
+#[derive(Debug, Clone, Default)]
+pub struct ResponseHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ResponseHeaders {
+    /// Adds a header, replacing the previous value if it was already set
+    pub fn add(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("Invalid header name: [{}]", name))?;
+        if MANAGED_HEADERS.contains(&name) {
+            return Err(format!("The [{}] header can't be set", name));
+        }
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("Invalid value for the [{}] header", name))?;
+        self.headers.retain(|(previous, _)| *previous != name);
+        self.headers.push((name, value));
+        Ok(())
+    }
+
+    /// Advertises HTTP/2 on `ports` with an `Alt-Svc` header (RFC 7838), unless the header
+    /// was already set
+    pub fn add_alt_svc(&mut self, ports: impl IntoIterator<Item = u16>) {
+        if self
+            .headers
+            .iter()
+            .any(|(name, _)| *name == header::ALT_SVC)
+        {
+            return;
+        }
+        let mut alternatives: Vec<String> = vec![];
+        for port in ports {
+            let alternative = format!("h2=\":{}\"; ma={}", port, ALT_SVC_MAX_AGE_SECS);
+            if !alternatives.contains(&alternative) {
+                alternatives.push(alternative);
+            }
+        }
+        if alternatives.is_empty() {
+            return;
+        }
+        if let Ok(value) = HeaderValue::from_str(&alternatives.join(", ")) {
+            self.headers.push((header::ALT_SVC, value));
+        }
+    }
+
+    /// Adds a header written as `Name: value`
+    pub fn add_line(&mut self, line: &str) -> Result<(), String> {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => self.add(name, value),
+            _ => Err(format!("Expected [Name: value], got [{}]", line)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&HeaderName, &HeaderValue)> {
+        self.headers.iter().map(|(name, value)| (name, value))
+    }
+
+    /// Sets the headers on a response, replacing the ones with the same names
+    pub fn apply(&self, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+        for (name, value) in &self.headers {
+            headers.insert(name, value.clone());
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use crate::sockaddr;
+
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+const LISTEN_BACKLOG: libc::c_int = 1024;
+
+/// Binds a listening socket with `SO_REUSEPORT`, so that several sockets can accept connections
+/// on the same address, each with its own accept loop. The kernel spreads new connections
+/// between them.
+pub fn bind_reuseport(listen_address: &SocketAddr) -> io::Result<TcpListener> {
+    let family = match listen_address {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owned from now on, so that the socket is closed on errors
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for &option in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let enabled: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &enabled as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    sockaddr::bind(fd, listen_address)?;
+    if unsafe { libc::listen(listener.as_raw_fd(), LISTEN_BACKLOG) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
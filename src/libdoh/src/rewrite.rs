@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::defaults::REWRITE_TTL;
+
+/// Rules answering queries for matching names with fixed addresses, for walled gardens
+/// and family filters.
+/// `*.example.com` matches the subdomains of `example.com`, and other names only match
+/// themselves. The most specific rule applies.
+#[derive(Clone, Debug)]
+pub struct RewriteRules {
+    names: HashMap<String, Vec<IpAddr>>,
+    wildcards: HashMap<String, Vec<IpAddr>>,
+    ttl: u32,
+}
+
+impl Default for RewriteRules {
+    fn default() -> Self {
+        RewriteRules {
+            names: HashMap::new(),
+            wildcards: HashMap::new(),
+            ttl: REWRITE_TTL,
+        }
+    }
+}
+
+fn parse_rule(line: &str) -> Result<(String, IpAddr), String> {
+    let mut parts = line.splitn(2, "->");
+    let pattern = parts.next().unwrap().trim();
+    let ip = parts
+        .next()
+        .ok_or_else(|| "Expected <name> -> <address>".to_string())?
+        .trim();
+    let ip = ip
+        .parse()
+        .map_err(|_| format!("Invalid address: [{}]", ip))?;
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    if pattern.is_empty() || pattern.contains(char::is_whitespace) {
+        return Err(format!("Invalid name: [{}]", pattern));
+    }
+    Ok((pattern, ip))
+}
+
+impl RewriteRules {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses rules in the `<name> -> <address>` format, one per line.
+    /// A name can be rewritten to several addresses by repeating it.
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut rules = RewriteRules::default();
+        for (line_nb, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (pattern, ip) = parse_rule(line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: {}", line_nb + 1, e),
+                )
+            })?;
+            let addresses = match pattern.strip_prefix("*.") {
+                Some(suffix) => rules.wildcards.entry(suffix.to_string()),
+                None => rules.names.entry(pattern),
+            };
+            addresses.or_default().push(ip);
+        }
+        Ok(rules)
+    }
+
+    /// Sets the TTL of the rewritten answers
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// Returns the addresses a name is rewritten to, or `None` if no rules match
+    pub fn lookup(&self, qname: &str) -> Option<&[IpAddr]> {
+        if let Some(addresses) = self.names.get(qname) {
+            return Some(addresses);
+        }
+        let mut suffix = qname;
+        while let Some(dot) = suffix.find('.') {
+            suffix = &suffix[dot + 1..];
+            if let Some(addresses) = self.wildcards.get(suffix) {
+                return Some(addresses);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,42 @@
+use crate::upstream::Upstreams;
+
+use std::collections::HashMap;
+
+/// Upstream servers used instead of the default ones for specific domains.
+/// The most specific domain wins, and `*` matches every name.
+#[derive(Debug, Default)]
+pub struct UpstreamRoutes {
+    routes: HashMap<String, Upstreams>,
+}
+
+impl UpstreamRoutes {
+    /// Sends the queries for `domain` and its subdomains to `upstreams`
+    pub fn add(&mut self, domain: &str, upstreams: Upstreams) {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.routes.insert(domain, upstreams);
+    }
+
+    /// Returns the upstream servers for `qname`, that has to be in lowercase
+    pub fn get(&self, qname: &str) -> Option<&Upstreams> {
+        let mut name = qname.trim_end_matches('.');
+        loop {
+            if let Some(upstreams) = self.routes.get(name) {
+                return Some(upstreams);
+            }
+            match name.find('.') {
+                Some(dot) => name = &name[dot + 1..],
+                None => return self.routes.get("*"),
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Upstreams)> {
+        self.routes
+            .iter()
+            .map(|(domain, upstreams)| (domain.as_str(), upstreams))
+    }
+}
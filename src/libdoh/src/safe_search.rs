@@ -0,0 +1,52 @@
+const GOOGLE_SAFE_SEARCH_NAME: &str = "forcesafesearch.google.com";
+const BING_SAFE_SEARCH_NAME: &str = "strict.bing.com";
+const DUCKDUCKGO_SAFE_SEARCH_NAME: &str = "safe.duckduckgo.com";
+const YOUTUBE_SAFE_SEARCH_NAME: &str = "restrict.youtube.com";
+
+const BING_NAMES: &[&str] = &["bing.com", "www.bing.com"];
+const DUCKDUCKGO_NAMES: &[&str] = &["duckduckgo.com", "www.duckduckgo.com"];
+const YOUTUBE_NAMES: &[&str] = &[
+    "m.youtube.com",
+    "www.youtube-nocookie.com",
+    "www.youtube.com",
+    "youtube.googleapis.com",
+    "youtubei.googleapis.com",
+];
+
+/// Returns the name of the safe search variant of a search engine or video site, that
+/// always filters explicit results, if `qname` is one of its names. `qname` has to be in
+/// lowercase.
+pub fn safe_search_name(qname: &str) -> Option<&'static str> {
+    let name = qname.trim_end_matches('.');
+    if is_google_search_name(name) {
+        return Some(GOOGLE_SAFE_SEARCH_NAME);
+    }
+    if BING_NAMES.contains(&name) {
+        return Some(BING_SAFE_SEARCH_NAME);
+    }
+    if DUCKDUCKGO_NAMES.contains(&name) {
+        return Some(DUCKDUCKGO_SAFE_SEARCH_NAME);
+    }
+    if YOUTUBE_NAMES.contains(&name) {
+        return Some(YOUTUBE_SAFE_SEARCH_NAME);
+    }
+    None
+}
+
+/// Google search is served on `google.com` and on country domains, such as `google.de`,
+/// `google.co.uk` or `google.com.au`, with or without `www`
+fn is_google_search_name(name: &str) -> bool {
+    let name = name.strip_prefix("www.").unwrap_or(name);
+    let suffix = match name.strip_prefix("google.") {
+        None => return false,
+        Some(suffix) => suffix,
+    };
+    let is_country_code =
+        |label: &str| label.len() == 2 && label.bytes().all(|c| c.is_ascii_lowercase());
+    match suffix.split_once('.') {
+        None => suffix == "com" || is_country_code(suffix),
+        Some((second_level, country_code)) => {
+            matches!(second_level, "co" | "com") && is_country_code(country_code)
+        }
+    }
+}
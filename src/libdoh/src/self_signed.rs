@@ -0,0 +1,105 @@
+use crate::constants::*;
+use crate::der::*;
+
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
+
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+fn der_sequence(elements: &[&[u8]]) -> Vec<u8> {
+    der_encode(DER_SEQUENCE, &elements.concat())
+}
+
+fn der_bit_string(bits: &[u8]) -> Vec<u8> {
+    der_encode(DER_BIT_STRING, &[&[0], bits].concat())
+}
+
+fn crypto_error<E>(_: E) -> io::Error {
+    io::Error::other("Unable to generate a self-signed certificate")
+}
+
+/// Generates an ECDSA key, and a certificate for `hostname` signed with it.
+/// `hostname` can also be an IP address.
+pub fn self_signed_cert(hostname: &str) -> io::Result<(Certificate, PrivateKey)> {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(crypto_error)?;
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+        .map_err(crypto_error)?;
+
+    let mut serial = [0u8; 16];
+    rng.fill(&mut serial).map_err(crypto_error)?;
+    serial[0] = serial[0] & 0x7f | 0x01; // positive, with no leading zero
+    let signature_algorithm = der_sequence(&[&der_encode(DER_OID, OID_ECDSA_WITH_SHA256)]);
+    let common_name = der_sequence(&[
+        &der_encode(DER_OID, OID_COMMON_NAME),
+        &der_encode(DER_UTF8_STRING, hostname.as_bytes()),
+    ]);
+    let name = der_sequence(&[&der_encode(DER_SET, &common_name)]);
+    let now = SystemTime::now();
+    let validity = der_sequence(&[
+        &der_time(now - Duration::from_secs(86400)),
+        &der_time(now + Duration::from_secs(SELF_SIGNED_CERT_VALIDITY_DAYS * 86400)),
+    ]);
+    let public_key_info = der_sequence(&[
+        &der_sequence(&[
+            &der_encode(DER_OID, OID_EC_PUBLIC_KEY),
+            &der_encode(DER_OID, OID_PRIME256V1),
+        ]),
+        &der_bit_string(key_pair.public_key().as_ref()),
+    ]);
+    let alt_name = match hostname.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => der_encode(DER_IP_ADDRESS, &ip.octets()),
+        Ok(IpAddr::V6(ip)) => der_encode(DER_IP_ADDRESS, &ip.octets()),
+        Err(_) => der_encode(DER_DNS_NAME, hostname.as_bytes()),
+    };
+    let extensions = der_sequence(&[
+        &der_sequence(&[
+            &der_encode(DER_OID, OID_SUBJECT_ALT_NAME),
+            &der_encode(DER_OCTET_STRING, &der_sequence(&[&alt_name])),
+        ]),
+        &der_sequence(&[
+            &der_encode(DER_OID, OID_BASIC_CONSTRAINTS),
+            &der_encode(DER_BOOLEAN, &[0xff]),
+            &der_encode(DER_OCTET_STRING, &der_sequence(&[])),
+        ]),
+    ]);
+    let tbs = der_sequence(&[
+        &der_encode(DER_CONTEXT_0, &der_encode(DER_INTEGER, &[2])), // v3
+        &der_encode(DER_INTEGER, &serial),
+        &signature_algorithm,
+        &name,
+        &validity,
+        &name,
+        &public_key_info,
+        &der_encode(DER_CONTEXT_3, &extensions),
+    ]);
+    let signature = key_pair.sign(&rng, &tbs).map_err(crypto_error)?;
+    let cert = der_sequence(&[
+        &tbs,
+        &signature_algorithm,
+        &der_bit_string(signature.as_ref()),
+    ]);
+
+    let fingerprint: Vec<_> = digest::digest(&digest::SHA256, &cert)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    tracing::info!(
+        hostname,
+        fingerprint = %fingerprint.join(":"),
+        "Generated a self-signed certificate"
+    );
+    Ok((Certificate(cert), PrivateKey(pkcs8.as_ref().to_vec())))
+}
@@ -0,0 +1,44 @@
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+
+/// Converts a socket address to the representation used by system calls, and returns its length
+pub fn to_sockaddr(address: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match address {
+        SocketAddr::V4(address) => {
+            let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            addr.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr.sin_port = address.port().to_be();
+            addr.sin_addr.s_addr = u32::from_ne_bytes(address.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            addr.sin6_port = address.port().to_be();
+            addr.sin6_addr.s6_addr = address.ip().octets();
+            addr.sin6_flowinfo = address.flowinfo();
+            addr.sin6_scope_id = address.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Binds a socket that was created with `libc::socket()`
+pub fn bind(fd: RawFd, address: &SocketAddr) -> io::Result<()> {
+    let (addr, len) = to_sockaddr(address);
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_storage as *const libc::sockaddr,
+            len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,428 @@
+use crate::constants::*;
+use crate::dns;
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Value counted instead of the values that are not allowed, or that exceed the cardinality limit
+const OTHER_LABEL_VALUE: &str = "other";
+
+/// Count-min sketch, estimating how many times every name was seen in a fixed amount of memory.
+/// Estimates can only be too high, never too low.
+#[derive(Debug)]
+struct CountMinSketch {
+    hasher: RandomState,
+    counters: Vec<u32>,
+}
+
+impl CountMinSketch {
+    fn new() -> Self {
+        CountMinSketch {
+            hasher: RandomState::new(),
+            counters: vec![0; STATS_SKETCH_DEPTH * STATS_SKETCH_WIDTH],
+        }
+    }
+
+    /// Returns the index of the counter of every row, derived from a single hash
+    fn indices(&self, name: &str) -> impl Iterator<Item = usize> {
+        let hash = self.hasher.hash_one(name);
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        (0..STATS_SKETCH_DEPTH).map(move |row| {
+            row * STATS_SKETCH_WIDTH + h1.wrapping_add(row.wrapping_mul(h2)) % STATS_SKETCH_WIDTH
+        })
+    }
+
+    /// Counts a name, and returns its new estimate.
+    /// Only the lowest counters are incremented, which reduces the overestimation.
+    fn add(&mut self, name: &str) -> u64 {
+        let indices: Vec<_> = self.indices(name).collect();
+        let estimate = indices
+            .iter()
+            .map(|&i| self.counters[i])
+            .min()
+            .unwrap_or(0)
+            .saturating_add(1);
+        for &i in &indices {
+            if self.counters[i] < estimate {
+                self.counters[i] = estimate;
+            }
+        }
+        estimate as u64
+    }
+}
+
+/// A dimension the queries can be broken down by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StatsLabel {
+    /// The path of the DoH endpoint
+    Path,
+    /// The record type
+    Qtype,
+    /// The upstream server the query was sent to
+    Upstream,
+}
+
+impl StatsLabel {
+    pub fn name(self) -> &'static str {
+        match self {
+            StatsLabel::Path => "path",
+            StatsLabel::Qtype => "qtype",
+            StatsLabel::Upstream => "upstream",
+        }
+    }
+}
+
+impl FromStr for StatsLabel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "path" => Ok(StatsLabel::Path),
+            "qtype" => Ok(StatsLabel::Qtype),
+            "upstream" => Ok(StatsLabel::Upstream),
+            label => Err(format!("Unsupported label: [{}]", label)),
+        }
+    }
+}
+
+/// The labels queries are broken down by, along with the values each of them can take.
+/// Without an explicit list of allowed values, the first values seen are kept, up to a limit.
+#[derive(Debug, Clone, Default)]
+pub struct StatsLabels {
+    labels: Vec<(StatsLabel, Option<HashSet<String>>)>,
+}
+
+impl StatsLabels {
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn contains(&self, label: StatsLabel) -> bool {
+        self.labels.iter().any(|(l, _)| *l == label)
+    }
+
+    /// Only allows the values of a comma-separated list for a label, given as `<label>=<values>`
+    pub fn allow(&mut self, allowed: &str) -> Result<(), String> {
+        let (label, values) = allowed
+            .split_once('=')
+            .ok_or_else(|| format!("Expected [<label>=<values>], got [{}]", allowed))?;
+        let label: StatsLabel = label.parse()?;
+        let values = values
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| match label {
+                StatsLabel::Qtype => dns::parse_qtype(value)
+                    .map(dns::qtype_name)
+                    .ok_or_else(|| format!("Unsupported record type: [{}]", value)),
+                _ => Ok(value.to_string()),
+            })
+            .collect::<Result<HashSet<_>, _>>()?;
+        match self.labels.iter_mut().find(|(l, _)| *l == label) {
+            None => Err(format!("The [{}] label is not enabled", label.name())),
+            Some((_, allowed_values)) => {
+                *allowed_values = Some(values);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for StatsLabels {
+    type Err = String;
+
+    /// Parses a comma-separated list of labels
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stats_labels = StatsLabels::default();
+        for label in s.split(',').filter(|label| !label.trim().is_empty()) {
+            let label: StatsLabel = label.parse()?;
+            if !stats_labels.contains(label) {
+                stats_labels.labels.push((label, None));
+            }
+        }
+        Ok(stats_labels)
+    }
+}
+
+/// Query counts broken down by a set of labels, with a bounded number of series
+#[derive(Debug)]
+struct LabeledCounts {
+    labels: Vec<(StatsLabel, Option<HashSet<String>>)>,
+    seen_values: Vec<HashSet<String>>,
+    series: HashMap<Vec<String>, u64>,
+}
+
+impl LabeledCounts {
+    fn new(labels: Vec<(StatsLabel, Option<HashSet<String>>)>) -> Self {
+        LabeledCounts {
+            seen_values: vec![HashSet::new(); labels.len()],
+            labels,
+            series: HashMap::new(),
+        }
+    }
+
+    /// Replaces a value with `other` if it is not allowed, or if too many values were already seen
+    fn label_value(&mut self, index: usize, value: &str) -> String {
+        let allowed = match &self.labels[index].1 {
+            Some(allowed_values) => allowed_values.contains(value),
+            None => {
+                let seen_values = &mut self.seen_values[index];
+                if seen_values.contains(value) {
+                    true
+                } else if seen_values.len() < STATS_MAX_LABEL_VALUES {
+                    seen_values.insert(value.to_string());
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        if allowed {
+            value.to_string()
+        } else {
+            OTHER_LABEL_VALUE.to_string()
+        }
+    }
+
+    fn add(&mut self, path: &str, qtype: u16, upstream: Option<&str>) {
+        let mut key = Vec::with_capacity(self.labels.len());
+        for index in 0..self.labels.len() {
+            let value = match self.labels[index].0 {
+                StatsLabel::Path => self.label_value(index, path),
+                StatsLabel::Qtype => self.label_value(index, &dns::qtype_name(qtype)),
+                StatsLabel::Upstream => self.label_value(index, upstream.unwrap_or_default()),
+            };
+            key.push(value);
+        }
+        if !self.series.contains_key(&key) && self.series.len() >= STATS_MAX_LABEL_SERIES {
+            key = vec![OTHER_LABEL_VALUE.to_string(); self.labels.len()];
+        }
+        *self.series.entry(key).or_insert(0) += 1;
+    }
+
+    fn summary(&self) -> Vec<(Vec<(StatsLabel, String)>, u64)> {
+        let mut series: Vec<_> = self
+            .series
+            .iter()
+            .map(|(values, &count)| {
+                let labels: Vec<_> = self
+                    .labels
+                    .iter()
+                    .map(|(label, _)| *label)
+                    .zip(values.iter().cloned())
+                    .collect();
+                (labels, count)
+            })
+            .collect();
+        series.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        series
+    }
+}
+
+#[derive(Debug)]
+struct QueryStatsInner {
+    sketch: CountMinSketch,
+    top_domains: HashMap<String, u64>,
+    top_domains_min: u64,
+    qtypes: HashMap<u16, u64>,
+    other_qtypes: u64,
+    rcodes: [u64; 16],
+    failures: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    /// Queries received, broken down by all the labels but the upstream server
+    labeled_queries: Option<LabeledCounts>,
+    /// Queries sent to upstream servers, broken down by all the labels
+    labeled_upstream_queries: Option<LabeledCounts>,
+}
+
+impl QueryStatsInner {
+    /// Keeps the names with the highest estimates, replacing the least queried one
+    /// when a name that is not tracked yet gets a higher estimate
+    fn add_domain(&mut self, name: String) {
+        let estimate = self.sketch.add(&name);
+        if let Some(count) = self.top_domains.get_mut(&name) {
+            *count = estimate;
+            return;
+        }
+        if self.top_domains.len() < STATS_TOP_DOMAINS_CAPACITY {
+            self.top_domains.insert(name, estimate);
+            return;
+        }
+        // Counts only increase, so the cached minimum can be lower than the real one
+        if estimate <= self.top_domains_min {
+            return;
+        }
+        let (least_queried, min) = match self.top_domains.iter().min_by_key(|(_, &count)| count) {
+            None => return,
+            Some((least_queried, &min)) => (least_queried.clone(), min),
+        };
+        self.top_domains_min = min;
+        if estimate > min {
+            self.top_domains.remove(&least_queried);
+            self.top_domains.insert(name, estimate);
+        }
+    }
+}
+
+/// Summary of the queries that were received, using a bounded amount of memory
+#[derive(Debug)]
+pub struct QueryStats {
+    inner: Mutex<QueryStatsInner>,
+    counts_upstream_queries: bool,
+}
+
+/// Snapshot of the query statistics
+#[derive(Debug, Clone)]
+pub struct QueryStatsSummary {
+    pub queries: u64,
+    pub top_domains: Vec<(String, u64)>,
+    pub qtypes: Vec<(u16, u64)>,
+    pub other_qtypes: u64,
+    pub rcodes: Vec<(u8, u64)>,
+    pub failures: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Queries received, for every combination of label values
+    pub labeled_queries: Vec<(Vec<(StatsLabel, String)>, u64)>,
+    /// Queries sent to upstream servers, for every combination of label values
+    pub labeled_upstream_queries: Vec<(Vec<(StatsLabel, String)>, u64)>,
+}
+
+impl Default for QueryStats {
+    fn default() -> Self {
+        QueryStats::new(StatsLabels::default())
+    }
+}
+
+impl QueryStats {
+    /// Also breaks the queries down by `stats_labels`
+    pub fn new(stats_labels: StatsLabels) -> Self {
+        let client_labels: Vec<_> = stats_labels
+            .labels
+            .iter()
+            .filter(|(label, _)| *label != StatsLabel::Upstream)
+            .cloned()
+            .collect();
+        let labeled_queries = if client_labels.is_empty() {
+            None
+        } else {
+            Some(LabeledCounts::new(client_labels))
+        };
+        let counts_upstream_queries = stats_labels.contains(StatsLabel::Upstream);
+        let labeled_upstream_queries = if counts_upstream_queries {
+            Some(LabeledCounts::new(stats_labels.labels))
+        } else {
+            None
+        };
+        QueryStats {
+            counts_upstream_queries,
+            inner: Mutex::new(QueryStatsInner {
+                sketch: CountMinSketch::new(),
+                top_domains: HashMap::new(),
+                top_domains_min: 0,
+                qtypes: HashMap::new(),
+                other_qtypes: 0,
+                rcodes: [0; 16],
+                failures: 0,
+                cache_hits: 0,
+                cache_misses: 0,
+                labeled_queries,
+                labeled_upstream_queries,
+            }),
+        }
+    }
+
+    /// Records a query received on `path`
+    pub fn record_query(&self, qname: String, qtype: u16, path: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.add_domain(qname);
+        if let Some(labeled_queries) = &mut inner.labeled_queries {
+            labeled_queries.add(path, qtype, None);
+        }
+        if let Some(count) = inner.qtypes.get_mut(&qtype) {
+            *count += 1;
+        } else if inner.qtypes.len() < STATS_MAX_QTYPES {
+            inner.qtypes.insert(qtype, 1);
+        } else {
+            inner.other_qtypes += 1;
+        }
+    }
+
+    /// Records the response code of a response, or a failure if no response was sent
+    pub fn record_rcode(&self, rcode: Option<u8>) {
+        let mut inner = self.inner.lock().unwrap();
+        match rcode {
+            Some(rcode) => inner.rcodes[rcode as usize & 0x0f] += 1,
+            None => inner.failures += 1,
+        }
+    }
+
+    /// Returns `true` if queries sent to upstream servers are counted
+    pub fn counts_upstream_queries(&self) -> bool {
+        self.counts_upstream_queries
+    }
+
+    /// Records a query received on `path` and sent to the `upstream` server
+    pub fn record_upstream_query(&self, path: &str, qtype: u16, upstream: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(labeled_upstream_queries) = &mut inner.labeled_upstream_queries {
+            labeled_upstream_queries.add(path, qtype, Some(upstream));
+        }
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if hit {
+            inner.cache_hits += 1;
+        } else {
+            inner.cache_misses += 1;
+        }
+    }
+
+    /// Returns the statistics, with the `top` most queried names first
+    pub fn summary(&self, top: usize) -> QueryStatsSummary {
+        let inner = self.inner.lock().unwrap();
+        let mut top_domains: Vec<_> = inner
+            .top_domains
+            .iter()
+            .map(|(name, &count)| (name.clone(), count))
+            .collect();
+        top_domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_domains.truncate(top);
+        let mut qtypes: Vec<_> = inner
+            .qtypes
+            .iter()
+            .map(|(&qtype, &count)| (qtype, count))
+            .collect();
+        qtypes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let rcodes: Vec<_> = (0..16u8)
+            .map(|rcode| (rcode, inner.rcodes[rcode as usize]))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        let queries = inner.qtypes.values().sum::<u64>() + inner.other_qtypes;
+        QueryStatsSummary {
+            queries,
+            top_domains,
+            qtypes,
+            other_qtypes: inner.other_qtypes,
+            rcodes,
+            failures: inner.failures,
+            cache_hits: inner.cache_hits,
+            cache_misses: inner.cache_misses,
+            labeled_queries: inner
+                .labeled_queries
+                .as_ref()
+                .map(LabeledCounts::summary)
+                .unwrap_or_default(),
+            labeled_upstream_queries: inner
+                .labeled_upstream_queries
+                .as_ref()
+                .map(LabeledCounts::summary)
+                .unwrap_or_default(),
+        }
+    }
+}
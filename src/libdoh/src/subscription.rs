@@ -0,0 +1,102 @@
+use crate::blocklist::{BlockList, BlockRules};
+use crate::constants::*;
+
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, ETAG, IF_NONE_MATCH};
+use hyper::{Body, Client, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::stream::StreamExt;
+use tokio_rustls::rustls::ClientConfig;
+
+/// Downloads a block list periodically, so that it doesn't have to be kept up to date
+/// with an external job.
+/// Lists are only downloaded again if they changed, according to their `ETag`.
+pub struct BlockListSubscription {
+    block_list: Arc<BlockList>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    etag: Option<HeaderValue>,
+}
+
+impl BlockListSubscription {
+    pub fn new(block_list: Arc<BlockList>) -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let mut tls_config = ClientConfig::new();
+        tls_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        let client = Client::builder().build((http, tls_config).into());
+        BlockListSubscription {
+            block_list,
+            client,
+            etag: None,
+        }
+    }
+
+    /// Downloads the list unless it didn't change since the last download, and returns the
+    /// number of bytes that were downloaded.
+    /// The previous rules are kept until the new list has been completely downloaded and parsed.
+    async fn update(&mut self) -> Result<Option<usize>, String> {
+        let url = self.block_list.url().ok_or("Not a remote block list")?;
+        let mut req = Request::get(url.clone());
+        if let Some(etag) = &self.etag {
+            req = req.header(IF_NONE_MATCH, etag.clone());
+        }
+        let req = req.body(Body::empty()).map_err(|e| e.to_string())?;
+        let res = self.client.request(req).await.map_err(|e| e.to_string())?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(format!("status {}", res.status()));
+        }
+        let etag = res.headers().get(ETAG).cloned();
+        let mut body = res.into_body();
+        let mut content = vec![];
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            if content.len() + chunk.len() > BLOCK_LIST_MAX_SIZE {
+                return Err("list too large".to_string());
+            }
+            content.extend_from_slice(&chunk);
+        }
+        let content = String::from_utf8(content).map_err(|_| "invalid UTF-8")?;
+        let rules = BlockRules::parse(&content).map_err(|e| e.to_string())?;
+        self.block_list.replace(rules);
+        self.etag = etag;
+        Ok(Some(content.len()))
+    }
+
+    /// Downloads the list right away, and then every `interval`.
+    /// Failed downloads are retried sooner.
+    pub async fn run(mut self, interval: Duration) {
+        let retry_interval = Duration::from_secs(BLOCK_LIST_RETRY_INTERVAL_SECS).min(interval);
+        let fetch_timeout = Duration::from_secs(BLOCK_LIST_FETCH_TIMEOUT_SECS);
+        loop {
+            let res = tokio::time::timeout(fetch_timeout, self.update())
+                .await
+                .unwrap_or_else(|_| Err("timeout".to_string()));
+            let delay = match res {
+                Ok(Some(size)) => {
+                    tracing::info!(url = %self.block_list, size, "Block list updated");
+                    interval
+                }
+                Ok(None) => {
+                    tracing::debug!(url = %self.block_list, "Block list unchanged");
+                    interval
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        url = %self.block_list,
+                        error = %e,
+                        "Unable to update the block list"
+                    );
+                    retry_interval
+                }
+            };
+            tokio::time::delay_for(delay).await;
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the listening sockets passed by systemd, if the process was socket-activated.
+/// The environment variables are removed so that child processes don't inherit them.
+pub fn listen_fds() -> io::Result<Vec<TcpListener>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let listen_pid: u32 = match listen_pid.and_then(|pid| pid.parse().ok()) {
+        Some(listen_pid) => listen_pid,
+        None => return Ok(vec![]),
+    };
+    if listen_pid != process::id() {
+        return Ok(vec![]);
+    }
+    let listen_fds: RawFd = match listen_fds.and_then(|fds| fds.parse().ok()) {
+        Some(listen_fds) => listen_fds,
+        None => return Ok(vec![]),
+    };
+    let mut listeners = Vec::with_capacity(listen_fds as usize);
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + listen_fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM
+            || socket_option(fd, libc::SO_ACCEPTCONN)? == 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File descriptor {} passed by systemd is not a listening TCP socket",
+                    fd
+                ),
+            ));
+        }
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Returns the value of an integer socket option, as `sd_is_socket()` does to check the
+/// sockets passed by systemd
+fn socket_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "File descriptor {} passed by systemd is not a socket: {}",
+                fd,
+                io::Error::last_os_error()
+            ),
+        ));
+    }
+    Ok(value)
+}
+
+/// Tells the service manager that the service is ready, if it asked to be notified.
+pub fn notify_ready() -> io::Result<()> {
+    notify("READY=1")
+}
+
+fn notify(state: &str) -> io::Result<()> {
+    let notify_socket = match env::var("NOTIFY_SOCKET") {
+        Ok(notify_socket) => notify_socket,
+        Err(_) => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    if let Some(abstract_name) = notify_socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(abstract_name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = abstract_name;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Abstract notification sockets are not supported on this platform",
+            ));
+        }
+    }
+    socket.send_to(state.as_bytes(), &notify_socket)?;
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+
+use byteorder::{BigEndian, ByteOrder};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Sends a query over a stream, and waits for the response.
+/// Messages are prefixed with their length, as in DNS over TCP (RFC 7766).
+/// Responses that don't pass `checks` are skipped.
+pub async fn tcp_exchange<S>(
+    stream: &mut S,
+    query: &[u8],
+    checks: dns::ResponseChecks,
+) -> Result<Vec<u8>, DoHError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if query.len() > 0xffff {
+        return Err(DoHError::TooLarge);
+    }
+    let mut message = Vec::with_capacity(2 + query.len());
+    message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    message.extend_from_slice(query);
+    stream.write_all(&message).await.map_err(DoHError::Io)?;
+    stream.flush().await.map_err(DoHError::Io)?;
+    loop {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).await.map_err(DoHError::Io)?;
+        let len = BigEndian::read_u16(&len) as usize;
+        let mut response = vec![0; len];
+        stream
+            .read_exact(&mut response)
+            .await
+            .map_err(DoHError::Io)?;
+        if len >= MIN_DNS_PACKET_LEN && checks.accept(&response, query) {
+            return Ok(response);
+        }
+    }
+}
@@ -1,42 +1,199 @@
+use crate::constants::*;
+use crate::der::*;
 use crate::errors::*;
+use crate::ocsp::OcspStapler;
+use crate::self_signed::self_signed_cert;
 use crate::{DoH, LocalExecutor};
 
 use hyper::server::conn::Http;
 use std::fs::File;
 use std::io::{self, BufReader};
-use std::path::Path;
-use std::sync::Arc;
-use tokio::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::stream::StreamExt;
 use tokio_rustls::{
-    rustls::{internal::pemfile, NoClientAuth, ServerConfig},
+    rustls::{
+        internal::pemfile,
+        sign::{self, CertifiedKey},
+        AllowAnyAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier,
+        ClientHello, DistinguishedNames, NoClientAuth, PrivateKey, ProducesTickets,
+        ResolvesServerCert, RootCertStore, ServerConfig, ServerSessionMemoryCache, Session,
+        TLSError, Ticketer,
+    },
+    server::TlsStream,
+    webpki::DNSName,
     TlsAcceptor,
 };
 
-pub fn create_tls_acceptor<P, P2>(certs_path: P, certs_keys_path: P2) -> io::Result<TlsAcceptor>
+struct TicketKeys {
+    current: Arc<dyn ProducesTickets>,
+    previous: Option<Arc<dyn ProducesTickets>>,
+    next_rotation: Instant,
+}
+
+/// Encrypts session tickets with a key that is replaced every `interval`.
+/// Tickets encrypted with the previous key are still accepted, so that tickets remain valid
+/// for at least `interval`, and at most twice that duration.
+struct TicketRotator {
+    interval: Duration,
+    keys: Mutex<TicketKeys>,
+}
+
+impl TicketRotator {
+    fn new(interval: Duration) -> Self {
+        TicketRotator {
+            interval,
+            keys: Mutex::new(TicketKeys {
+                current: Ticketer::new(),
+                previous: None,
+                next_rotation: Instant::now() + interval,
+            }),
+        }
+    }
+
+    /// Returns the current keys, after rotating them if the interval has elapsed
+    fn keys(&self) -> (Arc<dyn ProducesTickets>, Option<Arc<dyn ProducesTickets>>) {
+        let mut keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+        if now >= keys.next_rotation {
+            // If no tickets were issued for a whole interval, the previous key is useless
+            let previous = if now >= keys.next_rotation + self.interval {
+                None
+            } else {
+                Some(keys.current.clone())
+            };
+            *keys = TicketKeys {
+                current: Ticketer::new(),
+                previous,
+                next_rotation: now + self.interval,
+            };
+        }
+        (keys.current.clone(), keys.previous.clone())
+    }
+}
+
+impl ProducesTickets for TicketRotator {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.interval.as_secs() as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.keys().0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let (current, previous) = self.keys();
+        current
+            .decrypt(cipher)
+            .or_else(|| previous.and_then(|previous| previous.decrypt(cipher)))
+    }
+}
+
+fn load_certs<P: AsRef<Path>>(certs_path: P) -> io::Result<Vec<Certificate>> {
+    let certs_path_str = certs_path.as_ref().display().to_string();
+    let mut reader = BufReader::new(File::open(certs_path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Unable to load the certificates [{}]: {}",
+                certs_path_str, e
+            ),
+        )
+    })?);
+    pemfile::certs(&mut reader).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unable to parse the certificates",
+        )
+    })
+}
+
+/// Certificate authorities that client certificates have to be signed by, loaded from a file
+/// that can be reloaded while the server is running
+pub struct ClientCa {
+    path: PathBuf,
+    verifier: RwLock<Arc<dyn ClientCertVerifier>>,
+}
+
+impl ClientCa {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let verifier = RwLock::new(client_ca_verifier(&path)?);
+        Ok(ClientCa { path, verifier })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the certificate authorities again; new connections are verified using them
+    pub fn reload(&self) -> io::Result<()> {
+        let verifier = client_ca_verifier(&self.path)?;
+        *self.verifier.write().unwrap() = verifier;
+        Ok(())
+    }
+
+    fn verifier(&self) -> Arc<dyn ClientCertVerifier> {
+        self.verifier.read().unwrap().clone()
+    }
+}
+
+impl ClientCertVerifier for ClientCa {
+    fn client_auth_root_subjects(&self, sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        self.verifier().client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        self.verifier().verify_client_cert(presented_certs, sni)
+    }
+}
+
+fn client_ca_verifier(client_ca_path: &Path) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let mut client_roots = RootCertStore::empty();
+    for client_ca in load_certs(client_ca_path)? {
+        client_roots.add(&client_ca).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid client certificate authority",
+            )
+        })?;
+    }
+    if client_roots.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No client certificate authorities found",
+        ));
+    }
+    Ok(AllowAnyAuthenticatedClient::new(client_roots))
+}
+
+/// Creates a TLS acceptor. If `client_ca` is set, clients have to present a certificate
+/// signed by one of its certificate authorities.
+/// Sessions can be resumed with a session ID, or with a session ticket if
+/// `ticket_rotation_interval` is not zero.
+/// With `ocsp_stapling`, the returned stapler has to be run to fetch OCSP responses.
+pub fn create_tls_acceptor<P, P2>(
+    certs_path: P,
+    certs_keys_path: P2,
+    client_ca: Option<Arc<ClientCa>>,
+    ticket_rotation_interval: Duration,
+    ocsp_stapling: bool,
+) -> io::Result<(TlsAcceptor, Option<Arc<OcspStapler>>)>
 where
     P: AsRef<Path>,
     P2: AsRef<Path>,
 {
-    let certs = {
-        let certs_path_str = certs_path.as_ref().display().to_string();
-        let mut reader = BufReader::new(File::open(certs_path).map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!(
-                    "Unable to load the certificates [{}]: {}",
-                    certs_path_str,
-                    e.to_string()
-                ),
-            )
-        })?);
-        pemfile::certs(&mut reader).map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Unable to parse the certificates",
-            )
-        })?
-    };
+    let certs = load_certs(certs_path)?;
     let certs_keys = {
         let certs_keys_path_str = certs_keys_path.as_ref().display().to_string();
         let mut reader = BufReader::new(File::open(certs_keys_path).map_err(|e| {
@@ -44,8 +201,7 @@ where
                 e.kind(),
                 format!(
                     "Unable to load the certificate keys [{}]: {}",
-                    certs_keys_path_str,
-                    e.to_string()
+                    certs_keys_path_str, e
                 ),
             )
         })?);
@@ -63,19 +219,76 @@ where
         }
         keys
     };
-    let mut server_config = ServerConfig::new(NoClientAuth::new());
-    let has_valid_cert_and_key = certs_keys.into_iter().any(|certs_key| {
-        server_config
-            .set_single_cert(certs.clone(), certs_key)
-            .is_ok()
-    });
-    if !has_valid_cert_and_key {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Invalid private key for the given certificate",
-        ));
+    build_tls_acceptor(
+        certs,
+        certs_keys,
+        client_ca,
+        ticket_rotation_interval,
+        ocsp_stapling,
+    )
+}
+
+/// Creates a TLS acceptor using a self-signed certificate for `hostname`, generated in memory
+pub fn create_self_signed_tls_acceptor(
+    hostname: &str,
+    client_ca: Option<Arc<ClientCa>>,
+    ticket_rotation_interval: Duration,
+) -> io::Result<TlsAcceptor> {
+    let (cert, key) = self_signed_cert(hostname)?;
+    let (tls_acceptor, _) = build_tls_acceptor(
+        vec![cert],
+        vec![key],
+        client_ca,
+        ticket_rotation_interval,
+        false,
+    )?;
+    Ok(tls_acceptor)
+}
+
+fn build_tls_acceptor(
+    certs: Vec<Certificate>,
+    certs_keys: Vec<PrivateKey>,
+    client_ca: Option<Arc<ClientCa>>,
+    ticket_rotation_interval: Duration,
+    ocsp_stapling: bool,
+) -> io::Result<(TlsAcceptor, Option<Arc<OcspStapler>>)> {
+    let client_auth = match client_ca {
+        None => NoClientAuth::new(),
+        Some(client_ca) => client_ca as Arc<dyn ClientCertVerifier>,
+    };
+    let mut server_config = ServerConfig::new(client_auth);
+    server_config.set_persistence(ServerSessionMemoryCache::new(TLS_SESSION_CACHE_SIZE));
+    if ticket_rotation_interval > Duration::default() {
+        server_config.ticketer = Arc::new(TicketRotator::new(ticket_rotation_interval));
+    }
+    let signing_key = certs_keys
+        .iter()
+        .find_map(|certs_key| sign::any_supported_type(certs_key).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid private key for the given certificate",
+            )
+        })?;
+    let certified_key = CertifiedKey::new(certs, Arc::new(signing_key));
+    let ocsp_stapler = if ocsp_stapling {
+        let ocsp_stapler = Arc::new(OcspStapler::new(certified_key)?);
+        server_config.cert_resolver = ocsp_stapler.clone();
+        Some(ocsp_stapler)
+    } else {
+        server_config.cert_resolver = Arc::new(SingleCert(certified_key));
+        None
+    };
+    Ok((TlsAcceptor::from(Arc::new(server_config)), ocsp_stapler))
+}
+
+/// Always uses the same certificate
+struct SingleCert(CertifiedKey);
+
+impl ResolvesServerCert for SingleCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<CertifiedKey> {
+        Some(self.0.clone())
     }
-    Ok(TlsAcceptor::from(Arc::new(server_config)))
 }
 
 impl DoH {
@@ -86,20 +299,96 @@ impl DoH {
         server: Http<LocalExecutor>,
     ) -> Result<(), DoHError> {
         let listener_service = async {
-            while let Some(raw_stream) = listener.incoming().next().await {
-                let raw_stream = match raw_stream {
-                    Ok(raw_stream) => raw_stream,
-                    Err(_) => continue,
-                };
-                let stream = match tls_acceptor.accept(raw_stream).await {
-                    Ok(stream) => stream,
-                    Err(_) => continue,
+            loop {
+                // Connections are left in the queue of the socket while there are too many clients
+                self.globals.clients_count.wait_for_slot().await;
+                let raw_stream = match listener.incoming().next().await {
+                    None => break,
+                    Some(Err(_)) => continue,
+                    Some(Ok(raw_stream)) => raw_stream,
                 };
-                self.clone().client_serve(stream, server.clone()).await;
+                let client_slot = self.globals.clients_count.acquire().await;
+                let remote_addr = raw_stream.peer_addr().ok();
+                self.set_tcp_keepalive(&raw_stream);
+                let tls_acceptor = tls_acceptor.clone();
+                let server = server.clone();
+                let mut doh = self.clone();
+                // Handshakes are made in their own task, with a deadline,
+                // so that slow clients cannot prevent other connections from being accepted
+                tokio::spawn(async move {
+                    if let Some(stream) = doh.tls_handshake(&tls_acceptor, raw_stream).await {
+                        doh.client_serve(stream, remote_addr, server, client_slot)
+                            .await;
+                    }
+                });
             }
             Ok(()) as Result<(), DoHError>
         };
         listener_service.await?;
         Ok(())
     }
+
+    /// Completes the TLS handshake of a new connection, within the header read timeout,
+    /// and records the identity of the client if it presented a certificate
+    pub(crate) async fn tls_handshake(
+        &mut self,
+        tls_acceptor: &TlsAcceptor,
+        raw_stream: TcpStream,
+    ) -> Option<TlsStream<TcpStream>> {
+        let remote_addr = raw_stream.peer_addr().ok();
+        let handshake_timeout = self.globals.header_read_timeout;
+        let stream =
+            match tokio::time::timeout(handshake_timeout, tls_acceptor.accept(raw_stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    tracing::debug!(
+                        client = remote_addr.map(tracing::field::display),
+                        error = %e,
+                        "TLS handshake failed"
+                    );
+                    return None;
+                }
+                Err(_) => {
+                    tracing::debug!(
+                        client = remote_addr.map(tracing::field::display),
+                        "TLS handshake timed out"
+                    );
+                    return None;
+                }
+            };
+        self.client_identity = stream
+            .get_ref()
+            .1
+            .get_peer_certificates()
+            .and_then(|certs| certs.first().and_then(|cert| common_name(&cert.0)))
+            .map(Arc::from);
+        Some(stream)
+    }
+}
+
+/// Returns the common name of the subject of a DER-encoded certificate
+fn common_name(cert: &[u8]) -> Option<String> {
+    const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+    let (_, cert, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(cert)?;
+    let (tag, _, mut tbs) = der_element(tbs)?;
+    if tag == 0xa0 {
+        tbs = der_element(tbs)?.2; // serial number, after the version
+    }
+    // signature algorithm, issuer, validity, subject
+    let tbs = der_element(tbs)?.2;
+    let tbs = der_element(tbs)?.2;
+    let tbs = der_element(tbs)?.2;
+    let (_, mut rdns, _) = der_element(tbs)?;
+    while !rdns.is_empty() {
+        let (_, rdn, rest) = der_element(rdns)?;
+        rdns = rest;
+        let (_, attribute, _) = der_element(rdn)?;
+        let (_, oid, attribute) = der_element(attribute)?;
+        if oid == OID_COMMON_NAME {
+            let (_, value, _) = der_element(attribute)?;
+            return Some(String::from_utf8_lossy(value).into_owned());
+        }
+    }
+    None
 }
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+/// TTLs replacing the minimum and maximum TTLs of the path, for the names of a zone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TtlOverride {
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+}
+
+impl TtlOverride {
+    /// Returns the minimum and maximum TTLs, replacing the ones that are overridden
+    pub fn apply(&self, min_ttl: u32, max_ttl: u32) -> (u32, u32) {
+        (
+            self.min_ttl.unwrap_or(min_ttl),
+            self.max_ttl.unwrap_or(max_ttl),
+        )
+    }
+}
+
+/// Minimum and maximum TTLs for specific zones, so that the names of a CDN can be cached
+/// for less time, or stable names for longer. The most specific zone wins.
+#[derive(Debug, Clone, Default)]
+pub struct TtlOverrides {
+    overrides: HashMap<String, TtlOverride>,
+}
+
+impl TtlOverrides {
+    /// Applies `ttl_override` to `domain` and its subdomains
+    pub fn add(&mut self, domain: &str, ttl_override: TtlOverride) -> Result<(), String> {
+        if let (Some(min_ttl), Some(max_ttl)) = (ttl_override.min_ttl, ttl_override.max_ttl) {
+            if min_ttl > max_ttl {
+                return Err(format!(
+                    "The minimum TTL of [{}] is larger than its maximum TTL",
+                    domain
+                ));
+            }
+        }
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.overrides.insert(domain, ttl_override);
+        Ok(())
+    }
+
+    /// Returns the override for `qname`, that has to be in lowercase
+    pub fn get(&self, qname: &str) -> Option<&TtlOverride> {
+        let mut name = qname.trim_end_matches('.');
+        loop {
+            if let Some(ttl_override) = self.overrides.get(name) {
+                return Some(ttl_override);
+            }
+            match name.find('.') {
+                Some(dot) => name = &name[dot + 1..],
+                None => return None,
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.overrides.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &TtlOverride)> {
+        self.overrides
+            .iter()
+            .map(|(domain, ttl_override)| (domain.as_str(), ttl_override))
+    }
+}
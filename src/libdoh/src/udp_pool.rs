@@ -0,0 +1,339 @@
+use crate::buffer_pool::response_packet;
+use crate::constants::*;
+use crate::dns;
+use crate::egress;
+use crate::errors::*;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::UringDriver;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct PendingQuery {
+    server_address: SocketAddr,
+    query: Vec<u8>,
+    checks: dns::ResponseChecks,
+    tx: oneshot::Sender<Vec<u8>>,
+}
+
+type PendingQueries = Mutex<HashMap<u16, PendingQuery>>;
+
+#[derive(Debug)]
+enum SocketSender {
+    Tokio(tokio::sync::Mutex<SendHalf>),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(Arc<UringDriver>, RawFd),
+}
+
+#[derive(Debug)]
+struct PooledSocket {
+    sender: SocketSender,
+    pending: PendingQueries,
+    queries: AtomicUsize,
+    created: Instant,
+    /// Set once responses can no longer be received
+    retired: AtomicBool,
+}
+
+impl PooledSocket {
+    fn new(sender: SocketSender) -> Self {
+        PooledSocket {
+            sender,
+            pending: Mutex::new(HashMap::new()),
+            queries: AtomicUsize::new(0),
+            created: Instant::now(),
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    /// Sockets are replaced after a few queries, so that the source port of queries keeps
+    /// changing and can't be guessed by an attacker trying to spoof responses
+    fn is_worn_out(&self) -> bool {
+        self.is_retired()
+            || self.queries.load(Ordering::Relaxed) >= UDP_POOL_SOCKET_MAX_QUERIES
+            || self.created.elapsed() >= Duration::from_secs(UDP_POOL_SOCKET_MAX_AGE_SECS)
+    }
+
+    fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    /// Stops using the socket, and fails the queries waiting for a response
+    fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// Completes the pending query a response was received for, if there is one
+    fn dispatch(&self, response: &[u8], response_server_address: SocketAddr) {
+        if response.len() < MIN_DNS_PACKET_LEN {
+            return;
+        }
+        let id = BigEndian::read_u16(response);
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(&id) {
+            Some(pending_query)
+                if pending_query.server_address == response_server_address
+                    && pending_query.checks.accept(response, &pending_query.query) => {}
+            _ => return,
+        }
+        if let Some(pending_query) = pending.remove(&id) {
+            let _ = pending_query.tx.send(response_packet(response));
+        }
+    }
+
+    async fn send_to(&self, query: Vec<u8>, server_address: SocketAddr) -> Result<(), DoHError> {
+        match &self.sender {
+            SocketSender::Tokio(send_half) => send_half
+                .lock()
+                .await
+                .send_to(&query, &server_address)
+                .await
+                .map(|_| ()),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            SocketSender::Uring(driver, fd) => driver.send_to(*fd, query, server_address).await,
+        }
+        .map_err(DoHError::Io)
+    }
+}
+
+/// Removes a pending query if its response never arrived
+struct PendingGuard<'t> {
+    pending: &'t PendingQueries,
+    id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl Drop for PooledSocket {
+    fn drop(&mut self) {
+        if let SocketSender::Uring(driver, fd) = &self.sender {
+            let _ = driver.unregister(*fd);
+        }
+    }
+}
+
+/// A set of UDP sockets shared by all upstream queries.
+/// Queries sent over the same socket are told apart using their transaction ID,
+/// which is replaced with a random, unused value before the query is sent.
+/// Every slot has a socket for each local address queries are sent from, that is replaced
+/// with a socket bound to a new port after a bounded number of queries or amount of time.
+/// Replaced sockets are closed once the responses to their last queries have been received.
+#[derive(Debug)]
+pub struct UdpPool {
+    slots: Vec<tokio::sync::Mutex<HashMap<SocketAddr, Arc<PooledSocket>>>>,
+    next_slot: AtomicUsize,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<Arc<UringDriver>>,
+}
+
+impl UdpPool {
+    pub fn new(size: usize) -> Self {
+        let slots = (0..size.max(1))
+            .map(|_| tokio::sync::Mutex::new(HashMap::new()))
+            .collect();
+        UdpPool {
+            slots,
+            next_slot: AtomicUsize::new(0),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring: None,
+        }
+    }
+
+    /// Creates a pool whose sockets are driven by io_uring instead of epoll.
+    /// Fails if io_uring is not available.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn with_io_uring(size: usize) -> Result<Self, DoHError> {
+        let mut udp_pool = Self::new(size);
+        udp_pool.uring = Some(UringDriver::new().map_err(DoHError::Io)?);
+        Ok(udp_pool)
+    }
+
+    /// Returns `true` if the sockets of the pool are driven by io_uring
+    pub fn uses_io_uring(&self) -> bool {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        return self.uring.is_some();
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        false
+    }
+
+    async fn socket(
+        &self,
+        local_bind_address: SocketAddr,
+        bind_device: Option<&str>,
+    ) -> Result<Arc<PooledSocket>, DoHError> {
+        let slot_idx = self.next_slot.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let mut slot = self.slots[slot_idx].lock().await;
+        let socket = match slot.get(&local_bind_address) {
+            Some(socket) if !socket.is_worn_out() => socket.clone(),
+            Some(socket) => match self.bind(local_bind_address, bind_device) {
+                Ok(new_socket) => {
+                    slot.insert(local_bind_address, new_socket.clone());
+                    new_socket
+                }
+                Err(e) if socket.is_retired() => return Err(e),
+                Err(e) => {
+                    tracing::debug!(error = %e, "Unable to replace a pooled UDP socket");
+                    socket.clone()
+                }
+            },
+            None => {
+                let socket = self.bind(local_bind_address, bind_device)?;
+                slot.insert(local_bind_address, socket.clone());
+                socket
+            }
+        };
+        socket.queries.fetch_add(1, Ordering::Relaxed);
+        Ok(socket)
+    }
+
+    fn bind(
+        &self,
+        local_bind_address: SocketAddr,
+        bind_device: Option<&str>,
+    ) -> Result<Arc<PooledSocket>, DoHError> {
+        let socket = egress::bind_udp(local_bind_address, bind_device).map_err(DoHError::Io)?;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(driver) = &self.uring {
+                let pooled_socket = Arc::new(PooledSocket::new(SocketSender::Uring(
+                    driver.clone(),
+                    socket.as_raw_fd(),
+                )));
+                let pooled_socket_ = Arc::downgrade(&pooled_socket);
+                driver
+                    .register(
+                        socket,
+                        Box::new(move |response, response_server_address| {
+                            if let Some(pooled_socket) = pooled_socket_.upgrade() {
+                                pooled_socket.dispatch(response, response_server_address)
+                            }
+                        }),
+                    )
+                    .map_err(DoHError::Io)?;
+                return Ok(pooled_socket);
+            }
+        }
+        let socket = UdpSocket::from_std(socket).map_err(DoHError::Io)?;
+        let (recv_half, send_half) = socket.split();
+        let socket = Arc::new(PooledSocket::new(SocketSender::Tokio(
+            tokio::sync::Mutex::new(send_half),
+        )));
+        tokio::spawn(Self::dispatch_responses(recv_half, Arc::downgrade(&socket)));
+        Ok(socket)
+    }
+
+    /// Receives responses until the socket has been replaced and is no longer used, or until
+    /// receiving fails for good, in which case the socket is retired
+    async fn dispatch_responses(mut recv_half: RecvHalf, socket: Weak<PooledSocket>) {
+        let mut packet = vec![0; MAX_DNS_RESPONSE_LEN];
+        let check_interval = Duration::from_secs(UDP_POOL_SOCKET_CHECK_INTERVAL_SECS);
+        loop {
+            let res = tokio::time::timeout(check_interval, recv_half.recv_from(&mut packet)).await;
+            let socket = match socket.upgrade() {
+                None => return,
+                Some(socket) => socket,
+            };
+            let (len, response_server_address) = match res {
+                Ok(Ok(res)) => res,
+                Ok(Err(e)) if is_transient(&e) => {
+                    tracing::warn!("Unable to receive a datagram, retrying: {}", e);
+                    drop(socket);
+                    tokio::time::delay_for(Duration::from_millis(UDP_POOL_RECV_RETRY_DELAY_MS))
+                        .await;
+                    continue;
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Unable to receive datagrams: {}", e);
+                    socket.retire();
+                    return;
+                }
+                Err(_) => continue,
+            };
+            socket.dispatch(&packet[..len], response_server_address);
+        }
+    }
+
+    /// Sends a query to an upstream server and waits for the matching response.
+    /// Sockets are bound to `local_bind_address` and `bind_device` the first time they are used.
+    /// Responses that don't pass `checks` are ignored.
+    pub async fn exchange(
+        &self,
+        query: &[u8],
+        local_bind_address: SocketAddr,
+        bind_device: Option<&str>,
+        server_address: SocketAddr,
+        checks: dns::ResponseChecks,
+    ) -> Result<Vec<u8>, DoHError> {
+        if query.len() < MIN_DNS_PACKET_LEN {
+            return Err(DoHError::Incomplete);
+        }
+        let socket = self.socket(local_bind_address, bind_device).await?;
+        let original_id = BigEndian::read_u16(query);
+        let mut query = query.to_vec();
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut pending = socket.pending.lock().unwrap();
+            if pending.len() > 0xffff / 2 {
+                return Err(DoHError::UpstreamIssue);
+            }
+            let mut id = rand::random();
+            while pending.contains_key(&id) {
+                id = rand::random();
+            }
+            BigEndian::write_u16(&mut query, id);
+            pending.insert(
+                id,
+                PendingQuery {
+                    server_address,
+                    query: query.clone(),
+                    checks,
+                    tx,
+                },
+            );
+            id
+        };
+        let _guard = PendingGuard {
+            pending: &socket.pending,
+            id,
+        };
+        socket.send_to(query, server_address).await?;
+        let mut response = rx.await.map_err(|_| DoHError::UpstreamIssue)?;
+        BigEndian::write_u16(&mut response, original_id);
+        Ok(response)
+    }
+}
+
+/// Errors after which receiving again is likely to succeed, possibly after a while
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::OutOfMemory
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset => true,
+        #[cfg(unix)]
+        _ => matches!(
+            e.raw_os_error(),
+            Some(libc::ENOBUFS) | Some(libc::EHOSTUNREACH) | Some(libc::ENETUNREACH)
+        ),
+        #[cfg(not(unix))]
+        _ => false,
+    }
+}
@@ -0,0 +1,401 @@
+use crate::bootstrap::BootstrapResolver;
+use crate::constants::*;
+use crate::globals::unspecified_bind_address;
+
+use rand::Rng;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CLIENT_COOKIE_LEN: usize = 8;
+const SERVER_COOKIE_MIN_LEN: usize = 8;
+const SERVER_COOKIE_MAX_LEN: usize = 32;
+
+/// How the upstream server a query is sent to is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamStrategy {
+    /// The first responding server, in the order they were given
+    Failover,
+    /// A random server, with a probability proportional to its weight
+    Weighted,
+    /// The server with the lowest round-trip time
+    Latency,
+}
+
+impl FromStr for UpstreamStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(UpstreamStrategy::Failover),
+            "weighted" => Ok(UpstreamStrategy::Weighted),
+            "latency" => Ok(UpstreamStrategy::Latency),
+            _ => Err(format!("Unsupported upstream strategy: [{}]", s)),
+        }
+    }
+}
+
+/// Statistics about the queries sent to an upstream server
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamStats {
+    pub queries: u64,
+    pub timeouts: u64,
+    /// Moving average of the round-trip time
+    pub rtt: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct UpstreamState {
+    stats: UpstreamStats,
+    rtt_updated: Option<Instant>,
+    consecutive_timeouts: u32,
+    last_timeout: Option<Instant>,
+    /// Set when the fallback address answered a query the preferred address didn't
+    fallback_answered: bool,
+    /// Server cookie returned by the server in its last response (RFC 7873)
+    server_cookie: Option<Vec<u8>>,
+    /// Set after a timeout, when large responses may be dropped due to fragmentation
+    udp_size_reduced: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UpstreamAddresses {
+    server_address: SocketAddr,
+    /// Address of the other family, that queries are also sent to if the server doesn't
+    /// answer quickly enough
+    fallback_address: Option<SocketAddr>,
+}
+
+impl UpstreamAddresses {
+    /// Prefers the first address, and uses the first address of the other family as a
+    /// fallback (RFC 8305)
+    fn from_resolved(server_addresses: &[SocketAddr]) -> Option<Self> {
+        let server_address = *server_addresses.first()?;
+        let fallback_address = server_addresses
+            .iter()
+            .find(|address| address.is_ipv4() != server_address.is_ipv4())
+            .copied();
+        Some(UpstreamAddresses {
+            server_address,
+            fallback_address,
+        })
+    }
+
+    /// Returns `true` if the addresses are still valid for a server name that now resolves
+    /// to `server_addresses`, so that a different order doesn't cause a change
+    fn matches_resolved(&self, server_addresses: &[SocketAddr]) -> bool {
+        server_addresses.contains(&self.server_address)
+            && match self.fallback_address {
+                Some(fallback_address) => server_addresses.contains(&fallback_address),
+                None => server_addresses
+                    .iter()
+                    .all(|address| address.is_ipv4() == self.server_address.is_ipv4()),
+            }
+    }
+}
+
+/// An upstream server, along with the addresses to send queries from
+#[derive(Debug)]
+pub struct Upstream {
+    /// `host:port` name of the server, if it was not given as an address
+    pub server_name: Option<String>,
+    pub weight: u32,
+    addresses: Mutex<UpstreamAddresses>,
+    local_bind_address_v4: SocketAddr,
+    local_bind_address_v6: SocketAddr,
+    client_cookie: [u8; CLIENT_COOKIE_LEN],
+    state: Mutex<UpstreamState>,
+}
+
+impl Upstream {
+    /// Sends queries to `server_address` from an address of the same family
+    pub fn new(server_address: SocketAddr, weight: u32) -> Self {
+        let mut upstream = Upstream {
+            server_name: None,
+            weight,
+            addresses: Mutex::new(UpstreamAddresses {
+                server_address,
+                fallback_address: None,
+            }),
+            local_bind_address_v4: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            local_bind_address_v6: (Ipv6Addr::UNSPECIFIED, 0).into(),
+            client_cookie: rand::random(),
+            state: Mutex::new(UpstreamState::default()),
+        };
+        if server_address.is_ipv6() {
+            upstream.local_bind_address_v6 = unspecified_bind_address(&server_address);
+        }
+        upstream
+    }
+
+    /// Resolves a `host:port` server name, that can be resolved again later with
+    /// `refresh_addresses()`, using the `bootstrap` resolver if there is one.
+    /// If the name has addresses of both families, the first one is preferred, and the first
+    /// address of the other family is used as a fallback.
+    pub fn resolve(
+        server_name: &str,
+        weight: u32,
+        bootstrap: Option<&BootstrapResolver>,
+    ) -> Result<Self, String> {
+        if let Ok(server_address) = server_name.parse() {
+            return Ok(Upstream::new(server_address, weight));
+        }
+        let server_addresses: Vec<_> = match bootstrap {
+            Some(bootstrap) => bootstrap.lookup_host_blocking(server_name),
+            None => server_name
+                .to_socket_addrs()
+                .map(|server_addresses| server_addresses.collect()),
+        }
+        .map_err(|e| format!("[{}]: {}", server_name, e))?;
+        let addresses = UpstreamAddresses::from_resolved(&server_addresses)
+            .ok_or_else(|| format!("[{}]: no addresses found", server_name))?;
+        let mut upstream = Upstream::new(addresses.server_address, weight);
+        if let Some(fallback_address) = addresses.fallback_address {
+            upstream = upstream.with_fallback_address(fallback_address);
+        }
+        upstream.server_name = Some(server_name.to_string());
+        Ok(upstream)
+    }
+
+    /// Also sends queries to `fallback_address` if `server_address` doesn't answer quickly enough
+    pub fn with_fallback_address(mut self, fallback_address: SocketAddr) -> Self {
+        if fallback_address.is_ipv6() {
+            self.local_bind_address_v6 = unspecified_bind_address(&fallback_address);
+        }
+        self.addresses.get_mut().unwrap().fallback_address = Some(fallback_address);
+        self
+    }
+
+    /// Resolves the server name again, with the `bootstrap` resolver if there is one, and uses
+    /// the new addresses if the previous ones are gone. Returns `true` if the addresses changed.
+    pub async fn refresh_addresses(
+        &self,
+        bootstrap: Option<&BootstrapResolver>,
+    ) -> io::Result<bool> {
+        let server_name = match &self.server_name {
+            None => return Ok(false),
+            Some(server_name) => server_name.as_str(),
+        };
+        let server_addresses: Vec<_> = match bootstrap {
+            Some(bootstrap) => bootstrap.lookup_host(server_name).await?,
+            None => tokio::net::lookup_host(server_name).await?.collect(),
+        };
+        let new_addresses = UpstreamAddresses::from_resolved(&server_addresses)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No addresses found"))?;
+        let mut addresses = self.addresses.lock().unwrap();
+        if addresses.matches_resolved(&server_addresses) {
+            return Ok(false);
+        }
+        *addresses = new_addresses;
+        let mut state = self.state.lock().unwrap();
+        state.fallback_answered = false;
+        state.server_cookie = None;
+        state.udp_size_reduced = false;
+        Ok(true)
+    }
+
+    pub fn server_address(&self) -> SocketAddr {
+        self.addresses.lock().unwrap().server_address
+    }
+
+    pub fn fallback_address(&self) -> Option<SocketAddr> {
+        self.addresses.lock().unwrap().fallback_address
+    }
+
+    /// Returns the address to send a query to first, and the address to race it with, if any.
+    /// The address that answered the previous query comes first.
+    pub fn race_addresses(&self) -> (SocketAddr, Option<SocketAddr>) {
+        let addresses = *self.addresses.lock().unwrap();
+        match addresses.fallback_address {
+            Some(fallback_address) if self.state.lock().unwrap().fallback_answered => {
+                (fallback_address, Some(addresses.server_address))
+            }
+            fallback_address => (addresses.server_address, fallback_address),
+        }
+    }
+
+    pub fn record_answering_address(&self, address: SocketAddr) {
+        let fallback_answered = Some(address) == self.fallback_address();
+        self.state.lock().unwrap().fallback_answered = fallback_answered;
+    }
+
+    /// Returns the address to send queries to `server_address` from
+    pub fn local_bind_address(&self, server_address: &SocketAddr) -> SocketAddr {
+        match server_address {
+            SocketAddr::V4(_) => self.local_bind_address_v4,
+            SocketAddr::V6(_) => self.local_bind_address_v6,
+        }
+    }
+
+    /// Sends the queries to the servers of the same family from `local_bind_address`
+    pub fn set_local_bind_address(&mut self, local_bind_address: SocketAddr) {
+        match local_bind_address {
+            SocketAddr::V4(_) => self.local_bind_address_v4 = local_bind_address,
+            SocketAddr::V6(_) => self.local_bind_address_v6 = local_bind_address,
+        }
+    }
+
+    /// Returns the data of the DNS cookie option to send: the client cookie, followed by the
+    /// last server cookie of the server, if there is one
+    pub fn cookie(&self) -> Vec<u8> {
+        let mut cookie = self.client_cookie.to_vec();
+        if let Some(server_cookie) = &self.state.lock().unwrap().server_cookie {
+            cookie.extend_from_slice(server_cookie);
+        }
+        cookie
+    }
+
+    /// Checks that a cookie option sent by the server includes the client cookie, and keeps
+    /// the server cookie for the next queries. Returns `false` if the response has to be
+    /// ignored, as it is not for a query that was sent to this server.
+    pub fn record_cookie(&self, cookie: &[u8]) -> bool {
+        if cookie.len() < CLIENT_COOKIE_LEN || cookie[..CLIENT_COOKIE_LEN] != self.client_cookie {
+            return false;
+        }
+        let server_cookie = &cookie[CLIENT_COOKIE_LEN..];
+        if (SERVER_COOKIE_MIN_LEN..=SERVER_COOKIE_MAX_LEN).contains(&server_cookie.len()) {
+            self.state.lock().unwrap().server_cookie = Some(server_cookie.to_vec());
+        }
+        true
+    }
+
+    /// Returns `true` if queries have to advertise a smaller UDP payload size
+    pub fn is_udp_size_reduced(&self) -> bool {
+        self.state.lock().unwrap().udp_size_reduced
+    }
+
+    /// Advertises a smaller UDP payload size in the next queries.
+    /// Returns `false` if it was already reduced.
+    pub fn reduce_udp_size(&self) -> bool {
+        !std::mem::replace(&mut self.state.lock().unwrap().udp_size_reduced, true)
+    }
+
+    pub fn record_response(&self, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.queries += 1;
+        state.stats.rtt = Some(match state.stats.rtt {
+            None => rtt,
+            Some(average) => {
+                average.mul_f64(1.0 - UPSTREAM_RTT_SMOOTHING) + rtt.mul_f64(UPSTREAM_RTT_SMOOTHING)
+            }
+        });
+        state.rtt_updated = Some(Instant::now());
+        state.consecutive_timeouts = 0;
+    }
+
+    pub fn record_timeout(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.queries += 1;
+        state.stats.timeouts += 1;
+        state.consecutive_timeouts += 1;
+        state.last_timeout = Some(Instant::now());
+    }
+
+    pub fn stats(&self) -> UpstreamStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Returns `true` if the last query timed out, until it is time to try the server again
+    fn is_failing(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.consecutive_timeouts > 0
+            && matches!(state.last_timeout, Some(last_timeout)
+                if last_timeout.elapsed() < Duration::from_secs(UPSTREAM_FAILURE_RETRY_SECS))
+    }
+
+    /// Returns the average round-trip time, halved every `UPSTREAM_RTT_HALF_LIFE_SECS`
+    /// without new measurements, so that slow servers are eventually tried again.
+    /// Servers that were never queried get a zero score.
+    fn decayed_rtt(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        match (state.stats.rtt, state.rtt_updated) {
+            (Some(rtt), Some(rtt_updated)) => {
+                let half_lives =
+                    rtt_updated.elapsed().as_secs_f64() / UPSTREAM_RTT_HALF_LIFE_SECS as f64;
+                rtt.mul_f64(0.5f64.powf(half_lives))
+            }
+            _ => Duration::default(),
+        }
+    }
+}
+
+/// The upstream servers of a path, and the strategy used to choose between them
+#[derive(Debug)]
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+    strategy: UpstreamStrategy,
+}
+
+impl Upstreams {
+    pub fn new(upstreams: Vec<Upstream>, strategy: UpstreamStrategy) -> Self {
+        assert!(!upstreams.is_empty(), "No upstream servers");
+        Upstreams {
+            upstreams,
+            strategy,
+        }
+    }
+
+    pub fn single(server_address: SocketAddr) -> Self {
+        Self::new(
+            vec![Upstream::new(server_address, 1)],
+            UpstreamStrategy::Failover,
+        )
+    }
+
+    pub fn strategy(&self) -> UpstreamStrategy {
+        self.strategy
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Upstream> {
+        self.upstreams.iter()
+    }
+
+    /// Sends the queries to the servers of the same family from `local_bind_address`
+    pub fn set_local_bind_address(&mut self, local_bind_address: SocketAddr) {
+        for upstream in &mut self.upstreams {
+            upstream.set_local_bind_address(local_bind_address);
+        }
+    }
+
+    /// Returns the servers in the order they should be tried for a query.
+    /// Servers whose last query timed out come last.
+    pub fn order(&self) -> Vec<&Upstream> {
+        let (mut order, failing): (Vec<_>, Vec<_>) = self
+            .upstreams
+            .iter()
+            .partition(|upstream| !upstream.is_failing());
+        match self.strategy {
+            UpstreamStrategy::Failover => {}
+            UpstreamStrategy::Weighted => weighted_shuffle(&mut order),
+            UpstreamStrategy::Latency => order.sort_by_key(|upstream| upstream.decayed_rtt()),
+        }
+        order.extend(failing);
+        order
+    }
+}
+
+/// Orders servers randomly, each position being picked with a probability proportional to
+/// the weight of the remaining servers. Servers with a zero weight come last.
+fn weighted_shuffle(upstreams: &mut [&Upstream]) {
+    let mut rng = rand::thread_rng();
+    for i in 0..upstreams.len() {
+        let total: u64 = upstreams[i..]
+            .iter()
+            .map(|upstream| upstream.weight as u64)
+            .sum();
+        if total == 0 {
+            break;
+        }
+        let mut pick = rng.gen_range(0, total);
+        for j in i..upstreams.len() {
+            let weight = upstreams[j].weight as u64;
+            if pick < weight {
+                upstreams.swap(i, j);
+                break;
+            }
+            pick -= weight;
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use crate::constants::*;
+use crate::sockaddr;
+use crate::udp_pool::is_transient;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::oneshot;
+
+/// Called with every datagram received on a registered socket, and the address it came from
+pub type DatagramHandler = Box<dyn Fn(&[u8], SocketAddr) + Send + Sync>;
+
+const WAKEUP: u64 = 0;
+const RECV: u64 = 1;
+const SEND: u64 = 2;
+const CANCEL: u64 = 3;
+const RETRY: u64 = 4;
+const KIND_BITS: u64 = 3;
+
+/// How long to wait before receiving again after a transient error
+static RECV_RETRY_DELAY: types::Timespec =
+    types::Timespec::new().nsec(URING_RECV_RETRY_DELAY_MS * 1_000_000);
+
+enum Request {
+    Register {
+        socket: UdpSocket,
+        handler: DatagramHandler,
+    },
+    Send {
+        fd: RawFd,
+        packet: Vec<u8>,
+        to: SocketAddr,
+        tx: oneshot::Sender<io::Result<()>>,
+    },
+    Unregister {
+        fd: RawFd,
+    },
+}
+
+/// A datagram and its address, boxed so that the kernel can keep pointers to them
+/// until the operation completes
+struct Message {
+    header: libc::msghdr,
+    address: libc::sockaddr_storage,
+    iov: libc::iovec,
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(buf: Vec<u8>) -> Box<Self> {
+        let mut message = Box::new(Message {
+            header: unsafe { mem::zeroed() },
+            address: unsafe { mem::zeroed() },
+            iov: unsafe { mem::zeroed() },
+            buf,
+        });
+        message.reset();
+        message
+    }
+
+    fn reset(&mut self) {
+        self.iov.iov_base = self.buf.as_mut_ptr() as *mut libc::c_void;
+        self.iov.iov_len = self.buf.len();
+        self.header.msg_name =
+            &mut self.address as *mut libc::sockaddr_storage as *mut libc::c_void;
+        self.header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        self.header.msg_iov = &mut self.iov;
+        self.header.msg_iovlen = 1;
+        self.header.msg_flags = 0;
+    }
+}
+
+struct Receiver {
+    socket: UdpSocket,
+    handler: DatagramHandler,
+    message: Box<Message>,
+    /// Set once the socket was unregistered; it is closed when the pending receive completes
+    closing: bool,
+    /// Set if receiving failed for good. The socket is only closed once it is unregistered,
+    /// so that its descriptor doesn't get reused while it can still be used to send.
+    stopped: bool,
+}
+
+/// Sends and receives datagrams on behalf of async tasks, using an io_uring instance
+/// driven by a dedicated thread.
+/// A receive operation is always pending on every registered socket, until it is unregistered.
+pub struct UringDriver {
+    requests: Mutex<Vec<Request>>,
+    wakeup: File,
+}
+
+impl fmt::Debug for UringDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UringDriver").finish()
+    }
+}
+
+impl UringDriver {
+    /// Sets up the ring and starts the thread driving it.
+    /// Fails if the kernel doesn't support io_uring, or if it is disabled.
+    pub fn new() -> io::Result<Arc<Self>> {
+        let ring = IoUring::new(URING_ENTRIES)?;
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let driver = Arc::new(UringDriver {
+            requests: Mutex::new(vec![]),
+            wakeup: unsafe { File::from_raw_fd(fd) },
+        });
+        let driver_ = driver.clone();
+        thread::Builder::new()
+            .name("io-uring".to_string())
+            .spawn(move || driver_.run(ring))?;
+        Ok(driver)
+    }
+
+    /// Hands a socket over to the driver; `handler` is called from the driver thread
+    pub fn register(&self, socket: UdpSocket, handler: DatagramHandler) -> io::Result<()> {
+        socket.set_nonblocking(true)?;
+        self.request(Request::Register { socket, handler })
+    }
+
+    /// Closes a registered socket, once its pending receive operation has been cancelled
+    pub fn unregister(&self, fd: RawFd) -> io::Result<()> {
+        self.request(Request::Unregister { fd })
+    }
+
+    /// Sends a datagram over a registered socket
+    pub async fn send_to(&self, fd: RawFd, packet: Vec<u8>, to: SocketAddr) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.request(Request::Send { fd, packet, to, tx })?;
+        rx.await
+            .map_err(|_| io::Error::other("io_uring driver stopped"))?
+    }
+
+    fn request(&self, request: Request) -> io::Result<()> {
+        self.requests.lock().unwrap().push(request);
+        (&self.wakeup).write_all(&1u64.to_ne_bytes())
+    }
+
+    fn run(&self, mut ring: IoUring) {
+        let mut wakeup_buf = [0u8; 8];
+        let wakeup_read = opcode::Read::new(
+            types::Fd(self.wakeup.as_raw_fd()),
+            wakeup_buf.as_mut_ptr(),
+            wakeup_buf.len() as u32,
+        )
+        .build()
+        .user_data(WAKEUP);
+        let mut receivers: HashMap<u64, Receiver> = HashMap::new();
+        let mut next_receiver: u64 = 0;
+        let mut sends: HashMap<u64, (Box<Message>, oneshot::Sender<io::Result<()>>)> =
+            HashMap::new();
+        let mut next_send: u64 = 0;
+        push(&mut ring, &wakeup_read);
+        loop {
+            if let Err(e) = ring.submit_and_wait(1) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                tracing::error!("io_uring driver stopped: {}", e);
+                return;
+            }
+            let completions: Vec<_> = ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            for (user_data, result) in completions {
+                let index = user_data >> KIND_BITS;
+                match user_data & ((1 << KIND_BITS) - 1) {
+                    WAKEUP => {
+                        let requests = mem::take(&mut *self.requests.lock().unwrap());
+                        for request in requests {
+                            match request {
+                                Request::Register { socket, handler } => {
+                                    let index = next_receiver;
+                                    next_receiver = next_receiver.wrapping_add(1);
+                                    let receiver = receivers.entry(index).or_insert(Receiver {
+                                        socket,
+                                        handler,
+                                        message: Message::new(vec![0; MAX_DNS_RESPONSE_LEN]),
+                                        closing: false,
+                                        stopped: false,
+                                    });
+                                    push(&mut ring, &recv_entry(receiver, index));
+                                }
+                                Request::Unregister { fd } => {
+                                    if let Some((&index, receiver)) = receivers
+                                        .iter_mut()
+                                        .find(|(_, receiver)| receiver.socket.as_raw_fd() == fd)
+                                    {
+                                        if receiver.stopped {
+                                            receivers.remove(&index);
+                                            continue;
+                                        }
+                                        receiver.closing = true;
+                                        let entry =
+                                            opcode::AsyncCancel::new(index << KIND_BITS | RECV)
+                                                .build()
+                                                .user_data(index << KIND_BITS | CANCEL);
+                                        push(&mut ring, &entry);
+                                    }
+                                }
+                                Request::Send { fd, packet, to, tx } => {
+                                    let mut message = Message::new(packet);
+                                    let (address, address_len) = sockaddr::to_sockaddr(&to);
+                                    message.address = address;
+                                    message.header.msg_namelen = address_len;
+                                    let entry = opcode::SendMsg::new(
+                                        types::Fd(fd),
+                                        &message.header as *const libc::msghdr,
+                                    )
+                                    .build()
+                                    .user_data(next_send << KIND_BITS | SEND);
+                                    push(&mut ring, &entry);
+                                    sends.insert(next_send, (message, tx));
+                                    next_send = next_send.wrapping_add(1);
+                                }
+                            }
+                        }
+                        push(&mut ring, &wakeup_read);
+                    }
+                    RECV => {
+                        let receiver = match receivers.get_mut(&index) {
+                            None => continue,
+                            Some(receiver) => receiver,
+                        };
+                        if receiver.closing {
+                            receivers.remove(&index);
+                            continue;
+                        }
+                        if result < 0 {
+                            let e = io::Error::from_raw_os_error(-result);
+                            if is_transient(&e) {
+                                tracing::warn!("Unable to receive a datagram, retrying: {}", e);
+                                let entry = opcode::Timeout::new(&RECV_RETRY_DELAY)
+                                    .build()
+                                    .user_data(index << KIND_BITS | RETRY);
+                                push(&mut ring, &entry);
+                            } else {
+                                tracing::error!("Unable to receive datagrams: {}", e);
+                                receiver.stopped = true;
+                            }
+                            continue;
+                        }
+                        if result > 0 {
+                            let message = &receiver.message;
+                            if let Some(from) = read_sockaddr(&message.address) {
+                                (receiver.handler)(&message.buf[..result as usize], from);
+                            }
+                        }
+                        receiver.message.reset();
+                        push(&mut ring, &recv_entry(receiver, index));
+                    }
+                    RETRY => {
+                        let receiver = match receivers.get_mut(&index) {
+                            None => continue,
+                            Some(receiver) => receiver,
+                        };
+                        if receiver.closing {
+                            receivers.remove(&index);
+                            continue;
+                        }
+                        receiver.message.reset();
+                        push(&mut ring, &recv_entry(receiver, index));
+                    }
+                    SEND => {
+                        if let Some((_message, tx)) = sends.remove(&index) {
+                            let _ = tx.send(if result < 0 {
+                                Err(io::Error::from_raw_os_error(-result))
+                            } else {
+                                Ok(())
+                            });
+                        }
+                    }
+                    CANCEL => {}
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+fn recv_entry(receiver: &mut Receiver, index: u64) -> squeue::Entry {
+    opcode::RecvMsg::new(
+        types::Fd(receiver.socket.as_raw_fd()),
+        &mut receiver.message.header as *mut libc::msghdr,
+    )
+    .build()
+    .user_data(index << KIND_BITS | RECV)
+}
+
+/// Queues an operation, submitting the pending ones first if the submission queue is full
+fn push(ring: &mut IoUring, entry: &squeue::Entry) {
+    while unsafe { ring.submission().push(entry) }.is_err() {
+        if let Err(e) = ring.submit() {
+            if e.kind() != io::ErrorKind::Interrupted {
+                tracing::error!("Unable to submit io_uring operations: {}", e);
+            }
+        }
+    }
+}
+
+fn read_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
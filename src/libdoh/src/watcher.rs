@@ -0,0 +1,251 @@
+use crate::constants::*;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+type Loader<T> = Box<dyn Fn(&Path) -> io::Result<T> + Send + Sync>;
+
+/// A value loaded from a file, that is replaced when the file changes
+pub struct Reloadable<T> {
+    source: Option<(PathBuf, Loader<T>)>,
+    value: RwLock<Arc<T>>,
+}
+
+impl<T> Reloadable<T> {
+    /// A value that doesn't come from a file, and is never reloaded
+    pub fn new(value: T) -> Self {
+        Reloadable {
+            source: None,
+            value: RwLock::new(Arc::new(value)),
+        }
+    }
+
+    /// Loads a value from `path` using `load`, that is called again when the file is reloaded
+    pub fn load<P, F>(path: P, load: F) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Path) -> io::Result<T> + Send + Sync + 'static,
+    {
+        let path = path.as_ref().to_path_buf();
+        let value = load(&path)?;
+        Ok(Reloadable {
+            source: Some((path, Box::new(load))),
+            value: RwLock::new(Arc::new(value)),
+        })
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.value.read().unwrap().clone()
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.source.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    /// Loads the file again, keeping the previous value if it can't be loaded
+    pub fn reload(&self) -> io::Result<()> {
+        if let Some((path, load)) = &self.source {
+            let value = load(path)?;
+            *self.value.write().unwrap() = Arc::new(value);
+        }
+        Ok(())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Reloadable<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Reloadable")
+            .field("path", &self.path())
+            .field("value", &self.get())
+            .finish()
+    }
+}
+
+/// What is compared to tell whether a file changed; files replaced by a rename get a new inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileVersion {
+    modified: Option<SystemTime>,
+    len: u64,
+    #[cfg(unix)]
+    inode: u64,
+}
+
+impl FileVersion {
+    fn of(path: &Path) -> Option<Self> {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = fs::metadata(path).ok()?;
+        Some(FileVersion {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: metadata.ino(),
+        })
+    }
+}
+
+struct WatchedFile {
+    path: PathBuf,
+    description: String,
+    version: Option<FileVersion>,
+    reload: Box<dyn Fn() -> io::Result<()> + Send>,
+}
+
+/// Reloads files loaded at startup, such as block lists and local zones, when they change.
+/// On Linux, changes are noticed as soon as they happen using inotify; files are also
+/// checked periodically, which is the only way they are checked on other systems.
+#[derive(Default)]
+pub struct FileWatcher {
+    files: Vec<WatchedFile>,
+}
+
+impl FileWatcher {
+    /// Calls `reload` when the file at `path` changes. `description` is used in logs.
+    pub fn watch<F>(&mut self, path: &Path, description: &str, reload: F)
+    where
+        F: Fn() -> io::Result<()> + Send + 'static,
+    {
+        self.files.push(WatchedFile {
+            path: path.to_path_buf(),
+            description: description.to_string(),
+            version: FileVersion::of(path),
+            reload: Box::new(reload),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Watches the files from a dedicated thread, checking them at least every `poll_interval`
+    pub fn start(mut self, poll_interval: Duration) -> io::Result<()> {
+        thread::Builder::new()
+            .name("file-watcher".to_string())
+            .spawn(move || self.run(poll_interval))?;
+        Ok(())
+    }
+
+    fn run(&mut self, poll_interval: Duration) {
+        #[cfg(target_os = "linux")]
+        let events = match inotify::Events::new(self.files.iter().map(|file| file.path.as_path())) {
+            Ok(events) => Some(events),
+            Err(e) => {
+                tracing::warn!(error = %e, "Unable to watch files, polling them instead");
+                None
+            }
+        };
+        loop {
+            #[cfg(target_os = "linux")]
+            match &events {
+                Some(events) => {
+                    if events.wait(poll_interval) {
+                        // Give writers a chance to finish before the files are read
+                        thread::sleep(Duration::from_millis(FILE_WATCHER_DEBOUNCE_MS));
+                        events.drain();
+                    }
+                }
+                None => thread::sleep(poll_interval),
+            }
+            #[cfg(not(target_os = "linux"))]
+            thread::sleep(poll_interval);
+            self.check();
+        }
+    }
+
+    /// Reloads the files whose version changed. Files that can't be loaded keep their previous
+    /// content, and are loaded again at the next change.
+    fn check(&mut self) {
+        for file in &mut self.files {
+            let version = FileVersion::of(&file.path);
+            if version.is_none() || version == file.version {
+                continue;
+            }
+            match (file.reload)() {
+                Ok(()) => {
+                    file.version = version;
+                    tracing::info!(path = %file.path.display(), "Reloaded the {}", file.description);
+                }
+                Err(e) => tracing::warn!(
+                    path = %file.path.display(),
+                    error = %e,
+                    "Unable to reload the {}",
+                    file.description
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Directory events that can mean that a file changed: files are often replaced by
+    /// renaming a new file, so the directories are watched rather than the files
+    const WATCHED_EVENTS: u32 = libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_TO
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_ATTRIB;
+
+    pub struct Events {
+        fd: libc::c_int,
+    }
+
+    impl Events {
+        pub fn new<'t>(paths: impl Iterator<Item = &'t Path>) -> io::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let events = Events { fd };
+            for path in paths {
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                let dir = CString::new(dir.as_os_str().as_bytes())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), WATCHED_EVENTS) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(events)
+        }
+
+        /// Waits for events, returning `false` if none happened before `timeout`
+        pub fn wait(&self, timeout: Duration) -> bool {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            unsafe { libc::poll(&mut pollfd, 1, timeout) > 0 }
+        }
+
+        /// Discards the pending events; what changed is found by comparing the files
+        pub fn drain(&self) {
+            let mut buf = [0u8; 4096];
+            while unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) }
+                > 0
+            {}
+        }
+    }
+
+    impl Drop for Events {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
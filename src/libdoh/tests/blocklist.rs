@@ -0,0 +1,161 @@
+//! Parsing of block lists, and how their names, wildcards and regular expressions match queries.
+
+use libdoh::{BlockAction, BlockRules};
+
+fn rules(content: &str) -> BlockRules {
+    BlockRules::parse(content).unwrap()
+}
+
+fn parse_error(content: &str) -> String {
+    BlockRules::parse(content).unwrap_err().to_string()
+}
+
+#[test]
+fn names_match_themselves_and_their_subdomains() {
+    let rules = rules("example.com\n");
+    assert_eq!(rules.lookup("example.com"), Some(&BlockAction::Default));
+    assert_eq!(rules.lookup("example.com."), Some(&BlockAction::Default));
+    assert_eq!(rules.lookup("www.example.com"), Some(&BlockAction::Default));
+    assert_eq!(rules.lookup("a.b.example.com"), Some(&BlockAction::Default));
+    assert_eq!(rules.lookup("com"), None);
+    assert_eq!(rules.lookup("notexample.com"), None);
+    assert_eq!(rules.lookup("example.com.evil.org"), None);
+}
+
+#[test]
+fn wildcards_only_match_subdomains() {
+    let rules = rules("*.example.com\n");
+    assert_eq!(rules.lookup("example.com"), None);
+    assert_eq!(rules.lookup("example.com."), None);
+    assert_eq!(rules.lookup("www.example.com"), Some(&BlockAction::Default));
+    assert_eq!(rules.lookup("a.b.example.com"), Some(&BlockAction::Default));
+    assert_eq!(rules.lookup("www.badexample.com"), None);
+    assert_eq!(rules.lookup("www.example.com.evil.org"), None);
+}
+
+#[test]
+fn names_take_precedence_over_wildcards_and_regexes() {
+    let rules = rules(
+        "/^ads\\./$null\n\
+         *.example.com$nxdomain\n\
+         ads.example.com$refused\n",
+    );
+    assert_eq!(rules.lookup("ads.example.com"), Some(&BlockAction::Refused));
+    assert_eq!(
+        rules.lookup("x.ads.example.com"),
+        Some(&BlockAction::Refused)
+    );
+    assert_eq!(
+        rules.lookup("www.example.com"),
+        Some(&BlockAction::NxDomain)
+    );
+    assert_eq!(rules.lookup("ads.example.org"), Some(&BlockAction::Null));
+    assert_eq!(rules.lookup("example.com"), None);
+}
+
+#[test]
+fn wildcards_take_precedence_over_regexes() {
+    let rules = rules("/example/$null\n*.example.com$nxdomain\n");
+    assert_eq!(
+        rules.lookup("www.example.com"),
+        Some(&BlockAction::NxDomain)
+    );
+    assert_eq!(rules.lookup("example.com"), Some(&BlockAction::Null));
+}
+
+#[test]
+fn most_specific_name_selects_the_action() {
+    let rules = rules(
+        "example.com$refused\n\
+         ads.example.com$nxdomain\n\
+         *.example.org$null\n\
+         *.cdn.example.org$refused\n",
+    );
+    assert_eq!(rules.lookup("www.example.com"), Some(&BlockAction::Refused));
+    assert_eq!(
+        rules.lookup("ads.example.com"),
+        Some(&BlockAction::NxDomain)
+    );
+    assert_eq!(
+        rules.lookup("x.ads.example.com"),
+        Some(&BlockAction::NxDomain)
+    );
+    assert_eq!(rules.lookup("www.example.org"), Some(&BlockAction::Null));
+    assert_eq!(rules.lookup("cdn.example.org"), Some(&BlockAction::Null));
+    assert_eq!(
+        rules.lookup("img.cdn.example.org"),
+        Some(&BlockAction::Refused)
+    );
+}
+
+#[test]
+fn first_matching_regex_selects_the_action() {
+    let rules = rules("/^track/$refused\n/tracker/$nxdomain\n/^[0-9]+\\./\n");
+    assert_eq!(
+        rules.lookup("tracker.example.com"),
+        Some(&BlockAction::Refused)
+    );
+    assert_eq!(
+        rules.lookup("a.tracker.example.com"),
+        Some(&BlockAction::NxDomain)
+    );
+    assert_eq!(rules.lookup("123.example.com"), Some(&BlockAction::Default));
+    assert_eq!(rules.lookup("www.example.com"), None);
+}
+
+#[test]
+fn actions() {
+    let rules = rules(
+        "a.example$nxdomain\n\
+         b.example$null\n\
+         c.example$REFUSED\n\
+         d.example$redirect=192.0.2.1,2001:db8::1\n\
+         e.example\n",
+    );
+    assert_eq!(rules.lookup("a.example"), Some(&BlockAction::NxDomain));
+    assert_eq!(rules.lookup("b.example"), Some(&BlockAction::Null));
+    assert_eq!(rules.lookup("c.example"), Some(&BlockAction::Refused));
+    assert_eq!(
+        rules.lookup("d.example"),
+        Some(&BlockAction::Redirect(vec![
+            "192.0.2.1".parse().unwrap(),
+            "2001:db8::1".parse().unwrap()
+        ]))
+    );
+    assert_eq!(rules.lookup("e.example"), Some(&BlockAction::Default));
+}
+
+#[test]
+fn hosts_format() {
+    let rules = rules(
+        "# A hosts file\n\
+         127.0.0.1 localhost\n\
+         ::1 ip6-localhost\n\
+         0.0.0.0 ads.example.com Tracker.Example.com. # Two names\n\
+         \n\
+         plain.example.net\n",
+    );
+    assert_eq!(rules.lookup("localhost"), None);
+    assert_eq!(rules.lookup("ip6-localhost"), None);
+    assert_eq!(rules.lookup("ads.example.com"), Some(&BlockAction::Default));
+    assert_eq!(
+        rules.lookup("tracker.example.com"),
+        Some(&BlockAction::Default)
+    );
+    assert_eq!(
+        rules.lookup("plain.example.net"),
+        Some(&BlockAction::Default)
+    );
+    assert_eq!(rules.lookup("example.com"), None);
+}
+
+#[test]
+fn malformed_lines_are_rejected() {
+    assert!(parse_error("ok.example\nexample.com$bogus\n").starts_with("Line 2: "));
+    assert!(parse_error("example.com$redirect=not-an-address").starts_with("Line 1: "));
+    assert!(parse_error("example.com$redirect=").starts_with("Line 1: "));
+    assert!(parse_error("ok.example\n\n/^unterminated\n").starts_with("Line 3: "));
+    assert!(parse_error("/[a-/\n").starts_with("Line 1: "));
+    assert!(parse_error("/ads/ junk\n").starts_with("Line 1: "));
+    assert!(parse_error("/ads/$bogus\n").starts_with("Line 1: "));
+}
@@ -0,0 +1,50 @@
+//! TTLs of cached responses, decreased by the time they spent in the cache.
+
+mod common;
+
+use common::*;
+use libdoh::DoHBuilder;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Returns the TTL of the first answer of a response, whose name is a compression pointer
+fn answer_ttl(response: &[u8]) -> u32 {
+    let offset = question_end(response).unwrap() + 6;
+    u32::from_be_bytes([
+        response[offset],
+        response[offset + 1],
+        response[offset + 2],
+        response[offset + 3],
+    ])
+}
+
+#[test]
+fn ttls_are_decreased() {
+    let queries = Arc::new(AtomicUsize::new(0));
+    let queries_ = queries.clone();
+    let upstream = mock_resolver_with(move |query, _| {
+        queries_.fetch_add(1, Ordering::Relaxed);
+        answer(query)
+    });
+    let mut server = Server::with(DoHBuilder::new().upstream(upstream).padding(false));
+    let (_, body) = server.post(query(0, "cached.example.com"));
+    assert_eq!(answer_ttl(&body), ANSWER_TTL);
+
+    thread::sleep(Duration::from_millis(2100));
+    let (response, body) = server.post(query(1, "cached.example.com"));
+    assert_eq!(queries.load(Ordering::Relaxed), 1);
+    let ttl = answer_ttl(&body);
+    assert!(
+        (ANSWER_TTL - 3..=ANSWER_TTL - 2).contains(&ttl),
+        "TTL {}",
+        ttl
+    );
+    let age: u32 = response.headers()[hyper::header::AGE]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(age, ANSWER_TTL - ttl);
+}
@@ -0,0 +1,243 @@
+//! A mock resolver, and a `DoH` service driven without a listening socket, shared by the
+//! integration tests.
+
+#![allow(dead_code)]
+
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Response};
+use libdoh::{DoH, DoHBuilder};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::thread;
+
+pub const DNS_MESSAGE: &str = "application/dns-message";
+pub const ANSWER_TTL: u32 = 300;
+pub const OPTION_ECS: u16 = 8;
+pub const OPTION_PADDING: u16 = 12;
+
+/// Starts a resolver answering every query with `192.0.2.1`, and returns its address
+pub fn mock_resolver() -> SocketAddr {
+    mock_resolver_with(|query, _| answer(query))
+}
+
+/// Starts a resolver answering queries with `handler`, that is also given the address they
+/// were sent from, and returns its address. Queries are not answered if it returns `None`.
+pub fn mock_resolver_with<F>(handler: F) -> SocketAddr
+where
+    F: FnMut(&[u8], SocketAddr) -> Option<Vec<u8>> + Send + 'static,
+{
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    thread::spawn(move || serve_udp(socket, handler));
+    address
+}
+
+/// Starts a resolver answering queries sent over UDP with `udp_handler`, and queries sent
+/// over TCP to the same port with `tcp_handler`, and returns its address
+pub fn mock_resolver_with_tcp<F, G>(udp_handler: F, tcp_handler: G) -> SocketAddr
+where
+    F: FnMut(&[u8], SocketAddr) -> Option<Vec<u8>> + Send + 'static,
+    G: FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+{
+    // The port that was free for UDP may not be for TCP
+    let (socket, listener) = loop {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        if let Ok(listener) = TcpListener::bind(socket.local_addr().unwrap()) {
+            break (socket, listener);
+        }
+    };
+    let address = socket.local_addr().unwrap();
+    thread::spawn(move || serve_udp(socket, udp_handler));
+    thread::spawn(move || serve_tcp(listener, tcp_handler));
+    address
+}
+
+fn serve_udp<F>(socket: UdpSocket, mut handler: F)
+where
+    F: FnMut(&[u8], SocketAddr) -> Option<Vec<u8>>,
+{
+    let mut buf = [0u8; 4096];
+    while let Ok((len, client)) = socket.recv_from(&mut buf) {
+        if let Some(response) = handler(&buf[..len], client) {
+            let _ = socket.send_to(&response, client);
+        }
+    }
+}
+
+/// Answers the queries of every connection in turn, prefixed with their length
+fn serve_tcp<G>(listener: TcpListener, mut handler: G)
+where
+    G: FnMut(&[u8]) -> Option<Vec<u8>>,
+{
+    let read_query = |stream: &mut TcpStream| -> Option<Vec<u8>> {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).ok()?;
+        let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut query).ok()?;
+        Some(query)
+    };
+    for mut stream in listener.incoming().flatten() {
+        while let Some(query) = read_query(&mut stream) {
+            if let Some(response) = handler(&query) {
+                let _ = stream.write_all(&(response.len() as u16).to_be_bytes());
+                let _ = stream.write_all(&response);
+            }
+        }
+    }
+}
+
+/// Returns the offset of the end of the question of `packet`
+pub fn question_end(packet: &[u8]) -> Option<usize> {
+    let mut offset = 12;
+    loop {
+        let label_len = *packet.get(offset)? as usize;
+        offset += 1 + label_len;
+        if label_len == 0 {
+            break;
+        }
+    }
+    Some(offset + 4).filter(|&end| end <= packet.len())
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *packet.get(offset)?,
+        *packet.get(offset + 1)?,
+    ]))
+}
+
+/// Returns the offset of the type of the OPT record of `packet`, if it has one
+pub fn opt_record(packet: &[u8]) -> Option<usize> {
+    let mut offset = question_end(packet)?;
+    let rrcount = [6, 8, 10]
+        .iter()
+        .map(|&i| read_u16(packet, i).map_or(0, usize::from))
+        .sum();
+    for _ in 0..rrcount {
+        loop {
+            let label_len = *packet.get(offset)? as usize;
+            if label_len & 0xc0 == 0xc0 {
+                offset += 2;
+                break;
+            }
+            offset += 1 + label_len;
+            if label_len == 0 {
+                break;
+            }
+        }
+        if read_u16(packet, offset)? == 41 {
+            return Some(offset);
+        }
+        offset += 10 + read_u16(packet, offset + 8)? as usize;
+    }
+    None
+}
+
+/// Returns the EDNS flags of `packet`, if it has an OPT record
+pub fn edns_flags(packet: &[u8]) -> Option<u16> {
+    read_u16(packet, opt_record(packet)? + 6)
+}
+
+/// Returns the code and data of the EDNS options of `packet`
+pub fn edns_options(packet: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut options = vec![];
+    let opt_record = match opt_record(packet) {
+        None => return options,
+        Some(opt_record) => opt_record,
+    };
+    let mut offset = opt_record + 10;
+    let end = offset + read_u16(packet, opt_record + 8).unwrap() as usize;
+    while offset + 4 <= end {
+        let code = read_u16(packet, offset).unwrap();
+        let len = read_u16(packet, offset + 2).unwrap() as usize;
+        options.push((code, packet[offset + 4..offset + 4 + len].to_vec()));
+        offset += 4 + len;
+    }
+    options
+}
+
+/// Adds an OPT record with an option to a packet that doesn't have any additional record
+pub fn with_edns_option(mut packet: Vec<u8>, code: u16, data: &[u8]) -> Vec<u8> {
+    packet[11] = 1;
+    packet.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0]);
+    packet.extend_from_slice(&(data.len() as u16 + 4).to_be_bytes());
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// Copies the header and the question of `query`, ignoring any additional record
+pub fn answer(query: &[u8]) -> Option<Vec<u8>> {
+    let mut response = query.get(..question_end(query)?)?.to_vec();
+    response[2] = 0x81 | (query[2] & 0x01);
+    response[3] = 0x80;
+    response[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+    response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+    response.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+    response.extend_from_slice(&[0, 4, 192, 0, 2, 1]);
+    Some(response)
+}
+
+pub fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+    query
+}
+
+/// Returns a POST request for `query`
+pub fn post_request(query: Vec<u8>) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri("/dns-query")
+        .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE)
+        .header(hyper::header::ACCEPT, DNS_MESSAGE)
+        .body(Body::from(query))
+        .unwrap()
+}
+
+/// Sends a request to `doh`, and returns the response with its body
+pub async fn send(mut doh: DoH, req: Request<Body>) -> (Response<Body>, Vec<u8>) {
+    let (parts, body) = doh.call(req).await.unwrap().into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap().to_vec();
+    (Response::from_parts(parts, Body::empty()), body)
+}
+
+pub struct Server {
+    pub doh: DoH,
+    pub runtime: tokio::runtime::Runtime,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::with(DoHBuilder::new().upstream(mock_resolver()))
+    }
+
+    pub fn with(builder: DoHBuilder) -> Self {
+        Server {
+            doh: builder.build(),
+            runtime: tokio::runtime::Runtime::new().unwrap(),
+        }
+    }
+
+    pub fn send(&mut self, req: Request<Body>) -> (Response<Body>, Vec<u8>) {
+        self.runtime.block_on(send(self.doh.clone(), req))
+    }
+
+    pub fn get(&mut self, dns: &str) -> (Response<Body>, Vec<u8>) {
+        let req = Request::get(format!("/dns-query?dns={}", dns))
+            .header(hyper::header::ACCEPT, DNS_MESSAGE)
+            .body(Body::empty())
+            .unwrap();
+        self.send(req)
+    }
+
+    pub fn post(&mut self, query: Vec<u8>) -> (Response<Body>, Vec<u8>) {
+        self.send(post_request(query))
+    }
+}
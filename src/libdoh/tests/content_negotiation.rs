@@ -0,0 +1,112 @@
+//! Validation of GET and POST requests: content types, `Accept` header and query size (RFC 8484).
+//! Queries are for a denied record type, so that they are answered without an upstream server.
+
+use hyper::service::Service;
+use hyper::{Body, Method, Request, StatusCode};
+use libdoh::{DeniedQtypeResponse, DoHBuilder};
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// A query for the `A` record of `example.com`
+fn query() -> Vec<u8> {
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.extend_from_slice(b"\x07example\x03com\x00");
+    query.extend_from_slice(&[0, 1, 0, 1]);
+    query
+}
+
+fn status(req: Request<Body>) -> StatusCode {
+    let mut doh = DoHBuilder::new()
+        .denied_qtypes("A".parse().unwrap(), DeniedQtypeResponse::Empty)
+        .build();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(doh.call(req)).unwrap().status()
+}
+
+fn get(params: &str, accept: Option<&str>) -> StatusCode {
+    let dns = base64::encode_config(query(), base64::URL_SAFE_NO_PAD);
+    let mut req = Request::get(format!("/dns-query?dns={}{}", dns, params));
+    if let Some(accept) = accept {
+        req = req.header(hyper::header::ACCEPT, accept);
+    }
+    status(req.body(Body::empty()).unwrap())
+}
+
+fn post(content_type: Option<&str>, accept: Option<&str>) -> StatusCode {
+    let mut req = Request::builder().method(Method::POST).uri("/dns-query");
+    if let Some(content_type) = content_type {
+        req = req.header(hyper::header::CONTENT_TYPE, content_type);
+    }
+    if let Some(accept) = accept {
+        req = req.header(hyper::header::ACCEPT, accept);
+    }
+    status(req.body(Body::from(query())).unwrap())
+}
+
+#[test]
+fn get_accept_header() {
+    assert_eq!(get("", None), StatusCode::OK);
+    assert_eq!(get("", Some(DNS_MESSAGE)), StatusCode::OK);
+    assert_eq!(get("", Some("application/*")), StatusCode::OK);
+    assert_eq!(get("", Some("*/*")), StatusCode::OK);
+    assert_eq!(
+        get("", Some("text/html, application/dns-message;q=0.9")),
+        StatusCode::OK
+    );
+    assert_eq!(get("", Some("text/html")), StatusCode::NOT_ACCEPTABLE);
+    assert_eq!(
+        get("", Some("application/dns-message;q=0")),
+        StatusCode::NOT_ACCEPTABLE
+    );
+    assert_eq!(
+        get("", Some("application/dns-json")),
+        StatusCode::NOT_ACCEPTABLE
+    );
+}
+
+#[test]
+fn get_ct_param() {
+    assert_eq!(get("&ct=", None), StatusCode::OK);
+    assert_eq!(get("&ct=application/dns-message", None), StatusCode::OK);
+    assert_eq!(get("&ct=application%2Fdns-message", None), StatusCode::OK);
+    assert_eq!(
+        get("&ct=application/dns-json", None),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+    assert_eq!(
+        get("&ct=application/dns-message", Some("text/html")),
+        StatusCode::NOT_ACCEPTABLE
+    );
+}
+
+#[test]
+fn get_missing_dns_param() {
+    let req = Request::get("/dns-query?ct=application/dns-message")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(status(req), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn get_oversized_dns_param() {
+    let dns = "A".repeat(1000);
+    let req = Request::get(format!("/dns-query?dns={}", dns))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(status(req), StatusCode::URI_TOO_LONG);
+}
+
+#[test]
+fn post_content_type_and_accept_header() {
+    assert_eq!(post(Some(DNS_MESSAGE), None), StatusCode::OK);
+    assert_eq!(post(Some(DNS_MESSAGE), Some(DNS_MESSAGE)), StatusCode::OK);
+    assert_eq!(
+        post(Some(DNS_MESSAGE), Some("text/html")),
+        StatusCode::NOT_ACCEPTABLE
+    );
+    assert_eq!(
+        post(Some("text/plain"), None),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+    assert_eq!(post(None, None), StatusCode::NOT_ACCEPTABLE);
+}
@@ -0,0 +1,65 @@
+//! Responses that were not validated by the upstream server, with `--dnssec-validate`.
+
+mod common;
+
+use common::*;
+use libdoh::DoHBuilder;
+use std::sync::{Arc, Mutex};
+
+const FLAGS_AD: u8 = 0x20;
+const FLAGS_CD: u8 = 0x10;
+const EDNS_FLAGS_DO: u16 = 0x8000;
+
+/// The header flags and the EDNS flags of the queries sent upstream
+type ReceivedFlags = Arc<Mutex<Vec<(u8, Option<u16>)>>>;
+
+/// Starts a server with an upstream server that only sets the AD bit for `secure.example`,
+/// and returns it with the flags of the queries it received
+fn start_server() -> (Server, ReceivedFlags) {
+    let queries = Arc::new(Mutex::new(vec![]));
+    let received = queries.clone();
+    let upstream = mock_resolver_with(move |query, _| {
+        received.lock().unwrap().push((query[3], edns_flags(query)));
+        let mut response = answer(query)?;
+        if query[13..19].eq_ignore_ascii_case(b"secure") {
+            response[3] |= FLAGS_AD;
+        }
+        Some(response)
+    });
+    let server = Server::with(DoHBuilder::new().upstream(upstream).dnssec_validate(true));
+    (server, queries)
+}
+
+#[test]
+fn validation_is_requested() {
+    let (mut server, queries) = start_server();
+    server.post(query(0, "secure.example"));
+    let (flags, edns_flags) = queries.lock().unwrap()[0];
+    assert_eq!(flags & FLAGS_AD, FLAGS_AD);
+    assert_eq!(flags & FLAGS_CD, 0);
+    assert_eq!(edns_flags.unwrap() & EDNS_FLAGS_DO, EDNS_FLAGS_DO);
+}
+
+#[test]
+fn responses_without_ad_are_servfail() {
+    let (mut server, _) = start_server();
+    let (_, body) = server.post(query(0, "secure.example"));
+    assert_eq!(body[3] & 0x0f, 0);
+    assert_eq!(body[7], 1);
+
+    // Even without any signature
+    let (_, body) = server.post(query(0, "insecure.example"));
+    assert_eq!(body[3] & 0x0f, 2);
+    assert_eq!(body[7], 0);
+}
+
+#[test]
+fn checking_disabled_is_forwarded() {
+    let (mut server, queries) = start_server();
+    let mut query = query(0, "insecure.example");
+    query[3] |= FLAGS_CD;
+    let (_, body) = server.post(query);
+    assert_eq!(body[3] & 0x0f, 0);
+    assert_eq!(body[7], 1);
+    assert_eq!(queries.lock().unwrap()[0].0 & FLAGS_CD, FLAGS_CD);
+}
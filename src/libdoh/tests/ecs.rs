@@ -0,0 +1,83 @@
+//! EDNS Client Subnet options of client queries, stripped, forwarded or replaced according to
+//! `--ecs-policy`.
+
+mod common;
+
+use common::*;
+use libdoh::{DoHBuilder, EcsPolicy};
+use std::sync::{Arc, Mutex};
+
+/// `198.51.100.0/24`
+const CLIENT_SUBNET: [u8; 7] = [0, 1, 24, 0, 198, 51, 100];
+
+/// The ECS options of the queries received by the upstream server
+type Received = Arc<Mutex<Vec<Option<Vec<u8>>>>>;
+
+/// Starts a server with an upstream server that returns the client subnet it received, and
+/// returns it with the ECS options of the queries
+fn start_server(ecs_policy: &str) -> (Server, Received) {
+    let received = Received::default();
+    let received_ = received.clone();
+    let upstream = mock_resolver_with(move |query, _| {
+        let ecs = edns_options(query)
+            .into_iter()
+            .find(|(code, _)| *code == OPTION_ECS)
+            .map(|(_, data)| data);
+        let response = answer(query)?;
+        let response = match &ecs {
+            Some(ecs) => with_edns_option(response, OPTION_ECS, ecs),
+            None => response,
+        };
+        received_.lock().unwrap().push(ecs);
+        Some(response)
+    });
+    let ecs_policy: EcsPolicy = ecs_policy.parse().unwrap();
+    let server = Server::with(
+        DoHBuilder::new()
+            .upstream(upstream)
+            .ecs_policy(ecs_policy)
+            .padding(false),
+    );
+    (server, received)
+}
+
+fn client_query() -> Vec<u8> {
+    with_edns_option(query(0, "example.com"), OPTION_ECS, &CLIENT_SUBNET)
+}
+
+fn has_ecs(packet: &[u8]) -> bool {
+    edns_options(packet)
+        .iter()
+        .any(|(code, _)| *code == OPTION_ECS)
+}
+
+#[test]
+fn strip() {
+    let (mut server, received) = start_server("strip");
+    let (_, body) = server.post(client_query());
+    assert_eq!(received.lock().unwrap()[0], None);
+    assert!(!has_ecs(&body));
+}
+
+#[test]
+fn forward() {
+    let (mut server, received) = start_server("forward");
+    let (_, body) = server.post(client_query());
+    assert_eq!(
+        received.lock().unwrap()[0].as_deref(),
+        Some(&CLIENT_SUBNET[..])
+    );
+    assert!(has_ecs(&body));
+}
+
+#[test]
+fn set() {
+    let (mut server, received) = start_server("set:192.0.2.1/24");
+    let (_, body) = server.post(client_query());
+    server.post(query(1, "other.example.com"));
+    let expected = [0, 1, 24, 0, 192, 0, 2];
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].as_deref(), Some(&expected[..]));
+    assert_eq!(received[1].as_deref(), Some(&expected[..]));
+    assert!(!has_ecs(&body));
+}
@@ -0,0 +1,228 @@
+//! Client addresses behind reverse proxies: trusted networks, and the `Forwarded` (RFC 7239)
+//! and `X-Forwarded-For` headers.
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use libdoh::{IpNetwork, TrustedProxies};
+use std::net::IpAddr;
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+fn network(network: &str) -> IpNetwork {
+    network.parse().unwrap()
+}
+
+fn headers(headers: &[(&str, &str)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        map.append(
+            HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            HeaderValue::from_str(value).unwrap(),
+        );
+    }
+    map
+}
+
+fn client_ip(trusted: &str, peer_ip: &str, headers_: &[(&str, &str)]) -> IpAddr {
+    let trusted: TrustedProxies = trusted.parse().unwrap();
+    trusted.client_ip(ip(peer_ip), &headers(headers_))
+}
+
+#[test]
+fn network_prefix_boundaries() {
+    let any = network("0.0.0.0/0");
+    assert!(any.contains(&ip("0.0.0.0")));
+    assert!(any.contains(&ip("255.255.255.255")));
+    assert!(!any.contains(&ip("::1")));
+    assert!(network("::/0").contains(&ip("ffff::1")));
+
+    let ula = network("fc00::/7");
+    assert!(ula.contains(&ip("fc00::")));
+    assert!(ula.contains(&ip("fdff:ffff::1")));
+    assert!(!ula.contains(&ip("fbff:ffff::1")));
+    assert!(!ula.contains(&ip("fe00::")));
+
+    let v4_7 = network("10.0.0.0/7");
+    assert!(v4_7.contains(&ip("11.255.255.255")));
+    assert!(!v4_7.contains(&ip("12.0.0.0")));
+    assert!(!v4_7.contains(&ip("9.255.255.255")));
+
+    let host = network("192.0.2.1/32");
+    assert!(host.contains(&ip("192.0.2.1")));
+    assert!(!host.contains(&ip("192.0.2.0")));
+    assert!(!host.contains(&ip("192.0.2.2")));
+    assert_eq!(network("192.0.2.1"), host);
+
+    let v6_32 = network("2001:db8::/32");
+    assert!(v6_32.contains(&ip("2001:db8:ffff:ffff::1")));
+    assert!(!v6_32.contains(&ip("2001:db9::")));
+    assert!(network("2001:db8::1/128").contains(&ip("2001:db8::1")));
+    assert!(!network("2001:db8::1/128").contains(&ip("2001:db8::2")));
+}
+
+#[test]
+fn invalid_networks() {
+    assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+    assert!("::/129".parse::<IpNetwork>().is_err());
+    assert!("10.0.0.0/".parse::<IpNetwork>().is_err());
+    assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+    assert!("10.0.0.0/8,nope".parse::<TrustedProxies>().is_err());
+}
+
+#[test]
+fn ipv4_mapped_addresses() {
+    let private = network("10.0.0.0/8");
+    assert!(private.contains(&ip("::ffff:10.1.2.3")));
+    assert!(!private.contains(&ip("::ffff:192.0.2.1")));
+    assert!(!private.contains(&ip("::10.1.2.3")));
+    assert!(!network("::ffff:10.0.0.0/104").contains(&ip("10.1.2.3")));
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8",
+            "::ffff:10.0.0.1",
+            &[("x-forwarded-for", "192.0.2.1")]
+        ),
+        ip("192.0.2.1")
+    );
+}
+
+#[test]
+fn untrusted_peers_are_ignored() {
+    let spoofed = [
+        ("forwarded", "for=192.0.2.1"),
+        ("x-forwarded-for", "192.0.2.2"),
+    ];
+    assert_eq!(client_ip("", "10.0.0.1", &spoofed), ip("10.0.0.1"));
+    assert_eq!(
+        client_ip("10.0.0.0/8", "203.0.113.1", &spoofed),
+        ip("203.0.113.1")
+    );
+}
+
+#[test]
+fn trusted_peers_without_headers() {
+    assert_eq!(client_ip("10.0.0.0/8", "10.0.0.1", &[]), ip("10.0.0.1"));
+}
+
+#[test]
+fn chains_stop_at_the_first_untrusted_hop() {
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8",
+            "10.0.0.1",
+            &[("x-forwarded-for", "203.0.113.7, 198.51.100.2, 10.0.0.5")]
+        ),
+        ip("198.51.100.2")
+    );
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8",
+            "10.0.0.1",
+            &[
+                ("x-forwarded-for", "203.0.113.7"),
+                ("x-forwarded-for", "198.51.100.2, 10.0.0.5")
+            ]
+        ),
+        ip("198.51.100.2")
+    );
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8,198.51.100.0/24",
+            "10.0.0.1",
+            &[("forwarded", "for=203.0.113.7, for=198.51.100.2;proto=https")]
+        ),
+        ip("203.0.113.7")
+    );
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8",
+            "10.0.0.1",
+            &[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]
+        ),
+        ip("10.0.0.3")
+    );
+}
+
+#[test]
+fn unparsable_hops_stop_the_chain() {
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8",
+            "10.0.0.1",
+            &[("x-forwarded-for", "203.0.113.7, garbage, 10.0.0.5")]
+        ),
+        ip("10.0.0.5")
+    );
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8",
+            "10.0.0.1",
+            &[("forwarded", "for=203.0.113.7, for=unknown")]
+        ),
+        ip("10.0.0.1")
+    );
+}
+
+#[test]
+fn quoted_and_bracketed_addresses() {
+    let cases = [
+        ("for=192.0.2.60;proto=http;by=203.0.113.43", "192.0.2.60"),
+        ("For=\"192.0.2.60:8080\"", "192.0.2.60"),
+        ("for=\"[2001:db8:cafe::17]:4711\"", "2001:db8:cafe::17"),
+        ("for=\"[2001:db8:cafe::17]\"", "2001:db8:cafe::17"),
+        ("proto=https; for=\"2001:db8::1\"", "2001:db8::1"),
+    ];
+    for (forwarded, expected) in cases.iter() {
+        assert_eq!(
+            client_ip("10.0.0.0/8", "10.0.0.1", &[("forwarded", forwarded)]),
+            ip(expected),
+            "{}",
+            forwarded
+        );
+    }
+    let cases = [
+        ("192.0.2.60:8080", "192.0.2.60"),
+        ("[2001:db8::2]:443", "2001:db8::2"),
+        ("[2001:db8::2]", "2001:db8::2"),
+        (" 2001:db8::3 ", "2001:db8::3"),
+    ];
+    for (x_forwarded_for, expected) in cases.iter() {
+        assert_eq!(
+            client_ip(
+                "10.0.0.0/8",
+                "10.0.0.1",
+                &[("x-forwarded-for", x_forwarded_for)]
+            ),
+            ip(expected),
+            "{}",
+            x_forwarded_for
+        );
+    }
+}
+
+#[test]
+fn forwarded_takes_precedence_over_x_forwarded_for() {
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8",
+            "10.0.0.1",
+            &[
+                ("x-forwarded-for", "198.51.100.2"),
+                ("forwarded", "for=192.0.2.1")
+            ]
+        ),
+        ip("192.0.2.1")
+    );
+    assert_eq!(
+        client_ip(
+            "10.0.0.0/8",
+            "10.0.0.1",
+            &[
+                ("x-forwarded-for", "198.51.100.2"),
+                ("forwarded", "proto=https;by=10.0.0.1")
+            ]
+        ),
+        ip("198.51.100.2")
+    );
+}
@@ -0,0 +1,54 @@
+//! EDNS padding of the queries sent upstream and of the responses (RFC 8467).
+
+mod common;
+
+use common::*;
+use libdoh::DoHBuilder;
+use std::sync::{Arc, Mutex};
+
+const QUERY_BLOCK_SIZE: usize = 128;
+const RESPONSE_BLOCK_SIZE: usize = 468;
+
+/// The queries received by the upstream server
+type Received = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Starts a server with an upstream server that keeps the queries it received
+fn start_server(padding: bool) -> (Server, Received) {
+    let received = Received::default();
+    let received_ = received.clone();
+    let upstream = mock_resolver_with(move |query, _| {
+        received_.lock().unwrap().push(query.to_vec());
+        answer(query)
+    });
+    let server = Server::with(DoHBuilder::new().upstream(upstream).padding(padding));
+    (server, received)
+}
+
+fn is_padded(packet: &[u8]) -> bool {
+    edns_options(packet)
+        .iter()
+        .any(|(code, _)| *code == OPTION_PADDING)
+}
+
+#[test]
+fn queries_and_responses_are_padded() {
+    let (mut server, received) = start_server(true);
+    let padded_query = with_edns_option(query(1, "example.com"), OPTION_PADDING, &[0; 3]);
+    for query in [query(0, "example.com"), padded_query] {
+        let (_, body) = server.post(query);
+        assert!(is_padded(&body));
+        assert_eq!(body.len() % RESPONSE_BLOCK_SIZE, 0);
+    }
+    for query in received.lock().unwrap().iter() {
+        assert!(is_padded(query));
+        assert_eq!(query.len() % QUERY_BLOCK_SIZE, 0);
+    }
+}
+
+#[test]
+fn no_padding() {
+    let (mut server, received) = start_server(false);
+    let (_, body) = server.post(query(0, "example.com"));
+    assert!(!is_padded(&body));
+    assert!(!is_padded(&received.lock().unwrap()[0]));
+}
@@ -0,0 +1,95 @@
+//! Client limits with a runtime per thread, each serving its own share of the sockets,
+//! as with `--per-core-runtime`.
+
+mod common;
+
+use common::*;
+use libdoh::DoHBuilder;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+const WORKERS: usize = 4;
+const MAX_CLIENTS: usize = 2;
+
+fn unused_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Starts a server with `WORKERS` sockets bound with `SO_REUSEPORT`, each served by its own
+/// single-threaded runtime
+fn start_server() -> SocketAddr {
+    let listen_address = unused_address();
+    let doh = DoHBuilder::new()
+        .upstream(mock_resolver())
+        .listen_address(listen_address)
+        .reuseport(WORKERS)
+        .max_clients(MAX_CLIENTS)
+        .build();
+    let listeners = doh.bind().unwrap();
+    for (i, listeners) in listeners.split(WORKERS).into_iter().enumerate() {
+        let doh = doh.clone();
+        thread::spawn(move || {
+            let mut runtime = tokio::runtime::Builder::new()
+                .enable_all()
+                .basic_scheduler()
+                .build()
+                .unwrap();
+            if i == 0 {
+                runtime.block_on(doh.serve(listeners))
+            } else {
+                runtime.block_on(doh.serve_worker(listeners))
+            }
+        });
+    }
+    listen_address
+}
+
+/// Opens a connection and sends a query over it, without waiting for the response
+fn connect(address: SocketAddr, id: u16) -> TcpStream {
+    let mut stream = TcpStream::connect(address).unwrap();
+    let query = query(id, "example.com");
+    write!(
+        stream,
+        "POST /dns-query HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        DNS_MESSAGE,
+        query.len()
+    )
+    .unwrap();
+    stream.write_all(&query).unwrap();
+    stream
+}
+
+/// Returns `true` if a successful response is received before `timeout`
+fn answered(stream: &mut TcpStream, timeout: Duration) -> bool {
+    stream.set_read_timeout(Some(timeout)).unwrap();
+    let mut buf = [0u8; 1024];
+    match stream.read(&mut buf) {
+        Ok(len) => buf[..len].starts_with(b"HTTP/1.1 200"),
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn max_clients_is_shared_by_all_workers() {
+    let address = start_server();
+    let mut clients: Vec<_> = (0..MAX_CLIENTS as u16)
+        .map(|id| connect(address, id))
+        .collect();
+    for client in &mut clients {
+        assert!(answered(client, Duration::from_secs(5)));
+    }
+
+    // Whatever socket they reach, the next clients wait for a slot, while the first ones
+    // are still connected
+    let mut waiting: Vec<_> = (0..WORKERS as u16 * 2)
+        .map(|id| connect(address, 100 + id))
+        .collect();
+    for client in &mut waiting {
+        assert!(!answered(client, Duration::from_millis(200)));
+    }
+}
@@ -0,0 +1,76 @@
+//! Randomized case of the names sent to the upstream servers (0x20 encoding), that responses
+//! must use as well.
+
+mod common;
+
+use common::*;
+use hyper::StatusCode;
+use libdoh::DoHBuilder;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const NAME: &str = "randomized.example.com";
+
+/// The names of the queries received by the upstream server
+type Received = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Starts a server with an upstream server that changes the name of the question to lowercase
+/// if `lowercase` is set
+fn start_server(qname_randomization: bool, lowercase: bool) -> (Server, Received) {
+    let received = Received::default();
+    let received_ = received.clone();
+    let upstream = mock_resolver_with(move |query, _| {
+        let question_end = question_end(query)?;
+        received_
+            .lock()
+            .unwrap()
+            .push(query[12..question_end - 4].to_vec());
+        let mut response = answer(query)?;
+        if lowercase {
+            response[12..question_end].make_ascii_lowercase();
+        }
+        Some(response)
+    });
+    let server = Server::with(
+        DoHBuilder::new()
+            .upstream(upstream)
+            .upstream_timeout(Duration::from_millis(200), 0)
+            .qname_randomization(qname_randomization),
+    );
+    (server, received)
+}
+
+#[test]
+fn names_are_randomized() {
+    let (mut server, received) = start_server(true, false);
+    for id in 0..10 {
+        let query = query(id, &format!("{}{}", id, NAME));
+        let (response, body) = server.post(query.clone());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body[12..query.len()],
+            query[12..],
+            "the case must be restored"
+        );
+    }
+    let received = received.lock().unwrap();
+    assert!(received
+        .iter()
+        .any(|name| name.iter().any(u8::is_ascii_uppercase)));
+}
+
+#[test]
+fn responses_must_use_the_same_case() {
+    let (mut server, _) = start_server(true, true);
+    let (response, _) = server.post(query(0, NAME));
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[test]
+fn randomization_can_be_disabled() {
+    let (mut server, received) = start_server(false, true);
+    let query = query(0, NAME);
+    let (response, _) = server.post(query.clone());
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(received.lock().unwrap()[0], query[12..query.len() - 4]);
+}
@@ -0,0 +1,187 @@
+//! RFC 8484 conformance: queries sent over GET and POST are forwarded to a mock resolver,
+//! and the responses must be DNS messages with HTTP caching headers matching their TTL.
+
+mod common;
+
+use common::*;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use proptest::prelude::*;
+
+/// The query for the `A` record of `www.example.com` from section 4.1.1 of RFC 8484
+const RFC_EXAMPLE_QUERY: &str = "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB";
+
+fn header(response: &Response<Body>, name: hyper::header::HeaderName) -> Option<&str> {
+    response.headers().get(name)?.to_str().ok()
+}
+
+/// Checks that `body` answers `query`, and that the response can be cached for its TTL
+fn assert_answers(response: &Response<Body>, body: &[u8], query: &[u8]) {
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        header(response, hyper::header::CONTENT_TYPE),
+        Some(DNS_MESSAGE)
+    );
+    let content_length = header(response, hyper::header::CONTENT_LENGTH);
+    assert_eq!(content_length, Some(body.len().to_string().as_str()));
+    assert!(body.len() > query.len());
+    assert_eq!(body[..2], query[..2], "the ID must be the one of the query");
+    assert_eq!(body[2] & 0x80, 0x80, "the QR bit must be set");
+    assert_eq!(
+        body[12..query.len()],
+        query[12..],
+        "the question must match"
+    );
+
+    let cache_control = header(response, hyper::header::CACHE_CONTROL).unwrap();
+    let max_age: u32 = cache_control
+        .split(',')
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .expect("Cache-Control must include max-age")
+        .parse()
+        .unwrap();
+    let age: u32 = header(response, hyper::header::AGE).map_or(0, |age| age.parse().unwrap());
+    assert!(max_age > 0 && max_age <= ANSWER_TTL + age);
+    let expires = header(response, hyper::header::EXPIRES).unwrap();
+    assert!(httpdate::parse_http_date(expires).is_ok());
+
+    // Intermediaries must not alter the message, and it is never compressed
+    assert!(cache_control
+        .split(',')
+        .any(|directive| directive.trim() == "no-transform"));
+    assert_eq!(header(response, hyper::header::CONTENT_ENCODING), None);
+}
+
+#[test]
+fn rfc_example_get_and_post() {
+    let mut server = Server::new();
+    let query = base64::decode_config(RFC_EXAMPLE_QUERY, base64::URL_SAFE_NO_PAD).unwrap();
+    assert_eq!(query, self::query(0, "www.example.com"));
+
+    let (response, body) = server.get(RFC_EXAMPLE_QUERY);
+    assert_answers(&response, &body, &query);
+    let (response, body) = server.post(query.clone());
+    assert_answers(&response, &body, &query);
+}
+
+#[test]
+fn cached_responses_keep_the_query_id() {
+    let mut server = Server::new();
+    for id in &[0, 0x1234, 0xffff] {
+        let query = query(*id, "cached.example.com");
+        let (response, body) = server.post(query.clone());
+        assert_answers(&response, &body, &query);
+    }
+}
+
+#[test]
+fn base64url_alphabet_and_padding() {
+    let mut server = Server::new();
+    // An ID encoded with the characters specific to base64url
+    let query = query(0xfbff, "example.com");
+    let dns = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+    assert!(dns.starts_with("-_"));
+    let (response, body) = server.get(&dns);
+    assert_answers(&response, &body, &query);
+
+    // The standard alphabet and padding are not valid base64url without padding
+    let standard = base64::encode_config(&query, base64::STANDARD_NO_PAD);
+    assert_eq!(server.get(&standard).0.status(), StatusCode::BAD_REQUEST);
+    let padded = base64::encode_config(self::query(0, "padded.example"), base64::URL_SAFE);
+    assert!(padded.ends_with('='));
+    assert_eq!(server.get(&padded).0.status(), StatusCode::BAD_REQUEST);
+
+    // A length that no sequence of bytes is encoded to
+    let truncated = &RFC_EXAMPLE_QUERY[..RFC_EXAMPLE_QUERY.len() - 3];
+    assert_eq!(server.get(truncated).0.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn compression_is_never_negotiated() {
+    let mut server = Server::new();
+    let query = query(0, "compressed.example.com");
+    let dns = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+    let req = Request::get(format!("/dns-query?dns={}", dns))
+        .header(hyper::header::ACCEPT, DNS_MESSAGE)
+        .header(hyper::header::ACCEPT_ENCODING, "gzip, br")
+        .body(Body::empty())
+        .unwrap();
+    let (response, body) = server.send(req);
+    assert_answers(&response, &body, &query);
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("/dns-query")
+        .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE)
+        .header(hyper::header::ACCEPT, DNS_MESSAGE)
+        .header(hyper::header::ACCEPT_ENCODING, "gzip;q=1.0, identity;q=0")
+        .body(Body::from(query.clone()))
+        .unwrap();
+    let (response, body) = server.send(req);
+    assert_answers(&response, &body, &query);
+}
+
+#[test]
+fn malformed_queries() {
+    let mut server = Server::new();
+    let short = StatusCode::UNPROCESSABLE_ENTITY;
+    assert_eq!(server.get("").0.status(), short);
+    assert_eq!(server.post(vec![]).0.status(), short);
+    assert_eq!(server.post(vec![0; 5]).0.status(), short);
+    let mut no_question = query(0, "example.com");
+    no_question[5] = 0;
+    assert_eq!(server.post(no_question).0.status(), StatusCode::BAD_REQUEST);
+}
+
+/// Returns a query of `len` bytes, padded with an EDNS padding option
+fn padded_query(len: usize) -> Vec<u8> {
+    let query = query(0, "example.com");
+    let padding = vec![0; len - query.len() - 15];
+    with_edns_option(query, OPTION_PADDING, &padding)
+}
+
+#[test]
+fn largest_queries() {
+    let mut server = Server::with(
+        libdoh::DoHBuilder::new()
+            .upstream(mock_resolver())
+            .max_query_size(65535),
+    );
+    let (response, body) = server.post(padded_query(4096));
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body[3] & 0x0f, 0);
+    let (response, _) = server.post(padded_query(4097));
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[test]
+fn other_methods() {
+    let mut server = Server::new();
+    for method in &[Method::PUT, Method::DELETE, Method::PATCH] {
+        let req = Request::builder()
+            .method(method)
+            .uri("/dns-query")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(server.send(req).0.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
+
+fn name() -> impl Strategy<Value = String> {
+    prop::collection::vec("[a-z0-9]([a-z0-9-]{0,20}[a-z0-9])?", 1..5)
+        .prop_map(|labels| labels.join("."))
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn get_and_post_answer_any_query(id: u16, name in name()) {
+        let mut server = Server::new();
+        let query = query(id, &name);
+        let dns = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+        let (response, body) = server.get(&dns);
+        assert_answers(&response, &body, &query);
+        let (response, body) = server.post(query.clone());
+        assert_answers(&response, &body, &query);
+    }
+}
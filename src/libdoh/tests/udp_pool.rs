@@ -0,0 +1,78 @@
+//! Upstream queries sent over a pool of UDP sockets, and told apart by their transaction ID.
+
+mod common;
+
+use common::*;
+use libdoh::DoHBuilder;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Number of queries after which pooled sockets are replaced
+const SOCKET_MAX_QUERIES: usize = 100;
+
+/// The addresses and transaction IDs of the queries received by the upstream server
+type Received = Arc<Mutex<Vec<(SocketAddr, u16)>>>;
+
+/// Starts a server with a single pooled socket, and an upstream server that waits before
+/// answering the first query, so that the following ones are sent in the meantime
+fn start_server() -> (Server, Received) {
+    let received = Received::default();
+    let received_ = received.clone();
+    let upstream = mock_resolver_with(move |query, client| {
+        let mut received = received_.lock().unwrap();
+        received.push((client, u16::from_be_bytes([query[0], query[1]])));
+        if received.len() == 1 {
+            thread::sleep(Duration::from_millis(100));
+        }
+        answer(query)
+    });
+    let server = Server::with(DoHBuilder::new().upstream(upstream).udp_pool_size(1));
+    (server, received)
+}
+
+#[test]
+fn concurrent_queries_are_multiplexed() {
+    let (mut server, received) = start_server();
+    let queries: Vec<_> = (0..20)
+        .map(|i| query(0x1234, &format!("name{}.example", i)))
+        .collect();
+    let responses = server.runtime.block_on(futures::future::join_all(
+        queries
+            .iter()
+            .map(|query| send(server.doh.clone(), post_request(query.clone()))),
+    ));
+    for (query, (response, body)) in queries.iter().zip(responses) {
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        assert_eq!(body[..2], query[..2]);
+        assert_eq!(body[12..query.len()], query[12..]);
+    }
+
+    // The queries were waiting for their response at the same time, on the same socket
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), queries.len());
+    assert!(received.iter().all(|(client, _)| *client == received[0].0));
+    let mut ids: Vec<_> = received.iter().map(|(_, id)| *id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), queries.len());
+}
+
+#[test]
+fn sockets_are_replaced() {
+    let (mut server, received) = start_server();
+    for i in 0..SOCKET_MAX_QUERIES * 3 {
+        let (response, _) = server.post(query(0, &format!("name{}.example", i)));
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+    }
+    let mut queries_per_port = HashMap::new();
+    for (client, _) in received.lock().unwrap().iter() {
+        *queries_per_port.entry(client.port()).or_insert(0) += 1;
+    }
+    assert!(queries_per_port.len() >= 3);
+    assert!(queries_per_port
+        .values()
+        .all(|&queries| queries <= SOCKET_MAX_QUERIES));
+}
@@ -0,0 +1,48 @@
+//! Responses that don't fit in the UDP payload size, sent again by the upstream server over TCP.
+
+mod common;
+
+use common::*;
+use libdoh::DoHBuilder;
+
+/// Enough `A` records for the response to be larger than any UDP payload
+const RECORDS: u16 = 400;
+
+/// Returns the header and the question of `query`, with the TC bit set
+fn truncated(query: &[u8]) -> Option<Vec<u8>> {
+    let mut response = query.get(..question_end(query)?)?.to_vec();
+    response[2] = 0x83 | (query[2] & 0x01);
+    response[3] = 0x80;
+    response[6..12].copy_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Some(response)
+}
+
+/// Returns a response with `RECORDS` records
+fn large_answer(query: &[u8]) -> Option<Vec<u8>> {
+    let mut response = answer(query)?;
+    let record = response[response.len() - 16..].to_vec();
+    for i in 1..RECORDS {
+        response.extend_from_slice(&record);
+        let len = response.len();
+        response[len - 2..].copy_from_slice(&i.to_be_bytes());
+    }
+    response[6..8].copy_from_slice(&RECORDS.to_be_bytes());
+    Some(response)
+}
+
+#[test]
+fn responses_larger_than_udp_packets() {
+    let upstream = mock_resolver_with_tcp(|query, _| truncated(query), large_answer);
+    let mut server = Server::with(DoHBuilder::new().upstream(upstream));
+    for id in 0..2 {
+        let (response, body) = server.post(query(id, "large.example"));
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        assert!(body.len() > 4096);
+        assert_eq!(body[3] & 0x0f, 0);
+        assert_eq!(u16::from_be_bytes([body[6], body[7]]), RECORDS);
+        let cache_control = response.headers()[hyper::header::CACHE_CONTROL]
+            .to_str()
+            .unwrap();
+        assert!(cache_control.contains(&format!("max-age={}", ANSWER_TTL)));
+    }
+}
@@ -0,0 +1,69 @@
+use libdoh::{max_log_level, set_max_log_level};
+use tracing::level_filters::LevelFilter;
+use tracing::subscriber::Interest;
+use tracing::{Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Layer exporting the spans
+#[cfg(feature = "tls")]
+pub type SpanLayer = libdoh::OtlpLayer;
+#[cfg(not(feature = "tls"))]
+pub type SpanLayer = tracing_subscriber::layer::Identity;
+
+/// Only enables the events from the application, ignoring the events from dependencies.
+/// The maximum level is checked for every event, as it can be changed at runtime.
+/// Spans are only enabled if they are exported.
+struct LevelLayer {
+    spans: bool,
+}
+
+fn is_own(metadata: &Metadata<'_>) -> bool {
+    metadata.target().starts_with("libdoh") || metadata.target().starts_with("doh_proxy")
+}
+
+impl<S: Subscriber> Layer<S> for LevelLayer {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if is_own(metadata) {
+            Interest::sometimes()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        if metadata.is_span() {
+            return self.spans && is_own(metadata);
+        }
+        is_own(metadata) && *metadata.level() <= max_log_level()
+    }
+}
+
+/// Writes log messages to the standard error output, as text or as one JSON object per line
+pub fn init(level: LevelFilter, json: bool, span_layer: Option<SpanLayer>) {
+    set_max_log_level(level);
+    let registry = tracing_subscriber::registry()
+        .with(LevelLayer {
+            spans: span_layer.is_some(),
+        })
+        .with(span_layer);
+    let _ = if json {
+        registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_writer(std::io::stderr),
+            )
+            .try_init()
+    } else {
+        registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .without_time()
+                    .with_target(false)
+                    .with_writer(std::io::stderr),
+            )
+            .try_init()
+    };
+}
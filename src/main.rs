@@ -1,53 +1,168 @@
+// jemalloc is used by default, except with the MSVC toolchain that it doesn't support.
+// mimalloc replaces it when its feature is enabled, and the system allocator is used
+// when neither is.
+#[cfg(all(
+    feature = "jemalloc",
+    not(feature = "mimalloc"),
+    not(target_env = "msvc")
+))]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static ALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
 #[macro_use]
 extern crate clap;
 
+#[cfg(feature = "tls")]
+mod bench;
 mod config;
-mod constants;
+mod config_file;
+mod logger;
 mod utils;
+#[cfg(windows)]
+mod winservice;
 
 use libdoh::*;
 
 use crate::config::*;
-use crate::constants::*;
-
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::Arc;
-use std::time::Duration;
 
 fn main() {
+    let mut doh_builder = DoHBuilder::new();
+    #[allow(unused_variables, unused_mut)]
+    let mut process_options = parse_opts(doh_builder.globals_mut());
+    #[cfg(feature = "tls")]
+    {
+        if let Some(bench_options) = process_options.bench.take() {
+            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            if let Err(e) = runtime.block_on(bench::run(bench_options)) {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+            return;
+        }
+    }
+    let doh = doh_builder.build();
+    if process_options.check_config {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let checks = runtime.block_on(doh.check());
+        for check in &checks {
+            println!("{}", check);
+        }
+        if !checks.iter().all(Check::is_ok) {
+            std::process::exit(1)
+        }
+        println!("The configuration is valid");
+        return;
+    }
+
+    // The process forks to the background and drops its privileges once the sockets are
+    // bound, and before the runtime starts its worker threads, that would otherwise not be
+    // forked or keep the previous privileges
+    let listeners = doh.bind().unwrap_or_else(|e| {
+        eprintln!("Unable to bind the listening sockets: {}", e);
+        std::process::exit(1)
+    });
+    #[cfg(unix)]
+    {
+        doh.globals.daemon.start().unwrap();
+        doh.globals.privdrop.apply().unwrap();
+    }
+
+    #[cfg(unix)]
+    let per_core_runtime = process_options.per_core_runtime;
+    #[cfg(not(unix))]
+    let per_core_runtime = false;
     let mut runtime_builder = tokio::runtime::Builder::new();
     runtime_builder.enable_all();
-    runtime_builder.threaded_scheduler();
+    if per_core_runtime {
+        runtime_builder.basic_scheduler();
+    } else {
+        runtime_builder.threaded_scheduler();
+        runtime_builder.core_threads(process_options.runtime_threads);
+    }
     runtime_builder.thread_name("doh-proxy");
     let mut runtime = runtime_builder.build().unwrap();
 
-    let mut globals = Globals {
-        #[cfg(feature = "tls")]
-        tls_cert_path: None,
-        #[cfg(feature = "tls")]
-        tls_cert_key_path: None,
-
-        listen_address: LISTEN_ADDRESS.parse().unwrap(),
-        local_bind_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
-        server_address: SERVER_ADDRESS.parse().unwrap(),
-        path: PATH.to_string(),
-        max_clients: MAX_CLIENTS,
-        timeout: Duration::from_secs(TIMEOUT_SEC),
-        clients_count: Default::default(),
-        min_ttl: MIN_TTL,
-        max_ttl: MAX_TTL,
-        err_ttl: ERR_TTL,
-        keepalive: true,
-        disable_post: false,
-
-        runtime_handle: runtime.handle().clone(),
-    };
-    parse_opts(&mut globals);
-    let doh = DoH {
-        globals: Arc::new(globals),
-    };
-    runtime.block_on(doh.entrypoint()).unwrap();
+    #[cfg(feature = "tls")]
+    {
+        if let Some(otlp_exporter) = process_options.otlp_exporter {
+            runtime.spawn(otlp_exporter.run());
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        if process_options.windows_service {
+            return winservice::run(doh, listeners, runtime).unwrap();
+        }
+    }
+    #[cfg(unix)]
+    {
+        let globals = doh.globals.clone();
+        let res = if per_core_runtime {
+            serve_per_core(
+                &mut runtime,
+                doh,
+                listeners,
+                process_options.runtime_threads,
+            )
+        } else {
+            runtime.block_on(doh.serve_until(listeners, terminated()))
+        };
+        globals.daemon.remove_pid_file();
+        res.unwrap();
+    }
+    #[cfg(not(unix))]
+    runtime.block_on(doh.serve(listeners)).unwrap();
+}
+
+/// Serves a share of the sockets on every thread, each running its own single-threaded
+/// runtime, so that connections never move between threads. The background tasks run on
+/// the runtime of the main thread, that also serves the first share.
+#[cfg(unix)]
+fn serve_per_core(
+    runtime: &mut tokio::runtime::Runtime,
+    doh: DoH,
+    listeners: Listeners,
+    threads: usize,
+) -> Result<(), DoHError> {
+    let mut shares = listeners.split(threads).into_iter();
+    let listeners = shares.next().unwrap();
+    let mut workers = vec![];
+    for (i, listeners) in shares.enumerate() {
+        let doh = doh.clone();
+        let worker = std::thread::Builder::new()
+            .name(format!("doh-proxy-{}", i + 1))
+            .spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new()
+                    .enable_all()
+                    .basic_scheduler()
+                    .thread_name("doh-proxy")
+                    .build()
+                    .map_err(DoHError::Io)?;
+                runtime.block_on(doh.serve_worker(listeners))
+            })
+            .map_err(DoHError::Io)?;
+        workers.push(worker);
+    }
+    let mut res = runtime.block_on(doh.serve_until(listeners, terminated()));
+    for worker in workers {
+        let worker_res = worker.join().expect("Worker thread panicked");
+        res = res.and(worker_res);
+    }
+    res
+}
+
+/// Completes when the process is asked to terminate
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+    use tokio::stream::StreamExt;
+
+    let sigterm = signal(SignalKind::terminate()).unwrap();
+    let sigint = signal(SignalKind::interrupt()).unwrap();
+    sigterm.merge(sigint).next().await;
 }
@@ -1,4 +1,10 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use libdoh::defaults::MAX_QUERY_SIZE_LIMIT;
+use libdoh::{
+    AddressFilter, BootstrapResolver, CanaryDomains, ClientKeyPolicy, CorsOrigins, DeniedQtypes,
+    Dns64Prefix, EcsPolicy, EdnsStrip, ResponseHeaders, StatsLabels, TrustedProxies, Upstream,
+    UpstreamProxy,
+};
+use std::net::{IpAddr, SocketAddr};
 
 // functions to verify the startup arguments as correct
 pub(crate) fn verify_sock_addr(arg_val: String) -> Result<(), String> {
@@ -11,15 +17,192 @@ pub(crate) fn verify_sock_addr(arg_val: String) -> Result<(), String> {
     }
 }
 
-pub(crate) fn verify_remote_server(arg_val: String) -> Result<(), String> {
-    match arg_val.to_socket_addrs() {
-        Ok(mut addr_iter) => match addr_iter.next() {
-            Some(_) => Ok(()),
-            None => Err(format!(
-                "Could not parse \"{}\" as a valid remote uri",
-                arg_val
-            )),
+/// Splits an upstream server given as `host:port`, optionally followed by `@weight`
+fn split_upstream(arg_val: &str) -> Result<(&str, u32), String> {
+    let (server, weight) = match arg_val.rfind('@') {
+        None => (arg_val, 1),
+        Some(pos) => match arg_val[pos + 1..].parse() {
+            Ok(weight) => (&arg_val[..pos], weight),
+            Err(_) => return Err(format!("Invalid upstream weight in \"{}\"", arg_val)),
         },
-        Err(err) => Err(format!("{}", err)),
+    };
+    match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => Ok((server, weight)),
+        _ => Err(format!("Expected host:port, got \"{}\"", server)),
     }
 }
+
+/// Parses and resolves an upstream server, with the bootstrap resolver if there is one
+pub(crate) fn parse_upstream(
+    arg_val: &str,
+    bootstrap: Option<&BootstrapResolver>,
+) -> Result<Upstream, String> {
+    let (server, weight) = split_upstream(arg_val)?;
+    Upstream::resolve(server, weight, bootstrap)
+}
+
+/// Parses the address of a bootstrap DNS server, on port 53 unless another port is given
+pub(crate) fn parse_bootstrap_dns(arg_val: &str) -> Result<SocketAddr, String> {
+    if let Ok(ip) = arg_val.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+    arg_val
+        .parse()
+        .map_err(|_| format!("Invalid bootstrap DNS server address: \"{}\"", arg_val))
+}
+
+/// Parses a public name given as `hostname`, or `hostname:port` if HTTPS is not served on port 443
+pub(crate) fn parse_ddr_hostname(arg_val: &str) -> Result<(String, u16), String> {
+    match arg_val.rsplit_once(':') {
+        None => Ok((arg_val.to_string(), 443)),
+        Some((hostname, port)) => match port.parse() {
+            Ok(port) if port > 0 => Ok((hostname.to_string(), port)),
+            _ => Err(format!("Invalid port in \"{}\"", arg_val)),
+        },
+    }
+}
+
+pub(crate) fn verify_ddr_hostname(arg_val: String) -> Result<(), String> {
+    parse_ddr_hostname(&arg_val).map(|_| ())
+}
+
+pub(crate) fn verify_max_query_size(arg_val: String) -> Result<(), String> {
+    match arg_val.parse::<usize>() {
+        Ok(max_query_size) if (512..=MAX_QUERY_SIZE_LIMIT).contains(&max_query_size) => Ok(()),
+        _ => Err(format!(
+            "Invalid query size \"{}\": it must be between 512 and {}",
+            arg_val, MAX_QUERY_SIZE_LIMIT
+        )),
+    }
+}
+
+pub(crate) fn verify_max_udp_size(arg_val: String) -> Result<(), String> {
+    match arg_val.parse::<u16>() {
+        Ok(max_udp_size) if (512..=4096).contains(&max_udp_size) => Ok(()),
+        _ => Err(format!(
+            "Invalid UDP payload size \"{}\": it must be between 512 and 4096",
+            arg_val
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn verify_bind_device(arg_val: String) -> Result<(), String> {
+    if arg_val.is_empty() || arg_val.len() > 15 || arg_val.contains('\0') {
+        return Err(format!("Invalid interface name \"{}\"", arg_val));
+    }
+    Ok(())
+}
+
+pub(crate) fn verify_runtime_threads(arg_val: String) -> Result<(), String> {
+    match arg_val.parse::<usize>() {
+        Ok(runtime_threads) if runtime_threads > 0 => Ok(()),
+        _ => Err(format!(
+            "Invalid number of threads \"{}\": it must be at least 1",
+            arg_val
+        )),
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn verify_reuseport(arg_val: String) -> Result<(), String> {
+    match arg_val.parse::<usize>() {
+        Ok(reuseport) if reuseport > 0 => Ok(()),
+        _ => Err(format!(
+            "Invalid number of sockets \"{}\": it must be at least 1",
+            arg_val
+        )),
+    }
+}
+
+pub(crate) fn verify_query_log_size(arg_val: String) -> Result<(), String> {
+    arg_val
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("Invalid query log size \"{}\"", arg_val))
+}
+
+pub(crate) fn verify_upstream(arg_val: String) -> Result<(), String> {
+    split_upstream(&arg_val).map(|_| ())
+}
+
+pub(crate) fn verify_bootstrap_dns(arg_val: String) -> Result<(), String> {
+    parse_bootstrap_dns(&arg_val).map(|_| ())
+}
+
+pub(crate) fn verify_upstream_proxy(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<UpstreamProxy>().map(|_| ())
+}
+
+#[cfg(feature = "tls")]
+pub(crate) fn verify_upstream_doh(arg_val: String) -> Result<(), String> {
+    libdoh::DoHUpstream::parse_url(&arg_val).map(|_| ())
+}
+
+#[cfg(feature = "tls")]
+pub(crate) fn verify_otlp_endpoint(arg_val: String) -> Result<(), String> {
+    libdoh::DoHUpstream::parse_url(&arg_val).map(|_| ())
+}
+
+#[cfg(feature = "tls")]
+pub(crate) fn verify_block_list_update_interval(arg_val: String) -> Result<(), String> {
+    match arg_val.parse::<u64>() {
+        Ok(interval) if interval > 0 => Ok(()),
+        _ => Err(format!(
+            "Invalid update interval \"{}\": it must be at least 1 second",
+            arg_val
+        )),
+    }
+}
+
+pub(crate) fn verify_client_key(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<ClientKeyPolicy>().map(|_| ())
+}
+
+pub(crate) fn verify_ecs_policy(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<EcsPolicy>().map(|_| ())
+}
+
+pub(crate) fn verify_edns_strip(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<EdnsStrip>().map(|_| ())
+}
+
+pub(crate) fn verify_canary_domain(arg_val: String) -> Result<(), String> {
+    CanaryDomains::default().add(&arg_val)
+}
+
+pub(crate) fn verify_cors_origins(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<CorsOrigins>().map(|_| ())
+}
+
+pub(crate) fn verify_response_header(arg_val: String) -> Result<(), String> {
+    ResponseHeaders::default().add_line(&arg_val)
+}
+
+pub(crate) fn verify_dns64_prefix(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<Dns64Prefix>().map(|_| ())
+}
+
+pub(crate) fn verify_stats_labels(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<StatsLabels>().map(|_| ())
+}
+
+pub(crate) fn verify_trusted_proxies(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<TrustedProxies>().map(|_| ())
+}
+
+#[cfg(feature = "tls")]
+pub(crate) fn verify_qtype(arg_val: String) -> Result<(), String> {
+    match libdoh::dns::parse_qtype(&arg_val) {
+        Some(_) => Ok(()),
+        None => Err(format!("Unsupported record type \"{}\"", arg_val)),
+    }
+}
+
+pub(crate) fn verify_qtypes(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<DeniedQtypes>().map(|_| ())
+}
+
+pub(crate) fn verify_address_filter(arg_val: String) -> Result<(), String> {
+    AddressFilter::parse(libdoh::dns::DNS_TYPE_AAAA, &arg_val).map(|_| ())
+}
@@ -0,0 +1,112 @@
+use libdoh::*;
+
+use std::ffi::OsString;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::{define_windows_service, service_dispatcher};
+
+const SERVICE_NAME: &str = "doh-proxy";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// The proxy, handed over to the thread the dispatcher starts the service on
+static SERVICE: Mutex<Option<(DoH, Listeners, Runtime)>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Connects to the Service Control Manager, and serves queries until the service is stopped
+pub fn run(doh: DoH, listeners: Listeners, runtime: Runtime) -> windows_service::Result<()> {
+    *SERVICE.lock().unwrap() = Some((doh, listeners, runtime));
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        tracing::error!("Windows service error: {}", e);
+    }
+}
+
+fn set_status(
+    status_handle: &ServiceStatusHandle,
+    current_state: ServiceState,
+    controls_accepted: ServiceControlAccept,
+    exit_code: u32,
+    wait_hint: Duration,
+) -> windows_service::Result<()> {
+    status_handle.set_service_status(ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    })
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let (doh, listeners, mut runtime) = match SERVICE.lock().unwrap().take() {
+        None => return Ok(()),
+        Some(service) => service,
+    };
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let mut stop_tx = Some(stop_tx);
+    let event_handler = move |control_event| match control_event {
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        // Connections cannot be suspended, so pausing the service stops it as well
+        ServiceControl::Stop | ServiceControl::Shutdown | ServiceControl::Pause => {
+            if let Some(stop_tx) = stop_tx.take() {
+                let _ = stop_tx.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
+    set_status(
+        &status_handle,
+        ServiceState::Running,
+        ServiceControlAccept::STOP
+            | ServiceControlAccept::SHUTDOWN
+            | ServiceControlAccept::PAUSE_CONTINUE,
+        0,
+        Duration::default(),
+    )?;
+
+    // Connections are closed once the queries being processed have been answered
+    let timeout = doh.globals.timeout;
+    let stopped = async {
+        let _ = stop_rx.await;
+        let _ = set_status(
+            &status_handle,
+            ServiceState::StopPending,
+            ServiceControlAccept::empty(),
+            0,
+            timeout,
+        );
+    };
+    let res = runtime.block_on(doh.serve_until(listeners, stopped));
+    runtime.shutdown_background();
+
+    let exit_code = match res {
+        Ok(()) => 0,
+        Err(e) => {
+            tracing::error!("{}", e);
+            1
+        }
+    };
+    set_status(
+        &status_handle,
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+        Duration::default(),
+    )
+}